@@ -0,0 +1,60 @@
+//! Per-frame cost of the two ways to turn a 640x480 RGB24 frame into
+//! lane states: building a full `Vec<bool>` mask and reducing it, versus
+//! the fused single-pass `LaneReducer::reduce_frame`, plus the cost of a
+//! 3x3 opening + closing mask filter on top.
+//!
+//! Run with `cargo bench --bench lane_reduction`.
+
+#![feature(test)]
+
+extern crate test;
+
+use rustspray_core::{lanes::LaneReducer, morphology::MaskFilter, vision::PlantVision};
+use test::{black_box, Bencher};
+
+const WIDTH: usize = 640;
+const HEIGHT: usize = 480;
+
+/// Green in lanes 0 and 2, soil elsewhere — the `--test-pattern` frame.
+fn frame() -> Vec<u8> {
+    let mut frame = Vec::with_capacity(WIDTH * HEIGHT * 3);
+    for _ in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let green = x < WIDTH / 4 || (WIDTH / 2..3 * WIDTH / 4).contains(&x);
+            let px = if green { [20, 200, 20] } else { [120, 90, 70] };
+            frame.extend_from_slice(&px);
+        }
+    }
+    frame
+}
+
+#[bench]
+fn detect_then_reduce(b: &mut Bencher) {
+    let frame = frame();
+    let vision = PlantVision::default();
+    let mut reducer = LaneReducer::new(4, 0.3, 0.15);
+    b.iter(|| {
+        let mask = vision.detect(black_box(&frame));
+        black_box(reducer.reduce(&mask, WIDTH, HEIGHT));
+    });
+}
+
+#[bench]
+fn fused_reduce_frame(b: &mut Bencher) {
+    let frame = frame();
+    let vision = PlantVision::default();
+    let mut reducer = LaneReducer::new(4, 0.3, 0.15);
+    b.iter(|| {
+        black_box(reducer.reduce_frame(black_box(&frame), WIDTH, HEIGHT, &vision));
+    });
+}
+
+#[bench]
+fn fused_reduce_frame_filtered(b: &mut Bencher) {
+    let frame = frame();
+    let vision = PlantVision::default();
+    let mut reducer = LaneReducer::new(4, 0.3, 0.15).with_filter(MaskFilter::new(3, 3));
+    b.iter(|| {
+        black_box(reducer.reduce_frame(black_box(&frame), WIDTH, HEIGHT, &vision));
+    });
+}
@@ -29,22 +29,45 @@ class RustSprayDetector:
     """
     Wraps the Rust-Spray binary as a high-performance inner loop.
 
-    IPC protocol v1:
-      stdin  <- [u32 width LE][u32 height LE][width*height*3 bytes RGB24]
-      stdout -> {"v":1,"frame":N,"ts_us":T,"lanes":[bool,...],"latency_us":L}\\n
+    IPC protocol v1 (v2 for RGBN):
+      stdin  <- [u32 width LE][u32 height LE][width*height*C bytes]
+      (C = 3 for RGB24; C = 4 for RGBN with ``[camera] pixel_format = "rgbn"``,
+      which the binary reports as protocol v2)
+      stdout -> {"v":1,"frame":N,"ts_us":T,"lanes":[bool,...],"latency_us":L,
+                 "coverage":[float,...],"pixels":[int,...],
+                 "blobs":[{"x","y","w","h","area","cx","cy"},...],
+                 "crop_rows":[float,...],"duty":[float,...],
+                 "gps":{"fix","speed_mps","lat","lon"},"exg_threshold":int}\\n
+      (``blobs`` only when ``[vision.blobs] enabled = true``, ``crop_rows``
+      only when ``[vision.crop_rows] enabled = true``, ``duty`` only when
+      ``[gpio.pwm] enabled = true``, ``gps`` only with ``[gps] device``)
 
     The subprocess drives GPIO itself (unless ``mock_gpio``), so the lane
     states returned here are for OWL's logging/dashboard and any additional
-    actuation OWL performs. Frames must be RGB (not BGR), uint8, HxWx3.
+    actuation OWL performs. Frames must be RGB (not BGR), uint8, HxWx3 —
+    or HxWx4 RGB+NIR when the config selects ``pixel_format = "rgbn"``.
 
     Failure policy: if the subprocess dies or a frame times out, it is
     restarted transparently up to ``MAX_RESTARTS`` times over the detector's
     lifetime. Once the budget is exhausted, :meth:`detect` raises
     ``RuntimeError`` so OWL's outer loop can fall back to its Python ExG
     detector.
+
+    After each :meth:`detect`, :attr:`lane_coverage` holds the per-lane
+    vegetation coverage ratios reported by the binary (empty if the binary
+    predates the ``coverage`` field), for threshold tuning on the dashboard,
+    and :attr:`blobs` the weed blobs (dicts with ``x``, ``y``, ``w``,
+    ``h``, ``area``, ``cx``, ``cy``) when the blob stage is enabled.
+    :attr:`crop_rows` holds the crop row centre columns when crop row
+    tracking is enabled, :attr:`lane_duty` the PWM duty per lane when PWM
+    output is enabled, and :attr:`gps` the GPS reading (``None`` without
+    a receiver).
+    :attr:`exg_threshold` is the ExG threshold the binary used (per frame
+    with ``[vision.adaptive]``), or ``None`` from older binaries.
     """
 
     PROTOCOL_VERSION = 1
+    RGBN_PROTOCOL_VERSION = 2
     STARTUP_TIMEOUT_S = 5.0
     FRAME_TIMEOUT_S = 0.10  # 100 ms — safe at up to 30 km/h
     MAX_RESTARTS = 3
@@ -74,6 +97,13 @@ class RustSprayDetector:
         self._restarts = 0
         self._lock = threading.Lock()
         self._closed = False
+        self._protocol = self.PROTOCOL_VERSION
+        self.lane_coverage: list[float] = []
+        self.blobs: list[dict] = []
+        self.crop_rows: list[float] = []
+        self.lane_duty: list[float] = []
+        self.gps: dict | None = None
+        self.exg_threshold: int | None = None
 
         if not os.path.isfile(self.binary_path):
             raise RuntimeError(f"rustspray binary not found: {self.binary_path}")
@@ -98,19 +128,22 @@ class RustSprayDetector:
 
         Returns ``(boxes, annotated_frame, lane_states)``:
 
-        - ``boxes`` — one ``(x, y, w, h)`` box per **active** lane, covering
-          that lane's vertical strip, so OWL's logger/dashboard have a
-          region to display.
-        - ``annotated_frame`` — the input frame with active lane strips
-          outlined in green.
+        - ``boxes`` — one ``(x, y, w, h)`` box per weed blob when the blob
+          stage (``[vision.blobs]``) is enabled; otherwise one per
+          **active** lane, covering that lane's vertical strip, so OWL's
+          logger/dashboard have a region to display.
+        - ``annotated_frame`` — the input frame with the boxes outlined in
+          green.
         - ``lane_states`` — list of bool, one per spray lane, in lane order.
 
         ``confidence`` and ``filter_id`` are accepted for interface
         compatibility; thresholds live in Rust-Spray's TOML config.
         """
-        if frame.ndim != 3 or frame.shape[2] != 3 or frame.dtype != np.uint8:
+        channels = 4 if self._protocol == self.RGBN_PROTOCOL_VERSION else 3
+        if frame.ndim != 3 or frame.shape[2] != channels or frame.dtype != np.uint8:
             raise ValueError(
-                f"expected HxWx3 uint8 RGB frame, got shape {frame.shape} dtype {frame.dtype}"
+                f"expected HxWx{channels} uint8 {'RGBN' if channels == 4 else 'RGB'} "
+                f"frame, got shape {frame.shape} dtype {frame.dtype}"
             )
         height, width = frame.shape[:2]
         payload = np.ascontiguousarray(frame).tobytes()
@@ -119,7 +152,16 @@ class RustSprayDetector:
             response = self._send_frame_with_restart(payload, width, height)
 
         lane_states = list(response["lanes"])[: self.num_lanes]
-        boxes = self._lane_boxes(lane_states, width, height)
+        self.lane_coverage = list(response.get("coverage", []))[: self.num_lanes]
+        self.blobs = list(response.get("blobs", []))
+        self.crop_rows = list(response.get("crop_rows", []))
+        self.lane_duty = list(response.get("duty", []))
+        self.gps = response.get("gps")
+        self.exg_threshold = response.get("exg_threshold")
+        if "blobs" in response:
+            boxes = [(b["x"], b["y"], b["w"], b["h"]) for b in self.blobs]
+        else:
+            boxes = self._lane_boxes(lane_states, width, height)
         annotated = self._annotate(frame, boxes)
         return boxes, annotated, lane_states
 
@@ -153,7 +195,7 @@ class RustSprayDetector:
         """Startup handshake: refuse to run against an incompatible binary."""
         try:
             out = subprocess.run(
-                [self.binary_path, "--output-version"],
+                [self.binary_path, "--output-version", "--config", self.config_path],
                 capture_output=True,
                 timeout=self.STARTUP_TIMEOUT_S,
                 check=True,
@@ -163,12 +205,14 @@ class RustSprayDetector:
             raise RuntimeError(f"rustspray --output-version failed: {exc}") from exc
 
         protocol = info.get("ipc_protocol")
-        if protocol != self.PROTOCOL_VERSION:
+        if protocol not in (self.PROTOCOL_VERSION, self.RGBN_PROTOCOL_VERSION):
             raise RuntimeError(
                 f"rustspray IPC protocol mismatch: binary speaks v{protocol}, "
                 f"this detector requires v{self.PROTOCOL_VERSION} "
+                f"(or v{self.RGBN_PROTOCOL_VERSION} for RGBN) "
                 f"(binary version {info.get('rustspray_version')})"
             )
+        self._protocol = protocol
         logger.info(
             "rustspray %s (IPC protocol v%s) at %s",
             info.get("rustspray_version"),
@@ -255,10 +299,10 @@ class RustSprayDetector:
             raise BrokenPipeError("rustspray closed its stdout")
 
         response = json.loads(line)
-        if response.get("v") != self.PROTOCOL_VERSION:
+        if response.get("v") != self._protocol:
             raise RuntimeError(
                 f"rustspray response protocol v{response.get('v')} != "
-                f"expected v{self.PROTOCOL_VERSION}"
+                f"expected v{self._protocol}"
             )
         return response
 
@@ -312,9 +356,10 @@ class RustSprayDetector:
     def _lane_boxes(
         self, lane_states: list[bool], width: int, height: int
     ) -> list[tuple[int, int, int, int]]:
-        """One (x, y, w, h) box per active lane, matching Rust-Spray's lane
-        geometry: base width ``width // lanes`` with the first
-        ``width % lanes`` lanes one pixel wider."""
+        """One (x, y, w, h) box per active lane, matching Rust-Spray's default
+        lane geometry: base width ``width // lanes`` with the first
+        ``width % lanes`` lanes one pixel wider. Custom ``[lanes]
+        boundaries`` / ``polygons`` are not reflected in the boxes."""
         n = len(lane_states)
         if n == 0:
             return []
@@ -332,10 +377,11 @@ class RustSprayDetector:
     def _annotate(
         frame: np.ndarray, boxes: list[tuple[int, int, int, int]]
     ) -> np.ndarray:
-        """Outline active lane strips in green (no OpenCV dependency)."""
+        """Outline boxes in green (no OpenCV dependency). The NIR channel
+        of an RGBN frame is dropped."""
         if not boxes:
-            return frame
-        annotated = frame.copy()
+            return frame[..., :3]
+        annotated = frame[..., :3].copy()
         green = (0, 255, 0)
         for x, y, w, h in boxes:
             annotated[y : y + 2, x : x + w] = green
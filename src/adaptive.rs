@@ -0,0 +1,192 @@
+//! Exposure-adaptive ExG threshold.
+//!
+//! A fixed `exg_threshold` tuned at noon fails at dusk or under cloud,
+//! when every pixel's Excess Green response shrinks. [`AdaptiveThreshold`]
+//! builds the ExG histogram of each frame (optionally summed over a
+//! window of recent frames) and picks the threshold with Otsu's method —
+//! the split maximising the between-class variance of soil and plants.
+//! Clamps keep a bare-soil frame, whose histogram has only one mode, from
+//! dragging the threshold down into sensor noise.
+
+use crate::vision::Rect;
+use crate::white_balance::WhiteBalance;
+
+/// Lowest possible ExG value (`2g - r - b`).
+const EXG_MIN: i32 = -510;
+/// Number of histogram bins, one per ExG value in `-510..=510`.
+const BINS: usize = 1021;
+
+/// Picks the ExG threshold per frame with Otsu's method.
+#[derive(Debug, Clone)]
+pub struct AdaptiveThreshold {
+    min: i16,
+    max: i16,
+    /// Per-frame histograms of the last `window` frames (ring buffer).
+    frames: Vec<Vec<u32>>,
+    next: usize,
+    /// Sum of `frames`.
+    sum: Vec<u64>,
+    current: i16,
+}
+
+impl AdaptiveThreshold {
+    /// Threshold clamped to `min..=max`, from the histogram of the last
+    /// `window` frames. Until a frame gives a usable histogram the
+    /// threshold is `min`.
+    ///
+    /// # Panics
+    /// Panics if `min > max` or `window` is zero.
+    pub fn new(min: i16, max: i16, window: usize) -> Self {
+        assert!(min <= max, "Minimum threshold must not exceed maximum");
+        assert!(window > 0, "Window must hold at least one frame");
+        Self {
+            min,
+            max,
+            frames: vec![vec![0; BINS]; window],
+            next: 0,
+            sum: vec![0; BINS],
+            current: min,
+        }
+    }
+
+    /// The threshold chosen for the last frame.
+    pub fn threshold(&self) -> i16 {
+        self.current
+    }
+
+    /// Add the ExG histogram of the `region` of an RGB frame `width`
+    /// pixels wide (after `balance`, as the detector scores it), dropping
+    /// the oldest frame of the window, and return the updated threshold.
+    pub fn observe(
+        &mut self,
+        rgb: &[u8],
+        width: usize,
+        region: Rect,
+        balance: Option<&WhiteBalance>,
+    ) -> i16 {
+        let hist = &mut self.frames[self.next];
+        for (total, &old) in self.sum.iter_mut().zip(hist.iter()) {
+            *total -= u64::from(old);
+        }
+        hist.fill(0);
+        for y in 0..region.height {
+            for &[r, g, b] in region.row(rgb, width, 3, y).as_chunks::<3>().0 {
+                let [r, g, b] = balance.map_or([r, g, b], |wb| wb.apply(r, g, b));
+                let exg = 2 * i32::from(g) - i32::from(r) - i32::from(b);
+                hist[(exg - EXG_MIN) as usize] += 1;
+            }
+        }
+        for (total, &new) in self.sum.iter_mut().zip(hist.iter()) {
+            *total += u64::from(new);
+        }
+        self.next = (self.next + 1) % self.frames.len();
+
+        if let Some(threshold) = otsu(&self.sum) {
+            self.current = threshold.clamp(i32::from(self.min), i32::from(self.max)) as i16;
+        }
+        self.current
+    }
+}
+
+/// Otsu threshold of an ExG histogram: the largest value of the lower
+/// class. `None` if the histogram has fewer than two distinct values.
+fn otsu(hist: &[u64]) -> Option<i32> {
+    let total: u64 = hist.iter().sum();
+    let weighted: f64 = hist
+        .iter()
+        .enumerate()
+        .map(|(bin, &n)| bin as f64 * n as f64)
+        .sum();
+    let (mut below, mut below_weighted) = (0u64, 0.0f64);
+    let mut best: Option<(f64, usize)> = None;
+    for (bin, &n) in hist.iter().enumerate() {
+        below += n;
+        below_weighted += bin as f64 * n as f64;
+        if below == 0 {
+            continue;
+        }
+        let above = total - below;
+        if above == 0 {
+            break;
+        }
+        let mean_below = below_weighted / below as f64;
+        let mean_above = (weighted - below_weighted) / above as f64;
+        let between = below as f64 * above as f64 * (mean_below - mean_above).powi(2);
+        if best.is_none_or(|(score, _)| between > score) {
+            best = Some((between, bin));
+        }
+    }
+    best.map(|(_, bin)| bin as i32 + EXG_MIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `width` x 1 frame: `plants` pixels of `plant`, soil elsewhere.
+    fn frame(width: usize, plants: usize, soil: [u8; 3], plant: [u8; 3]) -> Vec<u8> {
+        (0..width)
+            .flat_map(|x| if x < plants { plant } else { soil })
+            .collect()
+    }
+
+    #[test]
+    fn otsu_splits_soil_and_plants() {
+        // Soil ExG -2 and 4, plants ExG 40 and 46.
+        let mut hist = vec![0u64; BINS];
+        for (exg, n) in [(-2, 50), (4, 50), (40, 30), (46, 30)] {
+            hist[(exg - EXG_MIN) as usize] = n;
+        }
+        let threshold = otsu(&hist).unwrap();
+        assert!((4..40).contains(&threshold), "threshold {threshold}");
+        assert_eq!(otsu(&vec![0u64; BINS]), None);
+    }
+
+    #[test]
+    fn dusk_frame_lowers_threshold_within_clamps() {
+        let width = 100;
+        let mut adaptive = AdaptiveThreshold::new(5, 80, 1);
+        // Dim soil ExG -2, dim plants ExG 30.
+        let dusk = frame(width, 30, [40, 32, 26], [22, 42, 2]);
+        let threshold = adaptive.observe(&dusk, width, Rect::full(width, 1), None);
+        assert!((5..30).contains(&threshold), "threshold {threshold}");
+        assert_eq!(adaptive.threshold(), threshold);
+    }
+
+    #[test]
+    fn bare_soil_is_held_at_the_minimum() {
+        let width = 64;
+        let mut adaptive = AdaptiveThreshold::new(15, 80, 1);
+        // Soil with slight noise: ExG -6..=6, no plants at all.
+        let soil: Vec<u8> = (0..width)
+            .flat_map(|x| [100 + (x % 4) as u8, 90 + (x % 7) as u8, 80])
+            .collect();
+        assert_eq!(
+            adaptive.observe(&soil, width, Rect::full(width, 1), None),
+            15
+        );
+    }
+
+    #[test]
+    fn window_pools_recent_frames() {
+        let width = 100;
+        let region = Rect::full(width, 1);
+        let noon = frame(width, 30, [120, 90, 70], [40, 210, 40]);
+        let dusk = frame(width, 30, [40, 32, 26], [22, 42, 2]);
+        let both: Vec<u8> = noon.iter().chain(&dusk).copied().collect();
+        let pooled = AdaptiveThreshold::new(-100, 400, 1).observe(
+            &both,
+            2 * width,
+            Rect::full(2 * width, 1),
+            None,
+        );
+        let dusk_only = AdaptiveThreshold::new(-100, 400, 1).observe(&dusk, width, region, None);
+        assert_ne!(pooled, dusk_only);
+
+        let mut windowed = AdaptiveThreshold::new(-100, 400, 2);
+        windowed.observe(&noon, width, region, None);
+        assert_eq!(windowed.observe(&dusk, width, region, None), pooled);
+        // The noon frame leaves the window.
+        assert_eq!(windowed.observe(&dusk, width, region, None), dusk_only);
+    }
+}
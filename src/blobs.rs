@@ -0,0 +1,294 @@
+//! Connected-component ("blob") labelling of the vegetation mask.
+//!
+//! Lane coverage alone cannot tell a scatter of specks from one real
+//! weed. [`BlobDetector`] groups set pixels into 8-connected components,
+//! drops those smaller than a minimum area and reports the rest with
+//! their bounding box and centroid. Labelling works on horizontal runs
+//! read straight from the packed [`BitMask`] words, so cost scales with
+//! the number of runs rather than pixels.
+
+use crate::morphology::BitMask;
+use serde::Serialize;
+
+/// One connected group of vegetation pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Blob {
+    /// Left column of the bounding box.
+    pub x: usize,
+    /// Top row of the bounding box.
+    pub y: usize,
+    /// Bounding box width in pixels.
+    #[serde(rename = "w")]
+    pub width: usize,
+    /// Bounding box height in pixels.
+    #[serde(rename = "h")]
+    pub height: usize,
+    /// Number of pixels in the blob.
+    pub area: u32,
+    /// Centroid column (pixel centres at `x + 0.5`).
+    pub cx: f32,
+    /// Centroid row (pixel centres at `y + 0.5`).
+    pub cy: f32,
+}
+
+impl Blob {
+    /// The same blob moved by (`dx`, `dy`) pixels.
+    pub fn offset(self, dx: usize, dy: usize) -> Self {
+        Self {
+            x: self.x + dx,
+            y: self.y + dy,
+            cx: self.cx + dx as f32,
+            cy: self.cy + dy as f32,
+            ..self
+        }
+    }
+}
+
+/// Half-open run `start..end` of set pixels on row `y`.
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    y: usize,
+    start: usize,
+    end: usize,
+}
+
+/// Per-component accumulator, indexed by root run.
+#[derive(Debug, Clone, Copy)]
+struct Stats {
+    area: u64,
+    sum_x: u64,
+    sum_y: u64,
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+
+/// Labels 8-connected blobs and filters them by area. Buffers are kept
+/// between frames.
+#[derive(Debug, Clone, Default)]
+pub struct BlobDetector {
+    min_area: u32,
+    runs: Vec<Run>,
+    parent: Vec<u32>,
+    stats: Vec<Stats>,
+    blobs: Vec<Blob>,
+}
+
+impl BlobDetector {
+    /// Detector keeping blobs of at least `min_area` pixels.
+    pub fn new(min_area: u32) -> Self {
+        Self {
+            min_area,
+            ..Self::default()
+        }
+    }
+
+    /// Smallest blob area kept, in pixels.
+    pub fn min_area(&self) -> u32 {
+        self.min_area
+    }
+
+    /// Label `mask` and return the blobs of at least `min_area` pixels in
+    /// order of their first (top-most, then left-most) pixel.
+    pub fn detect(&mut self, mask: &BitMask) -> &[Blob] {
+        self.label(mask);
+        &self.blobs
+    }
+
+    /// Like [`Self::detect`], and also clear the pixels of every blob
+    /// below `min_area` from `mask`.
+    pub fn retain(&mut self, mask: &mut BitMask) -> &[Blob] {
+        self.label(mask);
+        for i in 0..self.runs.len() {
+            let root = find(&mut self.parent, i as u32) as usize;
+            if self.stats[root].area < u64::from(self.min_area) {
+                let Run { y, start, end } = self.runs[i];
+                mask.clear_span(y, start, end);
+            }
+        }
+        &self.blobs
+    }
+
+    /// The blobs found by the last call.
+    pub fn blobs(&self) -> &[Blob] {
+        &self.blobs
+    }
+
+    fn label(&mut self, mask: &BitMask) {
+        self.runs.clear();
+        self.parent.clear();
+        self.blobs.clear();
+
+        let mut prev = 0..0;
+        for y in 0..mask.height() {
+            let first = self.runs.len();
+            mask.for_each_run(y, |start, end| self.runs.push(Run { y, start, end }));
+            let row = first..self.runs.len();
+            self.parent.extend(row.clone().map(|i| i as u32));
+
+            // Runs touch (8-connected) when their column ranges overlap
+            // after widening by one pixel. Both rows are sorted, so one
+            // sweep finds every touching pair.
+            let mut p = prev.start;
+            for i in row.clone() {
+                let run = self.runs[i];
+                while p < prev.end && self.runs[p].end < run.start {
+                    p += 1;
+                }
+                let mut q = p;
+                while q < prev.end && self.runs[q].start <= run.end {
+                    union(&mut self.parent, i as u32, q as u32);
+                    q += 1;
+                }
+            }
+            prev = row;
+        }
+
+        self.stats.clear();
+        self.stats.resize(
+            self.runs.len(),
+            Stats {
+                area: 0,
+                sum_x: 0,
+                sum_y: 0,
+                x0: usize::MAX,
+                y0: usize::MAX,
+                x1: 0,
+                y1: 0,
+            },
+        );
+        for i in 0..self.runs.len() {
+            let Run { y, start, end } = self.runs[i];
+            let root = find(&mut self.parent, i as u32) as usize;
+            let stats = &mut self.stats[root];
+            let len = (end - start) as u64;
+            stats.area += len;
+            stats.sum_x += (start + end - 1) as u64 * len / 2;
+            stats.sum_y += y as u64 * len;
+            stats.x0 = stats.x0.min(start);
+            stats.y0 = stats.y0.min(y);
+            stats.x1 = stats.x1.max(end);
+            stats.y1 = stats.y1.max(y + 1);
+        }
+        // A root is the lowest-numbered run of its component (see
+        // `union`), so walking roots in run order yields blobs in scan
+        // order.
+        for i in 0..self.runs.len() {
+            let stats = self.stats[i];
+            if self.parent[i] != i as u32 || stats.area < u64::from(self.min_area) {
+                continue;
+            }
+            let area = stats.area as f64;
+            self.blobs.push(Blob {
+                x: stats.x0,
+                y: stats.y0,
+                width: stats.x1 - stats.x0,
+                height: stats.y1 - stats.y0,
+                area: stats.area as u32,
+                cx: (stats.sum_x as f64 / area + 0.5) as f32,
+                cy: (stats.sum_y as f64 / area + 0.5) as f32,
+            });
+        }
+    }
+}
+
+/// Root of `i`, halving the path on the way.
+fn find(parent: &mut [u32], mut i: u32) -> u32 {
+    while parent[i as usize] != i {
+        let grandparent = parent[parent[i as usize] as usize];
+        parent[i as usize] = grandparent;
+        i = grandparent;
+    }
+    i
+}
+
+/// Merge the components of `a` and `b`, keeping the smaller root.
+fn union(parent: &mut [u32], a: u32, b: u32) {
+    let (a, b) = (find(parent, a), find(parent, b));
+    let (root, child) = (a.min(b), a.max(b));
+    parent[child as usize] = root;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a picture of `#` (set) and `.` (clear) rows.
+    fn mask(rows: &[&str]) -> BitMask {
+        let width = rows[0].len();
+        let pixels: Vec<bool> = rows
+            .iter()
+            .flat_map(|r| r.bytes().map(|b| b == b'#'))
+            .collect();
+        BitMask::from_bools(&pixels, width, rows.len())
+    }
+
+    #[test]
+    fn labels_eight_connected_blobs_with_boxes_and_centroids() {
+        let bits = mask(&[
+            "##......#.",
+            "##.....#..",
+            "......#...",
+            "..........",
+            "...###....",
+            "...###...#",
+        ]);
+        let mut detector = BlobDetector::new(1);
+        let blobs = detector.detect(&bits);
+        assert_eq!(blobs.len(), 4);
+        assert_eq!(
+            blobs[0],
+            Blob {
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 2,
+                area: 4,
+                cx: 1.0,
+                cy: 1.0
+            }
+        );
+        // The diagonal joins through corners.
+        assert_eq!(
+            (blobs[1].x, blobs[1].y, blobs[1].width, blobs[1].height),
+            (6, 0, 3, 3)
+        );
+        assert_eq!(blobs[1].area, 3);
+        assert_eq!((blobs[2].cx, blobs[2].cy), (4.5, 5.0));
+        assert_eq!((blobs[3].x, blobs[3].y, blobs[3].area), (9, 5, 1));
+    }
+
+    #[test]
+    fn u_shape_merges_into_one_blob() {
+        let bits = mask(&["#...#", "#...#", "#####"]);
+        let blobs = BlobDetector::new(1).detect(&bits).to_vec();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!((blobs[0].width, blobs[0].height, blobs[0].area), (5, 3, 9));
+    }
+
+    #[test]
+    fn retain_drops_small_blobs_from_mask() {
+        let mut bits = mask(&["#.......", "....###.", "..#.###.", "....###."]);
+        let mut detector = BlobDetector::new(4);
+        let blobs = detector.retain(&mut bits).to_vec();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].area, 9);
+        assert_eq!(
+            bits,
+            mask(&["........", "....###.", "....###.", "....###."])
+        );
+    }
+
+    #[test]
+    fn runs_span_word_boundaries() {
+        let width = 200;
+        let mut pixels = vec![false; width * 2];
+        pixels[60..140].fill(true);
+        pixels[width + 139..width + 190].fill(true);
+        let bits = BitMask::from_bools(&pixels, width, 2);
+        let blobs = BlobDetector::new(1).detect(&bits).to_vec();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!((blobs[0].x, blobs[0].width, blobs[0].area), (60, 130, 131));
+    }
+}
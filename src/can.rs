@@ -0,0 +1,543 @@
+//! Nozzle output as CAN frames for CAN-bus boom controllers.
+//!
+//! Larger booms switch their valves from a section controller on a CAN
+//! bus. [`CanNozzles`] encodes the lane states into one frame
+//! ([`LaneMessage`]: identifier, payload layout) and sends it whenever the
+//! states change, plus a periodic refresh so the controller's own
+//! timeout keeps the sections alive only while Rust-Spray is running.
+//! Frames go out through a [`CanBus`]; [`CanSocket`] is the SocketCAN
+//! implementation (Linux only, e.g. `can0` or a `vcan0` for testing).
+
+use crate::io_gpio::NozzleControl;
+use serde::Deserialize;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// A classic CAN data frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanFrame {
+    /// 11-bit standard or 29-bit extended identifier.
+    pub id: u32,
+    pub extended: bool,
+    pub len: u8,
+    pub data: [u8; 8],
+}
+
+impl CanFrame {
+    /// The payload, `len` bytes.
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..usize::from(self.len)]
+    }
+}
+
+/// A CAN bus connection.
+pub trait CanBus: Send + Sync {
+    fn send(&self, frame: &CanFrame) -> io::Result<()>;
+    /// The next frame from the bus, or `None` if none arrived within the
+    /// connection's read timeout.
+    fn recv(&self) -> io::Result<Option<CanFrame>>;
+}
+
+/// Where lane states go in the payload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadLayout {
+    /// One bit per lane, lane 0 in the least significant bit of the first
+    /// byte.
+    #[default]
+    Bits,
+    /// One bit per lane, lane 0 in the most significant bit of the first
+    /// byte.
+    BitsMsb,
+    /// One byte per lane, `0x00` off and `0x01` on.
+    Bytes,
+}
+
+impl PayloadLayout {
+    /// Payload bytes needed for `lanes` lanes.
+    pub fn bytes_for(self, lanes: usize) -> usize {
+        match self {
+            Self::Bits | Self::BitsMsb => lanes.div_ceil(8),
+            Self::Bytes => lanes,
+        }
+    }
+}
+
+/// The frame carrying the lane states: identifier and payload layout.
+/// Bytes outside the lane field are sent as zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaneMessage {
+    pub id: u32,
+    pub extended: bool,
+    pub layout: PayloadLayout,
+    /// First payload byte of the lane field.
+    pub offset: usize,
+    /// Payload length (DLC), `1..=8`.
+    pub len: u8,
+}
+
+impl LaneMessage {
+    /// The frame for `lanes`.
+    ///
+    /// # Panics
+    /// Panics if the lanes do not fit in the payload.
+    pub fn frame(&self, lanes: &[bool]) -> CanFrame {
+        assert!(
+            self.offset + self.layout.bytes_for(lanes.len()) <= usize::from(self.len),
+            "Lanes do not fit in the CAN payload"
+        );
+        let mut data = [0; 8];
+        let field = &mut data[self.offset..];
+        for (lane, &on) in lanes.iter().enumerate() {
+            if !on {
+                continue;
+            }
+            match self.layout {
+                PayloadLayout::Bits => field[lane / 8] |= 1 << (lane % 8),
+                PayloadLayout::BitsMsb => field[lane / 8] |= 0x80 >> (lane % 8),
+                PayloadLayout::Bytes => field[lane] = 1,
+            }
+        }
+        CanFrame {
+            id: self.id,
+            extended: self.extended,
+            len: self.len,
+            data,
+        }
+    }
+}
+
+struct Current {
+    frame: CanFrame,
+    sent: Instant,
+    /// Error of the last send, `None` once one goes through.
+    error: Option<String>,
+}
+
+impl Current {
+    fn record(&mut self, result: io::Result<()>) {
+        self.sent = Instant::now();
+        self.error = result.err().map(|e| format!("CAN send failed: {e}"));
+    }
+}
+
+/// Nozzles behind a CAN section controller.
+///
+/// An all-off frame is sent on construction, on [`NozzleControl::all_off`]
+/// and on drop; in between the current states are resent every
+/// `heartbeat` when they have not changed. A failed send, by a frame or
+/// the heartbeat, is reported through [`NozzleControl::fault`] and
+/// retried by every following frame until one goes through.
+pub struct CanNozzles {
+    bus: Arc<dyn CanBus>,
+    message: LaneMessage,
+    lanes: usize,
+    current: Arc<Mutex<Current>>,
+    fault: Option<String>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CanNozzles {
+    /// Drive `lanes` lanes with `message` frames on `bus`, refreshed every
+    /// `heartbeat` (`None`: only on change).
+    ///
+    /// Fails if the all-off frame cannot be sent.
+    pub fn new(
+        bus: Arc<dyn CanBus>,
+        message: LaneMessage,
+        lanes: usize,
+        heartbeat: Option<Duration>,
+    ) -> io::Result<Self> {
+        let frame = message.frame(&vec![false; lanes]);
+        bus.send(&frame)?;
+        let current = Arc::new(Mutex::new(Current {
+            frame,
+            sent: Instant::now(),
+            error: None,
+        }));
+        let (stop, thread) = match heartbeat {
+            Some(period) => {
+                let (stop, stopped) = mpsc::channel::<()>();
+                let bus = bus.clone();
+                let current = current.clone();
+                let thread = std::thread::Builder::new()
+                    .name("can-heartbeat".into())
+                    .spawn(move || loop {
+                        let due = current.lock().unwrap().sent + period;
+                        match stopped.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                            Err(RecvTimeoutError::Timeout) => {
+                                let mut current = current.lock().unwrap();
+                                if current.sent.elapsed() >= period {
+                                    let result = bus.send(&current.frame);
+                                    current.record(result);
+                                }
+                            }
+                            _ => break,
+                        }
+                    })
+                    .expect("failed to spawn the CAN heartbeat thread");
+                (Some(stop), Some(thread))
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            bus,
+            message,
+            lanes,
+            current,
+            fault: None,
+            stop,
+            thread,
+        })
+    }
+
+    fn send(&mut self, frame: CanFrame, force: bool) {
+        let mut current = self.current.lock().unwrap();
+        // Resend after a failure so a lost frame does not wait for the
+        // heartbeat, which may be disabled.
+        if force || frame != current.frame || current.error.is_some() {
+            let result = self.bus.send(&frame);
+            current.frame = frame;
+            current.record(result);
+        }
+        if self.fault != current.error {
+            self.fault.clone_from(&current.error);
+        }
+    }
+}
+
+impl NozzleControl for CanNozzles {
+    fn apply(&mut self, lanes: &[bool]) {
+        self.send(self.message.frame(lanes), false);
+    }
+
+    fn all_off(&mut self, lanes: usize) {
+        self.send(self.message.frame(&vec![false; lanes]), true);
+    }
+
+    fn fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+}
+
+impl Drop for CanNozzles {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // Nobody is left to report to; all_off has reported the outcome.
+        let _ = self.bus.send(&self.message.frame(&vec![false; self.lanes]));
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use socket::CanSocket;
+
+#[cfg(target_os = "linux")]
+mod socket {
+    use super::{CanBus, CanFrame};
+    use std::ffi::CString;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::time::Duration;
+
+    /// A raw SocketCAN socket bound to one interface.
+    pub struct CanSocket {
+        fd: OwnedFd,
+    }
+
+    impl CanSocket {
+        /// Open a raw socket on `interface` (e.g. `can0`, `vcan0`).
+        pub fn open(interface: &str) -> io::Result<Self> {
+            let name = CString::new(interface)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // SAFETY: `name` is a valid NUL-terminated string.
+            let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+            if index == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no CAN interface {interface}"),
+                ));
+            }
+            // SAFETY: plain socket(2) call; the descriptor is owned below.
+            let fd = unsafe { libc::socket(libc::PF_CAN, libc::SOCK_RAW, libc::CAN_RAW) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` is a fresh descriptor nothing else owns.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            // SAFETY: sockaddr_can is plain data; zero is a valid value
+            // for the address union, which raw sockets ignore.
+            let mut addr: libc::sockaddr_can = unsafe { std::mem::zeroed() };
+            addr.can_family = libc::AF_CAN as libc::sa_family_t;
+            addr.can_ifindex = index as libc::c_int;
+            // SAFETY: `addr` is a sockaddr_can of the size passed.
+            let bound = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    (&addr as *const libc::sockaddr_can).cast(),
+                    std::mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+                )
+            };
+            if bound < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd })
+        }
+
+        /// Give up on [`CanBus::recv`] after `timeout`; without one it
+        /// blocks until a frame arrives.
+        pub fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+            let tv = libc::timeval {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+            };
+            // SAFETY: `tv` is a timeval of the size passed.
+            let set = unsafe {
+                libc::setsockopt(
+                    self.fd.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_RCVTIMEO,
+                    (&tv as *const libc::timeval).cast(),
+                    std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+                )
+            };
+            if set < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl CanBus for CanSocket {
+        fn send(&self, frame: &CanFrame) -> io::Result<()> {
+            // SAFETY: can_frame is plain data.
+            let mut raw: libc::can_frame = unsafe { std::mem::zeroed() };
+            raw.can_id = if frame.extended {
+                (frame.id & libc::CAN_EFF_MASK) | libc::CAN_EFF_FLAG
+            } else {
+                frame.id & libc::CAN_SFF_MASK
+            };
+            raw.can_dlc = frame.len;
+            raw.data = frame.data;
+            let size = std::mem::size_of::<libc::can_frame>();
+            // SAFETY: `raw` is readable for `size` bytes.
+            let n = unsafe {
+                libc::write(
+                    self.fd.as_raw_fd(),
+                    (&raw as *const libc::can_frame).cast(),
+                    size,
+                )
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if n as usize != size {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "short CAN frame write",
+                ));
+            }
+            Ok(())
+        }
+
+        fn recv(&self) -> io::Result<Option<CanFrame>> {
+            // SAFETY: can_frame is plain data.
+            let mut raw: libc::can_frame = unsafe { std::mem::zeroed() };
+            let size = std::mem::size_of::<libc::can_frame>();
+            // SAFETY: `raw` is writable for `size` bytes.
+            let n = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    (&mut raw as *mut libc::can_frame).cast(),
+                    size,
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Ok(None),
+                    _ => Err(e),
+                };
+            }
+            if n as usize != size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "short CAN frame read",
+                ));
+            }
+            let extended = raw.can_id & libc::CAN_EFF_FLAG != 0;
+            Ok(Some(CanFrame {
+                id: raw.can_id
+                    & if extended {
+                        libc::CAN_EFF_MASK
+                    } else {
+                        libc::CAN_SFF_MASK
+                    },
+                extended,
+                len: raw.can_dlc.min(8),
+                data: raw.data,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Bus recording every frame sent, failing every send while down.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<CanFrame>>, AtomicBool);
+
+    impl CanBus for Recorder {
+        fn send(&self, frame: &CanFrame) -> io::Result<()> {
+            if self.1.load(Ordering::Relaxed) {
+                return Err(io::Error::other("bus off"));
+            }
+            self.0.lock().unwrap().push(*frame);
+            Ok(())
+        }
+
+        fn recv(&self) -> io::Result<Option<CanFrame>> {
+            Ok(None)
+        }
+    }
+
+    impl Recorder {
+        fn payloads(&self) -> Vec<Vec<u8>> {
+            let frames = std::mem::take(&mut *self.0.lock().unwrap());
+            frames.iter().map(|f| f.payload().to_vec()).collect()
+        }
+    }
+
+    fn message(layout: PayloadLayout) -> LaneMessage {
+        LaneMessage {
+            id: 0x18FF_1080,
+            extended: true,
+            layout,
+            offset: 1,
+            len: 4,
+        }
+    }
+
+    #[test]
+    fn payload_layouts() {
+        let lanes = [true, false, true, false, false, false, false, false, true];
+        let frame = message(PayloadLayout::Bits).frame(&lanes);
+        assert_eq!(frame.payload(), [0, 0b0000_0101, 0b1, 0]);
+        assert_eq!((frame.id, frame.extended), (0x18FF_1080, true));
+        let frame = message(PayloadLayout::BitsMsb).frame(&lanes);
+        assert_eq!(frame.payload(), [0, 0b1010_0000, 0x80, 0]);
+        let frame = message(PayloadLayout::Bytes).frame(&lanes[..3]);
+        assert_eq!(frame.payload(), [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn sends_off_on_construction_changes_only_and_off_on_drop() {
+        let bus = Arc::new(Recorder::default());
+        let mut nozzles =
+            CanNozzles::new(bus.clone(), message(PayloadLayout::Bits), 3, None).unwrap();
+        nozzles.apply(&[true, false, true]);
+        nozzles.apply(&[true, false, true]);
+        nozzles.apply(&[false, false, true]);
+        nozzles.all_off(3);
+        nozzles.all_off(3);
+        assert_eq!(
+            bus.payloads(),
+            [
+                [0, 0, 0, 0],
+                [0, 5, 0, 0],
+                [0, 4, 0, 0],
+                [0, 0, 0, 0],
+                [0, 0, 0, 0]
+            ]
+        );
+        drop(nozzles);
+        assert_eq!(bus.payloads(), [[0, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn heartbeat_refreshes_unchanged_states() {
+        let bus = Arc::new(Recorder::default());
+        let mut nozzles = CanNozzles::new(
+            bus.clone(),
+            message(PayloadLayout::Bits),
+            2,
+            Some(Duration::from_millis(10)),
+        )
+        .unwrap();
+        nozzles.apply(&[false, true]);
+        std::thread::sleep(Duration::from_millis(100));
+        drop(nozzles);
+        let payloads = bus.payloads();
+        let refreshed = payloads.iter().filter(|p| p[1] == 2).count();
+        assert!(refreshed >= 4, "only {refreshed} frames in 100 ms");
+        assert_eq!(payloads.last().unwrap(), &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn failed_sends_are_reported_until_one_goes_through() {
+        let bus = Arc::new(Recorder::default());
+        let mut nozzles = CanNozzles::new(
+            bus.clone(),
+            message(PayloadLayout::Bits),
+            2,
+            Some(Duration::from_millis(10)),
+        )
+        .unwrap();
+        bus.1.store(true, Ordering::Relaxed);
+        nozzles.apply(&[true, false]);
+        assert_eq!(nozzles.fault(), Some("CAN send failed: bus off"));
+        // The heartbeat resends the states once the bus recovers.
+        bus.1.store(false, Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(50));
+        nozzles.apply(&[true, false]);
+        assert_eq!(nozzles.fault(), None);
+        assert_eq!(bus.payloads().last().unwrap(), &[0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn unchanged_states_are_resent_after_a_failure_without_heartbeat() {
+        let bus = Arc::new(Recorder::default());
+        let mut nozzles =
+            CanNozzles::new(bus.clone(), message(PayloadLayout::Bits), 2, None).unwrap();
+        bus.1.store(true, Ordering::Relaxed);
+        nozzles.apply(&[true, false]);
+        assert!(nozzles.fault().is_some());
+        bus.1.store(false, Ordering::Relaxed);
+        nozzles.apply(&[true, false]);
+        assert_eq!(nozzles.fault(), None);
+        nozzles.apply(&[true, false]);
+        assert_eq!(bus.payloads(), [[0, 0, 0, 0], [0, 1, 0, 0]]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[ignore = "needs vcan0"]
+    fn round_trip_on_vcan0() {
+        // Needs `ip link add dev vcan0 type vcan && ip link set up vcan0`.
+        let listener = CanSocket::open("vcan0").expect("vcan0");
+        listener.set_read_timeout(Duration::from_secs(1)).unwrap();
+        let bus = Arc::new(CanSocket::open("vcan0").unwrap());
+        let message = message(PayloadLayout::Bits);
+        let mut nozzles = CanNozzles::new(bus, message, 2, None).unwrap();
+        nozzles.apply(&[true, true]);
+        drop(nozzles);
+        let received: Vec<CanFrame> = (0..3).map(|_| listener.recv().unwrap().unwrap()).collect();
+        assert_eq!(
+            received,
+            [
+                message.frame(&[false, false]),
+                message.frame(&[true, true]),
+                message.frame(&[false, false]),
+            ]
+        );
+    }
+}
@@ -5,8 +5,26 @@
 //! matching the values in [`crate::vision::PlantVision`] and the
 //! `four_lane` example are used when keys are absent.
 
+use crate::adaptive::AdaptiveThreshold;
+use crate::can::{LaneMessage, PayloadLayout};
+use crate::crop_rows::CropRowTracker;
+use crate::delay::{DelayScheduler, GroundSpeed, MonotonicClock};
+use crate::dwell::{Axis, Dwell, DwellTimer};
+use crate::indices::{
+    Cive, Exg, Exgr, HueBand, IndexKind, Ngrdi, Veg, VegetationIndex, WeightedIndex,
+};
+use crate::isobus::{AddressClaim, Implement, Name, SectionControl, MAX_SECTIONS};
+use crate::lanes::LaneGeometry;
+use crate::modbus::Link;
+use crate::morphology::MaskFilter;
+use crate::pwm::{self, DutyCurve};
+use crate::serial::{self, Parity};
+use crate::vision::{Lighting, PixelFormat, Roi};
+use crate::white_balance::MAX_GAIN;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::path::Path;
+use std::time::Duration;
 
 /// Top-level configuration.
 #[derive(Debug, Default, Deserialize)]
@@ -16,6 +34,8 @@ pub struct Config {
     pub vision: VisionConfig,
     pub lanes: LanesConfig,
     pub gpio: GpioConfig,
+    pub timing: TimingConfig,
+    pub gps: GpsConfig,
     pub logging: LoggingConfig,
 }
 
@@ -41,6 +61,9 @@ pub struct CameraConfig {
     pub backend: String,
     /// V4L2 device path (used when `backend = "v4l2"`).
     pub device: String,
+    /// Pixel layout on stdin and in IPC frames: `"rgb24"`, or `"rgbn"`
+    /// for NIR-modified cameras (requires `[vision] index = "ndvi"`).
+    pub pixel_format: PixelFormat,
 }
 
 /// PlantVision tuning parameters.
@@ -51,6 +74,240 @@ pub struct VisionConfig {
     pub green_ratio_floor: f32,
     pub chroma_floor: f32,
     pub weights: VisionWeights,
+    /// Bits per channel of the precomputed RGB lookup table: `0` scores
+    /// every pixel directly, `5` builds a 32x32x32 table, `8` the exact
+    /// full 16M-entry table (2 MiB).
+    pub lut_bits: u8,
+    /// Worker threads for detection and lane counting, each taking a
+    /// horizontal band of the frame.
+    pub workers: usize,
+    /// Region of interest; pixels outside it are neither detected nor
+    /// counted towards any lane.
+    pub roi: RoiConfig,
+    /// Speckle removal on the vegetation mask.
+    pub morphology: MorphologyConfig,
+    /// Connected-component weed blobs.
+    pub blobs: BlobsConfig,
+    /// Crop row tracking for spraying between the rows of a crop.
+    pub crop_rows: CropRowsConfig,
+    /// Per-frame ExG threshold (Otsu) in place of `exg_threshold`.
+    pub adaptive: AdaptiveConfig,
+    /// Per-channel gains applied before scoring.
+    pub white_balance: WhiteBalanceConfig,
+    /// Luminance gates for deep shadow and specular glare.
+    pub lighting: LightingConfig,
+    /// Vegetation index: `"fused"` (the default multi-cue score), one of
+    /// `"exg"`, `"exgr"`, `"cive"`, `"veg"`, `"ngrdi"`, `"hue"`, a table
+    /// of index weights such as `{ exgr = 1.0, cive = 0.5 }`, or `"ndvi"`
+    /// for RGBN cameras.
+    pub index: IndexSelection,
+    /// Thresholds of the alternative indices.
+    pub index_thresholds: IndexThresholds,
+    /// Band of the `hue` index.
+    pub hue: HueConfig,
+}
+
+/// Which vegetation index scores pixels.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum IndexSelection {
+    Single(IndexKind),
+    Weighted(BTreeMap<IndexKind, f32>),
+}
+
+/// Per-index thresholds, in each index's own units (see
+/// [`crate::indices`]).
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct IndexThresholds {
+    pub exg: f32,
+    pub exgr: f32,
+    /// CIVE at or above this is background.
+    pub cive: f32,
+    /// Must be positive.
+    pub veg: f32,
+    pub ngrdi: f32,
+    /// NDVI, `-1..=1`.
+    pub ndvi: f32,
+}
+
+/// HSV band for the `hue` index: hue in degrees, saturation and value
+/// floors in `0..=1`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HueConfig {
+    pub min: f32,
+    pub max: f32,
+    pub min_saturation: f32,
+    pub min_value: f32,
+}
+
+/// Shadow and glare handling, in luma `(r + 2g + b) / 4`; see
+/// [`Lighting`]. All zero (the default) disables it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LightingConfig {
+    /// Reject pixels darker than this.
+    pub luma_floor: u8,
+    /// Treat pixels at least this bright as glare (`0` disables).
+    pub luma_ceiling: u8,
+    /// Relax the chroma floor in proportion to brightness below this.
+    pub shadow_luma: u8,
+}
+
+impl LightingConfig {
+    /// The gates for [`crate::vision::PlantVision::with_lighting`].
+    pub fn lighting(&self) -> Lighting {
+        Lighting {
+            luma_floor: self.luma_floor,
+            luma_ceiling: self.luma_ceiling,
+            shadow_luma: self.shadow_luma,
+        }
+    }
+}
+
+/// Software white balance. `gains` scale R, G, B before scoring (all 1
+/// disables it); `rustspray --calibrate-white-balance` measures them
+/// against a grey-world or `soil_reference` colour.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct WhiteBalanceConfig {
+    pub gains: [f32; 3],
+    /// Known RGB colour of the field's soil under neutral light, for
+    /// `--calibrate-white-balance soil`.
+    pub soil_reference: [u8; 3],
+}
+
+/// Exposure-adaptive ExG threshold: Otsu's method on the ExG histogram,
+/// pooled over the last `window` frames and clamped to
+/// `min_threshold..=max_threshold`. Replaces `exg_threshold` when enabled.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveConfig {
+    pub enabled: bool,
+    pub min_threshold: i16,
+    pub max_threshold: i16,
+    pub window: usize,
+}
+
+/// Blob stage: label connected vegetation pixels, drop blobs below
+/// `min_area` and count only the remaining pixels towards lane coverage.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BlobsConfig {
+    pub enabled: bool,
+    /// Smallest blob kept, in pixels.
+    pub min_area: u32,
+}
+
+/// Green-on-green mode: find crop rows `spacing` pixels apart in the
+/// vegetation mask and clear a `band` pixels wide around each, so only
+/// inter-row weeds count. Both are in pixels of the `[camera]` frame;
+/// `smoothing` is the weight of each frame in the running row histogram.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CropRowsConfig {
+    pub enabled: bool,
+    pub spacing: f32,
+    pub band: f32,
+    pub smoothing: f32,
+}
+
+/// Opening/closing kernel sizes (odd, in pixels); `0` disables each.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MorphologyConfig {
+    /// Opening (erode, then dilate): removes specks smaller than this.
+    pub open: usize,
+    /// Closing (dilate, then erode): fills holes smaller than this.
+    pub close: usize,
+}
+
+/// Margins, in pixels of the `[camera]` frame, cropped from each edge
+/// before detection. All zero (the default) processes the whole frame.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RoiConfig {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl VisionConfig {
+    /// The alternative index selected by `index`, or `None` for the
+    /// default fused score.
+    pub fn vegetation_index(&self) -> Option<Box<dyn VegetationIndex>> {
+        match &self.index {
+            IndexSelection::Single(IndexKind::Fused) => None,
+            IndexSelection::Single(kind) => self.build_index(*kind),
+            IndexSelection::Weighted(weights) => {
+                let mut blend = WeightedIndex::new();
+                for (&kind, &weight) in weights {
+                    blend.push(self.build_index(kind)?, weight);
+                }
+                Some(Box::new(blend))
+            }
+        }
+    }
+
+    fn build_index(&self, kind: IndexKind) -> Option<Box<dyn VegetationIndex>> {
+        let t = &self.index_thresholds;
+        Some(match kind {
+            IndexKind::Fused | IndexKind::Ndvi => return None,
+            IndexKind::Exg => Box::new(Exg { threshold: t.exg }),
+            IndexKind::Exgr => Box::new(Exgr { threshold: t.exgr }),
+            IndexKind::Cive => Box::new(Cive { threshold: t.cive }),
+            IndexKind::Veg => Box::new(Veg { threshold: t.veg }),
+            IndexKind::Ngrdi => Box::new(Ngrdi { threshold: t.ngrdi }),
+            IndexKind::Hue => Box::new(HueBand {
+                min: self.hue.min,
+                max: self.hue.max,
+                min_saturation: self.hue.min_saturation,
+                min_value: self.hue.min_value,
+            }),
+        })
+    }
+
+    /// The configured region of interest for a `width` x `height` camera
+    /// frame, or `None` when no margins are set.
+    pub fn roi(&self, width: usize, height: usize) -> Option<Roi> {
+        let RoiConfig {
+            top,
+            bottom,
+            left,
+            right,
+        } = self.roi;
+        (top | bottom | left | right != 0).then_some(Roi {
+            top,
+            bottom,
+            left,
+            right,
+            ref_width: width,
+            ref_height: height,
+        })
+    }
+
+    /// A fresh adaptive threshold tracker, or `None` when disabled.
+    pub fn adaptive_threshold(&self) -> Option<AdaptiveThreshold> {
+        let cfg = &self.adaptive;
+        cfg.enabled
+            .then(|| AdaptiveThreshold::new(cfg.min_threshold, cfg.max_threshold, cfg.window))
+    }
+
+    /// The configured mask filter, or `None` when it would do nothing.
+    pub fn mask_filter(&self) -> Option<MaskFilter> {
+        let filter = MaskFilter::new(self.morphology.open, self.morphology.close);
+        (!filter.is_noop()).then_some(filter)
+    }
+
+    /// The crop row tracker for frames `camera_width` pixels wide, or
+    /// `None` when `[vision.crop_rows]` is disabled.
+    pub fn crop_row_tracker(&self, camera_width: usize) -> Option<CropRowTracker> {
+        let rows = &self.crop_rows;
+        rows.enabled
+            .then(|| CropRowTracker::new(rows.spacing, rows.band, rows.smoothing, camera_width))
+    }
 }
 
 /// Fusion weights for the multi-cue scorer.
@@ -73,17 +330,390 @@ pub struct LanesConfig {
     pub on_threshold: f32,
     /// Coverage ratio to turn a lane **off** (hysteresis).
     pub off_threshold: f32,
+    /// Optional per-lane on thresholds (one per lane) overriding
+    /// `on_threshold`, e.g. stricter outer lanes that see more stubble.
+    pub on_thresholds: Option<Vec<f32>>,
+    /// Optional per-lane off thresholds overriding `off_threshold`.
+    pub off_thresholds: Option<Vec<f32>>,
+    /// Optional per-lane enable flags. A disabled lane is forced off
+    /// permanently, e.g. for a blocked nozzle.
+    pub enabled: Option<Vec<bool>>,
+    /// Optional explicit lane column edges (`count + 1` increasing values,
+    /// in pixels of the `[camera]` frame) replacing equal-width strips.
+    pub boundaries: Option<Vec<f32>>,
+    /// Optional lane polygons (one per lane, each a list of `[x, y]`
+    /// vertices in pixels of the `[camera]` frame), e.g. the trapezoidal
+    /// nozzle footprints seen by an angled camera.
+    pub polygons: Option<Vec<Vec<[f32; 2]>>>,
+}
+
+impl LanesConfig {
+    /// Effective on threshold of `lane`.
+    pub fn on_threshold_for(&self, lane: usize) -> f32 {
+        self.on_thresholds
+            .as_ref()
+            .and_then(|t| t.get(lane).copied())
+            .unwrap_or(self.on_threshold)
+    }
+
+    /// Effective off threshold of `lane`.
+    pub fn off_threshold_for(&self, lane: usize) -> f32 {
+        self.off_thresholds
+            .as_ref()
+            .and_then(|t| t.get(lane).copied())
+            .unwrap_or(self.off_threshold)
+    }
+
+    /// Whether `lane` may be switched on at all.
+    pub fn is_enabled(&self, lane: usize) -> bool {
+        self.enabled
+            .as_ref()
+            .and_then(|e| e.get(lane).copied())
+            .unwrap_or(true)
+    }
+
+    /// Whether anything is configured per lane: threshold overrides,
+    /// enable flags, or lane geometry.
+    pub fn has_per_lane_settings(&self) -> bool {
+        self.on_thresholds.is_some()
+            || self.off_thresholds.is_some()
+            || self.enabled.is_some()
+            || self.boundaries.is_some()
+            || self.polygons.is_some()
+    }
+
+    /// Lane geometry, with coordinates relative to a `width` x `height`
+    /// camera frame.
+    pub fn geometry(&self, width: usize, height: usize) -> LaneGeometry {
+        if let Some(edges) = &self.boundaries {
+            LaneGeometry::Columns {
+                edges: edges.clone(),
+                width,
+                height,
+            }
+        } else if let Some(polygons) = &self.polygons {
+            LaneGeometry::Polygons {
+                polygons: polygons.clone(),
+                width,
+                height,
+            }
+        } else {
+            LaneGeometry::Equal
+        }
+    }
 }
 
 /// GPIO pin configuration.
 #[derive(Debug, Deserialize)]
 #[serde(default)]
 pub struct GpioConfig {
+    /// Which GPIO driver the nozzles use.
+    pub backend: GpioBackend,
     /// BCM pin numbers, one per lane, controlling the relay/MOSFET for
-    /// each nozzle solenoid.
+    /// each nozzle solenoid (`backend = "rppal"`).
     pub pins: Vec<u8>,
+    /// GPIO chip and line, one per lane (`backend = "cdev"`).
+    pub lines: Vec<CdevLine>,
+    /// CAN section controller (`backend = "can"`).
+    pub can: CanConfig,
+    /// ISOBUS implement section control (`backend = "isobus"`).
+    pub isobus: IsobusConfig,
+    /// Modbus RTU relay board (`backend = "modbus"`).
+    pub modbus: ModbusConfig,
     /// Force mock GPIO even when compiled with real hardware support.
     pub mock: bool,
+    /// PWM duty proportional to weed density.
+    pub pwm: PwmConfig,
+}
+
+/// One lane's GPIO character device line: offset `line` on
+/// `/dev/gpiochip{chip}` (see [`crate::gpio_cdev`], Linux only).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct CdevLine {
+    pub chip: u32,
+    pub line: u32,
+}
+
+/// GPIO driver: `"rppal"` (Raspberry Pi registers via `/dev/gpiomem`,
+/// needs the `rpi` feature), `"cdev"` (the Linux GPIO character
+/// device, any board), `"can"` (frames to a CAN section controller
+/// over SocketCAN), `"isobus"` (ISO 11783 section control of an
+/// implement over SocketCAN) or `"modbus"` (coils of a Modbus RTU relay
+/// board on a serial line).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GpioBackend {
+    #[default]
+    Rppal,
+    Cdev,
+    Can,
+    Isobus,
+    Modbus,
+}
+
+/// CAN section controller output (see [`crate::can`]): the lane states
+/// are sent as one frame with identifier `id`, packed per `layout` from
+/// payload byte `offset`, on every change and every `heartbeat_ms`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CanConfig {
+    /// SocketCAN interface, e.g. `"can0"`.
+    pub interface: String,
+    pub id: u32,
+    /// 29-bit identifier instead of 11-bit.
+    pub extended: bool,
+    pub layout: PayloadLayout,
+    pub offset: usize,
+    /// Payload length (DLC), 1 to 8 bytes.
+    pub len: u8,
+    /// Resend period for unchanged states; `0` sends on change only.
+    pub heartbeat_ms: u64,
+}
+
+impl CanConfig {
+    /// The frame layout carrying the lane states.
+    pub fn message(&self) -> LaneMessage {
+        LaneMessage {
+            id: self.id,
+            extended: self.extended,
+            layout: self.layout,
+            offset: self.offset,
+            len: self.len,
+        }
+    }
+
+    /// The refresh period, or `None` when disabled.
+    pub fn heartbeat(&self) -> Option<Duration> {
+        (self.heartbeat_ms > 0).then(|| Duration::from_millis(self.heartbeat_ms))
+    }
+}
+
+/// Modbus RTU relay board (see [`crate::modbus`]): lane N switches coil
+/// `coils[N]` of `slave` on the serial `device`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ModbusConfig {
+    /// Serial device, e.g. `/dev/ttyUSB0`.
+    pub device: String,
+    pub baud: u32,
+    pub parity: Parity,
+    /// Slave address, 1 to 247.
+    pub slave: u8,
+    /// Coil address per lane; empty puts lane N on coil N.
+    pub coils: Vec<u16>,
+    /// Milliseconds to wait for each response.
+    pub timeout_ms: u64,
+    /// Further attempts after a request fails.
+    pub retries: u32,
+}
+
+impl ModbusConfig {
+    /// Coil address per lane for `lanes` lanes.
+    pub fn coils(&self, lanes: usize) -> Vec<u16> {
+        if self.coils.is_empty() {
+            (0..lanes as u16).collect()
+        } else {
+            self.coils.clone()
+        }
+    }
+
+    pub fn link(&self) -> Link {
+        Link {
+            timeout: Duration::from_millis(self.timeout_ms),
+            retries: self.retries,
+        }
+    }
+}
+
+/// ISOBUS section control (see [`crate::isobus`]): Rust-Spray claims an
+/// address as a task controller and sends the boom `element` of the
+/// implement whose NAME carries `implement_identity_number` and
+/// `implement_manufacturer_code` a section setpoint per lane, at
+/// whatever address the implement claims.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct IsobusConfig {
+    /// SocketCAN interface, e.g. `"can0"`.
+    pub interface: String,
+    /// Preferred source address; another in 128–247 is claimed if a
+    /// higher-priority node holds it.
+    pub address: u8,
+    /// NAME identity number of the implement's ECU (21 bits), as in its
+    /// address claims; required.
+    pub implement_identity_number: Option<u32>,
+    /// NAME manufacturer code of the implement's ECU (11 bits).
+    pub implement_manufacturer_code: u16,
+    /// Boom element number in the implement's device description.
+    pub element: u16,
+    /// Implement section (1-based) per lane; empty maps lane N to
+    /// section N + 1.
+    pub sections: Vec<u16>,
+    /// Resend period for unchanged setpoints.
+    pub refresh_ms: u64,
+    /// NAME identity number (21 bits), unique per device.
+    pub identity_number: u32,
+    /// NAME manufacturer code (11 bits).
+    pub manufacturer_code: u16,
+}
+
+impl IsobusConfig {
+    /// Section control for `lanes` lanes.
+    pub fn section_control(&self, lanes: usize) -> SectionControl {
+        let lane_sections = if self.sections.is_empty() {
+            (0..lanes).collect()
+        } else {
+            self.sections.iter().map(|&s| usize::from(s) - 1).collect()
+        };
+        SectionControl::new(
+            AddressClaim::new(
+                Name::task_controller(self.identity_number, self.manufacturer_code),
+                self.address,
+            ),
+            Implement {
+                identity_number: self.implement_identity_number.unwrap_or_default(),
+                manufacturer_code: self.implement_manufacturer_code,
+            },
+            self.element,
+            lane_sections,
+            Duration::from_millis(self.refresh_ms),
+        )
+    }
+}
+
+/// PWM nozzle output (see [`crate::pwm`]): lanes that are on spray with
+/// a duty from `min_duty` at zero coverage to `max_duty` at
+/// `full_coverage`, shaped by `gamma`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PwmConfig {
+    pub enabled: bool,
+    /// PWM frequency in Hz.
+    pub frequency_hz: f32,
+    pub min_duty: f32,
+    pub max_duty: f32,
+    /// Coverage ratio at which `max_duty` is reached.
+    pub full_coverage: f32,
+    pub gamma: f32,
+}
+
+impl PwmConfig {
+    /// The duty curve, or `None` when PWM is disabled.
+    pub fn duty_curve(&self) -> Option<DutyCurve> {
+        self.enabled.then_some(DutyCurve {
+            min_duty: self.min_duty,
+            max_duty: self.max_duty,
+            full_coverage: self.full_coverage,
+            gamma: self.gamma,
+        })
+    }
+}
+
+/// Camera-to-nozzle delay compensation: lane changes reach the nozzles
+/// once the ground has moved `camera_to_nozzle_m` (less the travel during
+/// `valve_latency_ms`) at `speed_kmh`. A zero distance (the default)
+/// applies each frame's lanes immediately. At or below `min_speed_kmh`
+/// the machine counts as stopped and every delayed lane is off.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TimingConfig {
+    /// Distance from the camera's view to the nozzles, in metres.
+    pub camera_to_nozzle_m: f32,
+    /// Time a valve takes to open or close, in milliseconds.
+    pub valve_latency_ms: u64,
+    /// Ground speed in km/h.
+    pub speed_kmh: f32,
+    /// Speed at or below which the machine counts as stopped, in km/h.
+    pub min_speed_kmh: f32,
+    /// Minimum spray times and pre/post spray extension.
+    pub dwell: DwellConfig,
+}
+
+impl TimingConfig {
+    /// The configured ground speed, to be shared by the timing layers
+    /// and updated by a GPS.
+    pub fn ground_speed(&self) -> GroundSpeed {
+        GroundSpeed::new(self.speed_kmh / 3.6).with_min_speed(self.min_speed_kmh / 3.6)
+    }
+
+    /// The delay scheduler for `lanes` lanes on the wall clock, or `None`
+    /// when the distance is zero. Edges are released `[timing.dwell]
+    /// pre_spray` early on top of the valve latency.
+    pub fn delay_scheduler(&self, lanes: usize, speed: GroundSpeed) -> Option<DelayScheduler<f32>> {
+        let dwell = &self.dwell;
+        let (distance, pre_ms) = match dwell.unit {
+            DwellUnit::Ms => (self.camera_to_nozzle_m, dwell.pre_spray),
+            DwellUnit::M => (self.camera_to_nozzle_m - dwell.pre_spray, 0.0),
+        };
+        (self.camera_to_nozzle_m > 0.0).then(|| {
+            DelayScheduler::new(
+                lanes,
+                distance.max(0.0),
+                Duration::from_millis(self.valve_latency_ms)
+                    + Duration::from_secs_f32(pre_ms / 1000.0),
+                speed,
+                Box::new(MonotonicClock::new()),
+            )
+        })
+    }
+
+    /// The dwell timer for `lanes` lanes on the wall clock, or `None`
+    /// when every dwell time is zero.
+    pub fn dwell_timer(&self, lanes: usize, speed: GroundSpeed) -> Option<DwellTimer> {
+        let cfg = &self.dwell;
+        let (scale, axis) = match cfg.unit {
+            DwellUnit::Ms => (1e-3, Axis::Time),
+            DwellUnit::M => (1.0, Axis::Distance(speed)),
+        };
+        let dwell = Dwell {
+            min_on: f64::from(cfg.min_on) * scale,
+            min_off: f64::from(cfg.min_off) * scale,
+            pre: f64::from(cfg.pre_spray) * scale,
+            post: f64::from(cfg.post_spray) * scale,
+        };
+        (!dwell.is_noop())
+            .then(|| DwellTimer::new(lanes, dwell, axis, Box::new(MonotonicClock::new())))
+    }
+}
+
+/// Per-lane dwell times (see [`crate::dwell`]), all in `unit`. Pre-spray
+/// is taken out of the camera-to-nozzle travel, so it needs a non-zero
+/// `camera_to_nozzle_m` and cannot open a valve sooner than the moment a
+/// weed is seen.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DwellConfig {
+    pub unit: DwellUnit,
+    /// Shortest time a valve stays open.
+    pub min_on: f32,
+    /// Shortest time a valve stays closed.
+    pub min_off: f32,
+    /// Opening ahead of each weed.
+    pub pre_spray: f32,
+    /// Closing after each weed.
+    pub post_spray: f32,
+}
+
+/// Unit of the dwell times: milliseconds, or metres travelled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DwellUnit {
+    #[default]
+    Ms,
+    M,
+}
+
+/// GPS receiver for ground speed and position, read as NMEA 0183
+/// (`RMC`/`VTG`) from a serial device. An empty `device` (the default)
+/// disables it.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct GpsConfig {
+    /// Serial device or pty path, e.g. `/dev/ttyACM0`.
+    pub device: String,
+    pub baud: u32,
+    /// Milliseconds without an `RMC` fix before the fix counts as
+    /// stale and `[timing] speed_kmh` takes over again.
+    pub stale_after_ms: u64,
 }
 
 /// Logging configuration.
@@ -107,6 +737,7 @@ impl Default for CameraConfig {
             stall_timeout_secs: 10,
             backend: "v4l2".to_string(),
             device: "/dev/video0".to_string(),
+            pixel_format: PixelFormat::default(),
         }
     }
 }
@@ -118,6 +749,88 @@ impl Default for VisionConfig {
             green_ratio_floor: 0.36,
             chroma_floor: 0.08,
             weights: VisionWeights::default(),
+            lut_bits: 0,
+            workers: 1,
+            roi: RoiConfig::default(),
+            morphology: MorphologyConfig::default(),
+            blobs: BlobsConfig::default(),
+            crop_rows: CropRowsConfig::default(),
+            adaptive: AdaptiveConfig::default(),
+            white_balance: WhiteBalanceConfig::default(),
+            lighting: LightingConfig::default(),
+            index: IndexSelection::default(),
+            index_thresholds: IndexThresholds::default(),
+            hue: HueConfig::default(),
+        }
+    }
+}
+
+impl Default for IndexSelection {
+    fn default() -> Self {
+        Self::Single(IndexKind::Fused)
+    }
+}
+
+impl Default for IndexThresholds {
+    fn default() -> Self {
+        Self {
+            exg: 0.1,
+            exgr: 0.0,
+            cive: 0.0,
+            veg: 1.2,
+            ngrdi: 0.05,
+            ndvi: 0.2,
+        }
+    }
+}
+
+impl Default for HueConfig {
+    fn default() -> Self {
+        Self {
+            min: 70.0,
+            max: 170.0,
+            min_saturation: 0.15,
+            min_value: 0.1,
+        }
+    }
+}
+
+impl Default for WhiteBalanceConfig {
+    fn default() -> Self {
+        Self {
+            gains: [1.0; 3],
+            soil_reference: [120, 90, 70],
+        }
+    }
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_threshold: 10,
+            max_threshold: 80,
+            window: 1,
+        }
+    }
+}
+
+impl Default for BlobsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_area: 20,
+        }
+    }
+}
+
+impl Default for CropRowsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            spacing: 160.0,
+            band: 40.0,
+            smoothing: 0.2,
         }
     }
 }
@@ -139,6 +852,11 @@ impl Default for LanesConfig {
             count: 4,
             on_threshold: 0.30,
             off_threshold: 0.15,
+            on_thresholds: None,
+            off_thresholds: None,
+            enabled: None,
+            boundaries: None,
+            polygons: None,
         }
     }
 }
@@ -146,8 +864,105 @@ impl Default for LanesConfig {
 impl Default for GpioConfig {
     fn default() -> Self {
         Self {
+            backend: GpioBackend::Rppal,
             pins: vec![17, 27, 22, 23],
+            lines: Vec::new(),
+            can: CanConfig::default(),
+            isobus: IsobusConfig::default(),
+            modbus: ModbusConfig::default(),
             mock: false,
+            pwm: PwmConfig::default(),
+        }
+    }
+}
+
+impl Default for ModbusConfig {
+    fn default() -> Self {
+        Self {
+            device: "/dev/ttyUSB0".into(),
+            baud: 9600,
+            parity: Parity::None,
+            slave: 1,
+            coils: Vec::new(),
+            timeout_ms: 100,
+            retries: 2,
+        }
+    }
+}
+
+impl Default for IsobusConfig {
+    fn default() -> Self {
+        Self {
+            interface: "can0".into(),
+            address: 0xF7,
+            implement_identity_number: None,
+            implement_manufacturer_code: 0,
+            element: 1,
+            sections: Vec::new(),
+            refresh_ms: 1000,
+            identity_number: 0,
+            manufacturer_code: 0,
+        }
+    }
+}
+
+impl Default for CanConfig {
+    fn default() -> Self {
+        Self {
+            interface: "can0".into(),
+            id: 0x18FF_0080,
+            extended: true,
+            layout: PayloadLayout::Bits,
+            offset: 0,
+            len: 8,
+            heartbeat_ms: 100,
+        }
+    }
+}
+
+impl Default for PwmConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            frequency_hz: 20.0,
+            min_duty: 0.3,
+            max_duty: 1.0,
+            full_coverage: 0.5,
+            gamma: 1.0,
+        }
+    }
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            camera_to_nozzle_m: 0.0,
+            valve_latency_ms: 0,
+            speed_kmh: 5.0,
+            min_speed_kmh: 0.5,
+            dwell: DwellConfig::default(),
+        }
+    }
+}
+
+impl Default for DwellConfig {
+    fn default() -> Self {
+        Self {
+            unit: DwellUnit::Ms,
+            min_on: 0.0,
+            min_off: 0.0,
+            pre_spray: 0.0,
+            post_spray: 0.0,
+        }
+    }
+}
+
+impl Default for GpsConfig {
+    fn default() -> Self {
+        Self {
+            device: String::new(),
+            baud: 9600,
+            stale_after_ms: 2000,
         }
     }
 }
@@ -189,6 +1004,149 @@ impl Config {
         if self.camera.fps == 0 {
             return Err("camera.fps must be non-zero".into());
         }
+        if self.vision.lut_bits > 8 {
+            return Err(format!(
+                "vision.lut_bits ({}) must be between 0 (disabled) and 8",
+                self.vision.lut_bits,
+            ));
+        }
+        let adaptive = &self.vision.adaptive;
+        if adaptive.enabled {
+            if adaptive.min_threshold > adaptive.max_threshold {
+                return Err(format!(
+                    "vision.adaptive.min_threshold ({}) must be <= max_threshold ({})",
+                    adaptive.min_threshold, adaptive.max_threshold,
+                ));
+            }
+            if adaptive.window == 0 {
+                return Err("vision.adaptive.window must be at least 1".into());
+            }
+            if self.vision.lut_bits > 0 {
+                // The table bakes in a fixed threshold.
+                return Err("vision.adaptive cannot be combined with vision.lut_bits".into());
+            }
+        }
+        let gains = self.vision.white_balance.gains;
+        if !gains
+            .iter()
+            .all(|g| g.is_finite() && *g > 0.0 && *g <= MAX_GAIN)
+        {
+            return Err(format!(
+                "vision.white_balance.gains {gains:?} must each be > 0 and <= {MAX_GAIN}"
+            ));
+        }
+        if let IndexSelection::Weighted(weights) = &self.vision.index {
+            if weights.is_empty()
+                || weights.contains_key(&IndexKind::Fused)
+                || weights.contains_key(&IndexKind::Ndvi)
+            {
+                return Err("vision.index weights must name colour indices only, \
+                     not \"fused\" or \"ndvi\""
+                    .into());
+            }
+        }
+        let ndvi = self.vision.index == IndexSelection::Single(IndexKind::Ndvi);
+        if ndvi != (self.camera.pixel_format == PixelFormat::Rgbn) {
+            return Err(
+                "vision.index = \"ndvi\" and camera.pixel_format = \"rgbn\" go together".into(),
+            );
+        }
+        if ndvi && self.vision.lut_bits > 0 {
+            return Err("vision.index = \"ndvi\" cannot be combined with vision.lut_bits".into());
+        }
+        if self.vision.index != IndexSelection::default() && self.vision.adaptive.enabled {
+            return Err("vision.adaptive only applies to vision.index = \"fused\"".into());
+        }
+        if self.vision.index_thresholds.veg <= 0.0 {
+            return Err(format!(
+                "vision.index_thresholds.veg ({}) must be positive",
+                self.vision.index_thresholds.veg,
+            ));
+        }
+        if self.vision.hue.min > self.vision.hue.max {
+            return Err(format!(
+                "vision.hue.min ({}) must be <= max ({})",
+                self.vision.hue.min, self.vision.hue.max,
+            ));
+        }
+        let lighting = &self.vision.lighting;
+        if lighting.luma_ceiling > 0 && lighting.luma_ceiling <= lighting.luma_floor {
+            return Err(format!(
+                "vision.lighting.luma_ceiling ({}) must be 0 (disabled) or above luma_floor ({})",
+                lighting.luma_ceiling, lighting.luma_floor,
+            ));
+        }
+        if self.vision.workers == 0 {
+            return Err("vision.workers must be at least 1".into());
+        }
+        for (name, size) in [
+            ("open", self.vision.morphology.open),
+            ("close", self.vision.morphology.close),
+        ] {
+            if size % 2 == 0 && size != 0 {
+                return Err(format!(
+                    "vision.morphology.{name} ({size}) must be 0 (disabled) or odd"
+                ));
+            }
+        }
+        let rows = &self.vision.crop_rows;
+        if !(rows.band > 0.0 && rows.band < rows.spacing) {
+            return Err(format!(
+                "vision.crop_rows.band ({}) must be positive and below spacing ({})",
+                rows.band, rows.spacing
+            ));
+        }
+        if !(rows.smoothing > 0.0 && rows.smoothing <= 1.0) {
+            return Err(format!(
+                "vision.crop_rows.smoothing ({}) must be in (0, 1]",
+                rows.smoothing
+            ));
+        }
+        let timing = &self.timing;
+        if !(timing.camera_to_nozzle_m.is_finite() && timing.camera_to_nozzle_m >= 0.0) {
+            return Err(format!(
+                "timing.camera_to_nozzle_m ({}) must be a non-negative distance",
+                timing.camera_to_nozzle_m
+            ));
+        }
+        if !(timing.speed_kmh.is_finite() && timing.speed_kmh >= 0.0) {
+            return Err(format!(
+                "timing.speed_kmh ({}) must be a non-negative speed",
+                timing.speed_kmh
+            ));
+        }
+        if !(timing.min_speed_kmh.is_finite() && timing.min_speed_kmh >= 0.0) {
+            return Err(format!(
+                "timing.min_speed_kmh ({}) must be a non-negative speed",
+                timing.min_speed_kmh
+            ));
+        }
+        let dwell = &timing.dwell;
+        for (key, value) in [
+            ("min_on", dwell.min_on),
+            ("min_off", dwell.min_off),
+            ("pre_spray", dwell.pre_spray),
+            ("post_spray", dwell.post_spray),
+        ] {
+            if !(value.is_finite() && value >= 0.0) {
+                return Err(format!("timing.dwell.{key} ({value}) must be non-negative"));
+            }
+        }
+        if dwell.pre_spray > 0.0 && timing.camera_to_nozzle_m == 0.0 {
+            return Err("timing.dwell.pre_spray needs timing.camera_to_nozzle_m".into());
+        }
+        if dwell.unit == DwellUnit::M && dwell.pre_spray > timing.camera_to_nozzle_m {
+            return Err(format!(
+                "timing.dwell.pre_spray ({} m) must not exceed timing.camera_to_nozzle_m ({} m)",
+                dwell.pre_spray, timing.camera_to_nozzle_m
+            ));
+        }
+        if !self.gps.device.is_empty() && !serial::is_supported_baud(self.gps.baud) {
+            return Err(format!(
+                "gps.baud ({}) must be one of 4800, 9600, 19200, 38400, 57600, 115200",
+                self.gps.baud
+            ));
+        }
         if self.lanes.count == 0 {
             return Err("lanes.count must be non-zero".into());
         }
@@ -198,19 +1156,284 @@ impl Config {
                 self.camera.width, self.lanes.count,
             ));
         }
+        let roi = &self.vision.roi;
+        if roi.top + roi.bottom >= self.camera.height {
+            return Err(format!(
+                "vision.roi top + bottom ({}) must leave rows of the {}-pixel-high frame",
+                roi.top + roi.bottom,
+                self.camera.height,
+            ));
+        }
+        let roi_width = self.camera.width.saturating_sub(roi.left + roi.right);
+        if roi_width < self.lanes.count {
+            return Err(format!(
+                "vision.roi leaves {} columns, must be >= lanes.count ({})",
+                roi_width, self.lanes.count,
+            ));
+        }
         if self.lanes.on_threshold < self.lanes.off_threshold {
             return Err(format!(
                 "lanes.on_threshold ({}) must be >= lanes.off_threshold ({}) for hysteresis",
                 self.lanes.on_threshold, self.lanes.off_threshold,
             ));
         }
-        if self.gpio.pins.len() != self.lanes.count {
+        let per_lane = [
+            (
+                "on_thresholds",
+                self.lanes.on_thresholds.as_ref().map(Vec::len),
+            ),
+            (
+                "off_thresholds",
+                self.lanes.off_thresholds.as_ref().map(Vec::len),
+            ),
+            ("enabled", self.lanes.enabled.as_ref().map(Vec::len)),
+        ];
+        for (key, len) in per_lane {
+            if let Some(len) = len.filter(|&len| len != self.lanes.count) {
+                return Err(format!(
+                    "lanes.{key} has {len} entries but lanes.count is {} — one per lane required",
+                    self.lanes.count,
+                ));
+            }
+        }
+        for lane in 0..self.lanes.count {
+            let (on, off) = (
+                self.lanes.on_threshold_for(lane),
+                self.lanes.off_threshold_for(lane),
+            );
+            if !(0.0..=1.0).contains(&on) || !(0.0..=1.0).contains(&off) {
+                return Err(format!(
+                    "lane {lane}: thresholds (on {on}, off {off}) must be within 0.0..=1.0",
+                ));
+            }
+            if on < off {
+                return Err(format!(
+                    "lane {lane}: on threshold ({on}) must be >= off threshold ({off}) for hysteresis",
+                ));
+            }
+        }
+        if self.lanes.boundaries.is_some() && self.lanes.polygons.is_some() {
+            return Err("set at most one of lanes.boundaries and lanes.polygons".into());
+        }
+        if let Some(edges) = &self.lanes.boundaries {
+            if edges.len() != self.lanes.count + 1 {
+                return Err(format!(
+                    "lanes.boundaries has {} edges but lanes.count is {} — count + 1 required",
+                    edges.len(),
+                    self.lanes.count,
+                ));
+            }
+            if !edges.is_sorted_by(|a, b| a < b) || edges.iter().any(|e| !e.is_finite()) {
+                return Err("lanes.boundaries must be strictly increasing".into());
+            }
+            if edges[0] < 0.0 || edges[self.lanes.count] > self.camera.width as f32 {
+                return Err(format!(
+                    "lanes.boundaries must lie within 0..={} (camera.width)",
+                    self.camera.width,
+                ));
+            }
+        }
+        if let Some(polygons) = &self.lanes.polygons {
+            if polygons.len() != self.lanes.count {
+                return Err(format!(
+                    "lanes.polygons has {} entries but lanes.count is {} — one per lane required",
+                    polygons.len(),
+                    self.lanes.count,
+                ));
+            }
+            for (lane, polygon) in polygons.iter().enumerate() {
+                if polygon.len() < 3 {
+                    return Err(format!("lanes.polygons[{lane}] needs at least 3 vertices"));
+                }
+                if polygon.iter().flatten().any(|v| !v.is_finite()) {
+                    return Err(format!("lanes.polygons[{lane}] has a non-finite vertex"));
+                }
+            }
+        }
+        match self.gpio.backend {
+            GpioBackend::Rppal if self.gpio.pins.len() != self.lanes.count => {
+                return Err(format!(
+                    "gpio.pins has {} entries but lanes.count is {} — one pin per lane required",
+                    self.gpio.pins.len(),
+                    self.lanes.count,
+                ));
+            }
+            GpioBackend::Cdev if self.gpio.lines.len() != self.lanes.count => {
+                return Err(format!(
+                    "gpio.lines has {} entries but lanes.count is {} — one line per lane required",
+                    self.gpio.lines.len(),
+                    self.lanes.count,
+                ));
+            }
+            GpioBackend::Can => {
+                let can = &self.gpio.can;
+                let max_id = if can.extended { 0x1FFF_FFFF } else { 0x7FF };
+                if can.id > max_id {
+                    return Err(format!(
+                        "gpio.can.id ({:#X}) does not fit a {} identifier",
+                        can.id,
+                        if can.extended { "29-bit" } else { "11-bit" },
+                    ));
+                }
+                if !(1..=8).contains(&can.len) {
+                    return Err(format!("gpio.can.len ({}) must be 1 to 8", can.len));
+                }
+                let needed = can.offset + can.layout.bytes_for(self.lanes.count);
+                if needed > usize::from(can.len) {
+                    return Err(format!(
+                        "gpio.can lanes need payload bytes up to {needed} but len is {}",
+                        can.len
+                    ));
+                }
+                if self.gpio.pwm.enabled {
+                    return Err(
+                        "gpio.pwm is not supported with backend = \"can\" — the section controller switches the valves"
+                            .into(),
+                    );
+                }
+            }
+            GpioBackend::Isobus => {
+                let isobus = &self.gpio.isobus;
+                if isobus.address > 247 {
+                    return Err(format!(
+                        "gpio.isobus.address ({}) must be 0 to 247",
+                        isobus.address
+                    ));
+                }
+                match isobus.implement_identity_number {
+                    None => {
+                        return Err("gpio.isobus.implement_identity_number must be set".into());
+                    }
+                    Some(n) if n > 0x1F_FFFF || isobus.implement_manufacturer_code > 0x7FF => {
+                        return Err(format!(
+                            "gpio.isobus implement_identity_number ({n}) must fit 21 bits and implement_manufacturer_code ({}) 11 bits",
+                            isobus.implement_manufacturer_code
+                        ));
+                    }
+                    Some(_) => {}
+                }
+                if isobus.element > 0xFFF {
+                    return Err(format!(
+                        "gpio.isobus.element ({}) must be below 4096",
+                        isobus.element
+                    ));
+                }
+                if isobus.sections.is_empty() {
+                    if self.lanes.count > MAX_SECTIONS {
+                        return Err(format!(
+                            "gpio.isobus: {} lanes exceed the {MAX_SECTIONS} sections of an element",
+                            self.lanes.count
+                        ));
+                    }
+                } else if isobus.sections.len() != self.lanes.count {
+                    return Err(format!(
+                        "gpio.isobus.sections has {} entries but lanes.count is {}",
+                        isobus.sections.len(),
+                        self.lanes.count
+                    ));
+                }
+                if let Some(s) = isobus
+                    .sections
+                    .iter()
+                    .find(|&&s| s == 0 || usize::from(s) > MAX_SECTIONS)
+                {
+                    return Err(format!(
+                        "gpio.isobus.sections entry {s} must be 1 to {MAX_SECTIONS}"
+                    ));
+                }
+                if isobus.refresh_ms == 0 {
+                    return Err("gpio.isobus.refresh_ms must be positive".into());
+                }
+                if isobus.identity_number > 0x1F_FFFF || isobus.manufacturer_code > 0x7FF {
+                    return Err(format!(
+                        "gpio.isobus identity_number ({}) must fit 21 bits and manufacturer_code ({}) 11 bits",
+                        isobus.identity_number, isobus.manufacturer_code
+                    ));
+                }
+                if self.gpio.pwm.enabled {
+                    return Err(
+                        "gpio.pwm is not supported with backend = \"isobus\" — the implement switches the sections"
+                            .into(),
+                    );
+                }
+            }
+            GpioBackend::Modbus => {
+                let modbus = &self.gpio.modbus;
+                if modbus.device.is_empty() {
+                    return Err("gpio.modbus.device must be set".into());
+                }
+                if !serial::is_supported_baud(modbus.baud) {
+                    return Err(format!(
+                        "gpio.modbus.baud ({}) must be one of 4800, 9600, 19200, 38400, 57600, 115200",
+                        modbus.baud
+                    ));
+                }
+                if !(1..=247).contains(&modbus.slave) {
+                    return Err(format!(
+                        "gpio.modbus.slave ({}) must be 1 to 247",
+                        modbus.slave
+                    ));
+                }
+                let coils = modbus.coils(self.lanes.count);
+                if coils.len() != self.lanes.count {
+                    return Err(format!(
+                        "gpio.modbus.coils has {} entries but lanes.count is {}",
+                        coils.len(),
+                        self.lanes.count
+                    ));
+                }
+                let mut sorted = coils.clone();
+                sorted.sort_unstable();
+                if let Some(pair) = sorted.windows(2).find(|pair| pair[0] == pair[1]) {
+                    return Err(format!("gpio.modbus.coils lists coil {} twice", pair[0]));
+                }
+                if modbus.timeout_ms == 0 {
+                    return Err("gpio.modbus.timeout_ms must be positive".into());
+                }
+                if self.gpio.pwm.enabled {
+                    return Err(
+                        "gpio.pwm is not supported with backend = \"modbus\" — relays only switch"
+                            .into(),
+                    );
+                }
+            }
+            _ => {}
+        }
+        let pwm = &self.gpio.pwm;
+        if !(pwm.frequency_hz.is_finite() && pwm.frequency_hz > 0.0) {
+            return Err(format!(
+                "gpio.pwm.frequency_hz ({}) must be positive",
+                pwm.frequency_hz
+            ));
+        }
+        if !(0.0 <= pwm.min_duty && pwm.min_duty <= pwm.max_duty && pwm.max_duty <= 1.0) {
             return Err(format!(
-                "gpio.pins has {} entries but lanes.count is {} — one pin per lane required",
-                self.gpio.pins.len(),
-                self.lanes.count,
+                "gpio.pwm duties must satisfy 0 <= min_duty ({}) <= max_duty ({}) <= 1",
+                pwm.min_duty, pwm.max_duty
             ));
         }
+        if !(pwm.full_coverage > 0.0 && pwm.full_coverage <= 1.0) {
+            return Err(format!(
+                "gpio.pwm.full_coverage ({}) must be in (0, 1]",
+                pwm.full_coverage
+            ));
+        }
+        if !(pwm.gamma.is_finite() && pwm.gamma > 0.0) {
+            return Err(format!("gpio.pwm.gamma ({}) must be positive", pwm.gamma));
+        }
+        if pwm.enabled && self.gpio.backend == GpioBackend::Rppal {
+            let pins = &self.gpio.pins;
+            for (lane, &pin) in pins.iter().enumerate() {
+                let Some(channel) = pwm::hardware_pwm_channel(pin) else {
+                    continue;
+                };
+                if pins[..lane].contains(&pin) {
+                    return Err(format!(
+                        "gpio.pins puts two lanes on BCM {pin}, hardware PWM channel {channel} — one lane per channel"
+                    ));
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -256,6 +1479,8 @@ device = "/dev/video1"
 exg_threshold = 30
 green_ratio_floor = 0.40
 chroma_floor = 0.10
+lut_bits = 5
+workers = 4
 
 [vision.weights]
 exg = 0.6
@@ -280,6 +1505,8 @@ level = "debug"
         assert_eq!(cfg.camera.stall_timeout_secs, 7);
         assert_eq!(cfg.camera.backend, "libcamera");
         assert_eq!(cfg.vision.exg_threshold, 30);
+        assert_eq!(cfg.vision.lut_bits, 5);
+        assert_eq!(cfg.vision.workers, 4);
         assert!((cfg.vision.weights.bias - 0.05).abs() < f32::EPSILON);
         assert_eq!(cfg.lanes.count, 6);
         assert_eq!(cfg.gpio.pins, vec![5, 6, 13, 19, 26, 21]);
@@ -308,6 +1535,484 @@ count = 6
         assert!(err.contains("gpio.pins"), "unexpected error: {err}");
     }
 
+    #[test]
+    fn validate_rejects_oversized_lut() {
+        let cfg: Config = toml::from_str(
+            r#"
+[vision]
+lut_bits = 9
+"#,
+        )
+        .unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(err.contains("vision.lut_bits"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn per_lane_overrides_fall_back_to_global_thresholds() {
+        let cfg: Config = toml::from_str(
+            r#"
+[lanes]
+on_thresholds = [0.45, 0.30, 0.30, 0.45]
+enabled = [true, true, false, true]
+"#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        assert!((cfg.lanes.on_threshold_for(0) - 0.45).abs() < f32::EPSILON);
+        assert!((cfg.lanes.off_threshold_for(0) - 0.15).abs() < f32::EPSILON);
+        assert!(!cfg.lanes.is_enabled(2));
+        assert!(cfg.lanes.is_enabled(3));
+    }
+
+    #[test]
+    fn validate_rejects_per_lane_length_mismatch() {
+        let cfg: Config = toml::from_str(
+            r#"
+[lanes]
+enabled = [true, false]
+"#,
+        )
+        .unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(err.contains("lanes.enabled"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn validate_rejects_inverted_per_lane_hysteresis() {
+        let cfg: Config = toml::from_str(
+            r#"
+[lanes]
+off_thresholds = [0.15, 0.15, 0.40, 0.15]
+"#,
+        )
+        .unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(err.contains("lane 2"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn lane_geometry_from_toml() {
+        let cfg: Config = toml::from_str(
+            r#"
+[lanes]
+count = 2
+polygons = [
+    [[0, 480], [200, 0], [320, 0], [320, 480]],
+    [[320, 480], [320, 0], [440, 0], [640, 480]],
+]
+
+[gpio]
+pins = [17, 27]
+"#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        match cfg.lanes.geometry(640, 480) {
+            LaneGeometry::Polygons { polygons, .. } => assert_eq!(polygons[1][3], [640.0, 480.0]),
+            other => panic!("unexpected geometry {other:?}"),
+        }
+    }
+
+    #[test]
+    fn validate_rejects_bad_boundaries() {
+        for (edges, needle) in [
+            ("[0, 160, 320, 480]", "count + 1"),
+            ("[0, 320, 160, 480, 640]", "strictly increasing"),
+            ("[0, 160, 320, 480, 700]", "camera.width"),
+        ] {
+            let cfg: Config = toml::from_str(&format!("[lanes]\nboundaries = {edges}\n")).unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains(needle), "{edges}: unexpected error: {err}");
+        }
+    }
+
+    #[test]
+    fn roi_margins_from_toml() {
+        let cfg: Config = toml::from_str(
+            r#"
+[vision.roi]
+top = 120
+left = 40
+right = 40
+"#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        let roi = cfg.vision.roi(640, 480).unwrap();
+        assert_eq!(
+            (roi.rect(640, 480).width, roi.rect(640, 480).height),
+            (560, 360)
+        );
+        assert!(Config::default().vision.roi(640, 480).is_none());
+    }
+
+    #[test]
+    fn validate_rejects_roi_without_room_for_lanes() {
+        for (roi, needle) in [
+            ("top = 240\nbottom = 240", "top + bottom"),
+            ("left = 318\nright = 320", "lanes.count"),
+        ] {
+            let cfg: Config = toml::from_str(&format!("[vision.roi]\n{roi}\n")).unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains(needle), "{roi}: unexpected error: {err}");
+        }
+    }
+
+    #[test]
+    fn morphology_from_toml() {
+        let cfg: Config = toml::from_str("[vision.morphology]\nopen = 3\n").unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.vision.mask_filter(), Some(MaskFilter::new(3, 0)));
+        assert_eq!(Config::default().vision.mask_filter(), None);
+
+        let cfg: Config = toml::from_str("[vision.morphology]\nclose = 4\n").unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(
+            err.contains("vision.morphology.close"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn blobs_from_toml() {
+        let cfg: Config = toml::from_str("[vision.blobs]\nenabled = true\n").unwrap();
+        assert!(cfg.vision.blobs.enabled);
+        assert_eq!(cfg.vision.blobs.min_area, 20);
+        assert!(!Config::default().vision.blobs.enabled);
+    }
+
+    #[test]
+    fn timing_from_toml() {
+        let speed = Config::default().timing.ground_speed();
+        assert!(Config::default().timing.delay_scheduler(4, speed).is_none());
+        let cfg: Config = toml::from_str(
+            "[timing]\ncamera_to_nozzle_m = 0.6\nvalve_latency_ms = 40\nspeed_kmh = 7.2\n",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        let scheduler = cfg
+            .timing
+            .delay_scheduler(4, cfg.timing.ground_speed())
+            .unwrap();
+        assert_eq!(scheduler.speed().get(), 2.0);
+        assert!(!scheduler.speed().is_stopped());
+        scheduler.speed().set(0.1);
+        assert!(scheduler.speed().is_stopped());
+        for timing in [
+            "camera_to_nozzle_m = -1",
+            "speed_kmh = -3",
+            "min_speed_kmh = nan",
+        ] {
+            let cfg: Config = toml::from_str(&format!("[timing]\n{timing}\n")).unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains("timing."), "{timing}: {err}");
+        }
+    }
+
+    #[test]
+    fn cdev_lines_from_toml() {
+        assert_eq!(Config::default().gpio.backend, GpioBackend::Rppal);
+        let cfg: Config = toml::from_str(
+            "[lanes]\ncount = 2\n[gpio]\nbackend = \"cdev\"\n\
+             lines = [{ chip = 0, line = 17 }, { chip = 4, line = 3 }]\n",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok(), "pins are not needed with cdev");
+        assert_eq!(cfg.gpio.backend, GpioBackend::Cdev);
+        assert_eq!(cfg.gpio.lines[1], CdevLine { chip: 4, line: 3 });
+        let cfg: Config =
+            toml::from_str("[gpio]\nbackend = \"cdev\"\nlines = [{ chip = 0, line = 17 }]\n")
+                .unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(err.contains("gpio.lines"), "{err}");
+    }
+
+    #[test]
+    fn can_from_toml() {
+        let cfg: Config = toml::from_str(
+            "[lanes]\ncount = 12\n[gpio]\nbackend = \"can\"\n\
+             [gpio.can]\ninterface = \"vcan0\"\nid = 0x321\nextended = false\n\
+             layout = \"bits_msb\"\noffset = 2\nlen = 4\nheartbeat_ms = 0\n",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok(), "pins are not needed with can");
+        let can = &cfg.gpio.can;
+        assert_eq!(can.interface, "vcan0");
+        assert!(can.heartbeat().is_none());
+        let message = can.message();
+        assert_eq!((message.id, message.extended), (0x321, false));
+        assert_eq!(message.layout, PayloadLayout::BitsMsb);
+        for can in [
+            "id = 0x800\nextended = false",
+            "len = 9",
+            "offset = 8",
+            "layout = \"bytes\"\nlen = 2",
+        ] {
+            let cfg: Config =
+                toml::from_str(&format!("[gpio]\nbackend = \"can\"\n[gpio.can]\n{can}\n")).unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains("gpio.can"), "{can}: {err}");
+        }
+        let cfg: Config =
+            toml::from_str("[gpio]\nbackend = \"can\"\n[gpio.pwm]\nenabled = true\n").unwrap();
+        assert!(cfg.validate().unwrap_err().contains("gpio.pwm"));
+    }
+
+    #[test]
+    fn isobus_from_toml() {
+        let cfg: Config = toml::from_str(
+            "[lanes]\ncount = 4\n[gpio]\nbackend = \"isobus\"\n\
+             [gpio.isobus]\ninterface = \"vcan0\"\nimplement_identity_number = 77\n\
+             implement_manufacturer_code = 12\nelement = 3\nsections = [1, 1, 2, 3]\n\
+             identity_number = 42\n",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok(), "pins are not needed with isobus");
+        let isobus = &cfg.gpio.isobus;
+        assert_eq!(isobus.address, 0xF7);
+        assert_eq!(
+            (
+                isobus.implement_identity_number,
+                isobus.implement_manufacturer_code
+            ),
+            (Some(77), 12)
+        );
+        let node = isobus.section_control(4);
+        assert_eq!((node.address(), node.implement_address()), (None, None));
+        let mut cfg = Config::default();
+        cfg.gpio.backend = GpioBackend::Isobus;
+        assert!(cfg
+            .validate()
+            .unwrap_err()
+            .contains("implement_identity_number"));
+        cfg.gpio.isobus.implement_identity_number = Some(0x20_0000);
+        assert!(cfg.validate().unwrap_err().contains("21 bits"));
+        for isobus in [
+            "address = 248",
+            "implement_manufacturer_code = 2048",
+            "element = 4096",
+            "sections = [1, 2]",
+            "sections = [1, 2, 3, 0]",
+            "refresh_ms = 0",
+            "manufacturer_code = 2048",
+        ] {
+            let cfg: Config = toml::from_str(&format!(
+                "[gpio]\nbackend = \"isobus\"\n[gpio.isobus]\nimplement_identity_number = 1\n{isobus}\n"
+            ))
+            .unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains("gpio.isobus"), "{isobus}: {err}");
+        }
+    }
+
+    #[test]
+    fn modbus_from_toml() {
+        let cfg: Config = toml::from_str(
+            "[lanes]\ncount = 3\n[gpio]\nbackend = \"modbus\"\n\
+             [gpio.modbus]\ndevice = \"/dev/ttyS1\"\nbaud = 19200\nparity = \"even\"\n\
+             slave = 16\ncoils = [8, 9, 12]\nretries = 0\n",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok(), "pins are not needed with modbus");
+        let modbus = &cfg.gpio.modbus;
+        assert_eq!((modbus.parity, modbus.slave), (Parity::Even, 16));
+        assert_eq!(modbus.coils(3), [8, 9, 12]);
+        assert_eq!(modbus.link().retries, 0);
+        assert_eq!(Config::default().gpio.modbus.coils(4), [0, 1, 2, 3]);
+        for modbus in [
+            "device = \"\"",
+            "baud = 1200",
+            "slave = 0",
+            "slave = 248",
+            "coils = [1, 2]",
+            "coils = [1, 2, 3, 2]",
+            "timeout_ms = 0",
+        ] {
+            let cfg: Config = toml::from_str(&format!(
+                "[gpio]\nbackend = \"modbus\"\n[gpio.modbus]\n{modbus}\n"
+            ))
+            .unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains("gpio.modbus"), "{modbus}: {err}");
+        }
+    }
+
+    #[test]
+    fn pwm_from_toml() {
+        assert!(Config::default().gpio.pwm.duty_curve().is_none());
+        let cfg: Config =
+            toml::from_str("[gpio.pwm]\nenabled = true\nmin_duty = 0.5\nfull_coverage = 0.25\n")
+                .unwrap();
+        assert!(cfg.validate().is_ok());
+        let curve = cfg.gpio.pwm.duty_curve().unwrap();
+        assert_eq!((curve.duty(0.0), curve.duty(0.25)), (0.5, 1.0));
+        for pwm in [
+            "min_duty = 0.9\nmax_duty = 0.8",
+            "full_coverage = 0",
+            "frequency_hz = 0",
+        ] {
+            let cfg: Config = toml::from_str(&format!("[gpio.pwm]\n{pwm}\n")).unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains("gpio.pwm"), "{pwm}: {err}");
+        }
+        // One lane per hardware channel; BCM 12 and 13 pulse in software.
+        let cfg: Config = toml::from_str(
+            "[lanes]\ncount = 3\n[gpio]\npins = [12, 18, 18]\n[gpio.pwm]\nenabled = true\n",
+        )
+        .unwrap();
+        assert!(cfg
+            .validate()
+            .unwrap_err()
+            .contains("hardware PWM channel 0"));
+        let cfg: Config = toml::from_str(
+            "[lanes]\ncount = 3\n[gpio]\npins = [12, 18, 19]\n[gpio.pwm]\nenabled = true\n",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn dwell_from_toml() {
+        let timing = Config::default().timing;
+        assert!(timing.dwell_timer(4, timing.ground_speed()).is_none());
+        let cfg: Config = toml::from_str(
+            "[timing]\ncamera_to_nozzle_m = 0.6\n[timing.dwell]\nunit = \"m\"\nmin_on = 0.1\npre_spray = 0.05\n",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.timing.dwell.unit, DwellUnit::M);
+        assert!(cfg
+            .timing
+            .dwell_timer(4, cfg.timing.ground_speed())
+            .is_some());
+        for (toml, key) in [
+            ("[timing.dwell]\nmin_off = -5", "timing.dwell.min_off"),
+            (
+                "[timing.dwell]\npre_spray = 50",
+                "timing.camera_to_nozzle_m",
+            ),
+            (
+                "[timing]\ncamera_to_nozzle_m = 0.6\n[timing.dwell]\nunit = \"m\"\npre_spray = 0.8",
+                "must not exceed",
+            ),
+        ] {
+            let cfg: Config = toml::from_str(toml).unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains(key), "{toml}: {err}");
+        }
+    }
+
+    #[test]
+    fn gps_from_toml() {
+        let cfg: Config = toml::from_str("[gps]\ndevice = \"/dev/ttyACM0\"\n").unwrap();
+        assert_eq!((cfg.gps.baud, cfg.gps.stale_after_ms), (9600, 2000));
+        assert!(cfg.validate().is_ok());
+        let cfg: Config =
+            toml::from_str("[gps]\ndevice = \"/dev/ttyACM0\"\nbaud = 1234\n").unwrap();
+        assert!(cfg.validate().unwrap_err().contains("gps.baud"));
+        assert!(Config::default().gps.device.is_empty());
+    }
+
+    #[test]
+    fn crop_rows_from_toml() {
+        let cfg: Config =
+            toml::from_str("[vision.crop_rows]\nenabled = true\nspacing = 120\n").unwrap();
+        assert!(cfg.vision.crop_rows.enabled);
+        assert_eq!(cfg.vision.crop_rows.spacing, 120.0);
+        assert!(cfg.validate().is_ok());
+        for rows in ["band = 200", "band = 0", "smoothing = 0", "smoothing = 1.5"] {
+            let cfg: Config = toml::from_str(&format!("[vision.crop_rows]\n{rows}\n")).unwrap();
+            let err = cfg.validate().unwrap_err();
+            assert!(err.contains("vision.crop_rows"), "{rows}: {err}");
+        }
+    }
+
+    #[test]
+    fn adaptive_threshold_from_toml() {
+        let cfg: Config = toml::from_str(
+            r#"
+[vision.adaptive]
+enabled = true
+min_threshold = 5
+window = 10
+"#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.vision.adaptive_threshold().unwrap().threshold(), 5);
+        assert!(Config::default().vision.adaptive_threshold().is_none());
+    }
+
+    #[test]
+    fn validate_rejects_adaptive_with_lookup_table() {
+        let cfg: Config = toml::from_str(
+            r#"
+[vision]
+lut_bits = 5
+
+[vision.adaptive]
+enabled = true
+"#,
+        )
+        .unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(err.contains("lut_bits"), "unexpected error: {err}");
+
+        let cfg: Config =
+            toml::from_str("[vision.adaptive]\nenabled = true\nmin_threshold = 90\n").unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(err.contains("min_threshold"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn white_balance_from_toml() {
+        let cfg: Config =
+            toml::from_str("[vision.white_balance]\ngains = [1.1, 0.95, 1.3]\n").unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.vision.white_balance.soil_reference, [120, 90, 70]);
+        let gains = crate::vision::PlantVision::from_config(&cfg.vision)
+            .white_balance()
+            .map(|wb| wb.gains());
+        assert_eq!(gains, Some([1.1, 0.95, 1.3]));
+        assert!(
+            crate::vision::PlantVision::from_config(&Config::default().vision)
+                .white_balance()
+                .is_none()
+        );
+
+        let cfg: Config =
+            toml::from_str("[vision.white_balance]\ngains = [1.0, 0.0, 1.0]\n").unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(err.contains("white_balance"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn lighting_from_toml() {
+        let cfg: Config = toml::from_str(
+            "[vision.lighting]
+luma_floor = 12
+luma_ceiling = 240
+",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        let lighting = crate::vision::PlantVision::from_config(&cfg.vision).lighting();
+        assert_eq!((lighting.luma_floor, lighting.luma_ceiling), (12, 240));
+        assert_eq!(lighting.shadow_luma, 0);
+        assert!(Config::default().vision.lighting.lighting().is_off());
+
+        let cfg: Config = toml::from_str(
+            "[vision.lighting]
+luma_floor = 50
+luma_ceiling = 40
+",
+        )
+        .unwrap();
+        let err = cfg.validate().unwrap_err();
+        assert!(err.contains("luma_ceiling"), "unexpected error: {err}");
+    }
+
     #[test]
     fn validate_rejects_zero_fps() {
         let cfg: Config = toml::from_str(
@@ -319,4 +2024,55 @@ fps = 0
         .unwrap();
         assert!(cfg.validate().is_err());
     }
+
+    #[test]
+    fn index_selection_from_toml() {
+        let cfg: Config = toml::from_str("[vision]\nindex = \"exgr\"\n").unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.vision.index, IndexSelection::Single(IndexKind::Exgr));
+        let index = cfg.vision.vegetation_index().unwrap();
+        assert!(index.score(60, 200, 60) > 0.0);
+        assert!(Config::default().vision.vegetation_index().is_none());
+
+        let cfg: Config = toml::from_str(
+            "[vision]\nindex = { exgr = 1.0, cive = 0.5 }\n[vision.index_thresholds]\ncive = -10.0\n",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        let blend = cfg.vision.vegetation_index().unwrap();
+        let expected = Exgr { threshold: 0.0 }.score(120, 90, 70)
+            + 0.5 * Cive { threshold: -10.0 }.score(120, 90, 70);
+        assert_eq!(blend.score(120, 90, 70), expected);
+
+        for bad in [
+            "[vision]\nindex = { fused = 1.0, exg = 1.0 }\n",
+            "[vision]\nindex = \"ngrdi\"\n[vision.adaptive]\nenabled = true\n",
+            "[vision.hue]\nmin = 180.0\n",
+        ] {
+            let cfg: Config = toml::from_str(bad).unwrap();
+            assert!(cfg.validate().is_err(), "accepted {bad}");
+        }
+        assert!(toml::from_str::<Config>("[vision]\nindex = \"savi\"\n").is_err());
+    }
+
+    #[test]
+    fn ndvi_needs_rgbn_frames() {
+        let cfg: Config =
+            toml::from_str("[camera]\npixel_format = \"rgbn\"\n[vision]\nindex = \"ndvi\"\n")
+                .unwrap();
+        assert!(cfg.validate().is_ok());
+        let vision = crate::vision::PlantVision::from_config(&cfg.vision);
+        assert_eq!(vision.ndvi_threshold(), Some(0.2));
+        assert_eq!(vision.pixel_format(), PixelFormat::Rgbn);
+
+        for bad in [
+            "[vision]\nindex = \"ndvi\"\n",
+            "[camera]\npixel_format = \"rgbn\"\n",
+            "[camera]\npixel_format = \"rgbn\"\n[vision]\nindex = \"ndvi\"\nlut_bits = 5\n",
+            "[vision]\nindex = { ndvi = 1.0, exg = 1.0 }\n",
+        ] {
+            let cfg: Config = toml::from_str(bad).unwrap();
+            assert!(cfg.validate().is_err(), "accepted {bad}");
+        }
+    }
 }
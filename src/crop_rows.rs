@@ -0,0 +1,248 @@
+//! Crop row detection for green-on-green spraying.
+//!
+//! In a growing crop every leaf is green, so [`crate::vision::PlantVision`]
+//! alone would spray the crop itself. With a camera looking straight down
+//! and driving along the rows, the crop shows up as evenly spaced vertical
+//! bands of vegetation. [`CropRowTracker`] builds the column histogram of
+//! each frame's vegetation mask, smooths it over frames, and fits a comb of
+//! rows at the known row spacing: the comb phase whose bands hold the most
+//! vegetation gives the row centres. Vegetation inside each band is then
+//! cleared, so lane coverage only counts inter-row weeds.
+
+use crate::morphology::BitMask;
+
+/// The row bands must hold this many times the vegetation they would
+/// hold if it were spread evenly across the frame before rows are
+/// reported. Below it (a weed carpet, bare soil) nothing is masked.
+const MIN_CONTRAST: f32 = 1.5;
+
+/// Tracks crop row positions across frames.
+#[derive(Debug, Clone)]
+pub struct CropRowTracker {
+    spacing: f32,
+    band: f32,
+    smoothing: f32,
+    ref_width: usize,
+    /// Smoothed fraction of vegetation pixels per mask column.
+    hist: Vec<f32>,
+    /// Per-column counts of the current frame (difference array scratch).
+    diff: Vec<i32>,
+    /// Row centres in mask columns.
+    rows: Vec<f32>,
+    /// Band half-width in mask columns for the current frame size.
+    half_band: f32,
+    /// Whether `hist` holds a frame of the current size yet.
+    primed: bool,
+}
+
+impl CropRowTracker {
+    /// Tracker for rows `spacing` pixels apart whose crop occupies a band
+    /// `band` pixels wide, both measured in a frame `ref_width` pixels
+    /// wide (normally the `[camera]` width) and scaled for other sizes.
+    /// Each frame's histogram enters the running average with weight
+    /// `smoothing` (`1` uses the current frame only).
+    ///
+    /// # Panics
+    /// Panics unless `0 < band < spacing` and `0 < smoothing <= 1`.
+    pub fn new(spacing: f32, band: f32, smoothing: f32, ref_width: usize) -> Self {
+        assert!(
+            band > 0.0 && band < spacing,
+            "Row band must be positive and narrower than the row spacing"
+        );
+        assert!(
+            smoothing > 0.0 && smoothing <= 1.0,
+            "Smoothing must be in (0, 1]"
+        );
+        Self {
+            spacing,
+            band,
+            smoothing,
+            ref_width,
+            hist: Vec::new(),
+            diff: Vec::new(),
+            rows: Vec::new(),
+            half_band: 0.0,
+            primed: false,
+        }
+    }
+
+    /// Row centres found by the last update, in mask columns. Empty when
+    /// no row pattern stands out.
+    pub fn rows(&self) -> &[f32] {
+        &self.rows
+    }
+
+    /// Add `mask` (a region of a frame `frame_width` pixels wide) to the
+    /// column histogram and refit the rows.
+    pub fn update(&mut self, mask: &BitMask, frame_width: usize) -> &[f32] {
+        let width = mask.width();
+        let scale = if self.ref_width == 0 || frame_width == self.ref_width {
+            1.0
+        } else {
+            frame_width as f32 / self.ref_width as f32
+        };
+        let spacing = self.spacing * scale;
+        self.half_band = self.band * scale / 2.0;
+        if self.hist.len() != width {
+            // New frame size: start the average afresh.
+            self.hist.clear();
+            self.hist.resize(width, 0.0);
+            self.primed = false;
+        }
+
+        self.diff.clear();
+        self.diff.resize(width + 1, 0);
+        for y in 0..mask.height() {
+            mask.for_each_run(y, |start, end| {
+                self.diff[start] += 1;
+                self.diff[end] -= 1;
+            });
+        }
+        let weight = if self.primed { self.smoothing } else { 1.0 };
+        self.primed = true;
+        let rows_in_mask = mask.height().max(1) as f32;
+        let mut count = 0;
+        for (h, &d) in self.hist.iter_mut().zip(&self.diff) {
+            count += d;
+            *h += weight * (count as f32 / rows_in_mask - *h);
+        }
+
+        self.fit(spacing);
+        &self.rows
+    }
+
+    /// Pick the comb phase whose bands hold the most vegetation.
+    fn fit(&mut self, spacing: f32) {
+        self.rows.clear();
+        let width = self.hist.len();
+        // prefix[i] = vegetation in columns 0..i.
+        let mut prefix = Vec::with_capacity(width + 1);
+        prefix.push(0.0f32);
+        for &h in &self.hist {
+            prefix.push(prefix.last().unwrap() + h);
+        }
+        let total = prefix[width];
+        if total <= 0.0 || spacing < 1.0 {
+            return;
+        }
+        let band_sum = |centre: f32| {
+            let start = (centre - self.half_band).round().clamp(0.0, width as f32) as usize;
+            let end = (centre + self.half_band).round().clamp(0.0, width as f32) as usize;
+            prefix[end] - prefix[start]
+        };
+        let comb = |phase: f32| {
+            let mut sum = 0.0;
+            let mut centre = phase;
+            while centre < width as f32 {
+                sum += band_sum(centre);
+                centre += spacing;
+            }
+            sum
+        };
+        let scores: Vec<f32> = (0..spacing.ceil() as usize)
+            .map(|phase| comb(phase as f32))
+            .collect();
+        let best = scores.iter().copied().fold(f32::MIN, f32::max);
+        // Bands wider than the crop score equally over a run of phases;
+        // the row centre is the middle of that run.
+        let near_best = |score: f32| score >= best - best.abs() * 1e-4;
+        let first = scores.iter().position(|&s| near_best(s)).unwrap_or(0);
+        let run = scores[first..]
+            .iter()
+            .take_while(|&&s| near_best(s))
+            .count();
+        let best_phase = first as f32 + (run - 1) as f32 / 2.0;
+        let uniform_share = (2.0 * self.half_band / spacing).min(1.0);
+        if best / total < MIN_CONTRAST * uniform_share {
+            return;
+        }
+        let mut centre = best_phase;
+        while centre < width as f32 {
+            self.rows.push(centre);
+            centre += spacing;
+        }
+    }
+
+    /// Clear the vegetation inside each row band of `mask`.
+    pub fn mask_out(&self, mask: &mut BitMask) {
+        let width = mask.width() as f32;
+        for &centre in &self.rows {
+            let start = (centre - self.half_band).round().clamp(0.0, width) as usize;
+            let end = (centre + self.half_band).round().clamp(0.0, width) as usize;
+            for y in 0..mask.height() {
+                mask.clear_span(y, start, end);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `width` x `height` mask with crop bands of `band` columns every
+    /// `spacing` columns from `first`, plus the given weed pixels.
+    fn field(
+        width: usize,
+        height: usize,
+        first: usize,
+        spacing: usize,
+        band: usize,
+        weeds: &[(usize, usize)],
+    ) -> BitMask {
+        let mut pixels = vec![false; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let offset = (x + spacing - first % spacing) % spacing;
+                pixels[y * width + x] = offset < band / 2 || offset >= spacing - band / 2;
+            }
+        }
+        for &(x, y) in weeds {
+            pixels[y * width + x] = true;
+        }
+        BitMask::from_bools(&pixels, width, height)
+    }
+
+    #[test]
+    fn finds_rows_and_keeps_only_inter_row_weeds() {
+        let weeds = [(20, 3), (21, 3), (60, 7)];
+        let mut mask = field(100, 10, 10, 40, 8, &weeds);
+        let mut tracker = CropRowTracker::new(40.0, 10.0, 1.0, 100);
+        assert_eq!(tracker.update(&mask, 100), [10.0, 50.0, 90.0]);
+        tracker.mask_out(&mut mask);
+        assert_eq!(mask, field(100, 10, 10, 40, 0, &weeds));
+    }
+
+    #[test]
+    fn no_rows_without_a_row_pattern() {
+        let mut tracker = CropRowTracker::new(40.0, 10.0, 1.0, 100);
+        assert!(tracker.update(&BitMask::new(100, 10), 100).is_empty());
+        // Evenly spread vegetation (a weed carpet) has no comb to fit.
+        let carpet = BitMask::from_bools(&[true; 1000], 100, 10);
+        assert!(tracker.update(&carpet, 100).is_empty());
+    }
+
+    #[test]
+    fn smoothing_rides_out_a_weedy_frame() {
+        let mut tracker = CropRowTracker::new(40.0, 10.0, 0.2, 100);
+        let crop = field(100, 10, 10, 40, 8, &[]);
+        for _ in 0..5 {
+            tracker.update(&crop, 100);
+        }
+        // A dense weed patch between rows in one frame.
+        let weeds: Vec<(usize, usize)> = (0..10)
+            .flat_map(|y| (26..34).map(move |x| (x, y)))
+            .collect();
+        let weedy = field(100, 10, 10, 40, 0, &weeds);
+        assert_eq!(tracker.update(&weedy, 100), [10.0, 50.0, 90.0]);
+        let mut fresh = CropRowTracker::new(40.0, 10.0, 1.0, 100);
+        assert_ne!(fresh.update(&weedy, 100), [10.0, 50.0, 90.0]);
+    }
+
+    #[test]
+    fn spacing_scales_with_frame_width() {
+        let mut tracker = CropRowTracker::new(40.0, 10.0, 1.0, 100);
+        let mask = field(200, 4, 20, 80, 16, &[]);
+        assert_eq!(tracker.update(&mask, 200), [20.0, 100.0, 180.0]);
+    }
+}
@@ -0,0 +1,548 @@
+//! Camera-to-nozzle delay compensation.
+//!
+//! The camera looks ahead of the boom, so a weed is decided on some
+//! distance before it passes under its nozzle. [`DelayScheduler`] sits
+//! between [`crate::lanes::LaneReducer`] and the nozzles: it integrates
+//! ground speed into an odometer and releases each change of lane states
+//! once the ground has moved by the camera-to-nozzle distance, less the
+//! distance covered while the valve opens. Working in distance rather than
+//! time keeps edges in place when the machine speeds up or slows down
+//! between detection and spraying.
+//!
+//! A machine at or below its minimum speed (see [`GroundSpeed`]) never
+//! carries a queued edge to the nozzles, so standing still switches every
+//! lane off and drops the queue; spraying resumes with whatever the camera
+//! sees once the machine moves again.
+//!
+//! Edges are released when the scheduler is polled: on every frame and
+//! every [`RELEASE_PERIOD`] from a thread in [`DelayedNozzles`], so a
+//! stalled frame feed cannot hold lanes open.
+
+use crate::io_gpio::NozzleControl;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often [`DelayedNozzles`] releases due edges between frames.
+pub const RELEASE_PERIOD: Duration = Duration::from_millis(5);
+
+/// Source of monotonic time for the scheduler.
+pub trait Clock: Send {
+    /// Time elapsed since some fixed origin.
+    fn now(&self) -> Duration;
+}
+
+/// Wall clock time since construction.
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Simulated clock that only moves when told to. Clones share the time,
+/// so a test can keep one and hand another to the scheduler.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    /// Clock at time zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move time forward by `step`.
+    pub fn advance(&self, step: Duration) {
+        *self.now.lock().unwrap() += step;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Current ground speed in metres per second, shared between whatever
+/// measures it (config, GPS) and the scheduler. Clones share the value.
+#[derive(Debug, Clone)]
+pub struct GroundSpeed {
+    mps: Arc<AtomicU32>,
+    /// Speed at or below which the machine counts as stopped.
+    min: f32,
+}
+
+impl GroundSpeed {
+    /// Shared speed starting at `mps`, stopped only at zero.
+    pub fn new(mps: f32) -> Self {
+        Self {
+            mps: Arc::new(AtomicU32::new(mps.to_bits())),
+            min: 0.0,
+        }
+    }
+
+    /// Count speeds at or below `mps` as stopped.
+    pub fn with_min_speed(mut self, mps: f32) -> Self {
+        self.min = mps;
+        self
+    }
+
+    /// Update the speed. Negative or non-finite speeds count as stopped.
+    pub fn set(&self, mps: f32) {
+        let mps = if mps.is_finite() { mps.max(0.0) } else { 0.0 };
+        self.mps.store(mps.to_bits(), Ordering::Relaxed);
+    }
+
+    /// The current speed.
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.mps.load(Ordering::Relaxed))
+    }
+
+    /// Whether the machine is at or below its minimum speed.
+    pub fn is_stopped(&self) -> bool {
+        self.get() <= self.min
+    }
+}
+
+/// Delays lane state changes by the camera-to-nozzle travel. Lane
+/// states are `bool`s, or PWM duty fractions as `f32`.
+pub struct DelayScheduler<T = bool> {
+    distance: f64,
+    latency: Duration,
+    speed: GroundSpeed,
+    clock: Box<dyn Clock>,
+    last_tick: Duration,
+    /// Distance travelled since construction, in metres.
+    odometer: f64,
+    /// Lane states waiting for the ground to move, with the odometer
+    /// reading at which they reach the nozzles.
+    pending: VecDeque<(f64, Vec<T>)>,
+    /// Most recent lane states pushed.
+    latest: Vec<T>,
+    /// Lane states released to the nozzles.
+    output: Vec<T>,
+}
+
+impl<T: Copy + PartialEq + Default> DelayScheduler<T> {
+    /// Scheduler for `lanes` lanes, with nozzles `distance` metres behind
+    /// the camera's view and valves taking `latency` to open or close.
+    /// All lanes start off (`T::default()`).
+    ///
+    /// # Panics
+    /// Panics if `distance` is negative or not finite.
+    pub fn new(
+        lanes: usize,
+        distance: f32,
+        latency: Duration,
+        speed: GroundSpeed,
+        clock: Box<dyn Clock>,
+    ) -> Self {
+        assert!(
+            distance.is_finite() && distance >= 0.0,
+            "Camera-to-nozzle distance must be a non-negative number of metres"
+        );
+        let last_tick = clock.now();
+        Self {
+            distance: f64::from(distance),
+            latency,
+            speed,
+            clock,
+            last_tick,
+            odometer: 0.0,
+            pending: VecDeque::new(),
+            latest: vec![T::default(); lanes],
+            output: vec![T::default(); lanes],
+        }
+    }
+
+    /// The shared ground speed.
+    pub fn speed(&self) -> &GroundSpeed {
+        &self.speed
+    }
+
+    /// Record the lane states decided for the current frame and return
+    /// the states due at the nozzles now. At a standstill every lane is
+    /// off and nothing is recorded.
+    pub fn push(&mut self, lanes: &[T]) -> &[T] {
+        assert_eq!(
+            lanes.len(),
+            self.latest.len(),
+            "One state per lane required"
+        );
+        if !self.tick() {
+            return &self.output;
+        }
+        if lanes != self.latest {
+            self.latest.copy_from_slice(lanes);
+            let due = self.odometer + self.distance;
+            match self.pending.back_mut() {
+                // No ground covered since the last change: the newer
+                // states reach the nozzles at the same spot, so they
+                // replace it.
+                Some((last_due, states)) if *last_due == due => states.copy_from_slice(lanes),
+                _ => self.pending.push_back((due, lanes.to_vec())),
+            }
+        }
+        self.release()
+    }
+
+    /// Release the states that are due without recording new ones.
+    pub fn poll(&mut self) -> &[T] {
+        if !self.tick() {
+            return &self.output;
+        }
+        self.release()
+    }
+
+    /// Drop everything pending and switch every lane off at once.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.latest.fill(T::default());
+        self.output.fill(T::default());
+    }
+
+    /// Advance the odometer to the current time at the current speed.
+    /// Returns `false`, with every lane switched off, at a standstill.
+    fn tick(&mut self) -> bool {
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.last_tick);
+        self.last_tick = now;
+        if self.speed.is_stopped() {
+            self.clear();
+            return false;
+        }
+        self.odometer += f64::from(self.speed.get()) * elapsed.as_secs_f64();
+        true
+    }
+
+    fn release(&mut self) -> &[T] {
+        // Open (or close) the valve early by the distance covered while it
+        // moves.
+        let lead = f64::from(self.speed.get()) * self.latency.as_secs_f64();
+        while let Some((due, _)) = self.pending.front() {
+            if *due - lead > self.odometer {
+                break;
+            }
+            let (_, states) = self.pending.pop_front().unwrap();
+            self.output = states;
+        }
+        &self.output
+    }
+}
+
+/// Nozzles driven through a [`DelayScheduler`]: every frame's lane states
+/// are delayed by the camera-to-nozzle travel before reaching `inner`.
+/// States travel as duty fractions, so PWM duty is delayed with them.
+///
+/// A thread polls the scheduler every [`RELEASE_PERIOD`] and hands the
+/// due states to `inner` each time, so edges land on time between frames
+/// and time-driven stages behind it keep running. Coming to a standstill
+/// switches `inner` off through [`NozzleControl::all_off`].
+pub struct DelayedNozzles {
+    shared: Arc<Mutex<Shared>>,
+    duty: Vec<f32>,
+    fault: Option<String>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    inner: Box<dyn NozzleControl + Send>,
+    scheduler: DelayScheduler<f32>,
+    /// Whether `inner` was switched off for a standstill.
+    stopped: bool,
+}
+
+impl Shared {
+    /// Hand the states due now to `inner`, recording `duty` first when it
+    /// comes from a frame.
+    fn release(&mut self, duty: Option<&[f32]>) {
+        let stopped = self.scheduler.speed().is_stopped();
+        let due = match duty {
+            Some(duty) => self.scheduler.push(duty),
+            None => self.scheduler.poll(),
+        };
+        if stopped && !self.stopped {
+            self.inner.all_off(due.len());
+        } else {
+            self.inner.apply_duty(due);
+        }
+        self.stopped = stopped;
+    }
+}
+
+impl DelayedNozzles {
+    pub fn new(inner: Box<dyn NozzleControl + Send>, scheduler: DelayScheduler<f32>) -> Self {
+        let shared = Arc::new(Mutex::new(Shared {
+            inner,
+            scheduler,
+            stopped: false,
+        }));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("nozzle-delay".into())
+                .spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(RELEASE_PERIOD)
+                    {
+                        shared.lock().unwrap().release(None);
+                    }
+                })
+                .expect("failed to spawn the nozzle delay thread")
+        };
+        Self {
+            shared,
+            duty: Vec::new(),
+            fault: None,
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    fn refresh_fault(&mut self, shared: &Shared) {
+        self.fault = shared.inner.fault().map(str::to_owned);
+    }
+}
+
+impl NozzleControl for DelayedNozzles {
+    fn apply(&mut self, lanes: &[bool]) {
+        let mut duty = std::mem::take(&mut self.duty);
+        duty.clear();
+        duty.extend(lanes.iter().map(|&on| if on { 1.0 } else { 0.0 }));
+        self.apply_duty(&duty);
+        self.duty = duty;
+    }
+
+    fn apply_duty(&mut self, duty: &[f32]) {
+        let shared = self.shared.clone();
+        let mut shared = shared.lock().unwrap();
+        shared.release(Some(duty));
+        self.refresh_fault(&shared);
+    }
+
+    fn all_off(&mut self, lanes: usize) {
+        let shared = self.shared.clone();
+        let mut shared = shared.lock().unwrap();
+        shared.scheduler.clear();
+        shared.inner.all_off(lanes);
+        self.refresh_fault(&shared);
+    }
+
+    fn fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+}
+
+impl Drop for DelayedNozzles {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1/64 s, so distances add up exactly in binary floating point.
+    const FRAME: Duration = Duration::from_micros(15_625);
+
+    /// Scheduler 1 m behind the camera at 2 m/s (500 ms of travel) with
+    /// a 125 ms valve, and the clock driving it.
+    fn scheduler<T: Copy + PartialEq + Default>(lanes: usize) -> (DelayScheduler<T>, ManualClock) {
+        let clock = ManualClock::new();
+        let scheduler = DelayScheduler::new(
+            lanes,
+            1.0,
+            Duration::from_millis(125),
+            GroundSpeed::new(2.0),
+            Box::new(clock.clone()),
+        );
+        (scheduler, clock)
+    }
+
+    /// Push `lanes` every frame until it is released; returns the frames
+    /// it took.
+    fn frames_until(scheduler: &mut DelayScheduler, clock: &ManualClock, lanes: &[bool]) -> usize {
+        (1..1000)
+            .find(|_| {
+                clock.advance(FRAME);
+                scheduler.push(lanes) == lanes
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn edges_reach_the_nozzles_after_the_travel_less_valve_latency() {
+        let (mut scheduler, clock) = scheduler(2);
+        assert_eq!(scheduler.push(&[true, false]), [false, false]);
+        // 0.75 m of travel (1 m less 0.25 m covered while the valve
+        // opens) takes 375 ms, 24 frames.
+        assert_eq!(frames_until(&mut scheduler, &clock, &[true, false]), 24);
+        assert_eq!(scheduler.push(&[false, true]), [true, false]);
+        assert_eq!(frames_until(&mut scheduler, &clock, &[false, true]), 24);
+    }
+
+    #[test]
+    fn delay_follows_speed_changes_in_flight() {
+        let (mut scheduler, clock) = scheduler(1);
+        scheduler.push(&[true]);
+        // 8 frames at 2 m/s (0.25 m), then 1 m/s: the lead shrinks to
+        // 0.125 m, so the remaining 0.625 m takes 40 frames.
+        for _ in 0..8 {
+            clock.advance(FRAME);
+            scheduler.push(&[true]);
+        }
+        scheduler.speed().set(1.0);
+        assert_eq!(frames_until(&mut scheduler, &clock, &[true]), 40);
+    }
+
+    #[test]
+    fn standstill_switches_lanes_off_and_drops_the_queue() {
+        let (mut scheduler, clock) = scheduler(1);
+        frames_until(&mut scheduler, &clock, &[true]);
+        scheduler.push(&[false]);
+        scheduler.speed().set(0.0);
+        clock.advance(FRAME);
+        assert_eq!(scheduler.poll(), [false]);
+        for state in [true, false, true] {
+            clock.advance(Duration::from_secs(10));
+            assert_eq!(scheduler.push(&[state]), [false]);
+        }
+        assert!(scheduler.pending.is_empty());
+        // Moving again, what the camera sees now travels to the nozzles.
+        scheduler.speed().set(2.0);
+        assert_eq!(scheduler.push(&[true]), [false]);
+        assert_eq!(frames_until(&mut scheduler, &clock, &[true]), 24);
+    }
+
+    #[test]
+    fn minimum_speed_counts_as_stopped() {
+        let speed = GroundSpeed::new(0.1).with_min_speed(0.2);
+        assert!(speed.is_stopped());
+        speed.set(0.3);
+        assert!(!speed.is_stopped());
+        assert!(GroundSpeed::new(0.0).is_stopped());
+    }
+
+    #[test]
+    fn zero_distance_and_latency_pass_states_straight_through() {
+        let clock = ManualClock::new();
+        let mut scheduler = DelayScheduler::new(
+            1,
+            0.0,
+            Duration::ZERO,
+            GroundSpeed::new(1.5),
+            Box::new(clock),
+        );
+        assert_eq!(scheduler.push(&[true]), [true]);
+        assert_eq!(scheduler.push(&[false]), [false]);
+    }
+
+    /// Nozzles recording the last lane states applied, and how many
+    /// times `all_off` was called.
+    #[derive(Default, Clone)]
+    struct Recorder(Arc<Mutex<(Vec<bool>, usize)>>);
+
+    impl NozzleControl for Recorder {
+        fn apply(&mut self, lanes: &[bool]) {
+            self.0.lock().unwrap().0 = lanes.to_vec();
+        }
+
+        fn all_off(&mut self, lanes: usize) {
+            let mut seen = self.0.lock().unwrap();
+            seen.0 = vec![false; lanes];
+            seen.1 += 1;
+        }
+    }
+
+    impl Recorder {
+        fn last(&self) -> Vec<bool> {
+            self.0.lock().unwrap().0.clone()
+        }
+
+        /// Wait up to a second for the release thread to apply `lanes`.
+        fn wait_for(&self, lanes: &[bool]) -> bool {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while self.last() != lanes {
+                if Instant::now() > deadline {
+                    return false;
+                }
+                std::thread::sleep(RELEASE_PERIOD);
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn all_off_skips_the_queue() {
+        let recorder = Recorder::default();
+        let (mut scheduler, clock) = scheduler(2);
+        clock.advance(FRAME);
+        scheduler.push(&[1.0, 1.0]);
+        clock.advance(Duration::from_secs(1));
+        let mut nozzles = DelayedNozzles::new(Box::new(recorder.clone()), scheduler);
+        nozzles.apply(&[true, true]);
+        assert_eq!(recorder.last(), [true, true]);
+        nozzles.apply(&[false, false]);
+        assert_eq!(recorder.last(), [true, true]);
+        nozzles.all_off(2);
+        assert_eq!(recorder.last(), [false, false]);
+        nozzles.apply(&[true, true]);
+        clock.advance(Duration::from_millis(100));
+        nozzles.apply(&[false, false]);
+        assert_eq!(recorder.last(), [false, false]);
+    }
+
+    #[test]
+    fn edges_are_released_between_frames() {
+        let recorder = Recorder::default();
+        let (scheduler, clock) = scheduler(1);
+        let mut nozzles = DelayedNozzles::new(Box::new(recorder.clone()), scheduler);
+        nozzles.apply(&[true]);
+        assert_eq!(recorder.last(), [false]);
+        // No frame arrives, the edge is released all the same.
+        clock.advance(Duration::from_secs(1));
+        assert!(recorder.wait_for(&[true]));
+    }
+
+    #[test]
+    fn standstill_switches_the_nozzles_off_without_a_frame() {
+        let recorder = Recorder::default();
+        let (scheduler, clock) = scheduler(1);
+        let speed = scheduler.speed().clone();
+        let mut nozzles = DelayedNozzles::new(Box::new(recorder.clone()), scheduler);
+        nozzles.apply(&[true]);
+        clock.advance(Duration::from_secs(1));
+        assert!(recorder.wait_for(&[true]));
+        speed.set(0.0);
+        assert!(recorder.wait_for(&[false]));
+        assert_eq!(recorder.0.lock().unwrap().1, 1);
+    }
+}
@@ -0,0 +1,451 @@
+//! Minimum spray times and pre/post spray extension per lane.
+//!
+//! Hysteresis in [`crate::lanes::LaneReducer`] keeps coverage noise from
+//! toggling a lane, but a weed seen in a single frame still opens its
+//! valve for a single frame: the solenoid chatters and the weed is only
+//! partly covered. [`DwellTimer`] shapes each lane's state before it
+//! reaches the nozzles. A lane is held on `post` after its weed clears,
+//! stays on at least `min_on` once opened and off at least `min_off` once
+//! closed. All four times are measured along an [`Axis`]: seconds on the
+//! clock, or metres travelled at the current ground speed.
+//!
+//! The timer sits behind [`crate::delay::DelayScheduler`], on the edges it
+//! releases, so `min_on` and `min_off` hold at the valve however the speed
+//! changes during the camera-to-nozzle travel. Opening `pre` ahead of a
+//! weed needs to know it is coming, which only the camera does: the
+//! scheduler is set up to release every edge `pre` early and the timer
+//! holds lanes for `pre + post`, so the valve opens `pre` before the weed
+//! and closes `post` after it.
+//!
+//! Distance does not run down at a standstill, so on the distance axis a
+//! machine at or below its minimum speed (see [`GroundSpeed::is_stopped`])
+//! switches every lane off, as the scheduler does.
+
+use crate::delay::{Clock, GroundSpeed};
+use crate::io_gpio::NozzleControl;
+use std::time::Duration;
+
+/// What the dwell times are measured in.
+#[derive(Debug, Clone)]
+pub enum Axis {
+    /// Seconds on the clock.
+    Time,
+    /// Metres travelled at the shared ground speed; every lane is off
+    /// while the machine stands still.
+    Distance(GroundSpeed),
+}
+
+/// Per-lane dwell times, in the units of the timer's [`Axis`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Dwell {
+    /// Shortest time a valve stays open.
+    pub min_on: f64,
+    /// Shortest time a valve stays closed; a lane turning on sooner
+    /// waits.
+    pub min_off: f64,
+    /// Extension ahead of each weed, carried out by the delay scheduler.
+    pub pre: f64,
+    /// Extension after each weed.
+    pub post: f64,
+}
+
+impl Dwell {
+    /// Whether every time is zero, i.e. lanes would pass unchanged.
+    pub fn is_noop(&self) -> bool {
+        self.min_on == 0.0 && self.min_off == 0.0 && self.pre == 0.0 && self.post == 0.0
+    }
+}
+
+/// Position of one lane's last changes along the axis.
+#[derive(Debug, Clone, Copy)]
+struct LaneDwell {
+    on: bool,
+    /// When the output last changed.
+    changed_at: f64,
+    /// When the input was last on.
+    seen_at: f64,
+}
+
+impl LaneDwell {
+    const OFF: Self = Self {
+        on: false,
+        changed_at: f64::NEG_INFINITY,
+        seen_at: f64::NEG_INFINITY,
+    };
+}
+
+/// Applies [`Dwell`] times to every lane.
+pub struct DwellTimer {
+    dwell: Dwell,
+    axis: Axis,
+    clock: Box<dyn Clock>,
+    last_tick: Duration,
+    /// Distance along the axis since construction.
+    position: f64,
+    lanes: Vec<LaneDwell>,
+    output: Vec<bool>,
+}
+
+impl DwellTimer {
+    /// Timer for `lanes` lanes. All lanes start off, free to open at once.
+    ///
+    /// # Panics
+    /// Panics if any time is negative or not finite.
+    pub fn new(lanes: usize, dwell: Dwell, axis: Axis, clock: Box<dyn Clock>) -> Self {
+        assert!(
+            [dwell.min_on, dwell.min_off, dwell.pre, dwell.post]
+                .iter()
+                .all(|t| t.is_finite() && *t >= 0.0),
+            "Dwell times must be non-negative"
+        );
+        let last_tick = clock.now();
+        Self {
+            dwell,
+            axis,
+            clock,
+            last_tick,
+            position: 0.0,
+            lanes: vec![LaneDwell::OFF; lanes],
+            output: vec![false; lanes],
+        }
+    }
+
+    /// Record the lane states decided for the current frame and return
+    /// the shaped states.
+    pub fn update(&mut self, lanes: &[bool]) -> &[bool] {
+        assert_eq!(lanes.len(), self.lanes.len(), "One state per lane required");
+        if !self.tick() {
+            return &self.output;
+        }
+        let now = self.position;
+        let hold = self.dwell.pre + self.dwell.post;
+        for ((lane, out), &seen) in self.lanes.iter_mut().zip(&mut self.output).zip(lanes) {
+            if seen {
+                lane.seen_at = now;
+            }
+            let want = seen || now - lane.seen_at < hold;
+            let min = if lane.on {
+                self.dwell.min_on
+            } else {
+                self.dwell.min_off
+            };
+            if want != lane.on && now - lane.changed_at >= min {
+                lane.on = want;
+                lane.changed_at = now;
+            }
+            *out = lane.on;
+        }
+        &self.output
+    }
+
+    /// Forget every lane's history and switch all lanes off.
+    pub fn clear(&mut self) {
+        self.lanes.fill(LaneDwell::OFF);
+        self.output.fill(false);
+    }
+
+    /// Advance the position to the current time. Returns `false`, with
+    /// every lane switched off, at a standstill on the distance axis.
+    fn tick(&mut self) -> bool {
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.last_tick).as_secs_f64();
+        self.last_tick = now;
+        self.position += match &self.axis {
+            Axis::Time => elapsed,
+            Axis::Distance(speed) if speed.is_stopped() => {
+                self.clear();
+                return false;
+            }
+            Axis::Distance(speed) => f64::from(speed.get()) * elapsed,
+        };
+        true
+    }
+}
+
+/// Nozzles driven through a [`DwellTimer`]. With PWM duty, a lane held
+/// on past its weed keeps the last duty it was given.
+pub struct DwellNozzles {
+    inner: Box<dyn NozzleControl + Send>,
+    timer: DwellTimer,
+    /// Last non-zero duty per lane.
+    held: Vec<f32>,
+    duty: Vec<f32>,
+}
+
+impl DwellNozzles {
+    pub fn new(inner: Box<dyn NozzleControl + Send>, timer: DwellTimer) -> Self {
+        Self {
+            inner,
+            timer,
+            held: Vec::new(),
+            duty: Vec::new(),
+        }
+    }
+}
+
+impl NozzleControl for DwellNozzles {
+    fn apply(&mut self, lanes: &[bool]) {
+        let shaped = self.timer.update(lanes);
+        self.inner.apply(shaped);
+    }
+
+    fn apply_duty(&mut self, duty: &[f32]) {
+        let lanes: Vec<bool> = duty.iter().map(|&d| d > 0.0).collect();
+        let shaped = self.timer.update(&lanes);
+        self.held.resize(duty.len(), 1.0);
+        self.duty.clear();
+        for ((held, &d), &on) in self.held.iter_mut().zip(duty).zip(shaped) {
+            if d > 0.0 {
+                *held = d;
+            }
+            self.duty.push(if on { *held } else { 0.0 });
+        }
+        self.inner.apply_duty(&self.duty);
+    }
+
+    fn all_off(&mut self, lanes: usize) {
+        self.timer.clear();
+        self.inner.all_off(lanes);
+    }
+
+    fn fault(&self) -> Option<&str> {
+        self.inner.fault()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delay::{DelayScheduler, ManualClock};
+
+    /// 1/64 s, so positions add up exactly in binary floating point.
+    const FRAME: Duration = Duration::from_micros(15_625);
+
+    fn timer(lanes: usize, dwell: Dwell, axis: Axis) -> (DwellTimer, ManualClock) {
+        let clock = ManualClock::new();
+        let timer = DwellTimer::new(lanes, dwell, axis, Box::new(clock.clone()));
+        (timer, clock)
+    }
+
+    /// Feed `input` one frame at a time and collect lane 0 of the output.
+    fn run(timer: &mut DwellTimer, clock: &ManualClock, input: &[bool]) -> Vec<bool> {
+        input
+            .iter()
+            .map(|&state| {
+                clock.advance(FRAME);
+                timer.update(&[state])[0]
+            })
+            .collect()
+    }
+
+    fn frames(pattern: &str) -> Vec<bool> {
+        pattern.chars().map(|c| c == '#').collect()
+    }
+
+    #[test]
+    fn single_frame_flicker_is_held_for_min_on() {
+        let dwell = Dwell {
+            min_on: 4.0 / 64.0,
+            ..Dwell::default()
+        };
+        let (mut timer, clock) = timer(1, dwell, Axis::Time);
+        assert_eq!(
+            run(&mut timer, &clock, &frames("#.......##......")),
+            frames("####....####....")
+        );
+    }
+
+    #[test]
+    fn min_off_delays_reopening() {
+        let dwell = Dwell {
+            min_off: 3.0 / 64.0,
+            ..Dwell::default()
+        };
+        let (mut timer, clock) = timer(1, dwell, Axis::Time);
+        assert_eq!(
+            run(&mut timer, &clock, &frames("##.###....###")),
+            frames("##...#....###")
+        );
+    }
+
+    #[test]
+    fn lanes_are_held_for_pre_plus_post() {
+        let dwell = Dwell {
+            pre: 1.0 / 64.0,
+            post: 2.0 / 64.0,
+            ..Dwell::default()
+        };
+        let (mut timer, clock) = timer(1, dwell, Axis::Time);
+        clock.advance(FRAME);
+        assert_eq!(timer.update(&[true]), [true]);
+        assert_eq!(
+            run(&mut timer, &clock, &frames("..#.#.......")),
+            frames("#######.....")
+        );
+    }
+
+    #[test]
+    fn distance_dwell_runs_with_the_machine() {
+        let speed = GroundSpeed::new(4.0);
+        let dwell = Dwell {
+            min_on: 0.25,
+            ..Dwell::default()
+        };
+        let (mut timer, clock) = timer(1, dwell, Axis::Distance(speed.clone()));
+        // At 4 m/s a frame covers 1/16 m: on for four frames (0.25 m).
+        assert_eq!(run(&mut timer, &clock, &frames("#.....")), frames("####.."));
+        // At 2 m/s 0.25 m takes eight frames.
+        speed.set(2.0);
+        assert_eq!(
+            run(&mut timer, &clock, &frames("#.........")),
+            frames("########..")
+        );
+    }
+
+    #[test]
+    fn distance_dwell_switches_off_at_a_standstill() {
+        let speed = GroundSpeed::new(2.0).with_min_speed(0.1);
+        let dwell = Dwell {
+            min_on: 1.0,
+            post: 1.0,
+            ..Dwell::default()
+        };
+        let (mut timer, clock) = timer(1, dwell, Axis::Distance(speed.clone()));
+        assert_eq!(run(&mut timer, &clock, &frames("#..")), frames("###"));
+        speed.set(0.05);
+        assert_eq!(run(&mut timer, &clock, &frames("..#.")), frames("...."));
+        // Moving again, lanes open afresh.
+        speed.set(2.0);
+        assert_eq!(run(&mut timer, &clock, &frames("#.")), frames("##"));
+    }
+
+    #[test]
+    fn pre_spray_opens_ahead_of_the_weed_through_the_scheduler() {
+        // Nozzles 1 m behind the camera at 2 m/s, with 0.125 m pre-spray
+        // and 0.125 m post-spray: the scheduler releases edges 0.125 m
+        // early, the timer holds lanes 0.25 m past the released close.
+        let speed = GroundSpeed::new(2.0);
+        let clock = ManualClock::new();
+        let dwell = Dwell {
+            pre: 0.125,
+            post: 0.125,
+            ..Dwell::default()
+        };
+        let mut timer = DwellTimer::new(
+            1,
+            dwell,
+            Axis::Distance(speed.clone()),
+            Box::new(clock.clone()),
+        );
+        let mut scheduler = DelayScheduler::new(
+            1,
+            1.0 - 0.125,
+            Duration::ZERO,
+            speed,
+            Box::new(clock.clone()),
+        );
+        // A weed 0.25 m long (8 frames) under the camera from 0 m.
+        let nozzle: Vec<bool> = (0..64)
+            .map(|frame| {
+                let due = scheduler.push(&[(0..8).contains(&frame)]).to_vec();
+                let shaped = timer.update(&due)[0];
+                clock.advance(FRAME);
+                shaped
+            })
+            .collect();
+        // It passes the nozzle in frames 32..40; the valve opens 0.125 m
+        // (4 frames) before its first frame and closes 4 frames after its
+        // last.
+        let open: Vec<usize> = (0..64).filter(|&f| nozzle[f]).collect();
+        assert_eq!(open, (28..43).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn min_on_holds_at_the_nozzle_when_the_machine_speeds_up() {
+        // A weed seen for 4 frames at 1 m/s, nozzles 0.5 m behind. The
+        // machine then speeds up to 4 m/s, so the weed passes the nozzle
+        // in a single frame; the valve still stays open for min_on.
+        let speed = GroundSpeed::new(1.0);
+        let clock = ManualClock::new();
+        let dwell = Dwell {
+            min_on: 6.0 / 64.0,
+            ..Dwell::default()
+        };
+        let mut timer = DwellTimer::new(1, dwell, Axis::Time, Box::new(clock.clone()));
+        let mut scheduler = DelayScheduler::new(
+            1,
+            0.5,
+            Duration::ZERO,
+            speed.clone(),
+            Box::new(clock.clone()),
+        );
+        let open = (0..64)
+            .filter(|&frame| {
+                if frame == 4 {
+                    speed.set(4.0);
+                }
+                let due = scheduler.push(&[frame < 4]).to_vec();
+                let shaped = timer.update(&due)[0];
+                clock.advance(FRAME);
+                shaped
+            })
+            .count();
+        assert_eq!(open, 6);
+    }
+
+    #[test]
+    fn held_lanes_keep_their_last_duty() {
+        struct Duty(std::sync::Arc<std::sync::Mutex<Vec<f32>>>);
+        impl NozzleControl for Duty {
+            fn apply(&mut self, _: &[bool]) {
+                unreachable!("duty is applied as duty");
+            }
+            fn apply_duty(&mut self, duty: &[f32]) {
+                *self.0.lock().unwrap() = duty.to_vec();
+            }
+        }
+        let last = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let dwell = Dwell {
+            post: 2.0 / 64.0,
+            ..Dwell::default()
+        };
+        let (timer, clock) = timer(2, dwell, Axis::Time);
+        let mut nozzles = DwellNozzles::new(Box::new(Duty(last.clone())), timer);
+        for (input, output) in [
+            ([0.4, 0.0], [0.4, 0.0]),
+            ([0.7, 0.0], [0.7, 0.0]),
+            ([0.0, 0.5], [0.7, 0.5]),
+            ([0.0, 0.0], [0.0, 0.5]),
+        ] {
+            clock.advance(FRAME);
+            nozzles.apply_duty(&input);
+            assert_eq!(*last.lock().unwrap(), output);
+        }
+    }
+
+    #[test]
+    fn all_off_resets_the_lanes() {
+        struct Last(std::sync::Arc<std::sync::Mutex<Vec<bool>>>);
+        impl NozzleControl for Last {
+            fn apply(&mut self, lanes: &[bool]) {
+                *self.0.lock().unwrap() = lanes.to_vec();
+            }
+        }
+        let last = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let dwell = Dwell {
+            min_on: 1.0,
+            min_off: 1.0,
+            ..Dwell::default()
+        };
+        let (timer, clock) = timer(1, dwell, Axis::Time);
+        let mut nozzles = DwellNozzles::new(Box::new(Last(last.clone())), timer);
+        clock.advance(FRAME);
+        nozzles.apply(&[true]);
+        nozzles.all_off(1);
+        assert_eq!(*last.lock().unwrap(), [false]);
+        // No min_off after a reset: the lane may open at once.
+        clock.advance(FRAME);
+        nozzles.apply(&[true]);
+        assert_eq!(*last.lock().unwrap(), [true]);
+    }
+}
@@ -12,7 +12,7 @@
 
 use crate::config::Config;
 use crate::lanes::LaneReducer;
-use crate::vision::PlantVision;
+use crate::vision::{PixelFormat, PlantVision};
 use std::os::raw::c_char;
 
 // Negated on return, matching the "negative errno" convention.
@@ -33,8 +33,12 @@ const EINVAL: i32 = 22;
 ///
 /// # Returns
 /// - `0` on success
-/// - `-EINVAL` (-22) for NULL data pointers, invalid dimensions, or an
-///   unparseable/invalid config file
+/// - `-EINVAL` (-22) for NULL data pointers, invalid dimensions, an
+///   unparseable/invalid config file, or a config with per-lane
+///   settings (`on_thresholds`, `off_thresholds`, `enabled`,
+///   `boundaries`, `polygons`) whose `lanes.count` differs from
+///   `num_lanes`, a `[vision.roi]` that leaves fewer than `num_lanes`
+///   columns of the frame, or an RGBN (`"ndvi"`) config
 /// - `-ENOENT` (-2) if `config` names a file that does not exist
 /// - `-EIO` (-5) if the kernel panics internally
 ///
@@ -53,16 +57,69 @@ pub extern "C" fn rustspray_detect(
     num_lanes: u32,
 ) -> i32 {
     // The kernel must never unwind across the FFI boundary.
-    std::panic::catch_unwind(|| detect_impl(rgb24, width, height, config, lane_states, num_lanes))
-        .unwrap_or(-EIO)
+    std::panic::catch_unwind(|| {
+        detect_impl(
+            rgb24,
+            width,
+            height,
+            config,
+            lane_states,
+            std::ptr::null_mut(),
+            num_lanes,
+        )
+    })
+    .unwrap_or(-EIO)
 }
 
+/// [`rustspray_detect`] that also reports each lane's vegetation coverage.
+///
+/// # Parameters
+/// As for [`rustspray_detect`], plus:
+/// - `lane_coverage`: caller-allocated float array, length >= `num_lanes`,
+///   receiving the fraction (0.0–1.0) of each lane's pixels classified as
+///   vegetation — the value compared against `on_threshold`
+///
+/// # Returns
+/// As for [`rustspray_detect`]; `-EINVAL` also for a NULL `lane_coverage`.
+///
+/// # Safety
+/// As for [`rustspray_detect`], and `lane_coverage` must point to at least
+/// `num_lanes` writable floats.
+#[no_mangle]
+pub extern "C" fn rustspray_detect_coverage(
+    rgb24: *const u8,
+    width: u32,
+    height: u32,
+    config: *const c_char,
+    lane_states: *mut bool,
+    lane_coverage: *mut f32,
+    num_lanes: u32,
+) -> i32 {
+    if lane_coverage.is_null() {
+        return -EINVAL;
+    }
+    std::panic::catch_unwind(|| {
+        detect_impl(
+            rgb24,
+            width,
+            height,
+            config,
+            lane_states,
+            lane_coverage,
+            num_lanes,
+        )
+    })
+    .unwrap_or(-EIO)
+}
+
+/// Shared body of the entry points; `lane_coverage` may be NULL.
 fn detect_impl(
     rgb24: *const u8,
     width: u32,
     height: u32,
     config: *const c_char,
     lane_states: *mut bool,
+    lane_coverage: *mut f32,
     num_lanes: u32,
 ) -> i32 {
     if rgb24.is_null() || lane_states.is_null() {
@@ -98,7 +155,7 @@ fn detect_impl(
             Err(_) => return -EINVAL,
         }
     };
-    if cfg.validate().is_err() {
+    if cfg.validate().is_err() || cfg.camera.pixel_format != PixelFormat::Rgb24 {
         return -EINVAL;
     }
 
@@ -106,26 +163,53 @@ fn detect_impl(
     let frame = unsafe { std::slice::from_raw_parts(rgb24, frame_len) };
     let out = unsafe { std::slice::from_raw_parts_mut(lane_states, num_lanes as usize) };
 
-    let vision = PlantVision::new(
-        cfg.vision.exg_threshold,
-        cfg.vision.green_ratio_floor,
-        cfg.vision.chroma_floor,
-        (
-            cfg.vision.weights.exg,
-            cfg.vision.weights.green_ratio,
-            cfg.vision.weights.chroma,
-            cfg.vision.weights.bias,
-        ),
-    );
-    let mut reducer = LaneReducer::new(
-        num_lanes as usize,
-        cfg.lanes.on_threshold,
-        cfg.lanes.off_threshold,
-    );
-
-    let mask = vision.detect(frame);
-    let lanes = reducer.reduce(&mask, width as usize, height as usize);
-    out.copy_from_slice(&lanes);
+    let mut vision = PlantVision::from_config(&cfg.vision);
+    let mut reducer = if num_lanes as usize == cfg.lanes.count {
+        LaneReducer::from_config(&cfg)
+    } else if cfg.lanes.has_per_lane_settings() {
+        // Per-lane settings (notably disabled lanes) cannot be mapped
+        // onto a different lane count; refusing beats spraying a lane the
+        // config says is blocked.
+        return -EINVAL;
+    } else {
+        let reducer = LaneReducer::new(
+            num_lanes as usize,
+            cfg.lanes.on_threshold,
+            cfg.lanes.off_threshold,
+        );
+        let reducer = match cfg.vision.roi(cfg.camera.width, cfg.camera.height) {
+            Some(roi) => reducer.with_roi(roi),
+            None => reducer,
+        };
+        let reducer = match cfg.vision.mask_filter() {
+            Some(filter) => reducer.with_filter(filter),
+            None => reducer,
+        };
+        match cfg.vision.crop_row_tracker(cfg.camera.width) {
+            Some(tracker) => reducer.with_crop_rows(tracker),
+            None => reducer,
+        }
+    };
+    if !reducer.accepts(width as usize, height as usize) {
+        return -EINVAL;
+    }
+    if let Some(mut adaptive) = cfg.vision.adaptive_threshold() {
+        let region = reducer.region(width as usize, height as usize);
+        let balance = vision.white_balance();
+        vision.exg_threshold = adaptive.observe(frame, width as usize, region, balance);
+    }
+
+    let status = reducer.reduce_frame(frame, width as usize, height as usize, &vision);
+    for (out, lane) in out.iter_mut().zip(status) {
+        *out = lane.on;
+    }
+    if !lane_coverage.is_null() {
+        // SAFETY: caller guarantees `num_lanes` writable floats.
+        let coverage = unsafe { std::slice::from_raw_parts_mut(lane_coverage, num_lanes as usize) };
+        for (out, lane) in coverage.iter_mut().zip(status) {
+            *out = lane.ratio;
+        }
+    }
     0
 }
 
@@ -188,6 +272,38 @@ mod tests {
         assert_eq!(lanes, [false; 4]);
     }
 
+    #[test]
+    fn coverage_reports_lane_ratios() {
+        let (w, h) = (64usize, 16usize);
+        let frame = synthetic_frame(w, h, 16, 24); // half of lane 1 green
+        let mut lanes = [false; 4];
+        let mut coverage = [-1.0f32; 4];
+        let rc = rustspray_detect_coverage(
+            frame.as_ptr(),
+            w as u32,
+            h as u32,
+            std::ptr::null(),
+            lanes.as_mut_ptr(),
+            coverage.as_mut_ptr(),
+            4,
+        );
+        assert_eq!(rc, 0);
+        assert_eq!(lanes, [false, true, false, false]);
+        assert_eq!(coverage, [0.0, 0.5, 0.0, 0.0]);
+        assert_eq!(
+            rustspray_detect_coverage(
+                frame.as_ptr(),
+                w as u32,
+                h as u32,
+                std::ptr::null(),
+                lanes.as_mut_ptr(),
+                std::ptr::null_mut(),
+                4,
+            ),
+            -EINVAL,
+        );
+    }
+
     #[test]
     fn null_pointers_are_rejected() {
         let mut lanes = [false; 4];
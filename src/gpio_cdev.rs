@@ -0,0 +1,454 @@
+//! Nozzle output through the Linux GPIO character device.
+//!
+//! [`crate::io_gpio::RppalGpio`] drives the Raspberry Pi's GPIO registers
+//! through `/dev/gpiomem`, which other SoCs do not have. Every Linux board
+//! (Rockchip, Jetson, the Pi too) exposes its GPIO banks as
+//! `/dev/gpiochipN` instead, where a process requests lines with the v2
+//! uAPI ioctls and owns them until it closes the request. [`CdevGpio`]
+//! requests each lane's line as an output driven low, sets all lines of a
+//! chip with one ioctl per frame, and drives them low again on drop.
+
+use crate::config::CdevLine;
+use crate::io_gpio::NozzleControl;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+/// Consumer label shown for our lines by `gpioinfo`.
+const CONSUMER: &str = "rustspray";
+
+// Subset of <linux/gpio.h> (uAPI v2).
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_MAX_NAME_SIZE: usize = 32;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+const GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES: u32 = 2;
+
+/// `struct gpio_v2_line_attribute`; the union is always 64 bits wide.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+/// `struct gpio_v2_line_config_attribute`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+/// `struct gpio_v2_line_config`.
+#[repr(C)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+/// `struct gpio_v2_line_request`.
+#[repr(C)]
+struct LineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+/// `struct gpio_v2_line_values`.
+#[repr(C)]
+struct LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+/// `_IOWR(0xB4, nr, size)` for the architectures with the generic ioctl
+/// layout (x86, ARM, AArch64, RISC-V).
+const fn iowr(nr: u64, size: usize) -> u64 {
+    (3 << 30) | ((size as u64) << 16) | (0xB4 << 8) | nr
+}
+
+const GPIO_V2_GET_LINE_IOCTL: u64 = iowr(0x07, std::mem::size_of::<LineRequest>());
+const GPIO_V2_LINE_SET_VALUES_IOCTL: u64 = iowr(0x0F, std::mem::size_of::<LineValues>());
+
+/// Path of GPIO chip `chip`.
+pub fn chip_path(chip: u32) -> PathBuf {
+    PathBuf::from(format!("/dev/gpiochip{chip}"))
+}
+
+/// Output lines requested together from one chip.
+pub trait OutputLines: Send {
+    /// Drive the lines selected by `mask` to `bits`; bit `i` is the
+    /// `i`-th requested line.
+    fn set(&mut self, bits: u64, mask: u64) -> io::Result<()>;
+}
+
+/// Lines held by a kernel line request; released when dropped.
+pub struct ChipLines {
+    fd: OwnedFd,
+}
+
+impl ChipLines {
+    /// Request `offsets` on the chip at `path` as outputs driven low.
+    pub fn request(path: &Path, offsets: &[u32]) -> io::Result<Self> {
+        if offsets.is_empty() || offsets.len() > GPIO_V2_LINES_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("1 to {GPIO_V2_LINES_MAX} lines per chip required"),
+            ));
+        }
+        let chip = File::open(path)?;
+        // SAFETY: every field is an integer, so all-zero is a valid value.
+        let mut request: LineRequest = unsafe { std::mem::zeroed() };
+        request.offsets[..offsets.len()].copy_from_slice(offsets);
+        request.consumer[..CONSUMER.len()].copy_from_slice(CONSUMER.as_bytes());
+        request.num_lines = offsets.len() as u32;
+        request.config.flags = GPIO_V2_LINE_FLAG_OUTPUT;
+        request.config.num_attrs = 1;
+        request.config.attrs[0] = LineConfigAttribute {
+            attr: LineAttribute {
+                id: GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES,
+                padding: 0,
+                value: 0,
+            },
+            mask: all_lines(offsets.len()),
+        };
+        // SAFETY: `chip` is open and `request` matches the kernel's
+        // struct gpio_v2_line_request, which the ioctl reads and fills.
+        if unsafe { libc::ioctl(chip.as_raw_fd(), GPIO_V2_GET_LINE_IOCTL as _, &mut request) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: on success the kernel returns a new descriptor we own.
+        let fd = unsafe { OwnedFd::from_raw_fd(request.fd) };
+        Ok(Self { fd })
+    }
+}
+
+impl OutputLines for ChipLines {
+    fn set(&mut self, bits: u64, mask: u64) -> io::Result<()> {
+        let mut values = LineValues { bits, mask };
+        // SAFETY: `fd` is a line request and `values` matches struct
+        // gpio_v2_line_values.
+        if unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                GPIO_V2_LINE_SET_VALUES_IOCTL as _,
+                &mut values,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Mask of the first `n` lines of a request.
+fn all_lines(n: usize) -> u64 {
+    if n >= 64 {
+        u64::MAX
+    } else {
+        (1 << n) - 1
+    }
+}
+
+/// Nozzles on GPIO character device lines, one per lane.
+///
+/// All lines are requested low and are driven low again when the struct
+/// is dropped, so a graceful shutdown never leaves a valve open.
+pub struct CdevGpio {
+    requests: Vec<Box<dyn OutputLines>>,
+    /// Number of lines in each request.
+    widths: Vec<usize>,
+    /// Per lane: request index and bit within it.
+    lanes: Vec<(usize, u32)>,
+    bits: Vec<u64>,
+    /// Last failed set, cleared by the next frame that reaches every chip.
+    fault: Option<String>,
+}
+
+impl CdevGpio {
+    /// Request every lane's line from `/dev/gpiochipN`.
+    pub fn new(lines: &[CdevLine]) -> io::Result<Self> {
+        Self::with_lines(lines, |chip, offsets| {
+            let lines = ChipLines::request(&chip_path(chip), offsets).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("{}: lines {offsets:?}: {e}", chip_path(chip).display()),
+                )
+            })?;
+            Ok(Box::new(lines) as Box<dyn OutputLines>)
+        })
+    }
+
+    /// Like [`Self::new`], obtaining each chip's lines from `request`
+    /// (called with the chip number and its line offsets) — a mock chip
+    /// in tests.
+    pub fn with_lines(
+        lines: &[CdevLine],
+        mut request: impl FnMut(u32, &[u32]) -> io::Result<Box<dyn OutputLines>>,
+    ) -> io::Result<Self> {
+        // Group lanes by chip, in order of first appearance.
+        let mut chips: Vec<(u32, Vec<u32>)> = Vec::new();
+        let mut lanes = Vec::with_capacity(lines.len());
+        for line in lines {
+            let index = match chips.iter().position(|(chip, _)| *chip == line.chip) {
+                Some(index) => index,
+                None => {
+                    chips.push((line.chip, Vec::new()));
+                    chips.len() - 1
+                }
+            };
+            let offsets = &mut chips[index].1;
+            if offsets.contains(&line.line) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("gpiochip{} line {} used by two lanes", line.chip, line.line),
+                ));
+            }
+            if offsets.len() == GPIO_V2_LINES_MAX {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "more than {GPIO_V2_LINES_MAX} lanes on gpiochip{}",
+                        line.chip
+                    ),
+                ));
+            }
+            lanes.push((index, offsets.len() as u32));
+            offsets.push(line.line);
+        }
+        let mut requests = Vec::with_capacity(chips.len());
+        for (chip, offsets) in &chips {
+            requests.push(request(*chip, offsets)?);
+        }
+        let mut gpio = Self {
+            requests,
+            widths: chips.iter().map(|(_, offsets)| offsets.len()).collect(),
+            lanes,
+            bits: vec![0; chips.len()],
+            fault: None,
+        };
+        gpio.write_all_low()?;
+        Ok(gpio)
+    }
+
+    fn write_all_low(&mut self) -> io::Result<()> {
+        for (lines, &width) in self.requests.iter_mut().zip(&self.widths) {
+            lines.set(0, all_lines(width))?;
+        }
+        Ok(())
+    }
+}
+
+impl NozzleControl for CdevGpio {
+    fn apply(&mut self, lanes: &[bool]) {
+        self.bits.fill(0);
+        for (&(request, bit), &active) in self.lanes.iter().zip(lanes) {
+            if active {
+                self.bits[request] |= 1 << bit;
+            }
+        }
+        let mut fault = None;
+        for ((lines, &width), &bits) in self.requests.iter_mut().zip(&self.widths).zip(&self.bits) {
+            // Keep going so the other chips still follow the frame; the
+            // next frame (or drop) tries the failed one again.
+            if let Err(e) = lines.set(bits, all_lines(width)) {
+                fault.get_or_insert_with(|| format!("GPIO set failed: {e}"));
+            }
+        }
+        self.fault = fault;
+    }
+
+    fn fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+}
+
+impl Drop for CdevGpio {
+    fn drop(&mut self) {
+        let _ = self.write_all_low();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    /// Mock chip recording every set as `(chip, bits, mask)`.
+    #[derive(Clone, Default)]
+    struct Log(Arc<Mutex<Vec<(u32, u64, u64)>>>);
+
+    struct MockLines {
+        chip: u32,
+        log: Log,
+    }
+
+    impl OutputLines for MockLines {
+        fn set(&mut self, bits: u64, mask: u64) -> io::Result<()> {
+            self.log.0.lock().unwrap().push((self.chip, bits, mask));
+            Ok(())
+        }
+    }
+
+    fn mock_gpio(lines: &[CdevLine], log: &Log) -> io::Result<CdevGpio> {
+        CdevGpio::with_lines(lines, |chip, _| {
+            Ok(Box::new(MockLines {
+                chip,
+                log: log.clone(),
+            }) as Box<dyn OutputLines>)
+        })
+    }
+
+    fn take(log: &Log) -> Vec<(u32, u64, u64)> {
+        std::mem::take(&mut *log.0.lock().unwrap())
+    }
+
+    #[test]
+    fn uapi_layout_matches_the_kernel() {
+        assert_eq!(std::mem::size_of::<LineRequest>(), 592);
+        assert_eq!(std::mem::size_of::<LineValues>(), 16);
+        assert_eq!(GPIO_V2_GET_LINE_IOCTL, 0xC250_B407);
+        assert_eq!(GPIO_V2_LINE_SET_VALUES_IOCTL, 0xC010_B40F);
+    }
+
+    #[test]
+    fn lanes_on_two_chips_are_driven_low_set_and_released_low() {
+        let log = Log::default();
+        let lines = [
+            CdevLine { chip: 1, line: 4 },
+            CdevLine { chip: 0, line: 17 },
+            CdevLine { chip: 1, line: 9 },
+        ];
+        let mut gpio = mock_gpio(&lines, &log).unwrap();
+        assert_eq!(take(&log), [(1, 0, 0b11), (0, 0, 0b1)]);
+        gpio.apply(&[false, true, true]);
+        assert_eq!(take(&log), [(1, 0b10, 0b11), (0, 0b1, 0b1)]);
+        drop(gpio);
+        assert_eq!(take(&log), [(1, 0, 0b11), (0, 0, 0b1)]);
+    }
+
+    /// Lines whose sets fail while `broken` holds.
+    struct FlakyLines(Arc<Mutex<bool>>);
+
+    impl OutputLines for FlakyLines {
+        fn set(&mut self, _bits: u64, _mask: u64) -> io::Result<()> {
+            if *self.0.lock().unwrap() {
+                return Err(io::Error::from_raw_os_error(libc::ENODEV));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_set_is_reported_until_a_frame_goes_through() {
+        let broken = Arc::new(Mutex::new(false));
+        let lines = [CdevLine { chip: 0, line: 3 }];
+        let mut gpio = CdevGpio::with_lines(&lines, |_, _| {
+            Ok(Box::new(FlakyLines(broken.clone())) as Box<dyn OutputLines>)
+        })
+        .unwrap();
+        gpio.apply(&[true]);
+        assert_eq!(gpio.fault(), None);
+        *broken.lock().unwrap() = true;
+        gpio.apply(&[false]);
+        assert!(gpio.fault().unwrap().starts_with("GPIO set failed"));
+        *broken.lock().unwrap() = false;
+        gpio.apply(&[false]);
+        assert_eq!(gpio.fault(), None);
+    }
+
+    #[test]
+    fn rejects_a_line_shared_by_two_lanes() {
+        let line = CdevLine { chip: 0, line: 5 };
+        let err = mock_gpio(&[line, line], &Log::default()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn missing_chip_is_an_error() {
+        let err = ChipLines::request(Path::new("/nonexistent/gpiochip9"), &[0]).err();
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    /// A gpio-sim chip, removed again on drop.
+    struct SimChip {
+        dir: PathBuf,
+        chip: u32,
+        values: PathBuf,
+    }
+
+    impl SimChip {
+        /// Create a live 8-line chip, or `None` without gpio-sim (the
+        /// module loaded, configfs mounted, and root).
+        fn new() -> Option<Self> {
+            let dir = Path::new("/sys/kernel/config/gpio-sim")
+                .join(format!("rustspray-{}", std::process::id()));
+            fs::create_dir(&dir).ok()?;
+            let mut sim = Self {
+                dir,
+                chip: 0,
+                values: PathBuf::new(),
+            };
+            fs::create_dir(sim.dir.join("bank0")).ok()?;
+            fs::write(sim.dir.join("bank0/num_lines"), "8").ok()?;
+            fs::write(sim.dir.join("live"), "1").ok()?;
+            let read = |path: &str| fs::read_to_string(sim.dir.join(path)).ok();
+            let device = read("dev_name")?;
+            let chip_name = read("bank0/chip_name")?;
+            let (device, chip_name) = (device.trim(), chip_name.trim());
+            sim.chip = chip_name.strip_prefix("gpiochip")?.parse().ok()?;
+            sim.values = PathBuf::from(format!("/sys/devices/platform/{device}/{chip_name}"));
+            Some(sim)
+        }
+
+        fn value(&self, line: u32) -> String {
+            fs::read_to_string(self.values.join(format!("sim_gpio{line}/value")))
+                .unwrap()
+                .trim()
+                .to_string()
+        }
+    }
+
+    impl Drop for SimChip {
+        fn drop(&mut self) {
+            let _ = fs::write(self.dir.join("live"), "0");
+            let _ = fs::remove_dir(self.dir.join("bank0"));
+            let _ = fs::remove_dir(&self.dir);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs gpio-sim"]
+    fn drives_gpio_sim_lines() {
+        let sim = SimChip::new().expect("gpio-sim chip (module, configfs and root)");
+        let lines = [
+            CdevLine {
+                chip: sim.chip,
+                line: 2,
+            },
+            CdevLine {
+                chip: sim.chip,
+                line: 5,
+            },
+        ];
+        let mut gpio = CdevGpio::new(&lines).unwrap();
+        assert_eq!((sim.value(2), sim.value(5)), ("0".into(), "0".into()));
+        gpio.apply(&[true, false]);
+        assert_eq!((sim.value(2), sim.value(5)), ("1".into(), "0".into()));
+        gpio.apply(&[false, true]);
+        assert_eq!((sim.value(2), sim.value(5)), ("0".into(), "1".into()));
+        drop(gpio);
+        assert_eq!(sim.value(5), "0");
+    }
+}
@@ -0,0 +1,493 @@
+//! Ground speed and position from a GPS receiver speaking NMEA 0183.
+//!
+//! [`Gps::spawn`] (Unix only) reads sentences from a serial device (or a
+//! pty) on a background thread, reopening it whenever it disappears. `RMC`
+//! sentences give position, speed and fix status, `VTG` sentences speed
+//! only, so the fix is decided by `RMC` alone; the talker ID (`GP`, `GN`, `GL`, ...) is ignored and sentences with a
+//! bad checksum are dropped. [`Gps::poll`] reports the latest values and
+//! whether they are current: a receiver that reports no fix, or has gone
+//! quiet for longer than the staleness limit, is not trusted for speed.
+
+use serde::Serialize;
+use std::time::Duration;
+
+/// Metres per second in one knot.
+const KNOT: f32 = 1852.0 / 3600.0;
+
+/// A decoded NMEA sentence of interest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sentence {
+    /// Recommended minimum data.
+    Rmc {
+        valid: bool,
+        /// Latitude and longitude in degrees (north and east positive).
+        position: Option<(f64, f64)>,
+        speed_mps: Option<f32>,
+    },
+    /// Course and speed over ground. `valid` needs the NMEA 2.3 mode
+    /// indicator: older, mode-less sentences do not say whether their
+    /// speed is current.
+    Vtg { valid: bool, speed_mps: Option<f32> },
+}
+
+/// Decode one NMEA line. `None` for other sentence types, malformed
+/// sentences and checksum mismatches.
+pub fn parse(line: &str) -> Option<Sentence> {
+    let (body, checksum) = line.trim().strip_prefix('$')?.split_once('*')?;
+    let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+    if body.bytes().fold(0, |sum, b| sum ^ b) != expected {
+        return None;
+    }
+    let fields: Vec<&str> = body.split(',').collect();
+    let field = |i: usize| fields.get(i).copied().unwrap_or("");
+    // NMEA 2.3 mode indicator: `N` marks data as not valid.
+    let mode_valid = |i: usize| field(i) != "N";
+    match fields[0].get(2..)? {
+        "RMC" => {
+            let valid = field(2) == "A" && mode_valid(12);
+            let position =
+                coordinate(field(3), field(4), 'S').zip(coordinate(field(5), field(6), 'W'));
+            let speed_mps = field(7).parse::<f32>().ok().map(|knots| knots * KNOT);
+            Some(Sentence::Rmc {
+                valid,
+                position,
+                speed_mps,
+            })
+        }
+        "VTG" => {
+            let kmh = field(7).parse::<f32>().ok().map(|kmh| kmh / 3.6);
+            let knots = field(5).parse::<f32>().ok().map(|knots| knots * KNOT);
+            Some(Sentence::Vtg {
+                valid: !field(9).is_empty() && mode_valid(9),
+                speed_mps: kmh.or(knots),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Degrees from NMEA `(d)ddmm.mmmm` plus hemisphere, negative for
+/// `negative` (`S` or `W`).
+fn coordinate(value: &str, hemisphere: &str, negative: char) -> Option<f64> {
+    let value: f64 = value.parse().ok()?;
+    let degrees = (value / 100.0).trunc() + (value % 100.0) / 60.0;
+    match hemisphere.chars().next()? {
+        h if h == negative => Some(-degrees),
+        _ => Some(degrees),
+    }
+}
+
+/// Whether the GPS data can be trusted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FixStatus {
+    /// No sentence yet, or the receiver reports no fix.
+    #[default]
+    None,
+    /// The last fix is older than the staleness limit.
+    Stale,
+    /// A current fix.
+    Fix,
+}
+
+/// Latest GPS values, as reported in IPC responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct GpsReading {
+    pub fix: FixStatus,
+    /// Last speed over ground, in metres per second.
+    pub speed_mps: Option<f32>,
+    /// Last position, in degrees.
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+/// Folds sentences into the latest reading. The fix is decided by `RMC`
+/// sentences; a valid `VTG` only updates the speed.
+#[derive(Debug, Clone, Default)]
+pub struct GpsTracker {
+    reading: GpsReading,
+    /// Whether the last `RMC` sentence reported a fix.
+    valid: bool,
+    /// When a fix was last reported.
+    updated: Option<Duration>,
+}
+
+impl GpsTracker {
+    /// Tracker with no data yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take in a sentence received at time `now`.
+    pub fn update(&mut self, sentence: Sentence, now: Duration) {
+        match sentence {
+            Sentence::Rmc {
+                valid,
+                position,
+                speed_mps,
+            } => {
+                self.valid = valid;
+                if !valid {
+                    return;
+                }
+                self.updated = Some(now);
+                if let Some((lat, lon)) = position {
+                    self.reading.lat = Some(lat);
+                    self.reading.lon = Some(lon);
+                }
+                if speed_mps.is_some() {
+                    self.reading.speed_mps = speed_mps;
+                }
+            }
+            Sentence::Vtg {
+                valid: true,
+                speed_mps: Some(speed),
+            } => self.reading.speed_mps = Some(speed),
+            Sentence::Vtg { .. } => {}
+        }
+    }
+
+    /// The latest reading at time `now`, stale once no `RMC` fix has
+    /// arrived for longer than `stale_after`.
+    pub fn reading(&self, now: Duration, stale_after: Duration) -> GpsReading {
+        let fix = match self.updated {
+            Some(_) if !self.valid => FixStatus::None,
+            Some(updated) if now.saturating_sub(updated) > stale_after => FixStatus::Stale,
+            Some(_) => FixStatus::Fix,
+            None => FixStatus::None,
+        };
+        GpsReading {
+            fix,
+            ..self.reading
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use reader::Gps;
+
+#[cfg(unix)]
+mod reader {
+    use super::{parse, FixStatus, GpsReading, GpsTracker};
+    use crate::delay::{Clock, GroundSpeed};
+    use crate::serial::{self, Access, Parity};
+    use std::io::{self, Read};
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    /// Pause before reopening a device that failed or closed.
+    const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+    /// Longest wait for input before the reader checks for a stop.
+    const STOP_POLL: Duration = Duration::from_millis(100);
+
+    /// Longest NMEA sentence, including `$` and the CR LF ending.
+    const MAX_SENTENCE: usize = 82;
+
+    /// A GPS receiver read on a background thread.
+    pub struct Gps {
+        tracker: Arc<Mutex<GpsTracker>>,
+        clock: Arc<dyn Clock + Send + Sync>,
+        stale_after: Duration,
+        /// Ground speed driven by [`Self::poll`], with its fallback.
+        speed: Option<(GroundSpeed, f32)>,
+        stop: Option<Sender<()>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Gps {
+        /// Read NMEA sentences from the serial device or pty at `path`,
+        /// configured for `baud` (raw, 8N1), timing them with `clock`. The
+        /// device is opened on the reader thread and reopened after errors,
+        /// so a receiver may be plugged in later.
+        pub fn spawn(
+            path: &Path,
+            baud: u32,
+            stale_after: Duration,
+            clock: Arc<dyn Clock + Send + Sync>,
+        ) -> Self {
+            let tracker = Arc::new(Mutex::new(GpsTracker::new()));
+            let (stop, stopped) = mpsc::channel::<()>();
+            let reader = Reader {
+                path: path.to_path_buf(),
+                baud,
+                tracker: tracker.clone(),
+                clock: clock.clone(),
+                stopped,
+            };
+            let thread = std::thread::Builder::new()
+                .name("gps".into())
+                .spawn(move || reader.run())
+                .expect("failed to spawn the GPS reader thread");
+            Self {
+                tracker,
+                clock,
+                stale_after,
+                speed: None,
+                stop: Some(stop),
+                thread: Some(thread),
+            }
+        }
+
+        /// Drive `speed` from [`Self::poll`]: the GPS speed while there is a
+        /// fix, `fallback_mps` otherwise.
+        pub fn with_ground_speed(mut self, speed: GroundSpeed, fallback_mps: f32) -> Self {
+            self.speed = Some((speed, fallback_mps));
+            self
+        }
+
+        /// The latest reading, and an update of the linked ground speed.
+        pub fn poll(&self) -> GpsReading {
+            let reading = self
+                .tracker
+                .lock()
+                .unwrap()
+                .reading(self.clock.now(), self.stale_after);
+            if let Some((speed, fallback)) = &self.speed {
+                match (reading.fix, reading.speed_mps) {
+                    (FixStatus::Fix, Some(mps)) => speed.set(mps),
+                    _ => speed.set(*fallback),
+                }
+            }
+            reading
+        }
+    }
+
+    impl Drop for Gps {
+        fn drop(&mut self) {
+            // The reader waits for input at most STOP_POLL at a time.
+            drop(self.stop.take());
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// The reader thread's state.
+    struct Reader {
+        path: PathBuf,
+        baud: u32,
+        tracker: Arc<Mutex<GpsTracker>>,
+        clock: Arc<dyn Clock + Send + Sync>,
+        stopped: Receiver<()>,
+    }
+
+    impl Reader {
+        fn run(self) {
+            loop {
+                // Errors just mean the receiver is absent or was unplugged;
+                // the fix goes stale and the device is tried again.
+                let _ = self.read_device();
+                if !matches!(
+                    self.stopped.recv_timeout(REOPEN_DELAY),
+                    Err(RecvTimeoutError::Timeout)
+                ) {
+                    return;
+                }
+            }
+        }
+
+        fn read_device(&self) -> io::Result<()> {
+            let mut device = serial::open(&self.path, Access::ReadOnly, self.baud, Parity::None)?;
+            let mut line = Vec::with_capacity(MAX_SENTENCE);
+            // Whether the current line outgrew a sentence: noise or a wrong
+            // baud rate, dropped rather than buffered.
+            let mut overlong = false;
+            let mut chunk = [0; 256];
+            while let Err(TryRecvError::Empty) = self.stopped.try_recv() {
+                if !serial::wait_readable(&device, STOP_POLL)? {
+                    continue;
+                }
+                let n = device.read(&mut chunk)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                for &byte in &chunk[..n] {
+                    if byte != b'\n' {
+                        if line.len() < MAX_SENTENCE {
+                            line.push(byte);
+                        } else {
+                            overlong = true;
+                        }
+                        continue;
+                    }
+                    if !overlong {
+                        if let Some(sentence) = std::str::from_utf8(&line).ok().and_then(parse) {
+                            let now = self.clock.now();
+                            self.tracker.lock().unwrap().update(sentence, now);
+                        }
+                    }
+                    line.clear();
+                    overlong = false;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::delay::{GroundSpeed, ManualClock};
+    #[cfg(unix)]
+    use crate::serial::pty;
+    #[cfg(unix)]
+    use std::io::Write;
+    #[cfg(unix)]
+    use std::sync::Arc;
+
+    /// A recorded drive: no fix, a fix at standstill, then 5 and 10 km/h,
+    /// with a corrupted sentence and unrelated GGA/GSV sentences mixed in.
+    #[cfg(unix)]
+    const DRIVE: &str = include_str!("../tests/data/drive.nmea");
+
+    fn checksummed(body: &str) -> String {
+        format!("${body}*{:02X}", body.bytes().fold(0, |sum, b| sum ^ b))
+    }
+
+    #[test]
+    fn parses_rmc_and_vtg() {
+        let rmc = parse(&checksummed(
+            "GPRMC,123519,A,4807.038,N,01131.000,W,022.4,084.4,230394,003.1,W",
+        ))
+        .unwrap();
+        let Sentence::Rmc {
+            valid,
+            position: Some((lat, lon)),
+            speed_mps: Some(speed),
+        } = rmc
+        else {
+            panic!("{rmc:?}");
+        };
+        assert!(valid);
+        assert!((lat - 48.1173).abs() < 1e-9 && (lon + 11.516_666_666).abs() < 1e-6);
+        assert!((speed - 22.4 * KNOT).abs() < 1e-6);
+
+        let vtg = parse(&checksummed("GNVTG,054.7,T,034.4,M,005.5,N,010.2,K,A")).unwrap();
+        assert_eq!(
+            vtg,
+            Sentence::Vtg {
+                valid: true,
+                speed_mps: Some(10.2 / 3.6)
+            }
+        );
+        // Before NMEA 2.3 VTG has no mode indicator.
+        let old = parse(&checksummed("GPVTG,,T,,M,,N,,K")).unwrap();
+        assert!(matches!(old, Sentence::Vtg { valid: false, .. }));
+        let no_fix = parse(&checksummed("GPRMC,,V,,,,,,,,,,N")).unwrap();
+        assert!(matches!(no_fix, Sentence::Rmc { valid: false, .. }));
+        // Bad checksum, missing checksum, other sentences.
+        assert_eq!(parse("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K,A*00"), None);
+        assert_eq!(parse("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K,A"), None);
+        assert_eq!(parse(&checksummed("GPGGA,1,2,3")), None);
+    }
+
+    #[test]
+    fn tracker_flags_no_fix_and_stale_data() {
+        let stale_after = Duration::from_secs(2);
+        let mut tracker = GpsTracker::new();
+        assert_eq!(
+            tracker.reading(Duration::ZERO, stale_after).fix,
+            FixStatus::None
+        );
+        let fix = Sentence::Rmc {
+            valid: true,
+            position: Some((52.0, 13.0)),
+            speed_mps: Some(2.0),
+        };
+        tracker.update(fix, Duration::from_secs(1));
+        let reading = tracker.reading(Duration::from_secs(3), stale_after);
+        assert_eq!(
+            reading,
+            GpsReading {
+                fix: FixStatus::Fix,
+                speed_mps: Some(2.0),
+                lat: Some(52.0),
+                lon: Some(13.0),
+            }
+        );
+        let later = tracker.reading(Duration::from_millis(3001), stale_after);
+        assert_eq!(later.fix, FixStatus::Stale);
+        let lost = Sentence::Rmc {
+            valid: false,
+            position: None,
+            speed_mps: None,
+        };
+        tracker.update(lost, Duration::from_secs(4));
+        let reading = tracker.reading(Duration::from_secs(4), stale_after);
+        assert_eq!(
+            (reading.fix, reading.speed_mps),
+            (FixStatus::None, Some(2.0))
+        );
+    }
+
+    #[test]
+    fn only_rmc_decides_the_fix() {
+        let stale_after = Duration::from_secs(2);
+        let mut tracker = GpsTracker::new();
+        let no_fix = parse(&checksummed("GPRMC,,V,,,,,,,,,,N")).unwrap();
+        let old_vtg = parse(&checksummed("GPVTG,,T,,M,,N,,K")).unwrap();
+        tracker.update(no_fix, Duration::from_secs(1));
+        tracker.update(old_vtg, Duration::from_secs(1));
+        let reading = tracker.reading(Duration::from_secs(1), stale_after);
+        assert_eq!((reading.fix, reading.speed_mps), (FixStatus::None, None));
+        // A VTG with a mode updates the speed, never the fix.
+        let vtg = parse(&checksummed("GPVTG,,T,,M,,N,3.6,K,A")).unwrap();
+        tracker.update(vtg, Duration::from_secs(2));
+        let reading = tracker.reading(Duration::from_secs(2), stale_after);
+        assert_eq!(
+            (reading.fix, reading.speed_mps),
+            (FixStatus::None, Some(1.0))
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn replays_a_recorded_log_through_a_pty() {
+        let (mut master, slave) = pty();
+        let clock = ManualClock::new();
+        let speed = GroundSpeed::new(0.0);
+        let gps = Gps::spawn(
+            &slave,
+            9600,
+            Duration::from_secs(2),
+            Arc::new(clock.clone()),
+        )
+        .with_ground_speed(speed.clone(), 1.0);
+        // Until the reader has the device open the fallback speed applies.
+        assert_eq!(gps.poll().fix, FixStatus::None);
+        assert_eq!(speed.get(), 1.0);
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        let mut reading = gps.poll();
+        while reading.speed_mps != Some(10.0 / 3.6) {
+            assert!(std::time::Instant::now() < deadline, "last {reading:?}");
+            // Replay until the reader has opened the slave and caught up,
+            // after a line of noise far longer than any sentence.
+            master.write_all(&[b'$'; 4096]).unwrap();
+            master.write_all(DRIVE.as_bytes()).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            reading = gps.poll();
+        }
+        assert_eq!(reading.fix, FixStatus::Fix);
+        assert!((reading.lat.unwrap() - 52.502_05).abs() < 1e-9);
+        assert!((reading.lon.unwrap() - 13.390_933_333).abs() < 1e-6);
+        assert_eq!(speed.get(), 10.0 / 3.6);
+
+        // The receiver goes quiet: the speed falls back once stale.
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(gps.poll().fix, FixStatus::Stale);
+        assert_eq!(speed.get(), 1.0);
+
+        // Dropping stops the reader although the device stays open and
+        // silent.
+        let start = std::time::Instant::now();
+        drop(gps);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
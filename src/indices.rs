@@ -0,0 +1,256 @@
+//! Alternative vegetation indices for [`crate::vision::PlantVision`].
+//!
+//! The default detector fuses ExG with green-ratio and chroma cues. The
+//! indices here are the classic single-formula alternatives from the
+//! weed-detection literature, so they can be compared on the same frames
+//! by switching `[vision] index` in config. Each one reports a score that
+//! is positive for vegetation and roughly unit-scaled, which lets
+//! [`WeightedIndex`] blend several of them with comparable weights.
+
+use serde::Deserialize;
+
+/// Per-pixel vegetation index.
+pub trait VegetationIndex: Send + Sync + std::fmt::Debug {
+    /// Score of one pixel: positive for vegetation, negative otherwise.
+    fn score(&self, r: u8, g: u8, b: u8) -> f32;
+}
+
+/// Chromatic coordinates `(r, g, b) / (r + g + b)`; zero for black.
+#[inline]
+fn chromatic(r: u8, g: u8, b: u8) -> [f32; 3] {
+    let sum = f32::from(r) + f32::from(g) + f32::from(b);
+    if sum == 0.0 {
+        return [0.0; 3];
+    }
+    [f32::from(r) / sum, f32::from(g) / sum, f32::from(b) / sum]
+}
+
+/// Excess Green on chromatic coordinates, `2g - r - b`, in `-1..=2`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exg {
+    pub threshold: f32,
+}
+
+impl VegetationIndex for Exg {
+    fn score(&self, r: u8, g: u8, b: u8) -> f32 {
+        let [r, g, b] = chromatic(r, g, b);
+        2.0 * g - r - b - self.threshold
+    }
+}
+
+/// Excess Green minus Excess Red (`1.4r - g`), on chromatic
+/// coordinates. Its natural threshold is zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exgr {
+    pub threshold: f32,
+}
+
+impl VegetationIndex for Exgr {
+    fn score(&self, r: u8, g: u8, b: u8) -> f32 {
+        let [r, g, b] = chromatic(r, g, b);
+        (2.0 * g - r - b) - (1.4 * r - g) - self.threshold
+    }
+}
+
+/// Colour Index of Vegetation Extraction,
+/// `0.441R - 0.811G + 0.385B + 18.78745` on 0–255 channels. Vegetation
+/// lies *below* `threshold`; the score is `(threshold - CIVE) / 255`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cive {
+    pub threshold: f32,
+}
+
+impl VegetationIndex for Cive {
+    fn score(&self, r: u8, g: u8, b: u8) -> f32 {
+        let cive = 0.441 * f32::from(r) - 0.811 * f32::from(g) + 0.385 * f32::from(b) + 18.78745;
+        (self.threshold - cive) / 255.0
+    }
+}
+
+/// Vegetative index `G / (R^a B^(1-a))` with `a = 0.667`, on channels
+/// offset by one so black stays finite. Vegetation lies above
+/// `threshold`; the score is `ln(VEG / threshold)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Veg {
+    pub threshold: f32,
+}
+
+impl VegetationIndex for Veg {
+    fn score(&self, r: u8, g: u8, b: u8) -> f32 {
+        const A: f32 = 0.667;
+        let [r, g, b] = [r, g, b].map(|c| f32::from(c) + 1.0);
+        let ln_veg = g.ln() - A * r.ln() - (1.0 - A) * b.ln();
+        ln_veg - self.threshold.ln()
+    }
+}
+
+/// Normalised green-red difference `(G - R) / (G + R)`, in `-1..=1`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ngrdi {
+    pub threshold: f32,
+}
+
+impl VegetationIndex for Ngrdi {
+    fn score(&self, r: u8, g: u8, _b: u8) -> f32 {
+        let (r, g) = (f32::from(r), f32::from(g));
+        let ngrdi = if r + g == 0.0 { 0.0 } else { (g - r) / (g + r) };
+        ngrdi - self.threshold
+    }
+}
+
+/// HSV hue band: pixels whose hue lies in `min..=max` degrees, with
+/// saturation and value (both `0..=1`) at least the given floors. The
+/// score is the distance inside the band as a fraction of a half turn;
+/// pixels failing a floor score `-1`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HueBand {
+    pub min: f32,
+    pub max: f32,
+    pub min_saturation: f32,
+    pub min_value: f32,
+}
+
+impl VegetationIndex for HueBand {
+    fn score(&self, r: u8, g: u8, b: u8) -> f32 {
+        let maxc = r.max(g).max(b);
+        let delta = f32::from(maxc - r.min(g).min(b));
+        let value = f32::from(maxc) / 255.0;
+        if maxc == 0 || value < self.min_value || delta / f32::from(maxc) < self.min_saturation {
+            return -1.0;
+        }
+        let (r, g, b) = (f32::from(r), f32::from(g), f32::from(b));
+        let hue = if delta == 0.0 {
+            0.0
+        } else if r >= g && r >= b {
+            (60.0 * (g - b) / delta).rem_euclid(360.0)
+        } else if g >= b {
+            60.0 * (b - r) / delta + 120.0
+        } else {
+            60.0 * (r - g) / delta + 240.0
+        };
+        ((hue - self.min).min(self.max - hue)) / 180.0
+    }
+}
+
+/// Weighted sum of several indices' scores.
+#[derive(Debug, Default)]
+pub struct WeightedIndex {
+    terms: Vec<(Box<dyn VegetationIndex>, f32)>,
+}
+
+impl WeightedIndex {
+    /// An empty blend (scores every pixel `0`, i.e. not vegetation).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `index` with `weight`.
+    pub fn with(mut self, index: impl VegetationIndex + 'static, weight: f32) -> Self {
+        self.terms.push((Box::new(index), weight));
+        self
+    }
+
+    /// Add a boxed `index` with `weight`.
+    pub fn push(&mut self, index: Box<dyn VegetationIndex>, weight: f32) {
+        self.terms.push((index, weight));
+    }
+}
+
+impl VegetationIndex for WeightedIndex {
+    fn score(&self, r: u8, g: u8, b: u8) -> f32 {
+        self.terms
+            .iter()
+            .map(|(index, weight)| weight * index.score(r, g, b))
+            .sum()
+    }
+}
+
+/// Index names accepted by `[vision] index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// The default [`crate::vision::PlantVision`] multi-cue score.
+    Fused,
+    Exg,
+    Exgr,
+    Cive,
+    Veg,
+    Ngrdi,
+    Hue,
+    /// NDVI from a near-infrared channel, on RGBN input (`[camera]
+    /// pixel_format = "rgbn"`); handled by
+    /// [`crate::vision::PlantVision::with_ndvi`] rather than as a colour
+    /// index.
+    Ndvi,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEAF: [u8; 3] = [60, 200, 60];
+    const SOIL: [u8; 3] = [120, 90, 70];
+    const STRAW: [u8; 3] = [200, 180, 120];
+
+    fn indices() -> Vec<Box<dyn VegetationIndex>> {
+        vec![
+            Box::new(Exg { threshold: 0.1 }),
+            Box::new(Exgr { threshold: 0.0 }),
+            Box::new(Cive { threshold: 0.0 }),
+            Box::new(Veg { threshold: 1.2 }),
+            Box::new(Ngrdi { threshold: 0.05 }),
+            Box::new(HueBand {
+                min: 70.0,
+                max: 170.0,
+                min_saturation: 0.15,
+                min_value: 0.1,
+            }),
+        ]
+    }
+
+    #[test]
+    fn every_index_separates_leaf_from_soil_and_straw() {
+        for index in indices() {
+            let [r, g, b] = LEAF;
+            assert!(index.score(r, g, b) > 0.0, "{index:?} misses the leaf");
+            for [r, g, b] in [SOIL, STRAW, [0, 0, 0], [128, 128, 128]] {
+                assert!(
+                    index.score(r, g, b) <= 0.0,
+                    "{index:?} accepts ({r}, {g}, {b})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn index_values_match_their_formulas() {
+        let [r, g, b] = SOIL;
+        // Chromatic (3/7, 9/28, 1/4): ExG = -1/28.
+        let exg = Exg { threshold: 0.0 }.score(r, g, b);
+        assert!((exg + 1.0 / 28.0).abs() < 1e-6, "{exg}");
+        let cive = Cive { threshold: 0.0 }.score(r, g, b) * -255.0;
+        assert!((cive - 25.66745).abs() < 1e-3, "{cive}");
+        let ngrdi = Ngrdi { threshold: 0.0 }.score(r, g, b);
+        assert!((ngrdi + 30.0 / 210.0).abs() < 1e-6, "{ngrdi}");
+        // Hue of pure green is 120 degrees: 50 inside a 70..=170 band.
+        let hue = HueBand {
+            min: 70.0,
+            max: 170.0,
+            min_saturation: 0.0,
+            min_value: 0.0,
+        };
+        assert!((hue.score(0, 255, 0) - 50.0 / 180.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weighted_index_sums_weighted_scores() {
+        let blend = WeightedIndex::new()
+            .with(Exg { threshold: 0.1 }, 0.5)
+            .with(Ngrdi { threshold: 0.05 }, 2.0);
+        let [r, g, b] = LEAF;
+        let expected = 0.5 * Exg { threshold: 0.1 }.score(r, g, b)
+            + 2.0 * Ngrdi { threshold: 0.05 }.score(r, g, b);
+        assert_eq!(blend.score(r, g, b), expected);
+        assert_eq!(WeightedIndex::new().score(r, g, b), 0.0);
+    }
+}
@@ -4,20 +4,72 @@
 pub trait NozzleControl {
     /// Apply lane activations.
     fn apply(&mut self, lanes: &[bool]);
+
+    /// Apply a duty fraction per lane, from `0` (closed) to `1` (fully
+    /// open), for PWM-driven solenoids. Backends without PWM open every
+    /// lane with a non-zero duty.
+    fn apply_duty(&mut self, duty: &[f32]) {
+        let lanes: Vec<bool> = duty.iter().map(|&d| d > 0.0).collect();
+        self.apply(&lanes);
+    }
+
+    /// Switch all `lanes` nozzles off immediately, bypassing any
+    /// scheduling. Used on shutdown.
+    fn all_off(&mut self, lanes: usize) {
+        self.apply(&vec![false; lanes]);
+    }
+
+    /// Learn the duty per lane a software PWM wrapper (see
+    /// [`crate::pwm::SoftPwm`]) is about to pulse this backend with
+    /// through [`Self::apply`]. Only for reporting; ignored by default.
+    fn pulsing(&mut self, _duty: &[f32]) {}
+
+    /// A communication fault that leaves the outputs in an unknown state,
+    /// or `None` once a write has gone through again. Backends without a
+    /// link that can fail never report one.
+    fn fault(&self) -> Option<&str> {
+        None
+    }
 }
 
 /// Mock implementation that logs lane state **changes** to stderr as
-/// `[MOCK GPIO] lane=N state=ON/OFF`.
+/// `[MOCK GPIO] lane=N state=ON/OFF`, or duty changes as
+/// `[MOCK GPIO] lane=N duty=D` when driven with PWM duty. Under software
+/// PWM it logs the duty it is pulsed with instead of every edge.
 ///
 /// Output goes to stderr, never stdout: in `--ipc-mode` stdout carries the
 /// newline-delimited JSON protocol and must not be polluted.
 #[derive(Default)]
 pub struct MockGpio {
     last: Option<Vec<bool>>,
+    /// Last duty per lane, `None` after a plain on/off application.
+    duty: Option<Vec<f32>>,
+    /// Whether a software PWM wrapper drives the lanes, so state changes
+    /// are pulse edges.
+    pulsed: bool,
+}
+
+impl MockGpio {
+    fn log_duty(&mut self, duty: &[f32]) {
+        for (lane, &d) in duty.iter().enumerate() {
+            let changed = match &self.duty {
+                Some(prev) => prev.get(lane) != Some(&d),
+                None => true,
+            };
+            if changed {
+                eprintln!("[MOCK GPIO] lane={lane} duty={d:.2}");
+            }
+        }
+        self.duty = Some(duty.to_vec());
+    }
 }
 
 impl NozzleControl for MockGpio {
     fn apply(&mut self, lanes: &[bool]) {
+        if self.pulsed {
+            self.last = Some(lanes.to_vec());
+            return;
+        }
         for (lane, &state) in lanes.iter().enumerate() {
             let changed = match &self.last {
                 Some(prev) => prev.get(lane) != Some(&state),
@@ -32,6 +84,24 @@ impl NozzleControl for MockGpio {
             }
         }
         self.last = Some(lanes.to_vec());
+        self.duty = None;
+    }
+
+    fn apply_duty(&mut self, duty: &[f32]) {
+        self.log_duty(duty);
+        self.last = Some(duty.iter().map(|&d| d > 0.0).collect());
+    }
+
+    fn all_off(&mut self, lanes: usize) {
+        if self.pulsed {
+            self.log_duty(&vec![0.0; lanes]);
+        }
+        self.apply(&vec![false; lanes]);
+    }
+
+    fn pulsing(&mut self, duty: &[f32]) {
+        self.pulsed = true;
+        self.log_duty(duty);
     }
 }
 
@@ -46,6 +116,25 @@ mod tests {
         assert_eq!(gpio.last.as_deref(), Some(&[true, false][..]));
         gpio.apply(&[false, false]);
         assert_eq!(gpio.last.as_deref(), Some(&[false, false][..]));
+        gpio.apply_duty(&[0.4, 0.0]);
+        assert_eq!(gpio.last.as_deref(), Some(&[true, false][..]));
+        assert_eq!(gpio.duty.as_deref(), Some(&[0.4, 0.0][..]));
+        gpio.all_off(2);
+        assert_eq!(gpio.last.as_deref(), Some(&[false, false][..]));
+        assert_eq!(gpio.duty, None);
+    }
+
+    #[test]
+    fn pulsed_mock_gpio_keeps_the_duty_across_edges() {
+        let mut gpio = MockGpio::default();
+        gpio.pulsing(&[0.5, 0.0]);
+        for edge in [[true, false], [false, false], [true, false]] {
+            gpio.apply(&edge);
+            assert_eq!(gpio.duty.as_deref(), Some(&[0.5, 0.0][..]));
+        }
+        gpio.all_off(2);
+        assert_eq!(gpio.duty.as_deref(), Some(&[0.0, 0.0][..]));
+        assert_eq!(gpio.last.as_deref(), Some(&[false, false][..]));
     }
 }
 
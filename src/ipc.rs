@@ -1,11 +1,14 @@
-//! IPC protocol v1 for embedding Rust-Spray as an inner loop.
+//! IPC protocol v1 (v2 for RGBN frames) for embedding Rust-Spray as an
+//! inner loop.
 //!
 //! An outer shell (e.g. OpenWeedLocator's Python process) owns the camera
 //! and pipes frames to the `rustspray` binary running with `--ipc-mode`:
 //!
-//! * **stdin** — a stream of framed RGB24 images. Each frame is an 8-byte
+//! * **stdin** — a stream of framed images. Each frame is an 8-byte
 //!   little-endian header `[width: u32][height: u32]` followed immediately
-//!   by `width * height * 3` bytes of interleaved RGB pixel data.
+//!   by `width * height * channels` bytes of interleaved pixel data: RGB24
+//!   (3 channels, protocol v1) by default, RGBN (4, protocol v2) with
+//!   `[camera] pixel_format = "rgbn"`.
 //! * **stdout** — one newline-delimited JSON object per processed frame
 //!   (see [`IpcResponse`]). Nothing else is ever written to stdout in IPC
 //!   mode; logs and mock-GPIO output go to stderr.
@@ -13,6 +16,10 @@
 //! The full contract (versioning, error behaviour, handshake) is documented
 //! in `INTEGRATION.md` at the repository root.
 
+use crate::blobs::Blob;
+use crate::gps::GpsReading;
+use crate::lanes::LaneStatus;
+use crate::vision::PixelFormat;
 use serde::Serialize;
 use std::io::{Read, Write};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -23,6 +30,21 @@ use std::time::{SystemTime, UNIX_EPOCH};
 /// changes incompatibly, and record the change in `INTEGRATION.md`.
 pub const IPC_PROTOCOL_VERSION: u32 = 1;
 
+/// Protocol version spoken with `[camera] pixel_format = "rgbn"`: v1 with
+/// a 4-byte RGBN pixel payload. The frame header does not carry the
+/// pixel format, so an RGB24 producer must see a different version in the
+/// handshake and refuse to start rather than desync the stream.
+pub const IPC_PROTOCOL_VERSION_RGBN: u32 = 2;
+
+/// Protocol version for frames in `format`, as reported by
+/// `--output-version` and in every response's `v`.
+pub fn protocol_version(format: PixelFormat) -> u32 {
+    match format {
+        PixelFormat::Rgb24 => IPC_PROTOCOL_VERSION,
+        PixelFormat::Rgbn => IPC_PROTOCOL_VERSION_RGBN,
+    }
+}
+
 /// Size of the per-frame header: `[width: u32 LE][height: u32 LE]`.
 pub const FRAME_HEADER_BYTES: usize = 8;
 
@@ -33,7 +55,7 @@ pub const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
 /// Per-frame result written to stdout as one JSON line.
 #[derive(Debug, Serialize)]
 pub struct IpcResponse {
-    /// Protocol version ([`IPC_PROTOCOL_VERSION`]).
+    /// Protocol version ([`protocol_version`] of the pixel format).
     pub v: u32,
     /// Monotonically increasing frame counter, starting at 0.
     pub frame: u64,
@@ -43,6 +65,87 @@ pub struct IpcResponse {
     pub lanes: Vec<bool>,
     /// Detection latency for this frame in microseconds.
     pub latency_us: u64,
+    /// Vegetation coverage ratio (0.0–1.0) per lane, the value compared
+    /// against the on/off thresholds.
+    pub coverage: Vec<f32>,
+    /// Vegetation pixel count per lane.
+    pub pixels: Vec<u32>,
+    /// Weed blobs in frame coordinates; omitted unless the blob stage
+    /// (`[vision.blobs]`) is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blobs: Option<Vec<Blob>>,
+    /// Crop row centres as frame columns; omitted unless crop row
+    /// tracking (`[vision.crop_rows]`) is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crop_rows: Option<Vec<f32>>,
+    /// PWM duty per lane (0.0–1.0); omitted unless PWM output
+    /// (`[gpio.pwm]`) is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duty: Option<Vec<f32>>,
+    /// GPS fix status, speed and position; omitted unless a GPS
+    /// (`[gps] device`) is configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps: Option<GpsReading>,
+    /// ExG threshold used for this frame (changes per frame with
+    /// `[vision.adaptive]`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exg_threshold: Option<i16>,
+}
+
+impl IpcResponse {
+    /// Build a response from the pipeline's per-lane outcome.
+    pub fn new(frame: u64, ts_us: u64, status: &[LaneStatus], latency_us: u64) -> Self {
+        Self {
+            v: IPC_PROTOCOL_VERSION,
+            frame,
+            ts_us,
+            lanes: status.iter().map(|s| s.on).collect(),
+            latency_us,
+            coverage: status.iter().map(|s| s.ratio).collect(),
+            pixels: status.iter().map(|s| s.pixels).collect(),
+            blobs: None,
+            crop_rows: None,
+            duty: None,
+            gps: None,
+            exg_threshold: None,
+        }
+    }
+
+    /// Report the weed blobs (`None` without a blob stage).
+    pub fn with_blobs(mut self, blobs: Option<&[Blob]>) -> Self {
+        self.blobs = blobs.map(<[Blob]>::to_vec);
+        self
+    }
+
+    /// Report the protocol version for frames in `format`.
+    pub fn with_pixel_format(mut self, format: PixelFormat) -> Self {
+        self.v = protocol_version(format);
+        self
+    }
+
+    /// Report the crop row centres (`None` without crop row tracking).
+    pub fn with_crop_rows(mut self, rows: Option<&[f32]>) -> Self {
+        self.crop_rows = rows.map(<[f32]>::to_vec);
+        self
+    }
+
+    /// Report the PWM duty per lane (`None` without PWM output).
+    pub fn with_duty(mut self, duty: Option<&[f32]>) -> Self {
+        self.duty = duty.map(<[f32]>::to_vec);
+        self
+    }
+
+    /// Report the GPS reading (`None` without a GPS).
+    pub fn with_gps(mut self, gps: Option<GpsReading>) -> Self {
+        self.gps = gps;
+        self
+    }
+
+    /// Report the ExG threshold used for the frame.
+    pub fn with_exg_threshold(mut self, threshold: i16) -> Self {
+        self.exg_threshold = Some(threshold);
+        self
+    }
 }
 
 /// Frame dimensions decoded from a header.
@@ -53,11 +156,11 @@ pub struct FrameHeader {
 }
 
 impl FrameHeader {
-    /// Pixel payload size in bytes (`width * height * 3`).
+    /// Pixel payload size in bytes (`width * height * channels`).
     ///
     /// Returns an error if the dimensions are zero, overflow, or exceed
     /// [`MAX_FRAME_BYTES`].
-    pub fn payload_len(&self) -> Result<usize, String> {
+    pub fn payload_len(&self, channels: usize) -> Result<usize, String> {
         if self.width == 0 || self.height == 0 {
             return Err(format!(
                 "invalid frame header: dimensions {}x{} must be non-zero",
@@ -66,7 +169,7 @@ impl FrameHeader {
         }
         let len = (self.width as usize)
             .checked_mul(self.height as usize)
-            .and_then(|px| px.checked_mul(3))
+            .and_then(|px| px.checked_mul(channels))
             .ok_or_else(|| {
                 format!(
                     "invalid frame header: {}x{} overflows the frame size",
@@ -83,7 +186,8 @@ impl FrameHeader {
     }
 }
 
-/// Read one framed RGB24 image from `reader` into `buf`.
+/// Read one framed image of `channels` bytes per pixel from `reader`
+/// into `buf`.
 ///
 /// Returns `Ok(None)` on a clean end of stream (EOF exactly at a frame
 /// boundary — the normal way for the outer shell to shut us down). A
@@ -93,6 +197,7 @@ impl FrameHeader {
 pub fn read_frame<R: Read>(
     reader: &mut R,
     buf: &mut Vec<u8>,
+    channels: usize,
 ) -> std::io::Result<Option<FrameHeader>> {
     let mut header = [0u8; FRAME_HEADER_BYTES];
     let mut filled = 0;
@@ -115,7 +220,7 @@ pub fn read_frame<R: Read>(
         height: u32::from_le_bytes(header[4..8].try_into().unwrap()),
     };
     let len = hdr
-        .payload_len()
+        .payload_len(channels)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
     buf.resize(len, 0);
@@ -144,6 +249,7 @@ pub fn unix_micros() -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gps::FixStatus;
     use std::io::Cursor;
 
     fn framed(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
@@ -160,7 +266,7 @@ mod tests {
         let stream = framed(2, 2, &pixels);
         let mut cursor = Cursor::new(stream);
         let mut buf = Vec::new();
-        let hdr = read_frame(&mut cursor, &mut buf).unwrap().unwrap();
+        let hdr = read_frame(&mut cursor, &mut buf, 3).unwrap().unwrap();
         assert_eq!(
             hdr,
             FrameHeader {
@@ -170,21 +276,35 @@ mod tests {
         );
         assert_eq!(buf, pixels);
         // Stream is exhausted: next read is a clean EOF.
-        assert!(read_frame(&mut cursor, &mut buf).unwrap().is_none());
+        assert!(read_frame(&mut cursor, &mut buf, 3).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_framed_rgbn() {
+        let pixels: Vec<u8> = (0..3 * 2 * 4).map(|i| i as u8).collect();
+        let mut stream = framed(3, 2, &pixels);
+        stream.extend_from_slice(&framed(3, 2, &pixels[..18]));
+        let mut cursor = Cursor::new(stream);
+        let mut buf = Vec::new();
+        read_frame(&mut cursor, &mut buf, 4).unwrap().unwrap();
+        assert_eq!(buf, pixels);
+        // An RGB24-sized payload is short for RGBN.
+        let err = read_frame(&mut cursor, &mut buf, 4).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
     }
 
     #[test]
     fn clean_eof_returns_none() {
         let mut cursor = Cursor::new(Vec::<u8>::new());
         let mut buf = Vec::new();
-        assert!(read_frame(&mut cursor, &mut buf).unwrap().is_none());
+        assert!(read_frame(&mut cursor, &mut buf, 3).unwrap().is_none());
     }
 
     #[test]
     fn truncated_header_is_an_error() {
         let mut cursor = Cursor::new(vec![1, 0, 0]);
         let mut buf = Vec::new();
-        let err = read_frame(&mut cursor, &mut buf).unwrap_err();
+        let err = read_frame(&mut cursor, &mut buf, 3).unwrap_err();
         assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
     }
 
@@ -194,7 +314,7 @@ mod tests {
         stream.extend_from_slice(&[0u8; 10]); // needs 48 bytes
         let mut cursor = Cursor::new(stream);
         let mut buf = Vec::new();
-        let err = read_frame(&mut cursor, &mut buf).unwrap_err();
+        let err = read_frame(&mut cursor, &mut buf, 3).unwrap_err();
         assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
     }
 
@@ -203,7 +323,7 @@ mod tests {
         let stream = framed(0, 480, &[]);
         let mut cursor = Cursor::new(stream);
         let mut buf = Vec::new();
-        let err = read_frame(&mut cursor, &mut buf).unwrap_err();
+        let err = read_frame(&mut cursor, &mut buf, 3).unwrap_err();
         assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
     }
 
@@ -212,19 +332,25 @@ mod tests {
         let stream = framed(u32::MAX, u32::MAX, &[]);
         let mut cursor = Cursor::new(stream);
         let mut buf = Vec::new();
-        let err = read_frame(&mut cursor, &mut buf).unwrap_err();
+        let err = read_frame(&mut cursor, &mut buf, 3).unwrap_err();
         assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
     }
 
     #[test]
     fn response_serializes_to_expected_schema() {
-        let response = IpcResponse {
-            v: IPC_PROTOCOL_VERSION,
-            frame: 42,
-            ts_us: 1_718_000_000_123_456,
-            lanes: vec![true, false, false, true],
-            latency_us: 1840,
+        let lane = |ratio, pixels, on| LaneStatus {
+            ratio,
+            pixels,
+            was_on: false,
+            on,
         };
+        let status = [
+            lane(0.42, 3226, true),
+            lane(0.0, 0, false),
+            lane(0.125, 960, false),
+            lane(1.0, 7680, true),
+        ];
+        let response = IpcResponse::new(42, 1_718_000_000_123_456, &status, 1840);
         let mut out = Vec::new();
         write_response(&mut out, &response).unwrap();
         let line = String::from_utf8(out).unwrap();
@@ -238,5 +364,60 @@ mod tests {
             serde_json::json!([true, false, false, true])
         );
         assert_eq!(parsed["latency_us"], 1840);
+        assert_eq!(
+            parsed["coverage"],
+            serde_json::json!([0.42, 0.0, 0.125, 1.0])
+        );
+        assert_eq!(parsed["pixels"], serde_json::json!([3226, 0, 960, 7680]));
+        assert!(parsed.get("blobs").is_none());
+        assert!(parsed.get("crop_rows").is_none());
+        assert!(parsed.get("duty").is_none());
+        assert!(parsed.get("gps").is_none());
+    }
+
+    #[test]
+    fn rgbn_frames_speak_their_own_protocol_version() {
+        assert_eq!(protocol_version(PixelFormat::Rgb24), IPC_PROTOCOL_VERSION);
+        assert_ne!(protocol_version(PixelFormat::Rgbn), IPC_PROTOCOL_VERSION);
+        let response = IpcResponse::new(0, 0, &[], 0).with_pixel_format(PixelFormat::Rgbn);
+        assert_eq!(response.v, IPC_PROTOCOL_VERSION_RGBN);
+    }
+
+    #[test]
+    fn response_lists_blobs_when_enabled() {
+        let blob = Blob {
+            x: 10,
+            y: 20,
+            width: 4,
+            height: 3,
+            area: 9,
+            cx: 12.0,
+            cy: 21.5,
+        };
+        let response = IpcResponse::new(0, 0, &[], 0)
+            .with_blobs(Some(&[blob]))
+            .with_crop_rows(Some(&[40.5, 200.5]))
+            .with_duty(Some(&[0.5, 0.0]))
+            .with_gps(Some(GpsReading {
+                fix: FixStatus::Stale,
+                speed_mps: Some(2.5),
+                lat: Some(52.5),
+                lon: None,
+            }))
+            .with_exg_threshold(17);
+        let mut out = Vec::new();
+        write_response(&mut out, &response).unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            parsed["blobs"],
+            serde_json::json!([{"x": 10, "y": 20, "w": 4, "h": 3, "area": 9, "cx": 12.0, "cy": 21.5}])
+        );
+        assert_eq!(parsed["crop_rows"], serde_json::json!([40.5, 200.5]));
+        assert_eq!(parsed["duty"], serde_json::json!([0.5, 0.0]));
+        assert_eq!(
+            parsed["gps"],
+            serde_json::json!({"fix": "stale", "speed_mps": 2.5, "lat": 52.5, "lon": null})
+        );
+        assert_eq!(parsed["exg_threshold"], 17);
     }
 }
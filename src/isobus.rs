@@ -0,0 +1,1008 @@
+//! ISOBUS (ISO 11783) section control.
+//!
+//! Rust-Spray drives an ISOBUS implement's sections the way a Task
+//! Controller does. [`AddressClaim`] claims an address on the bus
+//! (ISO 11783-5) and defends it. [`SectionControl`] follows the
+//! implement, known by its NAME, through its address claims and sends
+//! its boom element process data (ISO 11783-10): Section Control State
+//! on, and a Setpoint Condensed Work State with the lanes mapped onto
+//! sections on every change and every refresh period. It also sends the
+//! TC status message every 2 s, which tells the implement its controller
+//! is alive.
+//!
+//! This is a minimal subset, not a conformant task controller. The TC
+//! client handshake is missing: there is no working-set master, version
+//! exchange, or device descriptor (DDOP) upload and activation. A
+//! certified implement ECU waits for that handshake and ignores process
+//! data from a controller it has not completed it with, so these
+//! setpoints only move sections on an ECU that accepts them without it,
+//! such as a bench controller or a custom one. There are no totals or
+//! rate control, and the boom element comes from the configuration.
+//! [`IsobusNozzles`] runs the protocol over a [`CanBus`].
+
+use crate::can::{CanBus, CanFrame};
+use crate::delay::{Clock, MonotonicClock};
+use crate::io_gpio::NozzleControl;
+use std::io;
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Destination address of broadcasts.
+pub const GLOBAL_ADDRESS: u8 = 0xFF;
+/// Source address of a node that could not claim one.
+pub const NULL_ADDRESS: u8 = 0xFE;
+
+const PGN_REQUEST: u32 = 0xEA00;
+const PGN_ADDRESS_CLAIMED: u32 = 0xEE00;
+const PGN_PROCESS_DATA: u32 = 0xCB00;
+
+/// Time other nodes have to contest a claim before the address is used.
+const CLAIM_WAIT: Duration = Duration::from_millis(250);
+/// Period of the TC status message.
+const STATUS_PERIOD: Duration = Duration::from_secs(2);
+
+/// Section Control State: 0 manual, 1 automatic.
+pub const DDI_SECTION_CONTROL_STATE: u16 = 160;
+/// Setpoint Condensed Work State for sections 1–16; each further 16
+/// sections use the next DDI, up to 256 sections.
+pub const DDI_SETPOINT_CONDENSED_WORK_STATE: u16 = 290;
+/// Most sections one boom element can have.
+pub const MAX_SECTIONS: usize = 256;
+
+const COMMAND_VALUE: u8 = 0x3;
+const COMMAND_STATUS: u8 = 0xE;
+
+/// A 29-bit J1939/ISO 11783 identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct J1939Id {
+    pub priority: u8,
+    /// Parameter group number; zero in the low byte for destination-
+    /// specific groups (PDU1).
+    pub pgn: u32,
+    /// Destination of a PDU1 group, [`GLOBAL_ADDRESS`] for PDU2.
+    pub destination: u8,
+    pub source: u8,
+}
+
+impl J1939Id {
+    pub fn new(priority: u8, pgn: u32, destination: u8, source: u8) -> Self {
+        Self {
+            priority,
+            pgn,
+            destination,
+            source,
+        }
+    }
+
+    fn is_pdu1(pgn: u32) -> bool {
+        (pgn >> 8) & 0xFF < 0xF0
+    }
+
+    pub fn to_raw(self) -> u32 {
+        let ps = if Self::is_pdu1(self.pgn) {
+            u32::from(self.destination)
+        } else {
+            self.pgn & 0xFF
+        };
+        (u32::from(self.priority) & 0x7) << 26
+            | (self.pgn & 0x3_FF00) << 8
+            | ps << 8
+            | u32::from(self.source)
+    }
+
+    pub fn from_raw(id: u32) -> Self {
+        let pgn = (id >> 8) & 0x3_FF00;
+        let ps = ((id >> 8) & 0xFF) as u8;
+        let (pgn, destination) = if Self::is_pdu1(pgn) {
+            (pgn, ps)
+        } else {
+            (pgn | u32::from(ps), GLOBAL_ADDRESS)
+        };
+        Self {
+            priority: ((id >> 26) & 0x7) as u8,
+            pgn,
+            destination,
+            source: id as u8,
+        }
+    }
+
+    fn frame(self, data: [u8; 8]) -> CanFrame {
+        CanFrame {
+            id: self.to_raw(),
+            extended: true,
+            len: 8,
+            data,
+        }
+    }
+}
+
+/// The 64-bit ISO 11783-5 NAME. A numerically lower NAME wins address
+/// contention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Name(pub u64);
+
+impl Name {
+    /// Industry group: agricultural and forestry equipment.
+    const AGRICULTURAL: u64 = 2;
+    /// Function: task controller.
+    const TASK_CONTROLLER: u64 = 130;
+
+    /// A self-configurable task controller NAME.
+    pub fn task_controller(identity_number: u32, manufacturer_code: u16) -> Self {
+        Self(
+            u64::from(identity_number & 0x1F_FFFF)
+                | u64::from(manufacturer_code & 0x7FF) << 21
+                | Self::TASK_CONTROLLER << 40
+                | Self::AGRICULTURAL << 60
+                | 1 << 63,
+        )
+    }
+
+    /// Whether the node may move to another address when it loses
+    /// contention.
+    pub fn self_configurable(self) -> bool {
+        self.0 >> 63 != 0
+    }
+
+    /// The 21-bit identity number.
+    pub fn identity_number(self) -> u32 {
+        (self.0 & 0x1F_FFFF) as u32
+    }
+
+    /// The 11-bit manufacturer code.
+    pub fn manufacturer_code(self) -> u16 {
+        ((self.0 >> 21) & 0x7FF) as u16
+    }
+}
+
+/// The implement's ECU, known by the identity number and manufacturer
+/// code of its NAME, which are unique to it on the bus wherever it
+/// claims an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Implement {
+    pub identity_number: u32,
+    pub manufacturer_code: u16,
+}
+
+impl Implement {
+    /// Whether `name` is this implement's.
+    pub fn is(self, name: Name) -> bool {
+        name.identity_number() == self.identity_number
+            && name.manufacturer_code() == self.manufacturer_code
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClaimState {
+    Claiming { since: Duration },
+    Claimed,
+    Lost,
+}
+
+/// ISO 11783-5 address claim: claim an address, wait for contention, and
+/// defend it or, when self-configurable, move through the dynamic range
+/// 128–247 when a lower NAME wants it.
+#[derive(Debug)]
+pub struct AddressClaim {
+    name: Name,
+    address: u8,
+    state: ClaimState,
+    tried: usize,
+}
+
+impl AddressClaim {
+    pub fn new(name: Name, preferred: u8) -> Self {
+        Self {
+            name,
+            address: preferred,
+            state: ClaimState::Claiming {
+                since: Duration::ZERO,
+            },
+            tried: 0,
+        }
+    }
+
+    /// The claimed address, once no one has contested it for 250 ms.
+    pub fn address(&self) -> Option<u8> {
+        (self.state == ClaimState::Claimed).then_some(self.address)
+    }
+
+    /// Whether the address went to a lower NAME with nowhere left to move.
+    pub fn lost(&self) -> bool {
+        self.state == ClaimState::Lost
+    }
+
+    /// Claim the preferred address.
+    pub fn start(&mut self, now: Duration) -> CanFrame {
+        self.state = ClaimState::Claiming { since: now };
+        self.claimed_frame()
+    }
+
+    fn claimed_frame(&self) -> CanFrame {
+        let source = match self.state {
+            ClaimState::Lost => NULL_ADDRESS,
+            _ => self.address,
+        };
+        J1939Id::new(6, PGN_ADDRESS_CLAIMED, GLOBAL_ADDRESS, source)
+            .frame(self.name.0.to_le_bytes())
+    }
+
+    /// React to a frame from the bus; returns the frame to send, if any.
+    pub fn on_frame(&mut self, frame: &CanFrame, now: Duration) -> Option<CanFrame> {
+        if !frame.extended {
+            return None;
+        }
+        let id = J1939Id::from_raw(frame.id);
+        match id.pgn {
+            PGN_REQUEST
+                if frame.payload().starts_with(&[0x00, 0xEE, 0x00])
+                    && (id.destination == GLOBAL_ADDRESS || id.destination == self.address) =>
+            {
+                Some(self.claimed_frame())
+            }
+            PGN_ADDRESS_CLAIMED
+                if id.source == self.address
+                    && self.state != ClaimState::Lost
+                    && frame.len == 8 =>
+            {
+                let theirs = Name(u64::from_le_bytes(frame.data));
+                if theirs > self.name {
+                    return Some(self.claimed_frame());
+                }
+                if theirs == self.name {
+                    return None;
+                }
+                self.tried += 1;
+                if self.name.self_configurable() && self.tried < 120 {
+                    self.address = match self.address {
+                        128..=246 => self.address + 1,
+                        _ => 128,
+                    };
+                    self.state = ClaimState::Claiming { since: now };
+                } else {
+                    self.state = ClaimState::Lost;
+                }
+                Some(self.claimed_frame())
+            }
+            _ => None,
+        }
+    }
+
+    /// Advance the claim timer; true when the address has just been
+    /// claimed.
+    pub fn poll(&mut self, now: Duration) -> bool {
+        match self.state {
+            ClaimState::Claiming { since } if now >= since + CLAIM_WAIT => {
+                self.state = ClaimState::Claimed;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Process data message payload: command, element number, DDI and value.
+pub fn process_data(command: u8, element: u16, ddi: u16, value: u32) -> [u8; 8] {
+    let mut data = [0; 8];
+    data[0] = (command & 0xF) | ((element & 0xF) as u8) << 4;
+    data[1] = (element >> 4) as u8;
+    data[2..4].copy_from_slice(&ddi.to_le_bytes());
+    data[4..8].copy_from_slice(&value.to_le_bytes());
+    data
+}
+
+/// Condensed work states of up to 16 sections: two bits per section,
+/// section 1 lowest, `01` on, `00` off, `11` not installed.
+pub fn condensed_work_state(sections: &[bool]) -> u32 {
+    (0..16).fold(0, |value, i| {
+        let state = match sections.get(i) {
+            Some(&on) => u32::from(on),
+            None => 0b11,
+        };
+        value | state << (2 * i)
+    })
+}
+
+/// Task controller side of ISOBUS section control for one boom element.
+#[derive(Debug)]
+pub struct SectionControl {
+    claim: AddressClaim,
+    implement: Implement,
+    /// The implement's address, from its last address claim.
+    implement_address: Option<u8>,
+    element: u16,
+    /// Section index per lane.
+    lane_sections: Vec<usize>,
+    sections: Vec<bool>,
+    refresh: Duration,
+    /// Whether section control of the implement at `implement_address`
+    /// is switched on.
+    active: bool,
+    setpoint_sent: Duration,
+    status_sent: Duration,
+}
+
+impl SectionControl {
+    /// Control the sections of `element` on `implement`, lane `i`
+    /// driving section `lane_sections[i]` (0-based; lanes sharing a
+    /// section are ORed). Setpoints are resent every `refresh`.
+    ///
+    /// # Panics
+    /// Panics if a section index is `MAX_SECTIONS` or more.
+    pub fn new(
+        claim: AddressClaim,
+        implement: Implement,
+        element: u16,
+        lane_sections: Vec<usize>,
+        refresh: Duration,
+    ) -> Self {
+        let count = lane_sections.iter().max().map_or(0, |&s| s + 1);
+        assert!(count <= MAX_SECTIONS, "ISOBUS allows at most 256 sections");
+        Self {
+            claim,
+            implement,
+            implement_address: None,
+            element,
+            lane_sections,
+            sections: vec![false; count],
+            refresh,
+            active: false,
+            setpoint_sent: Duration::ZERO,
+            status_sent: Duration::ZERO,
+        }
+    }
+
+    /// The claimed source address.
+    pub fn address(&self) -> Option<u8> {
+        self.claim.address()
+    }
+
+    /// The implement's address, once it has been seen claiming one.
+    pub fn implement_address(&self) -> Option<u8> {
+        self.implement_address
+    }
+
+    /// Why the sections are not being commanded, or `None` while they
+    /// are.
+    pub fn fault(&self) -> Option<&'static str> {
+        if self.claim.lost() {
+            Some("ISOBUS address lost")
+        } else if self.claim.address().is_none() {
+            Some("ISOBUS address not claimed")
+        } else if self.implement_address.is_none() {
+            Some("ISOBUS implement not on the bus")
+        } else {
+            None
+        }
+    }
+
+    /// Begin the address claim.
+    pub fn start(&mut self, now: Duration) -> Vec<CanFrame> {
+        self.active = false;
+        vec![self.claim.start(now)]
+    }
+
+    fn process_data(&self, destination: u8, data: [u8; 8]) -> CanFrame {
+        J1939Id::new(5, PGN_PROCESS_DATA, destination, self.claim.address).frame(data)
+    }
+
+    /// `value` for `ddi` of the element; only called while the
+    /// implement's address is known.
+    fn value(&self, ddi: u16, value: u32) -> CanFrame {
+        self.process_data(
+            self.implement_address.unwrap_or(GLOBAL_ADDRESS),
+            process_data(COMMAND_VALUE, self.element, ddi, value),
+        )
+    }
+
+    /// Ask every node for its address claim, so an implement that
+    /// claimed before we started is found.
+    fn request_address_claimed(&self) -> CanFrame {
+        let mut frame = J1939Id::new(6, PGN_REQUEST, GLOBAL_ADDRESS, self.claim.address)
+            .frame([0x00, 0xEE, 0x00, 0, 0, 0, 0, 0]);
+        frame.len = 3;
+        frame
+    }
+
+    fn setpoints(&mut self, now: Duration) -> Vec<CanFrame> {
+        self.setpoint_sent = now;
+        self.sections
+            .chunks(16)
+            .zip(DDI_SETPOINT_CONDENSED_WORK_STATE..)
+            .map(|(block, ddi)| self.value(ddi, condensed_work_state(block)))
+            .collect()
+    }
+
+    fn status(&mut self, now: Duration) -> CanFrame {
+        self.status_sent = now;
+        self.process_data(
+            GLOBAL_ADDRESS,
+            [
+                (0xF << 4) | COMMAND_STATUS,
+                0xFF,
+                0xFF,
+                0xFF,
+                0x01,
+                0x00,
+                0x00,
+                0xFF,
+            ],
+        )
+    }
+
+    /// Set the sections from lane states; returns the setpoints to send
+    /// if they changed and the address is claimed.
+    pub fn set_lanes(&mut self, lanes: &[bool], now: Duration) -> Vec<CanFrame> {
+        let mut sections = vec![false; self.sections.len()];
+        for (&on, &section) in lanes.iter().zip(&self.lane_sections) {
+            sections[section] |= on;
+        }
+        if sections == self.sections {
+            return Vec::new();
+        }
+        self.sections = sections;
+        if !self.active {
+            return Vec::new();
+        }
+        self.setpoints(now)
+    }
+
+    /// Follow the implement's address through an address claim: the
+    /// implement's own moves it, another NAME's at its address means it
+    /// has lost it.
+    fn track_implement(&mut self, frame: &CanFrame) {
+        let id = J1939Id::from_raw(frame.id);
+        if !frame.extended || id.pgn != PGN_ADDRESS_CLAIMED || frame.len != 8 {
+            return;
+        }
+        let address = if self.implement.is(Name(u64::from_le_bytes(frame.data))) {
+            (id.source != NULL_ADDRESS).then_some(id.source)
+        } else if self.implement_address == Some(id.source) {
+            None
+        } else {
+            return;
+        };
+        if address != self.implement_address {
+            self.implement_address = address;
+            self.active = false;
+        }
+    }
+
+    /// React to a frame from the bus.
+    pub fn on_frame(&mut self, frame: &CanFrame, now: Duration) -> Vec<CanFrame> {
+        self.track_implement(frame);
+        let reply = self.claim.on_frame(frame, now);
+        if self.claim.address().is_none() {
+            self.active = false;
+        }
+        reply.into_iter().collect()
+    }
+
+    /// Advance the timers: finish the address claim and look for the
+    /// implement, switch its section control on once both addresses are
+    /// known, then keep the TC status and setpoints flowing.
+    pub fn poll(&mut self, now: Duration) -> Vec<CanFrame> {
+        let mut frames = Vec::new();
+        if self.claim.poll(now) {
+            frames.push(self.status(now));
+            frames.push(self.request_address_claimed());
+        } else if self.claim.address().is_some() && now >= self.status_sent + STATUS_PERIOD {
+            frames.push(self.status(now));
+        }
+        if self.claim.address().is_none() || self.implement_address.is_none() {
+            return frames;
+        }
+        if !self.active {
+            self.active = true;
+            frames.push(self.value(DDI_SECTION_CONTROL_STATE, 1));
+            frames.extend(self.setpoints(now));
+        } else if now >= self.setpoint_sent + self.refresh {
+            frames.extend(self.setpoints(now));
+        }
+        frames
+    }
+
+    /// Close every section and hand control back to the operator.
+    pub fn stop(&mut self, now: Duration) -> Vec<CanFrame> {
+        if !self.active {
+            return Vec::new();
+        }
+        self.sections.fill(false);
+        let mut frames = self.setpoints(now);
+        frames.push(self.value(DDI_SECTION_CONTROL_STATE, 0));
+        self.active = false;
+        frames
+    }
+}
+
+/// Nozzles behind an ISOBUS implement's section control.
+///
+/// A background thread reads the bus and keeps the protocol running.
+/// Sections are only commanded once the address is claimed and the
+/// implement has been seen, starting with the current lanes, which are
+/// off until the first frame. On drop all sections are commanded off and
+/// section control goes back to manual. [`NozzleControl::fault`] reports
+/// an address that is not (or no longer) claimed or an implement that is
+/// not on the bus, then a failed send until a later one goes through.
+pub struct IsobusNozzles {
+    node: Arc<Mutex<SectionControl>>,
+    bus: Arc<dyn CanBus>,
+    /// Error of the last send, `None` once one goes through.
+    error: Arc<Mutex<Option<String>>>,
+    fault: Option<String>,
+    clock: MonotonicClock,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl IsobusNozzles {
+    /// Run `node` on `bus`, whose `recv` should time out within a few
+    /// milliseconds so the timers stay on schedule.
+    ///
+    /// Fails if the address claim cannot be sent.
+    pub fn new(bus: Arc<dyn CanBus>, mut node: SectionControl) -> io::Result<Self> {
+        let clock = MonotonicClock::new();
+        for frame in node.start(clock.now()) {
+            bus.send(&frame)?;
+        }
+        let node = Arc::new(Mutex::new(node));
+        let error = Arc::new(Mutex::new(None));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = {
+            let (bus, node, error) = (bus.clone(), node.clone(), error.clone());
+            std::thread::Builder::new()
+                .name("isobus".into())
+                .spawn(move || {
+                    while let Err(TryRecvError::Empty) = stopped.try_recv() {
+                        let received = bus.recv();
+                        let mut node = node.lock().unwrap();
+                        let now = clock.now();
+                        let mut frames = match received {
+                            Ok(Some(frame)) => node.on_frame(&frame, now),
+                            Ok(None) => Vec::new(),
+                            Err(_) => {
+                                std::thread::sleep(Duration::from_millis(10));
+                                Vec::new()
+                            }
+                        };
+                        frames.extend(node.poll(now));
+                        send(&*bus, &frames, &error);
+                    }
+                })
+                .expect("failed to spawn the ISOBUS thread")
+        };
+        Ok(Self {
+            node,
+            bus,
+            error,
+            fault: None,
+            clock,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    fn set_lanes(&mut self, lanes: &[bool]) {
+        let frames = self.node.lock().unwrap().set_lanes(lanes, self.clock.now());
+        // Lost frames are resent on the next refresh.
+        send(&*self.bus, &frames, &self.error);
+        let claim = self.node.lock().unwrap().fault();
+        let error = self.error.lock().unwrap();
+        let fault = claim.or(error.as_deref());
+        if self.fault.as_deref() != fault {
+            self.fault = fault.map(str::to_owned);
+        }
+    }
+}
+
+/// Send `frames`, recording the outcome in `error`.
+fn send(bus: &dyn CanBus, frames: &[CanFrame], error: &Mutex<Option<String>>) {
+    if frames.is_empty() {
+        return;
+    }
+    let mut failed = None;
+    for frame in frames {
+        if let Err(e) = bus.send(frame) {
+            failed = Some(format!("ISOBUS send failed: {e}"));
+        }
+    }
+    *error.lock().unwrap() = failed;
+}
+
+impl NozzleControl for IsobusNozzles {
+    fn apply(&mut self, lanes: &[bool]) {
+        self.set_lanes(lanes);
+    }
+
+    fn all_off(&mut self, lanes: usize) {
+        self.set_lanes(&vec![false; lanes]);
+    }
+
+    fn fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+}
+
+impl Drop for IsobusNozzles {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let frames = self.node.lock().unwrap().stop(self.clock.now());
+        send(&*self.bus, &frames, &self.error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const MS: Duration = Duration::from_millis(1);
+    const IMPLEMENT: Implement = Implement {
+        identity_number: 9,
+        manufacturer_code: 3,
+    };
+
+    /// The NAME the implement claims its address with.
+    fn implement_name() -> Name {
+        Name(9 | 3 << 21 | 1 << 63)
+    }
+
+    fn claimed_by(name: Name, source: u8) -> CanFrame {
+        J1939Id::new(6, PGN_ADDRESS_CLAIMED, GLOBAL_ADDRESS, source).frame(name.0.to_le_bytes())
+    }
+
+    fn decode(frame: &CanFrame) -> (J1939Id, u8, u16, u16, u32) {
+        let d = frame.data;
+        (
+            J1939Id::from_raw(frame.id),
+            d[0] & 0xF,
+            u16::from(d[0] >> 4) | u16::from(d[1]) << 4,
+            u16::from_le_bytes([d[2], d[3]]),
+            u32::from_le_bytes([d[4], d[5], d[6], d[7]]),
+        )
+    }
+
+    #[test]
+    fn identifiers() {
+        let claim = J1939Id::new(6, PGN_ADDRESS_CLAIMED, GLOBAL_ADDRESS, 0x80);
+        assert_eq!(claim.to_raw(), 0x18EE_FF80);
+        let pd = J1939Id::new(5, PGN_PROCESS_DATA, 0x81, 0xF7);
+        assert_eq!(pd.to_raw(), 0x14CB_81F7);
+        assert_eq!(J1939Id::from_raw(0x14CB_81F7), pd);
+        let pdu2 = J1939Id::new(6, 0xFEF1, GLOBAL_ADDRESS, 0x00);
+        assert_eq!(pdu2.to_raw(), 0x18FE_F100);
+        assert_eq!(J1939Id::from_raw(0x18FE_F100), pdu2);
+    }
+
+    #[test]
+    fn name_fields() {
+        let name = Name::task_controller(0x12345, 0x7FF);
+        assert_eq!(name.0 & 0x1F_FFFF, 0x12345);
+        assert_eq!((name.0 >> 21) & 0x7FF, 0x7FF);
+        assert_eq!((name.0 >> 40) & 0xFF, 130);
+        assert_eq!((name.0 >> 60) & 0x7, 2);
+        assert!(name.self_configurable());
+        assert!(!Name(name.0 & !(1 << 63)).self_configurable());
+        assert_eq!(
+            (name.identity_number(), name.manufacturer_code()),
+            (0x12345, 0x7FF)
+        );
+        assert!(IMPLEMENT.is(implement_name()));
+        assert!(!IMPLEMENT.is(name));
+    }
+
+    #[test]
+    fn claims_after_250_ms_and_answers_requests() {
+        let name = Name::task_controller(1, 0);
+        let mut claim = AddressClaim::new(name, 0xF7);
+        let frame = claim.start(Duration::ZERO);
+        assert_eq!(frame.id, 0x18EE_FFF7);
+        assert_eq!(frame.data, name.0.to_le_bytes());
+        assert!(!claim.poll(249 * MS));
+        assert_eq!(claim.address(), None);
+        assert!(claim.poll(250 * MS));
+        assert_eq!(claim.address(), Some(0xF7));
+        let request = J1939Id::new(6, PGN_REQUEST, GLOBAL_ADDRESS, 0x81)
+            .frame([0x00, 0xEE, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(claim.on_frame(&request, 300 * MS), Some(frame));
+    }
+
+    #[test]
+    fn contention_defends_or_moves() {
+        let name = Name::task_controller(5, 0);
+        let mut claim = AddressClaim::new(name, 0xF7);
+        claim.start(Duration::ZERO);
+        claim.poll(250 * MS);
+        // A higher NAME is told the address is taken.
+        let higher = Name(name.0 + 1);
+        let reply = claim.on_frame(&claimed_by(higher, 0xF7), 300 * MS);
+        assert_eq!(reply.unwrap().id, 0x18EE_FFF7);
+        assert_eq!(claim.address(), Some(0xF7));
+        // A lower NAME takes it; the dynamic range wraps to 128.
+        let lower = Name(name.0 - 1);
+        let reply = claim.on_frame(&claimed_by(lower, 0xF7), 400 * MS);
+        assert_eq!(reply.unwrap().id, 0x18EE_FF80);
+        assert_eq!(claim.address(), None);
+        assert!(claim.poll(650 * MS));
+        assert_eq!(claim.address(), Some(0x80));
+
+        // Without self-configuration the node gives up.
+        let fixed = Name(name.0 & !(1 << 63));
+        let mut claim = AddressClaim::new(fixed, 0xF7);
+        claim.start(Duration::ZERO);
+        let reply = claim.on_frame(&claimed_by(Name(0), 0xF7), 10 * MS);
+        assert_eq!(reply.unwrap().id, 0x18EE_FFFE);
+        assert!(!claim.poll(Duration::from_secs(1)));
+        assert_eq!(claim.address(), None);
+        assert!(claim.lost());
+    }
+
+    #[test]
+    fn condensed_states() {
+        assert_eq!(condensed_work_state(&[]), u32::MAX);
+        assert_eq!(
+            condensed_work_state(&[true, false, true]),
+            0xFFFF_FFC0 | 0b01_00_01
+        );
+        assert_eq!(condensed_work_state(&[false; 16]), 0);
+    }
+
+    #[test]
+    fn section_control_messages() {
+        let claim = AddressClaim::new(Name::task_controller(1, 0), 0xF7);
+        // Four lanes on three sections; lanes 0 and 1 share section 1.
+        let mut node = SectionControl::new(claim, IMPLEMENT, 2, vec![0, 0, 1, 2], 500 * MS);
+        node.start(Duration::ZERO);
+        assert!(node
+            .set_lanes(&[false, true, false, true], 10 * MS)
+            .is_empty());
+        assert!(node.poll(100 * MS).is_empty(), "nothing before the claim");
+        assert_eq!(node.fault(), Some("ISOBUS address not claimed"));
+
+        // Claimed: the status goes out and every node is asked for its
+        // address claim.
+        let frames = node.poll(250 * MS);
+        assert_eq!(node.fault(), Some("ISOBUS implement not on the bus"));
+        assert_eq!(frames.len(), 2);
+        let status = decode(&frames[0]);
+        assert_eq!(
+            (status.0.destination, frames[0].data[0]),
+            (GLOBAL_ADDRESS, 0xFE)
+        );
+        assert_eq!(frames[1].id, 0x18EA_FFF7);
+        assert_eq!(frames[1].payload(), [0x00, 0xEE, 0x00]);
+
+        // The implement answers from 0x81 and its sections are taken over.
+        assert!(node
+            .on_frame(&claimed_by(implement_name(), 0x81), 260 * MS)
+            .is_empty());
+        assert_eq!(node.implement_address(), Some(0x81));
+        let frames = node.poll(260 * MS);
+        assert_eq!(node.fault(), None);
+        let decoded: Vec<_> = frames.iter().map(decode).collect();
+        let id = J1939Id::new(5, PGN_PROCESS_DATA, 0x81, 0xF7);
+        assert_eq!(
+            decoded,
+            [
+                (id, COMMAND_VALUE, 2, DDI_SECTION_CONTROL_STATE, 1),
+                (id, COMMAND_VALUE, 2, 290, 0xFFFF_FFC0 | 0b01_00_01),
+            ]
+        );
+
+        // Changes go out at once, repeats only on refresh.
+        let frames = node.set_lanes(&[true, false, true, false], 300 * MS);
+        assert_eq!(decode(&frames[0]).4, 0xFFFF_FFC0 | 0b00_01_01);
+        assert!(node
+            .set_lanes(&[true, false, true, false], 310 * MS)
+            .is_empty());
+        assert!(node.poll(799 * MS).is_empty());
+        assert_eq!(node.poll(800 * MS).len(), 1);
+        let frames = node.poll(Duration::from_millis(2250));
+        assert_eq!(frames.len(), 2, "status and refresh");
+
+        let frames = node.stop(Duration::from_secs(3));
+        let decoded: Vec<_> = frames.iter().map(|f| (decode(f).3, decode(f).4)).collect();
+        assert_eq!(
+            decoded,
+            [(290, 0xFFFF_FFC0), (DDI_SECTION_CONTROL_STATE, 0)]
+        );
+        assert!(node.stop(Duration::from_secs(3)).is_empty());
+    }
+
+    #[test]
+    fn follows_the_implement_by_name() {
+        let claim = AddressClaim::new(Name::task_controller(1, 0), 0xF7);
+        let mut node = SectionControl::new(claim, IMPLEMENT, 1, vec![0], Duration::from_secs(10));
+        node.start(Duration::ZERO);
+        // Another NAME at the address the implement later uses is ignored.
+        node.on_frame(&claimed_by(Name(5), 0x81), 10 * MS);
+        assert_eq!(node.implement_address(), None);
+        node.on_frame(&claimed_by(implement_name(), 0x81), 20 * MS);
+        node.poll(250 * MS);
+        node.set_lanes(&[true], 300 * MS);
+
+        // The implement moves: section control follows it there.
+        node.on_frame(&claimed_by(implement_name(), 0x90), 400 * MS);
+        let frames = node.poll(400 * MS);
+        let decoded: Vec<_> = frames.iter().map(decode).collect();
+        let id = J1939Id::new(5, PGN_PROCESS_DATA, 0x90, 0xF7);
+        assert_eq!(
+            decoded,
+            [
+                (id, COMMAND_VALUE, 1, DDI_SECTION_CONTROL_STATE, 1),
+                (id, COMMAND_VALUE, 1, 290, 0xFFFF_FFFD),
+            ]
+        );
+
+        // A lower NAME takes its address: nothing is commanded until the
+        // implement claims again.
+        node.on_frame(&claimed_by(Name(0), 0x90), 500 * MS);
+        assert_eq!(node.implement_address(), None);
+        assert_eq!(node.fault(), Some("ISOBUS implement not on the bus"));
+        assert!(node
+            .poll(Duration::from_secs(20))
+            .iter()
+            .all(|f| { J1939Id::from_raw(f.id).destination == GLOBAL_ADDRESS }));
+        assert!(node.set_lanes(&[false], Duration::from_secs(20)).is_empty());
+        assert!(node.stop(Duration::from_secs(20)).is_empty());
+    }
+
+    #[test]
+    fn many_sections_use_consecutive_ddis() {
+        let claim = AddressClaim::new(Name::task_controller(1, 0), 0xF7);
+        let mut node = SectionControl::new(claim, IMPLEMENT, 1, (0..20).collect(), MS);
+        node.start(Duration::ZERO);
+        node.on_frame(&claimed_by(implement_name(), 0x81), MS);
+        let mut lanes = vec![false; 20];
+        lanes[17] = true;
+        node.set_lanes(&lanes, MS);
+        let frames = node.poll(250 * MS);
+        let setpoints: Vec<_> = frames[3..]
+            .iter()
+            .map(|f| (decode(f).3, decode(f).4))
+            .collect();
+        assert_eq!(setpoints, [(290, 0), (291, 0xFFFF_FF00 | 0b01_00)]);
+    }
+
+    /// Bus recording sent frames, failing every send while `down`, and
+    /// delivering the frames queued in `inbox`.
+    #[derive(Default)]
+    struct Recorder {
+        sent: Mutex<Vec<CanFrame>>,
+        inbox: Mutex<Vec<CanFrame>>,
+        down: AtomicBool,
+    }
+
+    impl CanBus for Recorder {
+        fn send(&self, frame: &CanFrame) -> io::Result<()> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err(io::Error::other("bus off"));
+            }
+            self.sent.lock().unwrap().push(*frame);
+            Ok(())
+        }
+
+        fn recv(&self) -> io::Result<Option<CanFrame>> {
+            std::thread::sleep(2 * MS);
+            Ok(self.inbox.lock().unwrap().pop())
+        }
+    }
+
+    #[test]
+    fn nozzles_claim_then_command_and_release_sections() {
+        let bus = Arc::new(Recorder::default());
+        bus.inbox
+            .lock()
+            .unwrap()
+            .push(claimed_by(implement_name(), 0x81));
+        let claim = AddressClaim::new(Name::task_controller(1, 0), 0xF7);
+        let node = SectionControl::new(claim, IMPLEMENT, 1, vec![0, 1], Duration::from_secs(10));
+        let mut nozzles = IsobusNozzles::new(bus.clone(), node).unwrap();
+        nozzles.apply(&[false, false]);
+        assert_eq!(nozzles.fault(), Some("ISOBUS address not claimed"));
+        std::thread::sleep(Duration::from_millis(400));
+        nozzles.apply(&[true, false]);
+        assert_eq!(nozzles.fault(), None);
+        drop(nozzles);
+        let sent = std::mem::take(&mut *bus.sent.lock().unwrap());
+        let pgns: Vec<u32> = sent.iter().map(|f| J1939Id::from_raw(f.id).pgn).collect();
+        assert_eq!(
+            pgns,
+            [
+                PGN_ADDRESS_CLAIMED,
+                PGN_PROCESS_DATA,
+                PGN_REQUEST,
+                PGN_PROCESS_DATA,
+                PGN_PROCESS_DATA,
+                PGN_PROCESS_DATA,
+                PGN_PROCESS_DATA,
+                PGN_PROCESS_DATA
+            ]
+        );
+        let values: Vec<_> = sent[3..]
+            .iter()
+            .map(|f| (decode(f).3, decode(f).4))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                (DDI_SECTION_CONTROL_STATE, 1),
+                (290, 0xFFFF_FFF0),
+                (290, 0xFFFF_FFF1),
+                (290, 0xFFFF_FFF0),
+                (DDI_SECTION_CONTROL_STATE, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn nozzles_report_send_errors_and_a_lost_address() {
+        let bus = Arc::new(Recorder::default());
+        bus.inbox
+            .lock()
+            .unwrap()
+            .push(claimed_by(implement_name(), 0x81));
+        let name = Name(Name::task_controller(1, 0).0 & !(1 << 63));
+        let claim = AddressClaim::new(name, 0xF7);
+        let node = SectionControl::new(claim, IMPLEMENT, 1, vec![0, 1], Duration::from_secs(10));
+        let mut nozzles = IsobusNozzles::new(bus.clone(), node).unwrap();
+        std::thread::sleep(Duration::from_millis(400));
+        bus.down.store(true, Ordering::Relaxed);
+        nozzles.apply(&[true, false]);
+        assert_eq!(nozzles.fault(), Some("ISOBUS send failed: bus off"));
+        bus.down.store(false, Ordering::Relaxed);
+        nozzles.apply(&[true, true]);
+        assert_eq!(nozzles.fault(), None);
+
+        // A lower NAME takes the address of a node that cannot move.
+        bus.inbox.lock().unwrap().push(claimed_by(Name(0), 0xF7));
+        std::thread::sleep(Duration::from_millis(50));
+        nozzles.apply(&[false, true]);
+        assert_eq!(nozzles.fault(), Some("ISOBUS address lost"));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[ignore = "needs vcan0"]
+    fn task_controller_on_vcan0() {
+        use crate::can::CanSocket;
+        // Needs `ip link add dev vcan0 type vcan && ip link set up vcan0`.
+        let implement = CanSocket::open("vcan0").expect("vcan0");
+        implement.set_read_timeout(Duration::from_secs(1)).unwrap();
+        let socket = CanSocket::open("vcan0").unwrap();
+        socket.set_read_timeout(5 * MS).unwrap();
+        let claim = AddressClaim::new(Name::task_controller(7, 0), 0xF7);
+        let node = SectionControl::new(claim, IMPLEMENT, 1, vec![0, 1], Duration::from_secs(10));
+        let mut nozzles = IsobusNozzles::new(Arc::new(socket), node).unwrap();
+        let next = || loop {
+            let frame = implement.recv().unwrap().expect("no frame within 1 s");
+            let id = J1939Id::from_raw(frame.id);
+            if id.pgn != PGN_PROCESS_DATA || id.destination == 0x81 {
+                return frame;
+            }
+        };
+        assert_eq!(next().id, 0x18EE_FFF7);
+        // A lower NAME on the bus requests our address, so the task
+        // controller moves to the dynamic range.
+        implement.send(&claimed_by(Name(0), 0xF7)).unwrap();
+        assert_eq!(next().id, 0x18EE_FF80);
+        // Once claimed it asks for address claims, and the implement
+        // answers with its own.
+        assert_eq!(next().id, 0x18EA_FF80);
+        implement.send(&claimed_by(implement_name(), 0x81)).unwrap();
+        assert_eq!(decode(&next()).3, DDI_SECTION_CONTROL_STATE);
+        let (id, _, element, ddi, value) = decode(&next());
+        assert_eq!((id.source, id.destination, element), (0x80, 0x81, 1));
+        assert_eq!((ddi, value), (290, 0xFFFF_FFF0));
+        nozzles.apply(&[false, true]);
+        assert_eq!(decode(&next()).4, 0xFFFF_FFF4);
+        drop(nozzles);
+        assert_eq!(decode(&next()).4, 0xFFFF_FFF0);
+        assert_eq!(decode(&next()).3, DDI_SECTION_CONTROL_STATE);
+    }
+}
@@ -1,15 +1,247 @@
 //! Lane reduction with hysteresis.
+//!
+//! Lanes are equal-width vertical strips by default, or arbitrary regions
+//! ([`LaneGeometry`]): explicit column boundaries, or polygons such as the
+//! trapezoidal nozzle footprints of an angled camera. The geometry is
+//! rasterised once per frame size into a [`LaneLayout`] of per-row spans.
+//! An optional region of interest ([`Roi`]) restricts both detection and
+//! lanes to part of the frame, an optional [`MaskFilter`] removes speckle
+//! from the mask, and an optional [`BlobDetector`] keeps only pixels of
+//! blobs large enough to be weeds before the mask is counted.
 
-/// Reduces a mask into fixed lanes and applies hysteresis.
+use crate::blobs::{Blob, BlobDetector};
+use crate::config::Config;
+use crate::crop_rows::CropRowTracker;
+use crate::morphology::{BitMask, MaskFilter};
+use crate::vision::{Rect, Roi, Scorer};
+use std::ops::Range;
+
+/// Outcome of one lane for one frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LaneStatus {
+    /// Fraction of the lane's pixels classified as vegetation.
+    pub ratio: f32,
+    /// Number of vegetation pixels in the lane.
+    pub pixels: u32,
+    /// Lane state before this frame.
+    pub was_on: bool,
+    /// Lane state after applying the thresholds with hysteresis.
+    pub on: bool,
+}
+
+/// Shape of the lanes in the image.
+///
+/// Coordinates are pixels of a reference frame of `width` x `height`
+/// (normally `[camera]` width/height) and are scaled proportionally for
+/// frames of any other size.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LaneGeometry {
+    /// Equal-width vertical strips spanning the frame; the first
+    /// `width % lanes` lanes are one column wider.
+    Equal,
+    /// Vertical strips between `lanes + 1` increasing column edges.
+    /// Columns outside the first and last edge belong to no lane.
+    Columns {
+        edges: Vec<f32>,
+        width: usize,
+        height: usize,
+    },
+    /// One polygon per lane, as `[x, y]` vertices (even-odd fill; a pixel
+    /// belongs to a lane when its centre is inside). Polygons may leave
+    /// pixels unassigned or overlap.
+    Polygons {
+        polygons: Vec<Vec<[f32; 2]>>,
+        width: usize,
+        height: usize,
+    },
+}
+
+/// Lane geometry rasterised for one frame size: the column spans each
+/// lane covers on every row of the region, and each lane's total pixel
+/// count. Rows and columns are relative to the region.
+#[derive(Debug, Clone)]
+pub struct LaneLayout {
+    lanes: usize,
+    width: usize,
+    height: usize,
+    region: Rect,
+    /// `spans[offsets[y * lanes + lane]..offsets[y * lanes + lane + 1]]`
+    /// are the half-open `(start, end)` column spans of `lane` on region
+    /// row `y`.
+    offsets: Vec<usize>,
+    spans: Vec<(usize, usize)>,
+    totals: Vec<u32>,
+}
+
+impl LaneLayout {
+    /// Rasterise `geometry` with `lanes` lanes for the `region` of a
+    /// `width` x `height` frame. Equal lanes split the region; column and
+    /// polygon lanes are placed on the frame and clipped to the region.
+    pub fn new(
+        geometry: &LaneGeometry,
+        lanes: usize,
+        width: usize,
+        height: usize,
+        region: Rect,
+    ) -> Self {
+        let mut offsets = Vec::with_capacity(region.height * lanes + 1);
+        let mut spans = Vec::new();
+        let mut totals = vec![0u32; lanes];
+        offsets.push(0);
+        let mut crossings = Vec::new();
+        let columns = region.x..region.x + region.width;
+        for y in 0..region.height {
+            for (lane, total) in totals.iter_mut().enumerate() {
+                let first = spans.len();
+                match geometry {
+                    LaneGeometry::Equal => {
+                        let (x_start, lane_width) = equal_span(lanes, region.width, lane);
+                        spans.push((x_start, x_start + lane_width));
+                    }
+                    LaneGeometry::Columns {
+                        edges,
+                        width: ref_width,
+                        ..
+                    } => {
+                        let scale = width as f32 / *ref_width as f32;
+                        let column = |edge: f32| {
+                            ((edge * scale).round().max(0.0) as usize)
+                                .clamp(columns.start, columns.end)
+                                - columns.start
+                        };
+                        spans.push((column(edges[lane]), column(edges[lane + 1])));
+                    }
+                    LaneGeometry::Polygons {
+                        polygons,
+                        width: ref_width,
+                        height: ref_height,
+                    } => {
+                        let scale = [
+                            width as f32 / *ref_width as f32,
+                            height as f32 / *ref_height as f32,
+                        ];
+                        polygon_row_spans(
+                            &polygons[lane],
+                            scale,
+                            region.y + y,
+                            columns.clone(),
+                            &mut crossings,
+                            &mut spans,
+                        );
+                    }
+                }
+                *total += spans[first..]
+                    .iter()
+                    .map(|&(start, end)| (end - start) as u32)
+                    .sum::<u32>();
+                offsets.push(spans.len());
+            }
+        }
+        Self {
+            lanes,
+            width,
+            height,
+            region,
+            offsets,
+            spans,
+            totals,
+        }
+    }
+
+    /// Number of lanes.
+    pub fn lane_count(&self) -> usize {
+        self.lanes
+    }
+
+    /// Frame size this layout was rasterised for.
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Part of the frame the lanes are counted over.
+    pub fn region(&self) -> Rect {
+        self.region
+    }
+
+    /// Number of pixels in `lane`.
+    pub fn lane_pixels(&self, lane: usize) -> u32 {
+        self.totals[lane]
+    }
+
+    /// Half-open `(start, end)` column spans of `lane` on region row `y`.
+    pub fn row_spans(&self, y: usize, lane: usize) -> &[(usize, usize)] {
+        let i = y * self.lanes + lane;
+        &self.spans[self.offsets[i]..self.offsets[i + 1]]
+    }
+
+    /// Add the vegetation pixels of region row `y` (a mask row
+    /// `region().width` long) to the per-lane `counts`.
+    ///
+    /// Rows can be counted in any order (or on different threads, then
+    /// summed) — [`LaneReducer::reduce_counts`] only needs the totals.
+    pub fn count_row(&self, y: usize, row: &[bool], counts: &mut [u32]) {
+        assert_eq!(
+            row.len(),
+            self.region.width,
+            "Row length must equal region width"
+        );
+        assert_eq!(counts.len(), self.lanes, "One count per lane required");
+        for (lane, count) in counts.iter_mut().enumerate() {
+            for &(start, end) in self.row_spans(y, lane) {
+                *count += row[start..end].iter().filter(|&&px| px).count() as u32;
+            }
+        }
+    }
+
+    /// Add the vegetation pixels of a packed region-sized `mask` to the
+    /// per-lane `counts`.
+    pub fn count_bits(&self, mask: &BitMask, counts: &mut [u32]) {
+        assert_eq!(
+            (mask.width(), mask.height()),
+            (self.region.width, self.region.height),
+            "Mask size must equal region size"
+        );
+        assert_eq!(counts.len(), self.lanes, "One count per lane required");
+        for y in 0..self.region.height {
+            for (lane, count) in counts.iter_mut().enumerate() {
+                for &(start, end) in self.row_spans(y, lane) {
+                    *count += mask.count_span(y, start, end);
+                }
+            }
+        }
+    }
+}
+
+/// Reduces a mask into lanes and applies hysteresis.
 pub struct LaneReducer {
     lanes: usize,
-    on: f32,
-    off: f32,
+    on: Vec<f32>,
+    off: Vec<f32>,
+    enabled: Vec<bool>,
     state: Vec<bool>,
+    status: Vec<LaneStatus>,
+    geometry: LaneGeometry,
+    roi: Option<Roi>,
+    filter: Option<MaskFilter>,
+    blob_detector: Option<BlobDetector>,
+    /// Blobs of the last frame, in frame coordinates.
+    blobs: Vec<Blob>,
+    crop_rows: Option<CropRowTracker>,
+    /// Crop row centres of the last frame, in frame columns.
+    rows: Vec<f32>,
+    /// Layout for the most recent frame size, rebuilt when it changes.
+    layout: Option<LaneLayout>,
+    /// Scratch for [`Self::reduce_frame`], reused across frames.
+    row_mask: Vec<bool>,
+    counts: Vec<u32>,
+    /// Packed region mask and filter scratch, used only with a filter,
+    /// crop row tracker or blob detector.
+    bits: BitMask,
+    scratch: BitMask,
 }
 
 impl LaneReducer {
-    /// Create a new reducer.
+    /// Create a new reducer with equal-width lanes.
     ///
     /// * `lanes` - number of lanes.
     /// * `on` - ratio threshold to switch lane on.
@@ -18,9 +250,144 @@ impl LaneReducer {
         assert!(lanes > 0, "Number of lanes must be greater than 0");
         Self {
             lanes,
-            on,
-            off,
+            on: vec![on; lanes],
+            off: vec![off; lanes],
+            enabled: vec![true; lanes],
             state: vec![false; lanes],
+            status: vec![LaneStatus::default(); lanes],
+            geometry: LaneGeometry::Equal,
+            roi: None,
+            filter: None,
+            blob_detector: None,
+            blobs: Vec::new(),
+            crop_rows: None,
+            rows: Vec::new(),
+            layout: None,
+            row_mask: Vec::new(),
+            counts: vec![0; lanes],
+            bits: BitMask::default(),
+            scratch: BitMask::default(),
+        }
+    }
+
+    /// Create a reducer from the config: `[lanes]` count, thresholds,
+    /// per-lane overrides and disabled lanes, plus the lane geometry
+    /// the `[vision.roi]` region (both relative to the `[camera]` frame
+    /// size), the `[vision.morphology]` noise filter, `[vision.crop_rows]`
+    /// tracking and the `[vision.blobs]` stage.
+    pub fn from_config(cfg: &Config) -> Self {
+        let lanes = &cfg.lanes;
+        let mut reducer = Self::new(lanes.count, lanes.on_threshold, lanes.off_threshold)
+            .with_geometry(lanes.geometry(cfg.camera.width, cfg.camera.height));
+        if let Some(roi) = cfg.vision.roi(cfg.camera.width, cfg.camera.height) {
+            reducer = reducer.with_roi(roi);
+        }
+        if let Some(filter) = cfg.vision.mask_filter() {
+            reducer = reducer.with_filter(filter);
+        }
+        if let Some(tracker) = cfg.vision.crop_row_tracker(cfg.camera.width) {
+            reducer = reducer.with_crop_rows(tracker);
+        }
+        if cfg.vision.blobs.enabled {
+            reducer = reducer.with_blobs(cfg.vision.blobs.min_area);
+        }
+        for lane in 0..lanes.count {
+            reducer.set_thresholds(
+                lane,
+                lanes.on_threshold_for(lane),
+                lanes.off_threshold_for(lane),
+            );
+            reducer.set_enabled(lane, lanes.is_enabled(lane));
+        }
+        reducer
+    }
+
+    /// Use `geometry` instead of equal-width strips.
+    ///
+    /// # Panics
+    /// Panics if the geometry does not describe exactly one region per
+    /// lane.
+    pub fn with_geometry(mut self, geometry: LaneGeometry) -> Self {
+        let regions = match &geometry {
+            LaneGeometry::Equal => self.lanes,
+            LaneGeometry::Columns { edges, .. } => edges.len().saturating_sub(1),
+            LaneGeometry::Polygons { polygons, .. } => polygons.len(),
+        };
+        assert_eq!(
+            regions, self.lanes,
+            "Lane geometry must define one region per lane"
+        );
+        self.geometry = geometry;
+        self.layout = None;
+        self
+    }
+
+    /// Only detect and count pixels inside `roi`.
+    pub fn with_roi(mut self, roi: Roi) -> Self {
+        self.roi = Some(roi);
+        self.layout = None;
+        self
+    }
+
+    /// Filter the mask (opening/closing) before counting lane pixels.
+    pub fn with_filter(mut self, filter: MaskFilter) -> Self {
+        self.filter = (!filter.is_noop()).then_some(filter);
+        self
+    }
+
+    /// The mask filter, if any.
+    pub fn filter(&self) -> Option<MaskFilter> {
+        self.filter
+    }
+
+    /// Track crop rows in the (filtered) mask and clear the vegetation
+    /// inside each row band before blobs are labelled and lanes counted,
+    /// so only weeds between the rows can trigger a lane.
+    pub fn with_crop_rows(mut self, tracker: CropRowTracker) -> Self {
+        self.crop_rows = Some(tracker);
+        self
+    }
+
+    /// Crop row centres found in the last frame, as frame columns, or
+    /// `None` without crop row tracking.
+    pub fn crop_rows(&self) -> Option<&[f32]> {
+        self.crop_rows.as_ref().map(|_| self.rows.as_slice())
+    }
+
+    /// Label blobs in the (filtered) mask and count only pixels of blobs
+    /// of at least `min_area` pixels towards lane coverage, so scattered
+    /// specks no longer add up to a lane trigger.
+    pub fn with_blobs(mut self, min_area: u32) -> Self {
+        self.blob_detector = Some(BlobDetector::new(min_area));
+        self
+    }
+
+    /// Blobs kept in the last frame, in frame coordinates, or `None`
+    /// without a blob stage.
+    pub fn blobs(&self) -> Option<&[Blob]> {
+        self.blob_detector.as_ref().map(|_| self.blobs.as_slice())
+    }
+
+    /// Whether reduction goes through a packed region mask
+    /// ([`Self::reduce_bits`]) rather than streaming row counts: true with
+    /// a mask filter, crop row tracker or blob stage, which need the
+    /// whole mask.
+    pub fn needs_full_mask(&self) -> bool {
+        self.filter.is_some() || self.crop_rows.is_some() || self.blob_detector.is_some()
+    }
+
+    /// Override the on/off ratio thresholds of a single lane.
+    pub fn set_thresholds(&mut self, lane: usize, on: f32, off: f32) {
+        self.on[lane] = on;
+        self.off[lane] = off;
+    }
+
+    /// Enable or disable a lane. A disabled lane is forced off (and is
+    /// switched off immediately) whatever its coverage.
+    pub fn set_enabled(&mut self, lane: usize, enabled: bool) {
+        self.enabled[lane] = enabled;
+        if !enabled {
+            self.state[lane] = false;
         }
     }
 
@@ -29,6 +396,55 @@ impl LaneReducer {
         self.lanes
     }
 
+    /// Current lane states, as decided by the last reduction.
+    pub fn lanes(&self) -> &[bool] {
+        &self.state
+    }
+
+    /// Per-lane detail (coverage ratio, pixel count, previous and new
+    /// state) from the last reduction.
+    pub fn status(&self) -> &[LaneStatus] {
+        &self.status
+    }
+
+    /// Part of a `width` x `height` frame that is detected and counted:
+    /// the region of interest, or the whole frame.
+    pub fn region(&self, width: usize, height: usize) -> Rect {
+        self.roi
+            .map_or(Rect::full(width, height), |roi| roi.rect(width, height))
+    }
+
+    /// Whether a `width` x `height` frame leaves at least one region
+    /// column per lane. Reducing a frame that does not panics.
+    pub fn accepts(&self, width: usize, height: usize) -> bool {
+        self.region(width, height).width >= self.lanes
+    }
+
+    /// Lane layout for a `width` x `height` frame, rasterised on first use
+    /// and cached until the frame size changes.
+    pub fn layout(&mut self, width: usize, height: usize) -> &LaneLayout {
+        assert!(
+            width >= self.lanes,
+            "Width must be greater than or equal to number of lanes"
+        );
+        assert!(
+            self.accepts(width, height),
+            "Region of interest must be at least one column per lane wide"
+        );
+        let stale = self.layout.as_ref().map(LaneLayout::size) != Some((width, height));
+        if stale {
+            let region = self.region(width, height);
+            self.layout = Some(LaneLayout::new(
+                &self.geometry,
+                self.lanes,
+                width,
+                height,
+                region,
+            ));
+        }
+        self.layout.as_ref().unwrap()
+    }
+
     /// Reduce the mask given image width/height.
     pub fn reduce(&mut self, mask: &[bool], width: usize, height: usize) -> Vec<bool> {
         assert!(
@@ -41,38 +457,196 @@ impl LaneReducer {
             "Mask length must equal width * height"
         );
 
-        let base_width = width / self.lanes;
-        let remainder = width % self.lanes;
-        let mut x_start = 0usize;
-        let mut out = vec![false; self.lanes];
-        for (lane, lane_out) in out.iter_mut().enumerate().take(self.lanes) {
-            let lane_width = base_width + usize::from(lane < remainder);
-            let mut count = 0u32;
-            for y in 0..height {
-                let start = y * width + x_start;
-                let end = start + lane_width;
-                for &px in &mask[start..end] {
-                    if px {
-                        count += 1;
-                    }
-                }
+        let region = self.layout(width, height).region();
+        if self.needs_full_mask() {
+            let mut bits = std::mem::take(&mut self.bits);
+            bits.resize(region.width, region.height);
+            for y in 0..region.height {
+                bits.set_row(y, region.row(mask, width, 1, y));
             }
-            let total = (lane_width * height) as f32;
+            self.reduce_bits(&mut bits, width, height);
+            self.bits = bits;
+            return self.state.clone();
+        }
+        let layout = self.layout.as_ref().unwrap();
+        let mut counts = vec![0u32; layout.lanes];
+        for y in 0..region.height {
+            layout.count_row(y, region.row(mask, width, 1, y), &mut counts);
+        }
+        self.reduce_counts(&counts, width, height);
+        self.state.clone()
+    }
+
+    /// Classify an interleaved frame (`scorer.channels()` bytes per
+    /// pixel) with `scorer` and reduce it in a single pass.
+    ///
+    /// Only the region of interest is scored. Each row is scored into a
+    /// reused row-sized scratch mask and counted immediately, so no
+    /// frame-sized mask is built and nothing is allocated once the first
+    /// frame of a given size has been seen. With a mask filter or blob
+    /// stage the rows are packed into a region-sized bit mask instead,
+    /// which is filtered, cleared of small blobs and then counted.
+    /// Decisions are identical to `reduce(&scorer.detect(rgb), ..)`.
+    pub fn reduce_frame<S: Scorer + ?Sized>(
+        &mut self,
+        rgb: &[u8],
+        width: usize,
+        height: usize,
+        scorer: &S,
+    ) -> &[LaneStatus] {
+        let channels = scorer.channels();
+        assert_eq!(
+            rgb.len(),
+            width * height * channels,
+            "Frame length must match width * height * {channels}"
+        );
+
+        self.layout(width, height);
+        let layout = self.layout.as_ref().unwrap();
+        let region = layout.region();
+        self.row_mask.resize(region.width, false);
+        if self.needs_full_mask() {
+            let mut bits = std::mem::take(&mut self.bits);
+            bits.resize(region.width, region.height);
+            for y in 0..region.height {
+                scorer.detect_into(region.row(rgb, width, channels, y), &mut self.row_mask);
+                bits.set_row(y, &self.row_mask);
+            }
+            self.reduce_bits(&mut bits, width, height);
+            self.bits = bits;
+            return &self.status;
+        }
+        self.counts.fill(0);
+        for y in 0..region.height {
+            scorer.detect_into(region.row(rgb, width, channels, y), &mut self.row_mask);
+            layout.count_row(y, &self.row_mask, &mut self.counts);
+        }
+        let counts = std::mem::take(&mut self.counts);
+        self.apply_hysteresis(&counts);
+        self.counts = counts;
+        &self.status
+    }
+
+    /// Apply the on/off thresholds with hysteresis to per-lane pixel
+    /// counts accumulated over a `width` x `height` frame (see
+    /// [`LaneLayout::count_row`]).
+    pub fn reduce_counts(&mut self, counts: &[u32], width: usize, height: usize) -> &[LaneStatus] {
+        assert_eq!(counts.len(), self.lanes, "One count per lane required");
+        self.layout(width, height);
+        self.apply_hysteresis(counts);
+        &self.status
+    }
+
+    /// Filter, mask out crop rows and label blobs (if configured), then
+    /// reduce a packed mask of the region of a `width` x `height` frame.
+    /// The mask is filtered, and cleared of crop rows and of blobs below
+    /// the minimum area, in place.
+    pub fn reduce_bits(
+        &mut self,
+        bits: &mut BitMask,
+        width: usize,
+        height: usize,
+    ) -> &[LaneStatus] {
+        self.layout(width, height);
+        if let Some(filter) = self.filter {
+            filter.apply(bits, &mut self.scratch);
+        }
+        let layout = self.layout.as_ref().unwrap();
+        if let Some(tracker) = &mut self.crop_rows {
+            let x = layout.region().x as f32;
+            self.rows.clear();
+            self.rows
+                .extend(tracker.update(bits, width).iter().map(|&centre| centre + x));
+            tracker.mask_out(bits);
+        }
+        if let Some(detector) = &mut self.blob_detector {
+            let region = layout.region();
+            self.blobs.clear();
+            self.blobs.extend(
+                detector
+                    .retain(bits)
+                    .iter()
+                    .map(|blob| blob.offset(region.x, region.y)),
+            );
+        }
+        let mut counts = std::mem::take(&mut self.counts);
+        counts.fill(0);
+        layout.count_bits(bits, &mut counts);
+        self.apply_hysteresis(&counts);
+        self.counts = counts;
+        &self.status
+    }
+
+    /// Update lane states from `counts` against the current layout.
+    fn apply_hysteresis(&mut self, counts: &[u32]) {
+        let layout = self
+            .layout
+            .as_ref()
+            .expect("layout is built before reducing");
+        let lanes = self.state.iter_mut().zip(&mut self.status);
+        for (lane, ((state, status), &count)) in lanes.zip(counts).enumerate() {
+            let total = layout.lane_pixels(lane) as f32;
             let ratio = if total > 0.0 {
                 count as f32 / total
             } else {
                 0.0
             };
-            let is_on = if self.state[lane] {
-                ratio > self.off
-            } else {
-                ratio > self.on
+            let was_on = *state;
+            *state = self.enabled[lane]
+                && if was_on {
+                    ratio > self.off[lane]
+                } else {
+                    ratio > self.on[lane]
+                };
+            *status = LaneStatus {
+                ratio,
+                pixels: count,
+                was_on,
+                on: *state,
             };
-            *lane_out = is_on;
-            x_start += lane_width;
         }
-        self.state.clone_from_slice(&out);
-        out
+    }
+}
+
+/// `(x_start, lane_width)` of `lane` among equal-width strips. The first
+/// `width % lanes` lanes are one column wider.
+fn equal_span(lanes: usize, width: usize, lane: usize) -> (usize, usize) {
+    let base_width = width / lanes;
+    let remainder = width % lanes;
+    let x_start = lane * base_width + lane.min(remainder);
+    (x_start, base_width + usize::from(lane < remainder))
+}
+
+/// Append the spans of frame row `y` whose pixel centres lie inside
+/// `polygon` (even-odd rule) after scaling its vertices by `scale`,
+/// clipped to `columns` and relative to its start.
+fn polygon_row_spans(
+    polygon: &[[f32; 2]],
+    scale: [f32; 2],
+    y: usize,
+    columns: Range<usize>,
+    crossings: &mut Vec<f32>,
+    out: &mut Vec<(usize, usize)>,
+) {
+    let yc = y as f32 + 0.5;
+    crossings.clear();
+    for (i, &[x0, y0]) in polygon.iter().enumerate() {
+        let [x1, y1] = polygon[(i + 1) % polygon.len()];
+        let (x0, y0, x1, y1) = (x0 * scale[0], y0 * scale[1], x1 * scale[0], y1 * scale[1]);
+        if (y0 <= yc) != (y1 <= yc) {
+            crossings.push(x0 + (yc - y0) * (x1 - x0) / (y1 - y0));
+        }
+    }
+    crossings.sort_by(f32::total_cmp);
+    // Pixel x is inside [a, b) when its centre x + 0.5 is.
+    let column = |edge: f32| {
+        ((edge - 0.5).ceil().max(0.0) as usize).clamp(columns.start, columns.end) - columns.start
+    };
+    for pair in crossings.chunks_exact(2) {
+        let (start, end) = (column(pair[0]), column(pair[1]));
+        if start < end {
+            out.push((start, end));
+        }
     }
 }
 
@@ -99,6 +673,160 @@ mod tests {
         assert_eq!(lanes, vec![false, false]);
     }
 
+    #[test]
+    fn status_reports_ratio_pixels_and_transition() {
+        let (width, height) = (4, 2);
+        let mut reducer = LaneReducer::new(2, 0.5, 0.25);
+        // Lane 0: 3 of 4 pixels; lane 1: 1 of 4.
+        let mask = [true, true, false, false, true, false, false, true];
+        reducer.reduce(&mask, width, height);
+        assert_eq!(
+            reducer.status(),
+            [
+                LaneStatus {
+                    ratio: 0.75,
+                    pixels: 3,
+                    was_on: false,
+                    on: true
+                },
+                LaneStatus {
+                    ratio: 0.25,
+                    pixels: 1,
+                    was_on: false,
+                    on: false
+                },
+            ],
+        );
+        // Lane 0 drops to 1 of 4 pixels: 0.25 is not above the 0.25 off
+        // threshold, so it switches off.
+        let mask = [true, false, false, false, false, false, false, false];
+        reducer.reduce(&mask, width, height);
+        let lane0 = reducer.status()[0];
+        assert_eq!((lane0.pixels, lane0.was_on, lane0.on), (1, true, false));
+        assert!((lane0.ratio - 0.25).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn per_lane_thresholds_and_disabled_lanes() {
+        let (width, height) = (6, 1);
+        let mut reducer = LaneReducer::new(3, 0.4, 0.2);
+        reducer.set_thresholds(0, 0.9, 0.6);
+        reducer.set_enabled(2, false);
+        // Every lane at 50% coverage: lane 0 is below its stricter 0.9,
+        // lane 1 uses the global 0.4, lane 2 is disabled.
+        let mask = [true, false, true, false, true, false];
+        assert_eq!(
+            reducer.reduce(&mask, width, height),
+            vec![false, true, false]
+        );
+        let disabled = reducer.status()[2];
+        assert!((disabled.ratio - 0.5).abs() < f32::EPSILON);
+        assert!(!disabled.on);
+    }
+
+    #[test]
+    fn from_config_applies_overrides() {
+        let cfg: Config = toml::from_str(
+            r#"
+[lanes]
+count = 2
+on_thresholds = [0.3, 0.8]
+enabled = [false, true]
+"#,
+        )
+        .unwrap();
+        let mut reducer = LaneReducer::from_config(&cfg);
+        // Lane 1 at 50% stays below its 0.8 override; lane 0 is disabled.
+        assert_eq!(
+            reducer.reduce(&[true, true, true, false], 4, 1),
+            vec![false, false]
+        );
+        assert_eq!(
+            reducer.reduce(&[true, true, true, true], 4, 1),
+            vec![false, true]
+        );
+    }
+
+    /// Mask with a vertical green bar over columns `bar`.
+    fn bar_mask(width: usize, height: usize, bar: std::ops::Range<usize>) -> Vec<bool> {
+        (0..width * height)
+            .map(|i| bar.contains(&(i % width)))
+            .collect()
+    }
+
+    #[test]
+    fn column_boundaries_define_uneven_lanes() {
+        let (width, height) = (10, 2);
+        let mut reducer = LaneReducer::new(3, 0.5, 0.25).with_geometry(LaneGeometry::Columns {
+            edges: vec![1.0, 3.0, 8.0, 10.0],
+            width,
+            height,
+        });
+        let layout = reducer.layout(width, height);
+        assert_eq!(
+            (0..3).map(|l| layout.lane_pixels(l)).collect::<Vec<_>>(),
+            vec![4, 10, 4],
+        );
+        // Green over columns 3..6 is 3/5 of lane 1 but would only be 30%
+        // of an equal-width middle strip.
+        assert_eq!(
+            reducer.reduce(&bar_mask(width, height, 3..6), width, height),
+            vec![false, true, false],
+        );
+    }
+
+    #[test]
+    fn rectangle_polygons_match_equal_strips() {
+        let (width, height) = (12, 5);
+        let rect = |x0: f32, x1: f32| vec![[x0, 0.0], [x1, 0.0], [x1, 5.0], [x0, 5.0]];
+        let mut polygons = LaneReducer::new(3, 0.3, 0.15).with_geometry(LaneGeometry::Polygons {
+            polygons: vec![rect(0.0, 4.0), rect(4.0, 8.0), rect(8.0, 12.0)],
+            width,
+            height,
+        });
+        let mut equal = LaneReducer::new(3, 0.3, 0.15);
+        for bar in [0..2, 3..9, 5..12, 0..0] {
+            let mask = bar_mask(width, height, bar.clone());
+            assert_eq!(
+                polygons.reduce(&mask, width, height),
+                equal.reduce(&mask, width, height),
+                "bar {bar:?}",
+            );
+            assert_eq!(polygons.status(), equal.status());
+        }
+    }
+
+    #[test]
+    fn trapezoid_lane_follows_perspective() {
+        // Lane 0 is a trapezoid 2 px wide at the top, widening to 6 px at
+        // the bottom of an 8x4 reference frame. A pixel is in the lane when
+        // its centre is.
+        let trapezoid = vec![[3.0, 0.0], [5.0, 0.0], [7.0, 4.0], [1.0, 4.0]];
+        let geometry = LaneGeometry::Polygons {
+            polygons: vec![trapezoid],
+            width: 8,
+            height: 4,
+        };
+        let layout = LaneLayout::new(&geometry, 1, 8, 4, Rect::full(8, 4));
+        let rows: Vec<_> = (0..4).map(|y| layout.row_spans(y, 0).to_vec()).collect();
+        assert_eq!(
+            rows,
+            vec![vec![(3, 5)], vec![(2, 6)], vec![(2, 6)], vec![(1, 7)]]
+        );
+        assert_eq!(layout.lane_pixels(0), 16);
+
+        // The same geometry scales with the frame: area 16 -> 64.
+        let doubled = LaneLayout::new(&geometry, 1, 16, 8, Rect::full(16, 8));
+        assert_eq!(doubled.lane_pixels(0), 64);
+    }
+
+    #[test]
+    fn layout_is_rebuilt_when_frame_size_changes() {
+        let mut reducer = LaneReducer::new(2, 0.5, 0.25);
+        assert_eq!(reducer.layout(4, 1).lane_pixels(0), 2);
+        assert_eq!(reducer.layout(6, 3).lane_pixels(0), 9);
+    }
+
     #[test]
     #[should_panic(expected = "Number of lanes must be greater than 0")]
     fn test_zero_lanes_panics() {
@@ -132,4 +860,262 @@ mod tests {
         let lanes = reducer.reduce(&mask, width, height);
         assert_eq!(lanes, vec![true, false]);
     }
+
+    #[test]
+    fn counted_rows_reduce_like_the_mask() {
+        let (width, height) = (7, 3);
+        let mask: Vec<bool> = (0..width * height).map(|i| i % 3 != 0).collect();
+        let mut from_mask = LaneReducer::new(3, 0.5, 0.25);
+        let mut from_counts = LaneReducer::new(3, 0.5, 0.25);
+        let mut counts = vec![0u32; 3];
+        // Rows in reverse order: counting is order-independent.
+        let layout = from_counts.layout(width, height);
+        for (y, row) in mask.chunks_exact(width).enumerate().rev() {
+            layout.count_row(y, row, &mut counts);
+        }
+        assert_eq!(counts, vec![6, 4, 4]);
+        let expected = from_mask.reduce(&mask, width, height);
+        from_counts.reduce_counts(&counts, width, height);
+        assert_eq!(from_counts.lanes(), expected);
+    }
+
+    #[test]
+    fn reduce_frame_matches_detect_then_reduce() {
+        use crate::vision::PlantVision;
+
+        let (width, height) = (9, 4);
+        let vision = PlantVision::default();
+        let mut streaming = LaneReducer::new(3, 0.4, 0.2);
+        let mut masked = LaneReducer::new(3, 0.4, 0.2);
+        // Green fills a growing number of leading columns, then recedes,
+        // so lanes switch on and back off through the hysteresis band.
+        for green_cols in [0, 3, 5, 9, 6, 4, 1, 0] {
+            let mut frame = Vec::new();
+            for _ in 0..height {
+                for x in 0..width {
+                    let px = if x < green_cols {
+                        [40, 210, 40]
+                    } else {
+                        [120, 90, 70]
+                    };
+                    frame.extend_from_slice(&px);
+                }
+            }
+            let expected = masked.reduce(&vision.detect(&frame), width, height);
+            streaming.reduce_frame(&frame, width, height, &vision);
+            assert_eq!(streaming.lanes(), expected, "{green_cols} green columns");
+        }
+    }
+
+    fn margins(top: usize, bottom: usize, left: usize, right: usize) -> Roi {
+        Roi {
+            top,
+            bottom,
+            left,
+            right,
+            ref_width: 8,
+            ref_height: 4,
+        }
+    }
+
+    #[test]
+    fn roi_ignores_pixels_outside_region() {
+        let (width, height) = (8, 4);
+        let mut reducer = LaneReducer::new(2, 0.5, 0.25).with_roi(margins(1, 1, 2, 2));
+        // Everything outside the central 4x2 region is green.
+        let mask: Vec<bool> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                !(2..6).contains(&x) || !(1..3).contains(&y)
+            })
+            .collect();
+        assert_eq!(reducer.reduce(&mask, width, height), vec![false, false]);
+        assert_eq!(reducer.layout(width, height).lane_pixels(0), 4);
+        // Green only in the left half of the region.
+        let mask: Vec<bool> = (0..width * height)
+            .map(|i| (2..4).contains(&(i % width)) && (1..3).contains(&(i / width)))
+            .collect();
+        assert_eq!(reducer.reduce(&mask, width, height), vec![true, false]);
+        assert_eq!(reducer.status()[0].pixels, 4);
+    }
+
+    #[test]
+    fn roi_scales_with_frame_size() {
+        let roi = margins(1, 0, 2, 2);
+        let rect = roi.rect(16, 8);
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (4, 2, 8, 6));
+        let reducer = LaneReducer::new(4, 0.5, 0.25).with_roi(margins(0, 0, 3, 2));
+        assert!(!reducer.accepts(8, 4));
+        assert!(reducer.accepts(16, 8));
+    }
+
+    #[test]
+    fn roi_clips_columns_and_polygons() {
+        let region = margins(0, 1, 2, 0).rect(8, 4);
+        let columns = LaneGeometry::Columns {
+            edges: vec![0.0, 4.0, 8.0],
+            width: 8,
+            height: 4,
+        };
+        let layout = LaneLayout::new(&columns, 2, 8, 4, region);
+        assert_eq!(layout.row_spans(0, 0), [(0, 2)]);
+        assert_eq!(layout.row_spans(0, 1), [(2, 6)]);
+        assert_eq!((layout.lane_pixels(0), layout.lane_pixels(1)), (6, 12));
+
+        let polygons = LaneGeometry::Polygons {
+            polygons: vec![vec![[0.0, 0.0], [8.0, 0.0], [8.0, 4.0], [0.0, 4.0]]],
+            width: 8,
+            height: 4,
+        };
+        let layout = LaneLayout::new(&polygons, 1, 8, 4, region);
+        assert_eq!(layout.row_spans(2, 0), [(0, 6)]);
+        assert_eq!(layout.lane_pixels(0), 18);
+    }
+
+    #[test]
+    fn reduce_frame_with_roi_matches_masked_reduce() {
+        use crate::vision::PlantVision;
+
+        let (width, height) = (8, 4);
+        let vision = PlantVision::default();
+        let roi = margins(1, 0, 1, 3);
+        let mut streaming = LaneReducer::new(2, 0.4, 0.2).with_roi(roi);
+        let mut masked = LaneReducer::new(2, 0.4, 0.2).with_roi(roi);
+        for green_cols in [0, 2, 3, 8, 4, 0] {
+            let frame: Vec<u8> = (0..width * height)
+                .flat_map(|i| {
+                    if i % width < green_cols {
+                        [40, 210, 40]
+                    } else {
+                        [120, 90, 70]
+                    }
+                })
+                .collect();
+            let expected = masked.reduce(&vision.detect(&frame), width, height);
+            streaming.reduce_frame(&frame, width, height, &vision);
+            assert_eq!(streaming.lanes(), expected, "{green_cols} green columns");
+            assert_eq!(streaming.status(), masked.status());
+        }
+    }
+
+    #[test]
+    fn mask_filter_suppresses_speckle_coverage() {
+        let (width, height) = (32, 16);
+        // Isolated specks on every other pixel of every other row: 25%
+        // coverage in lane 0; a solid 8x8 plant in lane 1.
+        let mask: Vec<bool> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                if x < 16 {
+                    x % 2 == 0 && y % 2 == 0
+                } else {
+                    (20..28).contains(&x) && (4..12).contains(&y)
+                }
+            })
+            .collect();
+        let mut raw = LaneReducer::new(2, 0.2, 0.1);
+        assert_eq!(raw.reduce(&mask, width, height), vec![true, true]);
+
+        let mut filtered = LaneReducer::new(2, 0.2, 0.1).with_filter(MaskFilter::new(3, 0));
+        assert_eq!(filtered.reduce(&mask, width, height), vec![false, true]);
+        assert_eq!(filtered.status()[0].pixels, 0);
+        assert_eq!(filtered.status()[1].pixels, 64);
+    }
+
+    #[test]
+    fn reduce_frame_with_filter_matches_masked_reduce() {
+        use crate::vision::PlantVision;
+
+        let (width, height) = (12, 6);
+        let vision = PlantVision::default();
+        let filter = MaskFilter::new(3, 3);
+        let mut streaming = LaneReducer::new(2, 0.3, 0.1).with_filter(filter);
+        let mut masked = LaneReducer::new(2, 0.3, 0.1).with_filter(filter);
+        for seed in 1..6usize {
+            let frame: Vec<u8> = (0..width * height)
+                .flat_map(|i| {
+                    if (i * seed) % 7 < 3 {
+                        [40, 210, 40]
+                    } else {
+                        [120, 90, 70]
+                    }
+                })
+                .collect();
+            let expected = masked.reduce(&vision.detect(&frame), width, height);
+            streaming.reduce_frame(&frame, width, height, &vision);
+            assert_eq!(streaming.lanes(), expected, "seed {seed}");
+            assert_eq!(streaming.status(), masked.status());
+        }
+    }
+
+    #[test]
+    fn blob_stage_ignores_specks_and_reports_frame_coordinates() {
+        let (width, height) = (32, 16);
+        // Lane 0: isolated specks (25% coverage). Lane 1: one 5x5 weed.
+        let mask: Vec<bool> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                if x < 16 {
+                    x % 2 == 0 && y % 2 == 0
+                } else {
+                    (20..25).contains(&x) && (6..11).contains(&y)
+                }
+            })
+            .collect();
+        let mut reducer = LaneReducer::new(2, 0.05, 0.02)
+            .with_roi(Roi {
+                top: 1,
+                bottom: 0,
+                left: 0,
+                right: 0,
+                ref_width: width,
+                ref_height: height,
+            })
+            .with_blobs(10);
+        assert_eq!(reducer.reduce(&mask, width, height), vec![false, true]);
+        assert_eq!(reducer.status()[1].pixels, 25);
+        let blobs = reducer.blobs().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(
+            (blobs[0].x, blobs[0].y, blobs[0].width, blobs[0].height),
+            (20, 6, 5, 5)
+        );
+        assert_eq!((blobs[0].cx, blobs[0].cy), (22.5, 8.5));
+        assert!(LaneReducer::new(2, 0.5, 0.2).blobs().is_none());
+    }
+
+    #[test]
+    fn crop_rows_are_masked_so_only_inter_row_weeds_trigger() {
+        let (width, height) = (80, 16);
+        // Crop rows 4 px wide centred on x = 10, 30, 50, 70, and one 4x4
+        // weed between the rows at x = 36..40.
+        let mask: Vec<bool> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                (8..12).contains(&(x % 20)) || ((36..40).contains(&x) && (4..8).contains(&y))
+            })
+            .collect();
+        let roi = Roi {
+            top: 0,
+            bottom: 0,
+            left: 4,
+            right: 0,
+            ref_width: width,
+            ref_height: height,
+        };
+        let mut sprayer = LaneReducer::new(4, 0.04, 0.02).with_roi(roi);
+        assert_eq!(sprayer.reduce(&mask, width, height), vec![true; 4]);
+        assert!(sprayer.crop_rows().is_none());
+
+        let mut reducer = LaneReducer::new(4, 0.04, 0.02)
+            .with_roi(roi)
+            .with_crop_rows(CropRowTracker::new(20.0, 6.0, 1.0, width));
+        assert!(reducer.needs_full_mask());
+        assert_eq!(
+            reducer.reduce(&mask, width, height),
+            vec![false, true, false, false]
+        );
+        assert_eq!(reducer.status()[1].pixels, 16);
+        assert_eq!(reducer.crop_rows().unwrap(), [10.0, 30.0, 50.0, 70.0]);
+    }
 }
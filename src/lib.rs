@@ -12,14 +12,31 @@
 //! protocol used to embed the binary inside an outer shell such as
 //! OpenWeedLocator lives in [`ipc`].
 
+pub mod adaptive;
+pub mod blobs;
+pub mod can;
 pub mod config;
+pub mod crop_rows;
+pub mod delay;
+pub mod dwell;
 pub mod exg;
 pub mod ffi;
+#[cfg(target_os = "linux")]
+pub mod gpio_cdev;
+pub mod gps;
+pub mod indices;
 pub mod io_gpio;
 pub mod ipc;
+pub mod isobus;
 pub mod lanes;
+pub mod modbus;
+pub mod morphology;
 pub mod pipeline;
+pub mod pwm;
+pub mod serial;
+pub mod train;
 pub mod vision;
+pub mod white_balance;
 
 #[cfg(test)]
 mod kernel_tests {
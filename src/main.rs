@@ -9,14 +9,21 @@ mod watchdog;
 use clap::Parser;
 use log::{error, info};
 use rustspray_core::{
-    config::Config,
+    config::{Config, DwellUnit, GpioBackend, IndexSelection},
+    delay::{DelayedNozzles, GroundSpeed},
+    dwell::DwellNozzles,
+    gps::FixStatus,
     io_gpio::{MockGpio, NozzleControl},
     ipc,
     lanes::LaneReducer,
     pipeline::Pipeline,
-    vision::PlantVision,
+    pwm::SoftPwm,
+    train::{self, Confusion, Image},
+    vision::{PixelFormat, PlantVision, Rect},
+    white_balance::{Calibration, Reference},
 };
 use std::io::Read;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -58,7 +65,12 @@ IPC MODE:
 #[command(name = "rustspray", version, about, after_help = CAMERA_HELP)]
 struct Cli {
     /// Configuration file
-    #[arg(short, long, default_value = "/etc/rustspray/config.toml")]
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "/etc/rustspray/config.toml"
+    )]
     config: String,
 
     /// Skip GPIO hardware; log lane state changes to stderr instead
@@ -89,18 +101,64 @@ struct Cli {
     /// Print version and IPC protocol number as JSON, then exit
     #[arg(long)]
     output_version: bool,
+
+    /// Measure white balance gains from raw RGB24 frames on stdin
+    /// (--frames of them, default 30) and print a [vision.white_balance]
+    /// TOML section, then exit
+    #[arg(long, value_enum, conflicts_with_all = ["ipc_mode", "test_pattern"])]
+    calibrate_white_balance: Option<WhiteBalanceReference>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Fit the [vision] weights and ExG threshold to labelled images by
+    /// logistic regression and print them as TOML
+    Train(TrainArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct TrainArgs {
+    /// Labelled images: each RGB PPM followed by its vegetation mask (PPM
+    /// or PGM of the same size, white = vegetation)
+    #[arg(required = true, num_args = 2.., value_names = ["IMAGE", "MASK"])]
+    images: Vec<PathBuf>,
+
+    /// Fraction of each image held out to report precision and recall
+    #[arg(long, default_value_t = 0.2)]
+    holdout: f32,
+}
+
+/// Target for `--calibrate-white-balance`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum WhiteBalanceReference {
+    /// The scene averages to neutral grey
+    GreyWorld,
+    /// The scene is mostly soil of `[vision.white_balance] soil_reference`
+    Soil,
 }
 
 fn main() {
     let cli = Cli::parse();
 
     // Startup handshake for outer shells: must work without a config
-    // file and must print nothing else on stdout.
+    // file and must print nothing else on stdout. The protocol version
+    // depends on the configured pixel format, so read the config when
+    // there is one.
     if cli.output_version {
+        let format = match Config::load(std::path::Path::new(&cli.config)) {
+            Ok(c) => c.camera.pixel_format,
+            Err(e) => {
+                eprintln!("Error: {e}");
+                std::process::exit(2);
+            }
+        };
         println!(
             "{{\"rustspray_version\":\"{}\",\"ipc_protocol\":{}}}",
             env!("CARGO_PKG_VERSION"),
-            ipc::IPC_PROTOCOL_VERSION,
+            ipc::protocol_version(format),
         );
         return;
     }
@@ -130,10 +188,33 @@ fn main() {
 
     info!("rustspray {} starting", env!("CARGO_PKG_VERSION"));
     info!(
-        "config: {}x{} @ {} fps, {} lanes",
-        config.camera.width, config.camera.height, config.camera.fps, config.lanes.count,
+        "config: {}x{} @ {} fps, {} lanes, {} worker(s)",
+        config.camera.width,
+        config.camera.height,
+        config.camera.fps,
+        config.lanes.count,
+        config.vision.workers,
     );
 
+    if let Some(Command::Train(args)) = &cli.command {
+        std::process::exit(train(&config, args));
+    }
+
+    if let Some(reference) = cli.calibrate_white_balance {
+        if config.camera.pixel_format != PixelFormat::Rgb24 {
+            error!("white balance calibration needs rgb24 frames");
+            std::process::exit(1);
+        }
+        let reference = match reference {
+            WhiteBalanceReference::GreyWorld => Reference::GreyWorld,
+            WhiteBalanceReference::Soil => {
+                Reference::Soil(config.vision.white_balance.soil_reference)
+            }
+        };
+        let frames = if cli.frames == 0 { 30 } else { cli.frames };
+        std::process::exit(calibrate_white_balance(&config, reference, frames));
+    }
+
     let mock_gpio = cli.mock_gpio || config.gpio.mock;
 
     // Graceful shutdown on SIGINT / SIGTERM.
@@ -145,56 +226,132 @@ fn main() {
     .expect("failed to install signal handler");
 
     // Build pipeline components.
-    let gpio: Box<dyn NozzleControl> = if mock_gpio {
-        info!("using mock GPIO (stderr)");
-        Box::new(MockGpio::default())
+    let mut gpio: Box<dyn NozzleControl + Send> = if mock_gpio {
+        build_mock_gpio(&config)
     } else {
         build_real_gpio(&config)
     };
+    let timing = &config.timing;
+    let ground_speed = timing.ground_speed();
+    // Dwell shapes the edges the delay releases, so minimum times hold
+    // at the valve.
+    if let Some(timer) = timing.dwell_timer(config.lanes.count, ground_speed.clone()) {
+        let dwell = &timing.dwell;
+        let unit = match dwell.unit {
+            DwellUnit::Ms => "ms",
+            DwellUnit::M => "m",
+        };
+        info!(
+            "timing: min on {} {unit}, min off {} {unit}, pre-spray {} {unit}, post-spray {} {unit}",
+            dwell.min_on, dwell.min_off, dwell.pre_spray, dwell.post_spray,
+        );
+        gpio = Box::new(DwellNozzles::new(gpio, timer));
+    }
+    if let Some(scheduler) = timing.delay_scheduler(config.lanes.count, ground_speed.clone()) {
+        info!(
+            "timing: nozzles {} m behind the camera, {} ms valve latency, {} km/h, stopped at or below {} km/h",
+            timing.camera_to_nozzle_m, timing.valve_latency_ms, timing.speed_kmh, timing.min_speed_kmh,
+        );
+        gpio = Box::new(DelayedNozzles::new(gpio, scheduler));
+    }
 
-    let vision = PlantVision::new(
-        config.vision.exg_threshold,
-        config.vision.green_ratio_floor,
-        config.vision.chroma_floor,
-        (
-            config.vision.weights.exg,
-            config.vision.weights.green_ratio,
-            config.vision.weights.chroma,
-            config.vision.weights.bias,
-        ),
-    );
+    let vision = PlantVision::from_config(&config.vision);
+    if let Some(bits) = vision.lookup_bits() {
+        info!("vision: using a {bits}-bit-per-channel RGB lookup table");
+    }
+    if let Some(threshold) = vision.ndvi_threshold() {
+        info!("vision: NDVI above {threshold} on RGBN frames");
+    }
+    if vision.index().is_some() {
+        info!("vision: index {:?}", config.vision.index);
+    }
+    let lighting = vision.lighting();
+    if !lighting.is_off() {
+        info!(
+            "vision: luma floor {}, glare ceiling {}, shadow relaxation below {}",
+            lighting.luma_floor, lighting.luma_ceiling, lighting.shadow_luma,
+        );
+    }
+    if let Some(balance) = vision.white_balance() {
+        let [r, g, b] = balance.gains();
+        info!("vision: white balance gains ({r:.3}, {g:.3}, {b:.3})");
+    }
 
-    let reducer = LaneReducer::new(
-        config.lanes.count,
-        config.lanes.on_threshold,
-        config.lanes.off_threshold,
-    );
+    let reducer = LaneReducer::from_config(&config);
+    for lane in (0..config.lanes.count).filter(|&l| !config.lanes.is_enabled(l)) {
+        log::warn!("lane {lane} is disabled in config — it will never spray");
+    }
+    let region = reducer.region(config.camera.width, config.camera.height);
+    if region != Rect::full(config.camera.width, config.camera.height) {
+        info!(
+            "vision: region of interest {}x{} at ({}, {})",
+            region.width, region.height, region.x, region.y,
+        );
+    }
+    if let Some(filter) = reducer.filter() {
+        info!(
+            "vision: mask filter opening {} px, closing {} px",
+            filter.open, filter.close,
+        );
+    }
+    if config.vision.crop_rows.enabled {
+        let rows = &config.vision.crop_rows;
+        info!(
+            "vision: crop row masking on, rows every {} px, band {} px, smoothing {}",
+            rows.spacing, rows.band, rows.smoothing,
+        );
+    }
+    if config.vision.blobs.enabled {
+        info!(
+            "vision: blob stage on, ignoring blobs under {} px",
+            config.vision.blobs.min_area,
+        );
+    }
+
+    let w = config.camera.width;
+    let h = config.camera.height;
+    let mut pipeline =
+        Pipeline::new(reducer, gpio, vision, w, h).with_workers(config.vision.workers);
+    if let Some(adaptive) = config.vision.adaptive_threshold() {
+        info!(
+            "vision: adaptive ExG threshold (Otsu over {} frame(s), clamped to {}..={})",
+            config.vision.adaptive.window,
+            config.vision.adaptive.min_threshold,
+            config.vision.adaptive.max_threshold,
+        );
+        pipeline = pipeline.with_adaptive_threshold(adaptive);
+    }
+    if let Some(curve) = config.gpio.pwm.duty_curve() {
+        info!(
+            "gpio: PWM duty {}..{} up to {} coverage (gamma {})",
+            curve.min_duty, curve.max_duty, curve.full_coverage, curve.gamma,
+        );
+        pipeline = pipeline.with_duty_curve(curve);
+    }
+    if !config.gps.device.is_empty() {
+        pipeline = attach_gps(pipeline, &config, ground_speed);
+    }
 
     let mut watchdog = Watchdog::new();
 
     if cli.ipc_mode {
         info!(
-            "IPC mode: framed RGB24 on stdin, JSON v{} on stdout",
-            ipc::IPC_PROTOCOL_VERSION,
+            "IPC mode: framed {} on stdin, JSON v{} on stdout",
+            pipeline.pixel_format(),
+            ipc::protocol_version(pipeline.pixel_format()),
         );
         let exit_code = run_ipc(
-            vision,
-            reducer,
-            gpio,
+            &mut pipeline,
             cli.frames,
             cli.oneshot,
             &running,
             &mut watchdog,
         );
-        info!("all nozzles off — shutdown complete");
+        log_shutdown(&pipeline);
         std::process::exit(exit_code);
     }
 
-    let w = config.camera.width;
-    let h = config.camera.height;
-    let mut pipeline = Pipeline::new(reducer, gpio, vision, w, h);
-
-    let frame_size = w * h * 3;
+    let frame_size = w * h * pipeline.pixel_format().channels();
     let frame_interval = Duration::from_secs_f64(1.0 / config.camera.fps as f64);
     let stall_timeout = Duration::from_secs(config.camera.stall_timeout_secs);
 
@@ -222,7 +379,8 @@ fn main() {
             std::process::exit(1);
         }
         info!(
-            "reading RGB24 frames from stdin (stall timeout: {})",
+            "reading {} frames from stdin (stall timeout: {})",
+            pipeline.pixel_format(),
             if stall_timeout.is_zero() {
                 "disabled".to_string()
             } else {
@@ -241,7 +399,7 @@ fn main() {
 
     // Fail safe: never exit with a valve left open.
     pipeline.all_off();
-    info!("all nozzles off — shutdown complete");
+    log_shutdown(&pipeline);
     if stalled {
         std::process::exit(EXIT_STALLED);
     }
@@ -259,16 +417,14 @@ fn main() {
 ///
 /// Returns the process exit code.
 fn run_ipc(
-    vision: PlantVision,
-    mut reducer: LaneReducer,
-    mut gpio: Box<dyn NozzleControl>,
+    pipeline: &mut Pipeline,
     max_frames: u64,
     oneshot: bool,
     running: &Arc<AtomicBool>,
     watchdog: &mut Watchdog,
 ) -> i32 {
-    let lane_count = reducer.lane_count();
-    let all_off = |gpio: &mut Box<dyn NozzleControl>| gpio.apply(&vec![false; lane_count]);
+    let lane_count = pipeline.lane_count();
+    let channels = pipeline.pixel_format().channels();
 
     let mut stdin = std::io::stdin().lock();
     let mut stdout = std::io::stdout().lock();
@@ -280,7 +436,7 @@ fn run_ipc(
             info!("signal received — leaving IPC loop");
             break 0;
         }
-        let header = match ipc::read_frame(&mut stdin, &mut buf) {
+        let header = match ipc::read_frame(&mut stdin, &mut buf, channels) {
             Ok(Some(h)) => h,
             Ok(None) => {
                 info!("end of input stream");
@@ -298,27 +454,25 @@ fn run_ipc(
 
         let width = header.width as usize;
         let height = header.height as usize;
-        if width < lane_count {
+        if !pipeline.accepts(width, height) {
             error!(
-                "frame width {} is smaller than the {} configured lanes",
-                width, lane_count,
+                "{}x{} frame leaves fewer region-of-interest columns than the {} configured lanes",
+                width, height, lane_count,
             );
             break 2;
         }
 
         let start = Instant::now();
-        let mask = vision.detect(&buf);
-        let lanes = reducer.reduce(&mask, width, height);
-        gpio.apply(&lanes);
+        pipeline.process_frame(&buf, width, height);
         let latency_us = start.elapsed().as_micros() as u64;
 
-        let response = ipc::IpcResponse {
-            v: ipc::IPC_PROTOCOL_VERSION,
-            frame: count,
-            ts_us,
-            lanes,
-            latency_us,
-        };
+        let response = ipc::IpcResponse::new(count, ts_us, pipeline.status(), latency_us)
+            .with_pixel_format(pipeline.pixel_format())
+            .with_blobs(pipeline.blobs())
+            .with_crop_rows(pipeline.crop_rows())
+            .with_duty(pipeline.duty())
+            .with_gps(pipeline.gps())
+            .with_exg_threshold(pipeline.exg_threshold());
         if let Err(e) = ipc::write_response(&mut stdout, &response) {
             // Broken pipe: the outer shell is gone.
             error!("failed to write IPC response: {e}");
@@ -327,12 +481,13 @@ fn run_ipc(
 
         count += 1;
         watchdog.ping();
+        log_frame(pipeline, count, Duration::from_micros(latency_us));
         if oneshot || (max_frames > 0 && count >= max_frames) {
             break 0;
         }
     };
 
-    all_off(&mut gpio);
+    pipeline.all_off();
     info!("processed {} frames", count);
     exit_code
 }
@@ -348,20 +503,19 @@ fn run_test_pattern(
     interval: Duration,
     watchdog: &mut Watchdog,
 ) {
-    let mut frame = vec![0u8; width * height * 3];
-    // Green in lanes 0 and 2 (quarters 1 and 3), soil elsewhere.
+    let channels = pipeline.pixel_format().channels();
+    let mut frame = vec![0u8; width * height * channels];
+    // Green in lanes 0 and 2 (quarters 1 and 3), soil elsewhere. Leaves
+    // are bright and soil dull in the near infrared.
     for y in 0..height {
         for x in 0..width {
-            let idx = (y * width + x) * 3;
-            if x < width / 4 || (x >= width / 2 && x < 3 * width / 4) {
-                frame[idx] = 20;
-                frame[idx + 1] = 200;
-                frame[idx + 2] = 20;
+            let idx = (y * width + x) * channels;
+            let pixel = if x < width / 4 || (x >= width / 2 && x < 3 * width / 4) {
+                [20, 200, 20, 220]
             } else {
-                frame[idx] = 120;
-                frame[idx + 1] = 90;
-                frame[idx + 2] = 70;
-            }
+                [120, 90, 70, 100]
+            };
+            frame[idx..idx + channels].copy_from_slice(&pixel[..channels]);
         }
     }
 
@@ -373,9 +527,7 @@ fn run_test_pattern(
         watchdog.ping();
 
         let elapsed = start.elapsed();
-        if count.is_multiple_of(100) || count == 1 {
-            info!("frame {}: {:.1} ms", count, elapsed.as_secs_f64() * 1000.0,);
-        }
+        log_frame(pipeline, count, elapsed);
 
         if oneshot || (max_frames > 0 && count >= max_frames) {
             break;
@@ -452,9 +604,7 @@ fn run_stdin(
                 watchdog.ping();
 
                 let elapsed = start.elapsed();
-                if count.is_multiple_of(100) || count == 1 {
-                    info!("frame {}: {:.1} ms", count, elapsed.as_secs_f64() * 1000.0,);
-                }
+                log_frame(pipeline, count, elapsed);
 
                 if max_frames > 0 && count >= max_frames {
                     break;
@@ -477,21 +627,402 @@ fn run_stdin(
     false
 }
 
+/// Average `frames` raw RGB24 camera frames from stdin and print the
+/// white balance gains that map them onto `reference` as TOML on stdout.
+///
+/// Returns the process exit code.
+fn calibrate_white_balance(config: &Config, reference: Reference, frames: u64) -> i32 {
+    let frame_size = config.camera.width * config.camera.height * 3;
+    let mut buf = vec![0u8; frame_size];
+    let mut stdin = std::io::stdin().lock();
+    let mut calibration = Calibration::new();
+    let mut count = 0;
+    while count < frames {
+        match stdin.read_exact(&mut buf) {
+            Ok(()) => {
+                calibration.add_frame(&buf);
+                count += 1;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                error!("stdin read error: {e}");
+                return 1;
+            }
+        }
+    }
+    if count == 0 {
+        error!("no complete frame on stdin — nothing to calibrate");
+        return 1;
+    }
+    let [r, g, b] = calibration.means();
+    info!("white balance: mean RGB ({r:.1}, {g:.1}, {b:.1}) over {count} frame(s)");
+    let [r, g, b] = calibration.gains(reference);
+    println!("# {reference:?} white balance over {count} frame(s)");
+    println!("[vision.white_balance]");
+    println!("gains = [{r:.3}, {g:.3}, {b:.3}]");
+    0
+}
+
+/// `rustspray train`: fit the fused detector to labelled images, report
+/// held-out precision and recall against the current config, and print
+/// the trained `[vision]` settings.
+fn train(config: &Config, args: &TrainArgs) -> i32 {
+    if config.vision.index != IndexSelection::default()
+        || config.camera.pixel_format != PixelFormat::Rgb24
+    {
+        error!("training fits the fused index on rgb24 frames; check [vision] index and [camera] pixel_format");
+        return 1;
+    }
+    if !args.images.len().is_multiple_of(2) {
+        error!("every image needs a mask: expected IMAGE MASK pairs");
+        return 1;
+    }
+    if !(0.0..1.0).contains(&args.holdout) {
+        error!("--holdout must be in [0, 1)");
+        return 1;
+    }
+    if config.vision.adaptive.enabled {
+        log::warn!("[vision.adaptive] is enabled and will replace the trained exg_threshold");
+    }
+    let mut images = Vec::new();
+    for pair in args.images.chunks_exact(2) {
+        let (image, mask) = match (Image::load(&pair[0]), Image::load(&pair[1])) {
+            (Ok(image), Ok(mask)) => (image, mask),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("{e}");
+                return 1;
+            }
+        };
+        if image.channels != 3 || (image.width, image.height) != (mask.width, mask.height) {
+            error!(
+                "{} must be an RGB PPM the size of {}",
+                pair[0].display(),
+                pair[1].display(),
+            );
+            return 1;
+        }
+        images.push((image, mask));
+    }
+
+    let (training, held_out) = train::split(&images, args.holdout);
+    info!(
+        "training on {} pixels ({} vegetation), {} held out",
+        training.len(),
+        training.vegetation(),
+        held_out.len(),
+    );
+    let current = PlantVision::from_config(&config.vision);
+    let trained = match train::fit(&current, &training) {
+        Ok(trained) => trained,
+        Err(e) => {
+            error!("training failed: {e}");
+            return 1;
+        }
+    };
+    info!(
+        "fitted {} pixels passing the detector's gates",
+        trained.fitted
+    );
+
+    println!(
+        "# Trained on {} pixels, {} held out",
+        training.len(),
+        held_out.len(),
+    );
+    if !held_out.is_empty() {
+        let before = Confusion::evaluate(&current, &held_out);
+        let after = Confusion::evaluate(&trained.apply(&current), &held_out);
+        info!(
+            "held out: precision {:.3} -> {:.3}, recall {:.3} -> {:.3}",
+            before.precision(),
+            after.precision(),
+            before.recall(),
+            after.recall(),
+        );
+        println!(
+            "# Held out: precision {:.3}, recall {:.3} (current config: precision {:.3}, recall {:.3})",
+            after.precision(),
+            after.recall(),
+            before.precision(),
+            before.recall(),
+        );
+    }
+    println!("[vision]");
+    println!("exg_threshold = {}", trained.exg_threshold);
+    println!();
+    println!("[vision.weights]");
+    println!("exg         = {:.5}", trained.exg);
+    println!("green_ratio = {:.5}", trained.green_ratio);
+    println!("chroma      = {:.5}", trained.chroma);
+    println!("bias        = {:.5}", trained.bias);
+    0
+}
+
+/// Read the `[gps] device` on a background thread, linked to
+/// `ground_speed`.
+#[cfg(unix)]
+fn attach_gps(pipeline: Pipeline, config: &Config, ground_speed: GroundSpeed) -> Pipeline {
+    use rustspray_core::{delay::MonotonicClock, gps::Gps};
+    info!(
+        "gps: NMEA from {} at {} baud, stale after {} ms",
+        config.gps.device, config.gps.baud, config.gps.stale_after_ms,
+    );
+    let gps = Gps::spawn(
+        std::path::Path::new(&config.gps.device),
+        config.gps.baud,
+        Duration::from_millis(config.gps.stale_after_ms),
+        Arc::new(MonotonicClock::new()),
+    )
+    .with_ground_speed(ground_speed, config.timing.speed_kmh / 3.6);
+    pipeline.with_gps(gps)
+}
+
+#[cfg(not(unix))]
+fn attach_gps(_pipeline: Pipeline, _config: &Config, _ground_speed: GroundSpeed) -> Pipeline {
+    error!("gps.device needs a Unix serial port");
+    std::process::exit(1);
+}
+
+/// Whether the final all-off reached the nozzles.
+fn log_shutdown(pipeline: &Pipeline) {
+    match pipeline.nozzle_fault() {
+        Some(fault) => error!("nozzles: {fault} — could not confirm all nozzles off"),
+        None => info!("all nozzles off — shutdown complete"),
+    }
+}
+
+/// Nozzle faults as they are raised and cleared, and a periodic progress
+/// line: frame time, plus the ExG threshold when it adapts per frame and
+/// the GPS status when there is a receiver.
+fn log_frame(pipeline: &Pipeline, count: u64, elapsed: Duration) {
+    if pipeline.nozzle_fault_changed() {
+        match pipeline.nozzle_fault() {
+            Some(fault) => error!("nozzles: {fault} — output states unknown"),
+            None => info!("nozzles: link restored"),
+        }
+    }
+    if !(count.is_multiple_of(100) || count == 1) {
+        return;
+    }
+    let ms = elapsed.as_secs_f64() * 1000.0;
+    if pipeline.is_adaptive() {
+        info!(
+            "frame {}: {:.1} ms, ExG threshold {}",
+            count,
+            ms,
+            pipeline.exg_threshold(),
+        );
+    } else {
+        info!("frame {}: {:.1} ms", count, ms);
+    }
+    if let Some(gps) = pipeline.gps() {
+        match (gps.fix, gps.speed_mps) {
+            (FixStatus::Fix, Some(mps)) => info!("gps: fix, {:.1} km/h", mps * 3.6),
+            (fix, _) => log::warn!("gps: no current fix ({fix:?})"),
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------
 // GPIO construction
 // ---------------------------------------------------------------------------
 
+fn build_real_gpio(config: &Config) -> Box<dyn NozzleControl + Send> {
+    match config.gpio.backend {
+        GpioBackend::Rppal => build_rppal_gpio(config),
+        GpioBackend::Cdev => build_cdev_gpio(config),
+        GpioBackend::Can => build_can_gpio(config),
+        GpioBackend::Isobus => build_isobus_gpio(config),
+        GpioBackend::Modbus => build_modbus_gpio(config),
+    }
+}
+
+/// Relays on a Modbus RTU board. Exits if the board cannot be reached.
+#[cfg(unix)]
+fn build_modbus_gpio(config: &Config) -> Box<dyn NozzleControl + Send> {
+    use rustspray_core::modbus::ModbusRelays;
+    let modbus = &config.gpio.modbus;
+    let coils = modbus.coils(config.lanes.count);
+    match ModbusRelays::open(
+        std::path::Path::new(&modbus.device),
+        modbus.baud,
+        modbus.parity,
+        modbus.slave,
+        &coils,
+        modbus.link(),
+    ) {
+        Ok(relays) => {
+            info!(
+                "using Modbus relays on {} at {} baud: slave {}, coils {coils:?}",
+                modbus.device, modbus.baud, modbus.slave
+            );
+            Box::new(relays)
+        }
+        Err(e) => {
+            error!(
+                "failed to switch off the Modbus relays on {}: {e}",
+                modbus.device
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn build_modbus_gpio(_config: &Config) -> Box<dyn NozzleControl + Send> {
+    error!("gpio.backend = \"modbus\" needs a Unix serial port");
+    std::process::exit(1);
+}
+
+/// Frames to a CAN section controller. Exits if the interface cannot be
+/// opened or the initial all-off frame cannot be sent.
+#[cfg(target_os = "linux")]
+fn build_can_gpio(config: &Config) -> Box<dyn NozzleControl + Send> {
+    use rustspray_core::can::{CanNozzles, CanSocket};
+    let can = &config.gpio.can;
+    let nozzles = CanSocket::open(&can.interface).and_then(|socket| {
+        CanNozzles::new(
+            Arc::new(socket),
+            can.message(),
+            config.lanes.count,
+            can.heartbeat(),
+        )
+    });
+    match nozzles {
+        Ok(nozzles) => {
+            info!(
+                "using CAN section controller on {} (id {:#X}, heartbeat {} ms)",
+                can.interface, can.id, can.heartbeat_ms
+            );
+            Box::new(nozzles)
+        }
+        Err(e) => {
+            error!("failed to open CAN interface {}: {e}", can.interface);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn build_can_gpio(_config: &Config) -> Box<dyn NozzleControl + Send> {
+    error!("gpio.backend = \"can\" needs SocketCAN (Linux)");
+    std::process::exit(1);
+}
+
+/// ISOBUS section control of an implement. Exits if the interface cannot
+/// be opened.
+#[cfg(target_os = "linux")]
+fn build_isobus_gpio(config: &Config) -> Box<dyn NozzleControl + Send> {
+    use rustspray_core::{can::CanSocket, isobus::IsobusNozzles};
+    let isobus = &config.gpio.isobus;
+    let nozzles = CanSocket::open(&isobus.interface).and_then(|socket| {
+        // Short reads keep the address claim and refresh timers on time.
+        socket.set_read_timeout(Duration::from_millis(10))?;
+        IsobusNozzles::new(Arc::new(socket), isobus.section_control(config.lanes.count))
+    });
+    match nozzles {
+        Ok(nozzles) => {
+            info!(
+                "using ISOBUS section control on {} (address {:#04X}, implement identity {} of manufacturer {}, element {})",
+                isobus.interface,
+                isobus.address,
+                isobus.implement_identity_number.unwrap_or_default(),
+                isobus.implement_manufacturer_code,
+                isobus.element
+            );
+            Box::new(nozzles)
+        }
+        Err(e) => {
+            error!("failed to open CAN interface {}: {e}", isobus.interface);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn build_isobus_gpio(_config: &Config) -> Box<dyn NozzleControl + Send> {
+    error!("gpio.backend = \"isobus\" needs SocketCAN (Linux)");
+    std::process::exit(1);
+}
+
+/// GPIO character device lines, pulsed by software PWM when
+/// `[gpio.pwm]` is enabled. Exits if a line cannot be requested.
+#[cfg(target_os = "linux")]
+fn build_cdev_gpio(config: &Config) -> Box<dyn NozzleControl + Send> {
+    use rustspray_core::gpio_cdev::CdevGpio;
+    let lines: Vec<String> = config
+        .gpio
+        .lines
+        .iter()
+        .map(|l| format!("gpiochip{}:{}", l.chip, l.line))
+        .collect();
+    let gpio = match CdevGpio::new(&config.gpio.lines) {
+        Ok(gpio) => gpio,
+        Err(e) => {
+            error!("failed to request GPIO lines: {e}");
+            std::process::exit(1);
+        }
+    };
+    let pwm = &config.gpio.pwm;
+    if pwm.enabled {
+        info!(
+            "using GPIO lines {lines:?} with {} Hz software PWM",
+            pwm.frequency_hz
+        );
+        return Box::new(SoftPwm::spawn(
+            Box::new(gpio),
+            config.lanes.count,
+            pwm.frequency_hz,
+        ));
+    }
+    info!("using GPIO lines {lines:?}");
+    Box::new(gpio)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn build_cdev_gpio(_config: &Config) -> Box<dyn NozzleControl + Send> {
+    error!("gpio.backend = \"cdev\" needs the GPIO character device (Linux)");
+    std::process::exit(1);
+}
+
 #[cfg(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64")))]
-fn build_real_gpio(config: &Config) -> Box<dyn NozzleControl> {
-    use rustspray_core::io_gpio::RppalGpio;
+fn build_rppal_gpio(config: &Config) -> Box<dyn NozzleControl + Send> {
+    use rustspray_core::{io_gpio::RppalGpio, pwm::RppalPwm};
+    let pwm = &config.gpio.pwm;
+    if pwm.enabled {
+        info!(
+            "using PWM on GPIO pins {:?} at {} Hz",
+            config.gpio.pins, pwm.frequency_hz
+        );
+        return Box::new(RppalPwm::new(&config.gpio.pins, pwm.frequency_hz));
+    }
     info!("using real GPIO pins: {:?}", config.gpio.pins);
     Box::new(RppalGpio::new(&config.gpio.pins))
 }
 
 #[cfg(not(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64"))))]
-fn build_real_gpio(_config: &Config) -> Box<dyn NozzleControl> {
+fn build_rppal_gpio(config: &Config) -> Box<dyn NozzleControl + Send> {
     log::warn!(
-        "real GPIO unavailable (requires an ARM build with --features rpi); falling back to mock"
+        "rppal GPIO unavailable (requires an ARM build with --features rpi); falling back to mock"
     );
+    build_mock_gpio(config)
+}
+
+/// Mock GPIO, pulsed by software PWM when `[gpio.pwm]` is enabled. It
+/// then logs the duty per lane rather than every edge.
+fn build_mock_gpio(config: &Config) -> Box<dyn NozzleControl + Send> {
+    let pwm = &config.gpio.pwm;
+    if pwm.enabled {
+        info!(
+            "using mock GPIO (stderr) with {} Hz software PWM",
+            pwm.frequency_hz
+        );
+        return Box::new(SoftPwm::spawn(
+            Box::new(MockGpio::default()),
+            config.lanes.count,
+            pwm.frequency_hz,
+        ));
+    }
+    info!("using mock GPIO (stderr)");
     Box::new(MockGpio::default())
 }
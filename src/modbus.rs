@@ -0,0 +1,607 @@
+//! Relay boards speaking Modbus RTU over a serial line (usually RS-485).
+//!
+//! [`ModbusRelays`] writes the lane states to the board's coils with
+//! Write Multiple Coils (function 15), one request per run of consecutive
+//! coil addresses, and waits for the slave's confirmation. A request
+//! that times out, comes back corrupted or is refused with an exception
+//! is retried. The requests go out from a worker thread, so a slow or
+//! silent board never holds up a frame. When every attempt fails the
+//! relays are in an unknown state, and [`NozzleControl::fault`] reports
+//! it. Every coil is then rewritten, one attempt at a time, until the
+//! board answers again. The serial line needs termios, so
+//! [`ModbusRelays`] exists on Unix only.
+
+#[cfg(unix)]
+use crate::io_gpio::NozzleControl;
+#[cfg(unix)]
+use crate::serial::{self, Access, Parity};
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(unix)]
+use std::thread::JoinHandle;
+use std::time::Duration;
+#[cfg(unix)]
+use std::time::Instant;
+
+const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+#[cfg(unix)]
+const EXCEPTION: u8 = 0x80;
+
+/// Modbus CRC-16 (polynomial 0xA001, reflected, initial 0xFFFF), sent
+/// low byte first.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &b| {
+        (0..8).fold(crc ^ u16::from(b), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn with_crc(mut frame: Vec<u8>) -> Vec<u8> {
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// A Write Multiple Coils request setting coils from `start` to `states`.
+pub fn write_coils_request(slave: u8, start: u16, states: &[bool]) -> Vec<u8> {
+    let mut frame = vec![slave, WRITE_MULTIPLE_COILS];
+    frame.extend_from_slice(&start.to_be_bytes());
+    frame.extend_from_slice(&(states.len() as u16).to_be_bytes());
+    frame.push(states.len().div_ceil(8) as u8);
+    for byte in states.chunks(8) {
+        frame.push(
+            byte.iter()
+                .enumerate()
+                .fold(0, |bits, (i, &on)| bits | u8::from(on) << i),
+        );
+    }
+    with_crc(frame)
+}
+
+/// Serial timing and retry policy for each request.
+#[derive(Debug, Clone, Copy)]
+pub struct Link {
+    /// Time to wait for the slave's response.
+    pub timeout: Duration,
+    /// Further attempts after a failed one.
+    pub retries: u32,
+}
+
+/// Coils at consecutive addresses, written in one request.
+#[cfg(unix)]
+#[derive(Debug)]
+struct Run {
+    start: u16,
+    /// Lane per coil.
+    lanes: Vec<usize>,
+}
+
+/// Relays on the coils of a Modbus RTU slave, one coil per lane.
+///
+/// Every coil is switched off when the board is opened, before `open`
+/// returns, and again on drop. In between, [`NozzleControl::apply`] only
+/// posts the states for the worker thread; [`NozzleControl::all_off`]
+/// waits for the board's answer so the shutdown outcome is known.
+#[cfg(unix)]
+pub struct ModbusRelays {
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+    fault: Option<String>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// State passed between [`ModbusRelays`] and its worker.
+#[cfg(unix)]
+struct Shared {
+    /// Latest lane states to write.
+    lanes: Vec<bool>,
+    /// New states posted since the worker last took them.
+    pending: bool,
+    /// Switch every coil off; cleared once the board has answered.
+    all_off: bool,
+    stop: bool,
+    fault: Option<String>,
+}
+
+/// The serial link to the board, owned by the worker thread.
+#[cfg(unix)]
+struct Board {
+    port: File,
+    slave: u8,
+    runs: Vec<Run>,
+    link: Link,
+    /// Minimum silence between frames (3.5 character times).
+    gap: Duration,
+    last_io: Instant,
+    /// Lane states the board has confirmed, `None` when unknown.
+    written: Option<Vec<bool>>,
+    states: Vec<bool>,
+    response: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl ModbusRelays {
+    /// Open the board on the serial device `path` (`baud`, 8 data bits,
+    /// `parity`, one stop bit) as `slave`, lane `i` on coil `coils[i]`.
+    ///
+    /// Fails if the port cannot be opened or the board does not confirm
+    /// that every coil is off.
+    pub fn open(
+        path: &Path,
+        baud: u32,
+        parity: Parity,
+        slave: u8,
+        coils: &[u16],
+        link: Link,
+    ) -> io::Result<Self> {
+        let port = serial::open(path, Access::ReadWrite, baud, parity)?;
+        let mut order: Vec<usize> = (0..coils.len()).collect();
+        order.sort_by_key(|&lane| coils[lane]);
+        let mut runs: Vec<Run> = Vec::new();
+        for lane in order {
+            match runs.last_mut() {
+                Some(run)
+                    if u32::from(run.start) + run.lanes.len() as u32 == u32::from(coils[lane]) =>
+                {
+                    run.lanes.push(lane)
+                }
+                _ => runs.push(Run {
+                    start: coils[lane],
+                    lanes: vec![lane],
+                }),
+            }
+        }
+        // A character is 11 bits with parity or two stop bits; above
+        // 19200 baud the specification fixes the gap at 1.75 ms.
+        let gap = if baud > 19200 {
+            Duration::from_micros(1750)
+        } else {
+            Duration::from_secs_f64(3.5 * 11.0 / f64::from(baud))
+        };
+        let mut board = Board {
+            port,
+            slave,
+            runs,
+            link,
+            gap,
+            last_io: Instant::now(),
+            written: None,
+            states: Vec::new(),
+            response: Vec::new(),
+        };
+        board.write(&vec![false; coils.len()], link.retries)?;
+        let shared = Arc::new((
+            Mutex::new(Shared {
+                lanes: vec![false; coils.len()],
+                pending: false,
+                all_off: false,
+                stop: false,
+                fault: None,
+            }),
+            Condvar::new(),
+        ));
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("modbus".into())
+                .spawn(move || board.serve(&shared))
+                .expect("failed to spawn the Modbus thread")
+        };
+        Ok(Self {
+            shared,
+            fault: None,
+            thread: Some(thread),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Shared {
+    /// Copy the worker's fault into `fault`.
+    fn report(&self, fault: &mut Option<String>) {
+        if *fault != self.fault {
+            fault.clone_from(&self.fault);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Board {
+    /// Write posted states until told to stop, then switch every coil
+    /// off unless the board has confirmed that already.
+    fn serve(mut self, shared: &(Mutex<Shared>, Condvar)) {
+        let (lock, changed) = shared;
+        let mut state = lock.lock().unwrap();
+        loop {
+            let idle = |s: &mut Shared| !(s.pending || s.all_off || s.stop);
+            state = if state.fault.is_some() {
+                // Retry after a timeout's pause, or at once for new states.
+                changed
+                    .wait_timeout_while(state, self.link.timeout, idle)
+                    .unwrap()
+                    .0
+            } else {
+                changed.wait_while(state, idle).unwrap()
+            };
+            if state.stop {
+                break;
+            }
+            let all_off = state.all_off;
+            if all_off {
+                state.lanes.fill(false);
+                self.written = None;
+            }
+            state.pending = false;
+            let lanes = state.lanes.clone();
+            // Keep retries short while the board is known to be down.
+            let retries = if state.fault.is_some() && !all_off {
+                0
+            } else {
+                self.link.retries
+            };
+            drop(state);
+            let result = self.write(&lanes, retries);
+            state = lock.lock().unwrap();
+            state.fault = result.err().map(|e| e.to_string());
+            if all_off {
+                state.all_off = false;
+                changed.notify_all();
+            }
+        }
+        drop(state);
+        let off = vec![false; self.lanes()];
+        if self.written.as_ref() != Some(&off) {
+            self.written = None;
+            let _ = self.write(&off, self.link.retries);
+        }
+    }
+
+    /// Write `lanes` to every run that differs from the confirmed states,
+    /// with `retries` further attempts per request.
+    fn write(&mut self, lanes: &[bool], retries: u32) -> io::Result<()> {
+        let written = self.written.take();
+        for run in 0..self.runs.len() {
+            let unchanged = written.as_ref().is_some_and(|written| {
+                self.runs[run].lanes.iter().all(|&l| written[l] == lanes[l])
+            });
+            if unchanged {
+                continue;
+            }
+            self.states.clear();
+            self.states
+                .extend(self.runs[run].lanes.iter().map(|&l| lanes[l]));
+            let request = write_coils_request(self.slave, self.runs[run].start, &self.states);
+            let mut attempt = 0;
+            while let Err(e) = self.transact(&request) {
+                if attempt == retries {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!(
+                            "slave {} coils {}..{}: {e} after {} attempt(s)",
+                            self.slave,
+                            self.runs[run].start,
+                            u32::from(self.runs[run].start) + self.states.len() as u32,
+                            attempt + 1,
+                        ),
+                    ));
+                }
+                attempt += 1;
+            }
+        }
+        self.written = Some(lanes.to_vec());
+        Ok(())
+    }
+
+    /// Send `request` and check the slave's echo of its address range.
+    fn transact(&mut self, request: &[u8]) -> io::Result<()> {
+        if let Some(wait) = (self.last_io + self.gap).checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+        // SAFETY: `port` is an open descriptor; discards stale input such
+        // as the tail of a late response.
+        unsafe { libc::tcflush(self.port.as_raw_fd(), libc::TCIFLUSH) };
+        self.port.write_all(request)?;
+        let result = self.read_response(request);
+        self.last_io = Instant::now();
+        result
+    }
+
+    fn read_response(&mut self, request: &[u8]) -> io::Result<()> {
+        let deadline = Instant::now() + self.link.timeout;
+        self.response.clear();
+        let mut expected = 8;
+        while self.response.len() < expected {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if !serial::wait_readable(&self.port, left)? {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no response"));
+            }
+            let mut buf = [0; 16];
+            let n = self.port.read(&mut buf[..expected - self.response.len()])?;
+            self.response.extend_from_slice(&buf[..n]);
+            if self.response.len() >= 2 && self.response[1] & EXCEPTION != 0 {
+                expected = 5;
+            }
+        }
+        let response = &self.response;
+        let (body, crc) = response.split_at(response.len() - 2);
+        if crc16(body).to_le_bytes() != crc {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch"));
+        }
+        if body[0] != self.slave || body[1] & !EXCEPTION != WRITE_MULTIPLE_COILS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response from another slave or function",
+            ));
+        }
+        if body[1] & EXCEPTION != 0 {
+            return Err(io::Error::other(format!("exception code {}", body[2])));
+        }
+        if body[2..6] != request[2..6] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response for other coils",
+            ));
+        }
+        Ok(())
+    }
+
+    fn lanes(&self) -> usize {
+        self.runs.iter().map(|run| run.lanes.len()).sum()
+    }
+}
+
+#[cfg(unix)]
+impl NozzleControl for ModbusRelays {
+    fn apply(&mut self, lanes: &[bool]) {
+        let (lock, changed) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        if state.lanes != lanes {
+            state.lanes.clear();
+            state.lanes.extend_from_slice(lanes);
+            state.pending = true;
+            changed.notify_all();
+        }
+        state.report(&mut self.fault);
+    }
+
+    fn all_off(&mut self, _lanes: usize) {
+        let (lock, changed) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        state.all_off = true;
+        changed.notify_all();
+        let state = changed.wait_while(state, |s| s.all_off).unwrap();
+        state.report(&mut self.fault);
+    }
+
+    fn fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ModbusRelays {
+    fn drop(&mut self) {
+        let (lock, changed) = &*self.shared;
+        lock.lock().unwrap().stop = true;
+        changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::serial::pty;
+    #[cfg(unix)]
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn crc_and_request_encoding() {
+        // Read Holding Registers example from the Modbus specification.
+        assert_eq!(
+            crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]).to_le_bytes(),
+            [0xC5, 0xCD]
+        );
+        let request = write_coils_request(
+            0x11,
+            0x13,
+            &[
+                true, false, true, true, false, false, true, true, true, false,
+            ],
+        );
+        assert_eq!(
+            request[..9],
+            [0x11, 0x0F, 0x00, 0x13, 0x00, 0x0A, 0x02, 0xCD, 0x01]
+        );
+        assert_eq!(crc16(&request), 0, "a frame with its CRC checks to zero");
+    }
+
+    /// How the simulated slave answers.
+    #[cfg(unix)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Mode {
+        Normal,
+        /// Ignore the next `n` requests.
+        Drop(usize),
+        /// Refuse requests with this exception code.
+        Exception(u8),
+    }
+
+    #[cfg(unix)]
+    struct Slave {
+        coils: [bool; 16],
+        mode: Mode,
+        requests: usize,
+    }
+
+    /// Run a Modbus RTU slave with address 7 on the master end of a pty.
+    #[cfg(unix)]
+    fn simulator(mut master: File) -> Arc<Mutex<Slave>> {
+        let slave = Arc::new(Mutex::new(Slave {
+            coils: [true; 16],
+            mode: Mode::Normal,
+            requests: 0,
+        }));
+        let state = slave.clone();
+        std::thread::spawn(move || {
+            let mut frame = Vec::new();
+            let mut buf = [0; 64];
+            // Ends with EIO once the relays close the slave end.
+            while let Ok(n) = master.read(&mut buf) {
+                frame.extend_from_slice(&buf[..n]);
+                if frame.len() < 7 || frame.len() < 9 + usize::from(frame[6]) {
+                    continue;
+                }
+                let request = std::mem::take(&mut frame);
+                assert_eq!(crc16(&request), 0);
+                assert_eq!(request[..2], [7, WRITE_MULTIPLE_COILS]);
+                let mut slave = state.lock().unwrap();
+                slave.requests += 1;
+                let response = match slave.mode {
+                    Mode::Drop(0) | Mode::Normal => {
+                        slave.mode = Mode::Normal;
+                        let start = usize::from(u16::from_be_bytes([request[2], request[3]]));
+                        let count = usize::from(u16::from_be_bytes([request[4], request[5]]));
+                        for i in 0..count {
+                            slave.coils[start + i] = request[7 + i / 8] >> (i % 8) & 1 != 0;
+                        }
+                        with_crc(request[..6].to_vec())
+                    }
+                    Mode::Drop(n) => {
+                        slave.mode = Mode::Drop(n - 1);
+                        continue;
+                    }
+                    Mode::Exception(code) => with_crc(vec![7, 0x8F, code]),
+                };
+                master.write_all(&response).unwrap();
+            }
+        });
+        slave
+    }
+
+    #[cfg(unix)]
+    fn coils(slave: &Mutex<Slave>) -> Vec<usize> {
+        let coils = slave.lock().unwrap().coils;
+        (0..16).filter(|&c| coils[c]).collect()
+    }
+
+    /// Apply `lanes` every few milliseconds, as frames would, until
+    /// `done`.
+    #[cfg(unix)]
+    fn frames_until(
+        relays: &mut ModbusRelays,
+        lanes: &[bool],
+        mut done: impl FnMut(&ModbusRelays) -> bool,
+    ) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            relays.apply(lanes);
+            if done(relays) {
+                return;
+            }
+            assert!(Instant::now() < deadline, "fault {:?}", relays.fault());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn drives_a_simulated_board_through_a_pty() {
+        let (master, path) = pty();
+        let slave = simulator(master);
+        let link = Link {
+            timeout: Duration::from_millis(50),
+            retries: 2,
+        };
+        // Lanes on coils 4, 5, 6 and 8: two requests per full write.
+        let mut relays =
+            ModbusRelays::open(&path, 115200, Parity::None, 7, &[4, 5, 6, 8], link).unwrap();
+        assert_eq!(coils(&slave), [0, 1, 2, 3, 7, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(slave.lock().unwrap().requests, 2);
+
+        frames_until(&mut relays, &[true, false, false, true], |_| {
+            coils(&slave) == [0, 1, 2, 3, 4, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        });
+        assert_eq!(slave.lock().unwrap().requests, 4);
+        // Unchanged states are not resent; only the changed run is.
+        frames_until(&mut relays, &[true, true, false, true], |_| {
+            coils(&slave).contains(&5)
+        });
+        assert_eq!(slave.lock().unwrap().requests, 5);
+        assert_eq!(relays.fault(), None);
+
+        // A lost request is retried.
+        slave.lock().unwrap().mode = Mode::Drop(2);
+        frames_until(&mut relays, &[false, false, false, true], |_| {
+            !coils(&slave).contains(&4)
+        });
+        assert_eq!(relays.fault(), None);
+        assert_eq!(slave.lock().unwrap().requests, 8);
+
+        // A board that stops answering is a fault, retried with every
+        // coil rewritten, without holding up the frames.
+        slave.lock().unwrap().mode = Mode::Drop(usize::MAX);
+        let start = Instant::now();
+        relays.apply(&[false, false, false, false]);
+        assert!(start.elapsed() < link.timeout);
+        frames_until(&mut relays, &[false, false, false, false], |r| {
+            r.fault().is_some()
+        });
+        let fault = relays.fault().unwrap();
+        assert!(
+            fault.contains("slave 7 coils 8..9: no response after 3 attempt(s)"),
+            "{fault}"
+        );
+        // Switching off on shutdown waits for the board's answer.
+        relays.all_off(4);
+        assert!(relays.fault().is_some());
+        slave.lock().unwrap().mode = Mode::Exception(4);
+        frames_until(&mut relays, &[false, false, false, false], |r| {
+            r.fault().is_some_and(|f| f.contains("exception code 4"))
+        });
+        slave.lock().unwrap().mode = Mode::Normal;
+        frames_until(&mut relays, &[false, false, false, false], |r| {
+            r.fault().is_none()
+        });
+        relays.all_off(4);
+        assert_eq!(relays.fault(), None);
+
+        relays.apply(&[false, true, true, false]);
+        drop(relays);
+        assert!(!coils(&slave).iter().any(|c| [4, 5, 6, 8].contains(c)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn opening_fails_without_a_response() {
+        let (master, path) = pty();
+        let slave = simulator(master);
+        slave.lock().unwrap().mode = Mode::Drop(usize::MAX);
+        let link = Link {
+            timeout: Duration::from_millis(20),
+            retries: 1,
+        };
+        let err = ModbusRelays::open(&path, 9600, Parity::Even, 7, &[0, 1], link)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Two attempts; no worker is started for a board that never
+        // answered.
+        assert_eq!(slave.lock().unwrap().requests, 2);
+    }
+}
@@ -0,0 +1,445 @@
+//! Morphological noise filtering on a bitpacked vegetation mask.
+//!
+//! Single-pixel speckle (sensor noise, green flecks in straw) adds up to
+//! lane coverage. An opening (erode, then dilate) removes specks smaller
+//! than the kernel; a closing (dilate, then erode) fills pinholes in
+//! leaves. Both use a square kernel and run on 64 pixels per word; the
+//! kernel is separable, so each pass is a horizontal word-shift sweep
+//! followed by a vertical row sweep.
+//!
+//! Pixels outside the frame never change the result: erosion treats them
+//! as vegetation and dilation as background, so blobs touching the edge
+//! are not eaten away.
+
+/// Row-major binary mask packed 64 pixels per `u64`, each row starting on
+/// a fresh word. Bit `x % 64` of word `x / 64` is column `x`; padding
+/// bits past the row end are always zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BitMask {
+    width: usize,
+    height: usize,
+    words_per_row: usize,
+    words: Vec<u64>,
+}
+
+impl BitMask {
+    /// All-clear mask of `width` x `height`.
+    pub fn new(width: usize, height: usize) -> Self {
+        let mut mask = Self::default();
+        mask.resize(width, height);
+        mask
+    }
+
+    /// Pack a row-major `bool` mask.
+    pub fn from_bools(mask: &[bool], width: usize, height: usize) -> Self {
+        assert_eq!(
+            mask.len(),
+            width * height,
+            "Mask length must equal width * height"
+        );
+        let mut bits = Self::new(width, height);
+        for (y, row) in mask.chunks_exact(width.max(1)).enumerate().take(height) {
+            bits.set_row(y, row);
+        }
+        bits
+    }
+
+    /// Change the size, reusing the allocation. Contents are unspecified
+    /// afterwards until every row is written.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.words_per_row = width.div_ceil(64);
+        self.words.resize(self.words_per_row * height, 0);
+    }
+
+    /// Mask width in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Mask height in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Number of `u64` words per row.
+    pub fn words_per_row(&self) -> usize {
+        self.words_per_row
+    }
+
+    /// All rows, `words_per_row()` words each. Rows can be filled
+    /// independently (e.g. one band per thread) with [`Self::pack_row`].
+    pub fn words_mut(&mut self) -> &mut [u64] {
+        &mut self.words
+    }
+
+    /// Pack `row` into `words` (one row of a mask), clearing padding bits.
+    pub fn pack_row(row: &[bool], words: &mut [u64]) {
+        for (word, chunk) in words.iter_mut().zip(row.chunks(64)) {
+            *word = chunk
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (bit, &px)| acc | (u64::from(px) << bit));
+        }
+    }
+
+    /// Overwrite row `y` from a `width`-long `bool` row.
+    pub fn set_row(&mut self, y: usize, row: &[bool]) {
+        assert_eq!(row.len(), self.width, "Row length must equal mask width");
+        let wpr = self.words_per_row;
+        Self::pack_row(row, &mut self.words[y * wpr..(y + 1) * wpr]);
+    }
+
+    /// Whether pixel (`x`, `y`) is set.
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        assert!(x < self.width && y < self.height, "Pixel out of bounds");
+        self.row(y)[x / 64] >> (x % 64) & 1 == 1
+    }
+
+    /// Number of set pixels in the half-open columns `start..end` of row
+    /// `y`.
+    pub fn count_span(&self, y: usize, start: usize, end: usize) -> u32 {
+        if start >= end {
+            return 0;
+        }
+        assert!(end <= self.width, "Span exceeds mask width");
+        let row = self.row(y);
+        let (first, last) = (start / 64, (end - 1) / 64);
+        let mut count = 0;
+        for (i, &word) in row.iter().enumerate().take(last + 1).skip(first) {
+            let mut word = word;
+            if i == first {
+                word &= !0u64 << (start % 64);
+            }
+            let end_bit = end - i * 64;
+            if end_bit < 64 {
+                word &= (1u64 << end_bit) - 1;
+            }
+            count += word.count_ones();
+        }
+        count
+    }
+
+    /// Call `f(start, end)` for every maximal half-open run of set
+    /// pixels on row `y`, left to right.
+    pub fn for_each_run(&self, y: usize, mut f: impl FnMut(usize, usize)) {
+        let mut run_start = None;
+        for (i, &word) in self.row(y).iter().enumerate() {
+            let mut bit = 0;
+            while bit < 64 {
+                // Inside a run look for its end (a clear bit), outside it
+                // for the next set bit.
+                let wanted = if run_start.is_some() { !word } else { word };
+                let next = (wanted & (!0u64 << bit)).trailing_zeros();
+                if next == 64 {
+                    break;
+                }
+                match run_start.take() {
+                    Some(start) => f(start, i * 64 + next as usize),
+                    None => run_start = Some(i * 64 + next as usize),
+                }
+                bit = next;
+            }
+        }
+        if let Some(start) = run_start {
+            f(start, self.width);
+        }
+    }
+
+    /// Clear the half-open columns `start..end` of row `y`.
+    pub fn clear_span(&mut self, y: usize, start: usize, end: usize) {
+        assert!(start <= end && end <= self.width, "Span exceeds mask width");
+        let row = &mut self.words[y * self.words_per_row..(y + 1) * self.words_per_row];
+        let mut x = start;
+        while x < end {
+            let (i, lo) = (x / 64, x % 64);
+            let hi = (end - i * 64).min(64);
+            let bits = if hi - lo == 64 {
+                !0
+            } else {
+                ((1u64 << (hi - lo)) - 1) << lo
+            };
+            row[i] &= !bits;
+            x = i * 64 + hi;
+        }
+    }
+
+    /// Total number of set pixels.
+    pub fn count_ones(&self) -> u32 {
+        self.words.iter().map(|w| w.count_ones()).sum()
+    }
+
+    fn row(&self, y: usize) -> &[u64] {
+        &self.words[y * self.words_per_row..(y + 1) * self.words_per_row]
+    }
+
+    /// Mask of the valid bits in the last word of a row.
+    fn tail_mask(&self) -> u64 {
+        match self.width % 64 {
+            0 => !0,
+            bits => (1u64 << bits) - 1,
+        }
+    }
+}
+
+/// Opening and closing with square kernels, applied in that order
+/// (remove specks, then fill holes). A kernel size of `0` or `1` skips
+/// the operation; sizes must be odd.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaskFilter {
+    pub open: usize,
+    pub close: usize,
+}
+
+impl MaskFilter {
+    /// Filter with an opening of `open` x `open` and a closing of
+    /// `close` x `close` pixels.
+    pub fn new(open: usize, close: usize) -> Self {
+        assert!(
+            open % 2 == 1 || open == 0,
+            "Opening kernel size must be odd"
+        );
+        assert!(
+            close % 2 == 1 || close == 0,
+            "Closing kernel size must be odd"
+        );
+        Self { open, close }
+    }
+
+    /// Whether the filter changes any mask at all.
+    pub fn is_noop(&self) -> bool {
+        self.open <= 1 && self.close <= 1
+    }
+
+    /// Filter `mask` in place. `scratch` is resized as needed and can be
+    /// reused across frames to avoid allocating.
+    pub fn apply(&self, mask: &mut BitMask, scratch: &mut BitMask) {
+        let open = self.open.saturating_sub(1) / 2;
+        let close = self.close.saturating_sub(1) / 2;
+        if open > 0 {
+            morph(mask, scratch, open, Op::Erode);
+            morph(mask, scratch, open, Op::Dilate);
+        }
+        if close > 0 {
+            morph(mask, scratch, close, Op::Dilate);
+            morph(mask, scratch, close, Op::Erode);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Erode,
+    Dilate,
+}
+
+impl Op {
+    /// Value of pixels outside the frame.
+    fn fill(self) -> u64 {
+        match self {
+            Op::Erode => !0,
+            Op::Dilate => 0,
+        }
+    }
+
+    #[inline]
+    fn combine(self, a: u64, b: u64) -> u64 {
+        match self {
+            Op::Erode => a & b,
+            Op::Dilate => a | b,
+        }
+    }
+}
+
+/// Erode or dilate `mask` in place with a `(2 * radius + 1)`-square
+/// kernel: horizontally into `scratch`, then vertically back.
+fn morph(mask: &mut BitMask, scratch: &mut BitMask, radius: usize, op: Op) {
+    scratch.resize(mask.width, mask.height);
+    let wpr = mask.words_per_row;
+    if wpr == 0 {
+        return;
+    }
+    let tail = mask.tail_mask();
+    let fill = op.fill();
+    for y in 0..mask.height {
+        let src = mask.row(y);
+        let dst = &mut scratch.words[y * wpr..(y + 1) * wpr];
+        // Word `i` of the row after padding bits take the fill value.
+        let word = |i: isize| -> u64 {
+            if i < 0 || i >= wpr as isize {
+                fill
+            } else if i as usize == wpr - 1 {
+                (src[i as usize] & tail) | (fill & !tail)
+            } else {
+                src[i as usize]
+            }
+        };
+        // Word `i` of the row shifted so bit x holds pixel x + shift.
+        let shifted = |i: usize, shift: isize| -> u64 {
+            let base = i as isize + shift.div_euclid(64);
+            let bits = shift.rem_euclid(64) as u32;
+            if bits == 0 {
+                word(base)
+            } else {
+                (word(base) >> bits) | (word(base + 1) << (64 - bits))
+            }
+        };
+        for (i, out) in dst.iter_mut().enumerate() {
+            let mut acc = word(i as isize);
+            for shift in 1..=radius as isize {
+                acc = op.combine(acc, shifted(i, shift));
+                acc = op.combine(acc, shifted(i, -shift));
+            }
+            *out = acc;
+        }
+        dst[wpr - 1] &= tail;
+    }
+    for y in 0..mask.height {
+        let rows = y.saturating_sub(radius)..(y + radius + 1).min(mask.height);
+        for i in 0..wpr {
+            mask.words[y * wpr + i] = rows
+                .clone()
+                .map(|row| scratch.words[row * wpr + i])
+                .reduce(|a, b| op.combine(a, b))
+                .unwrap_or(fill);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic speckle: roughly one pixel in `one_in` set.
+    fn speckle(width: usize, height: usize, one_in: u32, seed: u32) -> Vec<bool> {
+        let mut state = seed.wrapping_mul(2_654_435_761).max(1);
+        (0..width * height)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state.is_multiple_of(one_in)
+            })
+            .collect()
+    }
+
+    /// Straightforward per-pixel reference: in-bounds neighbours only.
+    fn reference(
+        mask: &[bool],
+        width: usize,
+        height: usize,
+        radius: usize,
+        erode: bool,
+    ) -> Vec<bool> {
+        let mut out = vec![false; mask.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let ys = y.saturating_sub(radius)..(y + radius + 1).min(height);
+                let xs = x.saturating_sub(radius)..(x + radius + 1).min(width);
+                let mut window = ys.flat_map(|yy| xs.clone().map(move |xx| mask[yy * width + xx]));
+                out[y * width + x] = if erode {
+                    window.all(|px| px)
+                } else {
+                    window.any(|px| px)
+                };
+            }
+        }
+        out
+    }
+
+    fn unpack(bits: &BitMask) -> Vec<bool> {
+        (0..bits.height())
+            .flat_map(|y| (0..bits.width()).map(move |x| bits.get(x, y)))
+            .collect()
+    }
+
+    #[test]
+    fn erode_and_dilate_match_reference() {
+        // Widths straddling word boundaries.
+        for (width, height) in [(5, 4), (64, 3), (70, 9), (130, 6)] {
+            for radius in 1..=3 {
+                for (seed, one_in) in [(1, 2), (2, 3), (3, 5)] {
+                    let mask = speckle(width, height, one_in, seed);
+                    for (op, erode) in [(Op::Erode, true), (Op::Dilate, false)] {
+                        let mut bits = BitMask::from_bools(&mask, width, height);
+                        morph(&mut bits, &mut BitMask::default(), radius, op);
+                        assert_eq!(
+                            unpack(&bits),
+                            reference(&mask, width, height, radius, erode),
+                            "{width}x{height} radius {radius} erode {erode}",
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn opening_removes_speckle_and_keeps_plants() {
+        let (width, height) = (96, 32);
+        let mut mask = speckle(width, height, 40, 7);
+        // A 6x6 plant.
+        for y in 10..16 {
+            mask[y * width + 70..y * width + 76].fill(true);
+        }
+        let mut bits = BitMask::from_bools(&mask, width, height);
+        assert!(bits.count_ones() > 36);
+        MaskFilter::new(3, 0).apply(&mut bits, &mut BitMask::default());
+        assert_eq!(bits.count_ones(), 36);
+        assert_eq!(bits.count_span(12, 70, 76), 6);
+    }
+
+    #[test]
+    fn closing_fills_pinholes() {
+        let (width, height) = (20, 10);
+        let mut mask = vec![true; width * height];
+        mask[4 * width + 7] = false;
+        mask[6 * width + 12] = false;
+        let mut bits = BitMask::from_bools(&mask, width, height);
+        MaskFilter::new(0, 3).apply(&mut bits, &mut BitMask::default());
+        assert_eq!(bits.count_ones(), (width * height) as u32);
+    }
+
+    #[test]
+    fn count_span_matches_bools() {
+        let (width, height) = (150, 2);
+        let mask = speckle(width, height, 2, 11);
+        let bits = BitMask::from_bools(&mask, width, height);
+        for (start, end) in [(0, 150), (3, 64), (63, 65), (64, 128), (100, 101), (7, 7)] {
+            let expected = mask[width + start..width + end]
+                .iter()
+                .filter(|&&px| px)
+                .count();
+            assert_eq!(
+                bits.count_span(1, start, end),
+                expected as u32,
+                "{start}..{end}"
+            );
+        }
+    }
+
+    #[test]
+    fn runs_and_clear_span_match_bools() {
+        let (width, height) = (150, 3);
+        let mask = speckle(width, height, 2, 5);
+        let original = BitMask::from_bools(&mask, width, height);
+        let mut bits = original.clone();
+        for y in 0..height {
+            let mut from_runs = vec![false; width];
+            bits.for_each_run(y, |start, end| from_runs[start..end].fill(true));
+            assert_eq!(from_runs, mask[y * width..(y + 1) * width], "row {y}");
+        }
+        bits.clear_span(1, 3, 140);
+        assert_eq!(bits.count_span(1, 3, 140), 0);
+        assert_eq!(bits.count_span(1, 0, 3), original.count_span(1, 0, 3));
+        assert_eq!(
+            bits.count_span(1, 140, 150),
+            original.count_span(1, 140, 150)
+        );
+        assert_eq!(
+            bits.count_ones(),
+            original.count_ones() - original.count_span(1, 3, 140)
+        );
+    }
+}
@@ -1,6 +1,17 @@
 //! Wiring of ExG mask -> lane reduction -> GPIO output.
 
-use crate::{io_gpio::NozzleControl, lanes::LaneReducer, vision::PlantVision};
+#[cfg(unix)]
+use crate::gps::Gps;
+use crate::{
+    adaptive::AdaptiveThreshold,
+    blobs::Blob,
+    gps::GpsReading,
+    io_gpio::NozzleControl,
+    lanes::{LaneLayout, LaneReducer, LaneStatus},
+    morphology::BitMask,
+    pwm::DutyCurve,
+    vision::{PixelFormat, PlantVision, Rect, Scorer},
+};
 
 /// Processing pipeline using a boxed GPIO implementation.
 pub struct Pipeline {
@@ -9,6 +20,20 @@ pub struct Pipeline {
     vision: PlantVision,
     width: usize,
     height: usize,
+    workers: usize,
+    adaptive: Option<AdaptiveThreshold>,
+    /// Packed region mask for banded detection ahead of a mask filter.
+    bits: BitMask,
+    #[cfg(unix)]
+    gps: Option<Gps>,
+    /// GPS reading taken for the last frame.
+    gps_reading: Option<GpsReading>,
+    duty_curve: Option<DutyCurve>,
+    /// PWM duty per lane for the last frame.
+    duty: Vec<f32>,
+    /// Nozzle backend fault after the last frame.
+    fault: Option<String>,
+    fault_changed: bool,
 }
 
 impl Pipeline {
@@ -26,25 +51,522 @@ impl Pipeline {
             vision,
             width,
             height,
+            workers: 1,
+            adaptive: None,
+            bits: BitMask::default(),
+            #[cfg(unix)]
+            gps: None,
+            gps_reading: None,
+            duty_curve: None,
+            duty: Vec::new(),
+            fault: None,
+            fault_changed: false,
         }
     }
 
-    /// Process one RGB frame.
-    pub fn process(&mut self, frame: &[u8]) {
+    /// Split detection and lane counting across `workers` threads, each
+    /// handling a horizontal band of the frame. Lane decisions are
+    /// identical for any worker count.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "At least one worker is required");
+        self.workers = workers;
+        self
+    }
+
+    /// Pick the ExG threshold for every frame with `adaptive` (Otsu's
+    /// method over the region of interest) before detection.
+    ///
+    /// # Panics
+    /// Panics if the detector uses a lookup table, which bakes in a fixed
+    /// threshold, or takes RGBN pixels.
+    pub fn with_adaptive_threshold(mut self, adaptive: AdaptiveThreshold) -> Self {
+        assert!(
+            self.vision.lookup_bits().is_none(),
+            "Adaptive threshold cannot be used with a lookup table"
+        );
+        assert_eq!(
+            self.vision.pixel_format(),
+            PixelFormat::Rgb24,
+            "Adaptive threshold needs RGB frames"
+        );
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Poll `gps` once per frame, before the nozzles are driven, so a
+    /// ground speed linked to it is current for delay compensation.
+    #[cfg(unix)]
+    pub fn with_gps(mut self, gps: Gps) -> Self {
+        self.gps = Some(gps);
+        self
+    }
+
+    /// Drive the nozzles with a PWM duty per lane from `curve` instead of
+    /// plain on/off.
+    pub fn with_duty_curve(mut self, curve: DutyCurve) -> Self {
+        self.duty_curve = Some(curve);
+        self
+    }
+
+    /// PWM duty applied per lane for the last frame, or `None` without a
+    /// duty curve.
+    pub fn duty(&self) -> Option<&[f32]> {
+        self.duty_curve.map(|_| self.duty.as_slice())
+    }
+
+    /// Nozzle backend fault after the last frame (see
+    /// [`NozzleControl::fault`]).
+    pub fn nozzle_fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+
+    /// Whether the last frame raised, changed or cleared the nozzle fault.
+    pub fn nozzle_fault_changed(&self) -> bool {
+        self.fault_changed
+    }
+
+    /// GPS reading taken for the last frame, or `None` without a GPS.
+    pub fn gps(&self) -> Option<GpsReading> {
+        self.gps_reading
+    }
+
+    /// ExG threshold used for the last frame (fixed or adaptive).
+    pub fn exg_threshold(&self) -> i16 {
+        self.vision.exg_threshold
+    }
+
+    /// Layout of the frames [`Self::process`] expects.
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.vision.pixel_format()
+    }
+
+    /// Whether the ExG threshold adapts per frame.
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive.is_some()
+    }
+
+    /// Process one frame (RGB, or RGBN in NDVI mode) and return the
+    /// per-lane outcome whose `on` states were applied to the nozzles.
+    pub fn process(&mut self, frame: &[u8]) -> &[LaneStatus] {
+        self.process_frame(frame, self.width, self.height)
+    }
+
+    /// Like [`Self::process`] for a frame of explicit dimensions (IPC
+    /// frames may vary in size; lane hysteresis carries over).
+    pub fn process_frame(&mut self, frame: &[u8], width: usize, height: usize) -> &[LaneStatus] {
+        let channels = self.vision.pixel_format().channels();
         assert_eq!(
             frame.len(),
-            self.width * self.height * 3,
-            "Frame length must match width * height * 3",
+            width * height * channels,
+            "Frame length must match width * height * {channels}",
         );
-        let mask = self.vision.detect(frame);
-        let lanes = self.reducer.reduce(&mask, self.width, self.height);
-        self.gpio.apply(&lanes);
+        #[cfg(unix)]
+        {
+            self.gps_reading = self.gps.as_ref().map(Gps::poll);
+        }
+        if let Some(adaptive) = &mut self.adaptive {
+            let region = self.reducer.region(width, height);
+            let balance = self.vision.white_balance();
+            self.vision.exg_threshold = adaptive.observe(frame, width, region, balance);
+        }
+        if self.workers == 1 {
+            self.reducer
+                .reduce_frame(frame, width, height, &self.vision);
+        } else if self.reducer.needs_full_mask() {
+            // Filtering and blob labelling need neighbouring rows, so
+            // bands only detect; the rest runs on the whole packed mask.
+            let region = self.reducer.layout(width, height).region();
+            detect_bits(
+                &self.vision,
+                region,
+                frame,
+                width,
+                self.workers,
+                &mut self.bits,
+            );
+            self.reducer.reduce_bits(&mut self.bits, width, height);
+        } else {
+            let layout = self.reducer.layout(width, height);
+            let counts = count_lanes(&self.vision, layout, frame, self.workers);
+            self.reducer.reduce_counts(&counts, width, height);
+        }
+        match &self.duty_curve {
+            Some(curve) => {
+                curve.lane_duty(self.reducer.status(), &mut self.duty);
+                self.gpio.apply_duty(&self.duty);
+            }
+            None => self.gpio.apply(self.reducer.lanes()),
+        }
+        self.update_fault();
+        self.reducer.status()
+    }
+
+    /// Per-lane outcome of the last frame.
+    pub fn status(&self) -> &[LaneStatus] {
+        self.reducer.status()
+    }
+
+    /// Blobs found in the last frame (see [`LaneReducer::blobs`]).
+    pub fn blobs(&self) -> Option<&[Blob]> {
+        self.reducer.blobs()
+    }
+
+    /// Crop row centres found in the last frame (see
+    /// [`LaneReducer::crop_rows`]).
+    pub fn crop_rows(&self) -> Option<&[f32]> {
+        self.reducer.crop_rows()
+    }
+
+    /// Whether `width` x `height` frames can be processed (see
+    /// [`LaneReducer::accepts`]).
+    pub fn accepts(&self, width: usize, height: usize) -> bool {
+        self.reducer.accepts(width, height)
+    }
+
+    /// Number of spray lanes.
+    pub fn lane_count(&self) -> usize {
+        self.reducer.lane_count()
     }
 
     /// Force every nozzle off. Call during shutdown so no valve is left
     /// open when the process exits.
     pub fn all_off(&mut self) {
-        let lanes = vec![false; self.reducer.lane_count()];
-        self.gpio.apply(&lanes);
+        self.gpio.all_off(self.reducer.lane_count());
+        self.update_fault();
+    }
+
+    fn update_fault(&mut self) {
+        let fault = self.gpio.fault();
+        self.fault_changed = fault != self.fault.as_deref();
+        if self.fault_changed {
+            self.fault = fault.map(str::to_owned);
+        }
+    }
+}
+
+/// Per-lane vegetation pixel counts for a frame, ready for
+/// [`LaneReducer::reduce_counts`].
+///
+/// Only the layout's region is scored. It is cut into up to `workers`
+/// horizontal bands. Each band is classified one row at a time into a
+/// row-sized scratch mask and counted straight into per-lane totals,
+/// which are summed at the end — the full frame mask is never
+/// materialised. With one worker everything runs on
+/// the calling thread; prefer [`LaneReducer::reduce_frame`] there, which
+/// also reuses its scratch buffers across frames.
+pub fn count_lanes<S: Scorer + Sync + ?Sized>(
+    scorer: &S,
+    layout: &LaneLayout,
+    frame: &[u8],
+    workers: usize,
+) -> Vec<u32> {
+    let (width, height) = layout.size();
+    let channels = scorer.channels();
+    assert_eq!(
+        frame.len(),
+        width * height * channels,
+        "Frame length must match width * height * {channels}",
+    );
+    let lanes = layout.lane_count();
+    let region = layout.region();
+    let count_band = |rows: std::ops::Range<usize>| {
+        let mut row_mask = vec![false; region.width];
+        let mut counts = vec![0u32; lanes];
+        for y in rows {
+            scorer.detect_into(region.row(frame, width, channels, y), &mut row_mask);
+            layout.count_row(y, &row_mask, &mut counts);
+        }
+        counts
+    };
+
+    let band_rows = region.height.div_ceil(workers.max(1)).max(1);
+    if band_rows >= region.height {
+        return count_band(0..region.height);
+    }
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..region.height)
+            .step_by(band_rows)
+            .map(|first| {
+                let rows = first..(first + band_rows).min(region.height);
+                scope.spawn(move || count_band(rows))
+            })
+            .collect();
+        let mut totals = vec![0u32; lanes];
+        for handle in handles {
+            let counts = handle.join().expect("lane counting worker panicked");
+            for (total, count) in totals.iter_mut().zip(counts) {
+                *total += count;
+            }
+        }
+        totals
+    })
+}
+
+/// Classify the `region` of a frame `width` pixels wide into the packed
+/// `bits`, in up to `workers` horizontal bands.
+pub fn detect_bits<S: Scorer + Sync + ?Sized>(
+    scorer: &S,
+    region: Rect,
+    frame: &[u8],
+    width: usize,
+    workers: usize,
+    bits: &mut BitMask,
+) {
+    bits.resize(region.width, region.height);
+    let words_per_row = bits.words_per_row();
+    if words_per_row == 0 || region.height == 0 {
+        return;
+    }
+    let channels = scorer.channels();
+    let detect_band = |first_row: usize, band: &mut [u64]| {
+        let mut row_mask = vec![false; region.width];
+        for (i, words) in band.chunks_exact_mut(words_per_row).enumerate() {
+            scorer.detect_into(
+                region.row(frame, width, channels, first_row + i),
+                &mut row_mask,
+            );
+            BitMask::pack_row(&row_mask, words);
+        }
+    };
+
+    let band_rows = region.height.div_ceil(workers.max(1)).max(1);
+    if band_rows >= region.height {
+        return detect_band(0, bits.words_mut());
+    }
+    std::thread::scope(|scope| {
+        for (i, band) in bits
+            .words_mut()
+            .chunks_mut(band_rows * words_per_row)
+            .enumerate()
+        {
+            scope.spawn(move || detect_band(i * band_rows, band));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::morphology::MaskFilter;
+    use crate::vision::Roi;
+
+    /// Deterministic frame mixing green, soil and in-between pixels so
+    /// lane coverage lands near the thresholds.
+    fn mixed_frame(width: usize, height: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2_654_435_761).max(1);
+        let mut frame = Vec::with_capacity(width * height * 3);
+        for _ in 0..width * height {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let px = match state % 3 {
+                0 => [40, 210, 40],
+                1 => [120, 90, 70],
+                _ => [(state >> 8) as u8, (state >> 16) as u8, (state >> 24) as u8],
+            };
+            frame.extend_from_slice(&px);
+        }
+        frame
+    }
+
+    #[test]
+    fn banded_workers_match_single_threaded_decisions() {
+        let (width, height) = (37, 23);
+        let vision = PlantVision::default();
+        let mut reference = LaneReducer::new(5, 0.3, 0.2);
+        let mut banded: Vec<LaneReducer> = (0..6).map(|_| LaneReducer::new(5, 0.3, 0.2)).collect();
+        for seed in 1..8 {
+            let frame = mixed_frame(width, height, seed);
+            let expected = reference.reduce(&vision.detect(&frame), width, height);
+            for (i, reducer) in banded.iter_mut().enumerate() {
+                let workers = i + 1;
+                let layout = reducer.layout(width, height);
+                let counts = count_lanes(&vision, layout, &frame, workers);
+                reducer.reduce_counts(&counts, width, height);
+                assert_eq!(
+                    reducer.lanes(),
+                    expected,
+                    "frame {seed} with {workers} workers",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn more_workers_than_rows_is_fine() {
+        let (width, height) = (8, 2);
+        let vision = PlantVision::default();
+        let mut reducer = LaneReducer::new(2, 0.3, 0.2);
+        let layout = reducer.layout(width, height);
+        let frame = mixed_frame(width, height, 3);
+        assert_eq!(
+            count_lanes(&vision, layout, &frame, 16),
+            count_lanes(&vision, layout, &frame, 1),
+        );
+    }
+
+    #[test]
+    fn banded_workers_respect_region_of_interest() {
+        let (width, height) = (24, 10);
+        let roi = Roi {
+            top: 3,
+            bottom: 2,
+            left: 5,
+            right: 1,
+            ref_width: width,
+            ref_height: height,
+        };
+        let vision = PlantVision::default();
+        let mut single = LaneReducer::new(3, 0.3, 0.2).with_roi(roi);
+        let mut banded = LaneReducer::new(3, 0.3, 0.2).with_roi(roi);
+        for seed in 0..5 {
+            let frame = mixed_frame(width, height, seed);
+            let expected = single.reduce_frame(&frame, width, height, &vision).to_vec();
+            let layout = banded.layout(width, height);
+            let counts = count_lanes(&vision, layout, &frame, 4);
+            assert_eq!(banded.reduce_counts(&counts, width, height), expected);
+        }
+    }
+
+    #[test]
+    fn banded_detection_with_mask_filter_matches_single_threaded() {
+        let (width, height) = (40, 13);
+        let vision = PlantVision::default();
+        let filter = MaskFilter::new(3, 3);
+        let mut single = LaneReducer::new(3, 0.3, 0.2).with_filter(filter);
+        let mut banded = LaneReducer::new(3, 0.3, 0.2).with_filter(filter);
+        let mut bits = BitMask::default();
+        for seed in 0..5 {
+            let frame = mixed_frame(width, height, seed);
+            let expected = single.reduce_frame(&frame, width, height, &vision).to_vec();
+            let region = banded.layout(width, height).region();
+            detect_bits(&vision, region, &frame, width, 4, &mut bits);
+            assert_eq!(banded.reduce_bits(&mut bits, width, height), expected);
+        }
+    }
+
+    #[test]
+    fn adaptive_threshold_follows_dusk_exposure() {
+        use crate::io_gpio::MockGpio;
+
+        let (width, height) = (16, 4);
+        // Pure ExG scorer tuned for noon: plants need ExG above 60.
+        let vision = PlantVision::new(60, 0.0, 0.0, (1.0, 0.0, 0.0, 0.0));
+        // Dusk: soil ExG -2, plants (left half) only ExG 40.
+        let frame: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                if i % width < 8 {
+                    [20, 50, 20]
+                } else {
+                    [40, 32, 26]
+                }
+            })
+            .collect();
+
+        let fixed = LaneReducer::new(2, 0.5, 0.2);
+        let mut pipeline = Pipeline::new(
+            fixed,
+            Box::new(MockGpio::default()),
+            vision.clone(),
+            width,
+            height,
+        );
+        assert!(!pipeline.process(&frame)[0].on);
+
+        let reducer = LaneReducer::new(2, 0.5, 0.2);
+        let mut pipeline = Pipeline::new(
+            reducer,
+            Box::new(MockGpio::default()),
+            vision,
+            width,
+            height,
+        )
+        .with_adaptive_threshold(AdaptiveThreshold::new(5, 80, 1));
+        let lanes: Vec<bool> = pipeline.process(&frame).iter().map(|s| s.on).collect();
+        assert_eq!(lanes, [true, false]);
+        assert!((5..40).contains(&pipeline.exg_threshold()));
+    }
+
+    #[test]
+    fn nozzle_faults_are_reported_on_change() {
+        /// Backend whose link fails while `down` is set.
+        struct Link(std::rc::Rc<std::cell::Cell<bool>>);
+
+        impl NozzleControl for Link {
+            fn apply(&mut self, _: &[bool]) {}
+
+            fn fault(&self) -> Option<&str> {
+                self.0.get().then_some("no response")
+            }
+        }
+
+        let down = std::rc::Rc::new(std::cell::Cell::new(false));
+        let (width, height) = (8, 2);
+        let frame = vec![120u8; width * height * 3];
+        let mut pipeline = Pipeline::new(
+            LaneReducer::new(2, 0.5, 0.2),
+            Box::new(Link(down.clone())),
+            PlantVision::default(),
+            width,
+            height,
+        );
+        let mut seen = Vec::new();
+        for state in [false, true, true, false, false] {
+            down.set(state);
+            pipeline.process(&frame);
+            seen.push((
+                pipeline.nozzle_fault_changed(),
+                pipeline.nozzle_fault().map(str::to_owned),
+            ));
+        }
+        assert_eq!(
+            seen,
+            [
+                (false, None),
+                (true, Some("no response".to_owned())),
+                (false, Some("no response".to_owned())),
+                (true, None),
+                (false, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn rgbn_frames_are_scored_by_ndvi_in_every_path() {
+        use crate::io_gpio::MockGpio;
+
+        let (width, height) = (24, 9);
+        // Reddish autumn leaves (bright NIR) in lanes 0 and 2, soil
+        // elsewhere. Colour alone would miss them.
+        let frame: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                if (i % width) / 6 % 2 == 0 {
+                    [140, 80, 60, 230]
+                } else {
+                    [120, 90, 70, 110]
+                }
+            })
+            .collect();
+        let vision = PlantVision::default().with_ndvi(0.2);
+        for workers in [1, 3] {
+            for filter in [None, Some(MaskFilter::new(3, 0))] {
+                let reducer = LaneReducer::new(4, 0.5, 0.2);
+                let reducer = match filter {
+                    Some(filter) => reducer.with_filter(filter),
+                    None => reducer,
+                };
+                let mut pipeline = Pipeline::new(
+                    reducer,
+                    Box::new(MockGpio::default()),
+                    vision.clone(),
+                    width,
+                    height,
+                )
+                .with_workers(workers);
+                assert_eq!(pipeline.pixel_format(), PixelFormat::Rgbn);
+                let lanes: Vec<bool> = pipeline.process(&frame).iter().map(|s| s.on).collect();
+                assert_eq!(lanes, [true, false, true, false], "{workers} worker(s)");
+            }
+        }
     }
 }
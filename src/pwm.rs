@@ -0,0 +1,413 @@
+//! PWM nozzle output proportional to weed density.
+//!
+//! PWM-capable solenoids can spray less than full flow: a lane over a
+//! few small weeds needs less than one over a dense patch. [`DutyCurve`]
+//! maps each lane's coverage ratio to a duty fraction for
+//! [`NozzleControl::apply_duty`], and two backends turn duty into pulses:
+//! [`SoftPwm`] toggles any on/off backend (the mock in particular) from a
+//! thread, and `RppalPwm` (with the `rpi` feature on ARM) drives the Pi's
+//! PWM hardware. Plain on/off backends keep working unchanged: by default
+//! they open every lane with a non-zero duty.
+
+use crate::io_gpio::NozzleControl;
+use crate::lanes::LaneStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Maps lane coverage to PWM duty: lanes that are on get `min_duty` at
+/// zero coverage rising to `max_duty` at `full_coverage`, shaped by
+/// `gamma` (`1` is linear, larger values hold low duty for longer).
+/// Lanes that are off get zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DutyCurve {
+    pub min_duty: f32,
+    pub max_duty: f32,
+    pub full_coverage: f32,
+    pub gamma: f32,
+}
+
+impl DutyCurve {
+    /// Duty of a lane that is on with `coverage`.
+    pub fn duty(&self, coverage: f32) -> f32 {
+        let t = (coverage / self.full_coverage)
+            .clamp(0.0, 1.0)
+            .powf(self.gamma);
+        self.min_duty + (self.max_duty - self.min_duty) * t
+    }
+
+    /// Duty of every lane in `status` into `duty`.
+    pub fn lane_duty(&self, status: &[LaneStatus], duty: &mut Vec<f32>) {
+        duty.clear();
+        duty.extend(
+            status
+                .iter()
+                .map(|s| if s.on { self.duty(s.ratio) } else { 0.0 }),
+        );
+    }
+}
+
+/// Hardware PWM channel (0 or 1) driving BCM `pin` on a Raspberry Pi.
+/// Only the pins the `pwm-2chan` overlay routes the channels to by
+/// default, 18 and 19, count: on 12 and 13 the channels are only wired
+/// with non-default overlay parameters.
+pub fn hardware_pwm_channel(pin: u8) -> Option<u8> {
+    match pin {
+        18 => Some(0),
+        19 => Some(1),
+        _ => None,
+    }
+}
+
+/// Slots per PWM period of [`SoftPwm`]: 5% duty resolution.
+pub const SOFT_PWM_STEPS: u32 = 20;
+
+/// On/off states of lanes with `duty` during slot `step` of a period of
+/// `steps` slots: each lane is on for the first `duty * steps` slots.
+pub fn pwm_states(duty: &[f32], step: u32, steps: u32, states: &mut [bool]) {
+    for (state, &d) in states.iter_mut().zip(duty) {
+        *state = (step as f32) < (d.clamp(0.0, 1.0) * steps as f32).round();
+    }
+}
+
+struct SoftPwmShared {
+    duty: Vec<f32>,
+    inner: Box<dyn NozzleControl + Send>,
+}
+
+/// Software PWM over an on/off backend: a thread switches the lanes of
+/// `inner` at [`SOFT_PWM_STEPS`] slots per period, passing on only
+/// changes, and each new duty is passed on through
+/// [`NozzleControl::pulsing`]. Boolean [`NozzleControl::apply`] calls run
+/// at full duty.
+pub struct SoftPwm {
+    shared: Arc<Mutex<SoftPwmShared>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    lanes: usize,
+    /// `inner`'s fault as of the last call into it from here.
+    fault: Option<String>,
+}
+
+impl SoftPwm {
+    /// Start pulsing `lanes` lanes of `inner` at `frequency` Hz, all off.
+    ///
+    /// # Panics
+    /// Panics unless `frequency` is positive and finite.
+    pub fn spawn(inner: Box<dyn NozzleControl + Send>, lanes: usize, frequency: f32) -> Self {
+        assert!(
+            frequency.is_finite() && frequency > 0.0,
+            "PWM frequency must be positive"
+        );
+        let shared = Arc::new(Mutex::new(SoftPwmShared {
+            duty: vec![0.0; lanes],
+            inner,
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let slot = Duration::from_secs_f32(1.0 / (frequency * SOFT_PWM_STEPS as f32));
+        let thread = {
+            let shared = shared.clone();
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("soft-pwm".into())
+                .spawn(move || {
+                    let mut states = vec![false; lanes];
+                    let mut last: Option<Vec<bool>> = None;
+                    let mut next = Instant::now();
+                    for step in (0..SOFT_PWM_STEPS).cycle() {
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        {
+                            let mut shared = shared.lock().unwrap();
+                            pwm_states(&shared.duty, step, SOFT_PWM_STEPS, &mut states);
+                            if last.as_deref() != Some(&states) {
+                                shared.inner.apply(&states);
+                                last = Some(states.clone());
+                            }
+                        }
+                        next += slot;
+                        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+                    }
+                })
+                .expect("failed to spawn the software PWM thread")
+        };
+        Self {
+            shared,
+            stop,
+            thread: Some(thread),
+            lanes,
+            fault: None,
+        }
+    }
+}
+
+impl NozzleControl for SoftPwm {
+    fn apply(&mut self, lanes: &[bool]) {
+        let duty: Vec<f32> = lanes.iter().map(|&on| if on { 1.0 } else { 0.0 }).collect();
+        self.apply_duty(&duty);
+    }
+
+    fn apply_duty(&mut self, duty: &[f32]) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.duty.clear();
+        shared.duty.extend_from_slice(duty);
+        shared.inner.pulsing(duty);
+        self.fault = shared.inner.fault().map(str::to_owned);
+    }
+
+    fn all_off(&mut self, lanes: usize) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.duty.fill(0.0);
+        shared.inner.all_off(lanes);
+        self.fault = shared.inner.fault().map(str::to_owned);
+    }
+
+    fn fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+}
+
+impl Drop for SoftPwm {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.inner.all_off(self.lanes);
+        }
+    }
+}
+
+// Hardware PWM needs rppal, which only builds for ARM (see io_gpio).
+#[cfg(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64")))]
+use rppal::{
+    gpio::{Gpio, OutputPin},
+    pwm::{Channel, Polarity, Pwm},
+};
+
+#[cfg(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64")))]
+enum PwmOutput {
+    /// One of the two hardware PWM channels.
+    Hardware(Pwm),
+    /// Any other pin, pulsed by rppal's software PWM.
+    Software(OutputPin),
+}
+
+#[cfg(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64")))]
+/// PWM implementation using `rppal`.
+///
+/// Lanes on BCM 18 (PWM0) or 19 (PWM1) use the hardware PWM channels,
+/// which need the `pwm-2chan` overlay in `/boot/config.txt`; lanes on
+/// other pins fall back to rppal's software PWM. Every output starts and
+/// is left at zero duty.
+pub struct RppalPwm {
+    outputs: Vec<PwmOutput>,
+    frequency: f64,
+    duty: Vec<f32>,
+}
+
+#[cfg(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64")))]
+impl RppalPwm {
+    /// Create from BCM pin numbers, pulsing at `frequency` Hz.
+    ///
+    /// # Panics
+    /// Panics with a descriptive message if a PWM channel or pin cannot be
+    /// acquired. Two lanes on one channel are rejected by
+    /// [`crate::config::Config::validate`].
+    pub fn new(pins: &[u8], frequency: f32) -> Self {
+        let frequency = f64::from(frequency);
+        let gpio = Gpio::new().expect(
+            "failed to access the GPIO peripheral — is this a Raspberry Pi \
+             and is /dev/gpiomem accessible (root or `gpio` group)?",
+        );
+        let outputs = pins
+            .iter()
+            .map(|&p| match hardware_channel(p) {
+                Some(channel) => {
+                    let pwm = Pwm::with_frequency(channel, frequency, 0.0, Polarity::Normal, true)
+                        .unwrap_or_else(|e| {
+                            panic!("failed to acquire PWM channel {channel:?} (pin {p}): {e}")
+                        });
+                    PwmOutput::Hardware(pwm)
+                }
+                None => {
+                    let mut pin = gpio
+                        .get(p)
+                        .unwrap_or_else(|e| panic!("failed to acquire GPIO pin {p}: {e}"))
+                        .into_output_low();
+                    pin.set_reset_on_drop(false);
+                    PwmOutput::Software(pin)
+                }
+            })
+            .collect();
+        Self {
+            outputs,
+            frequency,
+            duty: vec![0.0; pins.len()],
+        }
+    }
+}
+
+/// Hardware PWM channel routed to BCM `pin`, if any.
+#[cfg(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64")))]
+fn hardware_channel(pin: u8) -> Option<Channel> {
+    match hardware_pwm_channel(pin)? {
+        0 => Some(Channel::Pwm0),
+        _ => Some(Channel::Pwm1),
+    }
+}
+
+#[cfg(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64")))]
+impl NozzleControl for RppalPwm {
+    fn apply(&mut self, lanes: &[bool]) {
+        let duty: Vec<f32> = lanes.iter().map(|&on| if on { 1.0 } else { 0.0 }).collect();
+        self.apply_duty(&duty);
+    }
+
+    fn apply_duty(&mut self, duty: &[f32]) {
+        for ((output, last), &d) in self.outputs.iter_mut().zip(&mut self.duty).zip(duty) {
+            let d = d.clamp(0.0, 1.0);
+            if d == *last {
+                continue;
+            }
+            *last = d;
+            match output {
+                PwmOutput::Hardware(pwm) => {
+                    let _ = pwm.set_duty_cycle(f64::from(d));
+                }
+                PwmOutput::Software(pin) if d == 0.0 || d == 1.0 => {
+                    let _ = pin.clear_pwm();
+                    if d == 1.0 {
+                        pin.set_high();
+                    } else {
+                        pin.set_low();
+                    }
+                }
+                PwmOutput::Software(pin) => {
+                    let _ = pin.set_pwm_frequency(self.frequency, f64::from(d));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "rpi", any(target_arch = "arm", target_arch = "aarch64")))]
+impl Drop for RppalPwm {
+    fn drop(&mut self) {
+        for output in &mut self.outputs {
+            match output {
+                PwmOutput::Hardware(pwm) => {
+                    let _ = pwm.set_duty_cycle(0.0);
+                }
+                PwmOutput::Software(pin) => {
+                    let _ = pin.clear_pwm();
+                    pin.set_low();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(ratio: f32, on: bool) -> LaneStatus {
+        LaneStatus {
+            ratio,
+            pixels: 0,
+            was_on: false,
+            on,
+        }
+    }
+
+    #[test]
+    fn duty_follows_coverage_for_lanes_that_are_on() {
+        let curve = DutyCurve {
+            min_duty: 0.2,
+            max_duty: 1.0,
+            full_coverage: 0.4,
+            gamma: 2.0,
+        };
+        let mut duty = Vec::new();
+        curve.lane_duty(
+            &[
+                status(0.0, true),
+                status(0.2, true),
+                status(0.8, true),
+                status(0.3, false),
+            ],
+            &mut duty,
+        );
+        assert_eq!(duty, [0.2, 0.4, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn pwm_states_spread_duty_over_the_period() {
+        let duty = [0.0, 0.25, 0.5, 1.0];
+        let mut on = [0; 4];
+        let mut states = [false; 4];
+        for step in 0..20 {
+            pwm_states(&duty, step, 20, &mut states);
+            for (count, &state) in on.iter_mut().zip(&states) {
+                *count += u32::from(state);
+            }
+        }
+        assert_eq!(on, [0, 5, 10, 20]);
+    }
+
+    #[test]
+    fn soft_pwm_pulses_the_inner_backend() {
+        #[derive(Default)]
+        struct Seen {
+            on: Vec<bool>,
+            off: Vec<bool>,
+            last: Vec<bool>,
+        }
+        struct Recorder(Arc<Mutex<Seen>>);
+        impl NozzleControl for Recorder {
+            fn apply(&mut self, lanes: &[bool]) {
+                let mut seen = self.0.lock().unwrap();
+                seen.on.resize(lanes.len(), false);
+                seen.off.resize(lanes.len(), false);
+                for (lane, &state) in lanes.iter().enumerate() {
+                    seen.on[lane] |= state;
+                    seen.off[lane] |= !state;
+                }
+                seen.last = lanes.to_vec();
+            }
+        }
+        let seen = Arc::new(Mutex::new(Seen::default()));
+        let mut pwm = SoftPwm::spawn(Box::new(Recorder(seen.clone())), 3, 100.0);
+        pwm.apply_duty(&[1.0, 0.0, 0.5]);
+        std::thread::sleep(Duration::from_millis(50));
+        {
+            let mut seen = seen.lock().unwrap();
+            assert_eq!(seen.on, [true, false, true]);
+            assert!(seen.off[2], "half duty never switched off");
+            *seen = Seen::default();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!seen.lock().unwrap().off[0], "full duty switched off");
+        drop(pwm);
+        assert_eq!(seen.lock().unwrap().last, [false; 3]);
+    }
+
+    #[test]
+    fn soft_pwm_reports_the_fault_of_the_pulsed_backend() {
+        struct Unplugged;
+        impl NozzleControl for Unplugged {
+            fn apply(&mut self, _lanes: &[bool]) {}
+            fn fault(&self) -> Option<&str> {
+                Some("unplugged")
+            }
+        }
+        let mut pwm = SoftPwm::spawn(Box::new(Unplugged), 2, 100.0);
+        assert_eq!(pwm.fault(), None);
+        pwm.apply_duty(&[0.5, 0.0]);
+        assert_eq!(pwm.fault(), Some("unplugged"));
+    }
+}
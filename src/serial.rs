@@ -0,0 +1,129 @@
+//! Raw serial ports for the GPS receiver and Modbus relay boards.
+//!
+//! Opening and polling a port uses termios, so `open` and
+//! `wait_readable` exist on Unix only.
+
+use serde::Deserialize;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(all(unix, test))]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(all(unix, test))]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::time::Duration;
+
+/// Parity bit of an 8-bit, one stop bit serial line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    #[default]
+    None,
+    Even,
+    Odd,
+}
+
+/// Whether a port is opened for reading only or for both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// A receiver we only listen to, such as a GPS.
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Open the serial device or pty at `path` with `access` as a raw 8-bit
+/// line at `baud` with `parity` and one stop bit.
+#[cfg(unix)]
+pub fn open(path: &Path, access: Access, baud: u32, parity: Parity) -> io::Result<File> {
+    let speed = match baud {
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported baud rate {baud}"),
+            ))
+        }
+    };
+    let file = OpenOptions::new()
+        .read(true)
+        .write(access == Access::ReadWrite)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+    // SAFETY: `fd` is open for the lifetime of `file` and `termios` is a
+    // plain C struct that tcgetattr fully initialises before use.
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        match parity {
+            Parity::None => {}
+            Parity::Even => termios.c_cflag |= libc::PARENB,
+            Parity::Odd => termios.c_cflag |= libc::PARENB | libc::PARODD,
+        }
+        libc::cfsetispeed(&mut termios, speed);
+        libc::cfsetospeed(&mut termios, speed);
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+/// Wait up to `timeout` for `port` to have input. `true` also when the
+/// line has hung up, so the next read reports the end or the error.
+#[cfg(unix)]
+pub fn wait_readable(port: &File, timeout: Duration) -> io::Result<bool> {
+    let mut poll = libc::pollfd {
+        fd: port.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `poll` is a valid pollfd for the call.
+    let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+    if ready < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ready > 0)
+}
+
+/// Recognised baud rates, for config validation.
+pub fn is_supported_baud(baud: u32) -> bool {
+    matches!(baud, 4800 | 9600 | 19200 | 38400 | 57600 | 115200)
+}
+
+/// Open a pseudo-terminal: the master end and the slave's path.
+#[cfg(all(unix, test))]
+pub(crate) fn pty() -> (File, PathBuf) {
+    // SAFETY: plain libc calls on a descriptor we own; ptsname_r
+    // writes a NUL-terminated path into the buffer.
+    unsafe {
+        let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        assert!(master >= 0, "posix_openpt failed");
+        assert_eq!(libc::grantpt(master), 0);
+        assert_eq!(libc::unlockpt(master), 0);
+        let mut name = [0 as libc::c_char; 128];
+        assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0);
+        let path = std::ffi::CStr::from_ptr(name.as_ptr());
+        (
+            File::from_raw_fd(master),
+            PathBuf::from(path.to_str().unwrap()),
+        )
+    }
+}
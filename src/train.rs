@@ -0,0 +1,551 @@
+//! Fitting the fused detector's weights to labelled images.
+//!
+//! [`crate::vision::PlantVision`] scores a pixel as a weighted sum of
+//! three cue terms plus a bias, i.e. a linear model tuned by hand in
+//! `[vision.weights]`. Given RGB images with vegetation masks, [`fit`]
+//! learns the weights by class-balanced logistic regression on those same
+//! cues (see [`crate::vision::PlantVision::cues`]) and folds the fitted
+//! intercept into `exg_threshold`. [`Confusion`] scores any detector on a
+//! held-out part of the images.
+//!
+//! Images are binary PPM (`P6`) files; masks are PPM or PGM (`P5`) files
+//! of the same size in which any channel of at least 128 marks vegetation.
+
+use crate::vision::PlantVision;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::Range;
+use std::path::Path;
+
+/// Rows per band of the holdout split: neighbouring rows are nearly
+/// identical, so rows are held out in bands rather than one by one.
+const BAND_ROWS: usize = 16;
+
+/// Newton iterations before giving up on convergence.
+const MAX_ITERATIONS: usize = 50;
+
+/// L2 penalty per unit of sample weight. Keeps the weights finite when
+/// the classes are perfectly separable.
+const RIDGE: f64 = 1e-4;
+
+/// Largest PNM pixel payload read, so a corrupt header cannot demand an
+/// arbitrary allocation.
+const MAX_IMAGE_BYTES: usize = 256 * 1024 * 1024;
+
+/// A binary PNM image, 1 (PGM) or 3 (PPM) bytes per pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Read a binary PPM (`P6`) or PGM (`P5`) image with a maximum value
+    /// of 255.
+    pub fn read_pnm<R: Read>(reader: R) -> io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let magic = header_token(&mut reader)?;
+        let channels = match magic.as_str() {
+            "P6" => 3,
+            "P5" => 1,
+            _ => return Err(invalid(format!("unsupported PNM type {magic:?}"))),
+        };
+        let mut field = |name: &str| -> io::Result<usize> {
+            let token = header_token(&mut reader)?;
+            token
+                .parse()
+                .map_err(|_| invalid(format!("bad PNM {name} {token:?}")))
+        };
+        let width = field("width")?;
+        let height = field("height")?;
+        let max = field("maximum value")?;
+        if max != 255 {
+            return Err(invalid(format!(
+                "only 8-bit PNM is supported (maximum value {max})"
+            )));
+        }
+        let size = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(channels))
+            .filter(|&size| size <= MAX_IMAGE_BYTES)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "PNM image {width}x{height} exceeds {MAX_IMAGE_BYTES} bytes"
+                ))
+            })?;
+        let mut data = vec![0; size];
+        reader.read_exact(&mut data)?;
+        Ok(Self {
+            width,
+            height,
+            channels,
+            data,
+        })
+    }
+
+    /// Read a PPM or PGM file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Self::read_pnm(file)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Next whitespace-separated header token, skipping `#` comments. Consumes
+/// the single whitespace byte that ends it, so after the maximum value the
+/// reader sits on the first pixel byte.
+fn header_token<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut token = String::new();
+    let mut byte = [0u8];
+    loop {
+        reader.read_exact(&mut byte)?;
+        match byte[0] {
+            b'#' if token.is_empty() => {
+                let mut comment = Vec::new();
+                reader.read_until(b'\n', &mut comment)?;
+            }
+            c if c.is_ascii_whitespace() => {
+                if !token.is_empty() {
+                    return Ok(token);
+                }
+            }
+            c => token.push(char::from(c)),
+        }
+    }
+}
+
+/// Labelled pixels, kept as image rows so a detector sees them as it
+/// would see a frame.
+#[derive(Debug, Clone, Default)]
+pub struct LabelledRows {
+    rgb: Vec<u8>,
+    labels: Vec<bool>,
+    /// Pixel range of each row.
+    rows: Vec<Range<usize>>,
+}
+
+impl LabelledRows {
+    /// No rows yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the pixels of `image` whose row lies in `rows`, labelled by
+    /// `mask`.
+    ///
+    /// # Panics
+    /// Panics unless `image` is RGB and `mask` has the same size.
+    pub fn push_rows(&mut self, image: &Image, mask: &Image, rows: Range<usize>) {
+        assert_eq!(image.channels, 3, "Training images must be RGB");
+        assert_eq!(
+            (image.width, image.height),
+            (mask.width, mask.height),
+            "Mask size must equal image size"
+        );
+        for y in rows {
+            let start = self.labels.len();
+            let pixels = y * image.width..(y + 1) * image.width;
+            self.rgb
+                .extend_from_slice(&image.data[pixels.start * 3..pixels.end * 3]);
+            self.labels.extend(
+                mask.data[pixels.start * mask.channels..pixels.end * mask.channels]
+                    .chunks_exact(mask.channels)
+                    .map(|px| px.iter().any(|&v| v >= 128)),
+            );
+            self.rows.push(start..self.labels.len());
+        }
+    }
+
+    /// Number of pixels.
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Whether there are no pixels.
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Number of pixels labelled vegetation.
+    pub fn vegetation(&self) -> usize {
+        self.labels.iter().filter(|&&label| label).count()
+    }
+
+    /// Every pixel with its label.
+    fn pixels(&self) -> impl Iterator<Item = ([u8; 3], bool)> + '_ {
+        self.rgb
+            .as_chunks::<3>()
+            .0
+            .iter()
+            .copied()
+            .zip(self.labels.iter().copied())
+    }
+}
+
+/// Split labelled images into training and held-out rows. Each image is
+/// cut into bands of 16 rows and a `holdout` fraction of the bands, spread
+/// evenly down the image from the first, is held out.
+///
+/// # Panics
+/// Panics unless `holdout` is in `0..1`, or on a mismatched image/mask
+/// pair (see [`LabelledRows::push_rows`]).
+pub fn split(images: &[(Image, Image)], holdout: f32) -> (LabelledRows, LabelledRows) {
+    assert!(
+        (0.0..1.0).contains(&holdout),
+        "Holdout fraction must be in [0, 1)"
+    );
+    let (mut train, mut held_out) = (LabelledRows::new(), LabelledRows::new());
+    for (image, mask) in images {
+        for (band, start) in (0..image.height).step_by(BAND_ROWS).enumerate() {
+            let rows = start..(start + BAND_ROWS).min(image.height);
+            let held = ((band as f32 + 0.5) * holdout).fract() < holdout;
+            let set = if held { &mut held_out } else { &mut train };
+            set.push_rows(image, mask, rows);
+        }
+    }
+    (train, held_out)
+}
+
+/// Weights and ExG threshold fitted by [`fit`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trained {
+    pub exg_threshold: i16,
+    pub exg: f32,
+    pub green_ratio: f32,
+    pub chroma: f32,
+    pub bias: f32,
+    /// Training pixels that passed the detector's gates and were fitted.
+    pub fitted: usize,
+}
+
+impl Trained {
+    /// `vision` with the trained threshold and weights.
+    pub fn apply(&self, vision: &PlantVision) -> PlantVision {
+        let mut trained = vision.clone();
+        trained.exg_threshold = self.exg_threshold;
+        trained.with_weights((self.exg, self.green_ratio, self.chroma, self.bias))
+    }
+}
+
+/// Fit the fused score of `vision` to `pixels`.
+///
+/// Pixels are white-balanced as `vision` would, and pixels failing its
+/// green-dominance or luminance gates are skipped: they score `-1` whatever
+/// the weights. Both classes weigh equally however rare vegetation is. The
+/// weights are scaled to sum to 1 in magnitude, like the defaults, and the
+/// intercept becomes a new `exg_threshold` (the green-ratio and chroma
+/// floors are kept), with the rounding remainder left in `bias`.
+///
+/// Pixels of the same colour and label are fitted once, weighted by their
+/// count, so memory and time grow with the distinct colours rather than
+/// the size of the training set.
+///
+/// Returns an error when a class has no gated-in pixels or the cues carry
+/// no signal.
+pub fn fit(vision: &PlantVision, pixels: &LabelledRows) -> Result<Trained, String> {
+    let mut counts = BTreeMap::<([u8; 3], bool), usize>::new();
+    for pixel in pixels.pixels() {
+        *counts.entry(pixel).or_default() += 1;
+    }
+    let balance = vision.white_balance();
+    let samples: Vec<([f64; 4], bool, usize)> = counts
+        .into_iter()
+        .filter_map(|(([r, g, b], label), count)| {
+            let [r, g, b] = balance.map_or([r, g, b], |wb| wb.apply(r, g, b));
+            let [exg, green_ratio, chroma] = vision.cues(r, g, b)?;
+            Some((
+                [
+                    f64::from(exg),
+                    f64::from(green_ratio),
+                    f64::from(chroma),
+                    1.0,
+                ],
+                label,
+                count,
+            ))
+        })
+        .collect();
+    let count = |class: bool| -> usize {
+        samples
+            .iter()
+            .filter(|(_, label, _)| *label == class)
+            .map(|(_, _, count)| count)
+            .sum()
+    };
+    let (positives, negatives) = (count(true), count(false));
+    if positives == 0 || negatives == 0 {
+        return Err(format!(
+            "need both vegetation and background pixels passing the detector's gates \
+             ({positives} vegetation, {negatives} background)"
+        ));
+    }
+    let fitted = positives + negatives;
+    let n = fitted as f64;
+    let (w_pos, w_neg) = (n / (2.0 * positives as f64), n / (2.0 * negatives as f64));
+    let ridge = RIDGE * n;
+
+    let mut theta = [0.0f64; 4];
+    for _ in 0..MAX_ITERATIONS {
+        let mut gradient = theta.map(|t| ridge * t);
+        let mut hessian = [[0.0f64; 4]; 4];
+        for (i, row) in hessian.iter_mut().enumerate() {
+            row[i] = ridge;
+        }
+        for (x, label, count) in &samples {
+            let z: f64 = theta.iter().zip(x).map(|(t, x)| t * x).sum();
+            let p = 1.0 / (1.0 + (-z).exp());
+            let (weight, y) = if *label { (w_pos, 1.0) } else { (w_neg, 0.0) };
+            let weight = weight * *count as f64;
+            let curvature = weight * p * (1.0 - p);
+            for i in 0..4 {
+                gradient[i] += weight * (p - y) * x[i];
+                for j in 0..4 {
+                    hessian[i][j] += curvature * x[i] * x[j];
+                }
+            }
+        }
+        let step = solve(hessian, gradient);
+        for (t, s) in theta.iter_mut().zip(step) {
+            *t -= s;
+        }
+        if step.iter().all(|s| s.abs() < 1e-9) {
+            break;
+        }
+    }
+
+    let scale: f64 = theta[..3].iter().map(|w| w.abs()).sum();
+    if !scale.is_finite() || scale < 1e-9 {
+        return Err("the cues do not separate the labelled classes".into());
+    }
+    let [exg, green_ratio, chroma, intercept] = theta.map(|t| t / scale);
+    // exg * (v - t0) / 255 + intercept == exg * (v - t) / 255 + bias.
+    let t0 = f64::from(vision.exg_threshold);
+    let exg_threshold = if exg.abs() > 1e-3 {
+        (t0 - 255.0 * intercept / exg).round().clamp(-510.0, 510.0)
+    } else {
+        t0
+    };
+    let bias = intercept + exg * (exg_threshold - t0) / 255.0;
+    Ok(Trained {
+        exg_threshold: exg_threshold as i16,
+        exg: exg as f32,
+        green_ratio: green_ratio as f32,
+        chroma: chroma as f32,
+        bias: bias as f32,
+        fitted,
+    })
+}
+
+/// Solve `a x = b` by Gaussian elimination with partial pivoting. A
+/// singular pivot yields a zero component rather than a NaN.
+fn solve(mut a: [[f64; 4]; 4], mut b: [f64; 4]) -> [f64; 4] {
+    for col in 0..4 {
+        let pivot = (col..4)
+            .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+            .unwrap();
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        if a[col][col].abs() < 1e-12 {
+            continue;
+        }
+        for row in col + 1..4 {
+            let factor = a[row][col] / a[col][col];
+            let pivot_row = a[col];
+            for (value, pivot) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = [0.0; 4];
+    for row in (0..4).rev() {
+        if a[row][row].abs() < 1e-12 {
+            continue;
+        }
+        let rest: f64 = (row + 1..4).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - rest) / a[row][row];
+    }
+    x
+}
+
+/// Pixel counts of a detector's decisions against the labels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Confusion {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub false_negatives: usize,
+    pub true_negatives: usize,
+}
+
+impl Confusion {
+    /// Run `vision` over every row of `pixels` and count its decisions.
+    pub fn evaluate(vision: &PlantVision, pixels: &LabelledRows) -> Self {
+        let mut confusion = Self::default();
+        let mut mask = Vec::new();
+        for row in &pixels.rows {
+            mask.clear();
+            mask.resize(row.len(), false);
+            vision.detect_into(&pixels.rgb[row.start * 3..row.end * 3], &mut mask);
+            for (&detected, &label) in mask.iter().zip(&pixels.labels[row.clone()]) {
+                match (detected, label) {
+                    (true, true) => confusion.true_positives += 1,
+                    (true, false) => confusion.false_positives += 1,
+                    (false, true) => confusion.false_negatives += 1,
+                    (false, false) => confusion.true_negatives += 1,
+                }
+            }
+        }
+        confusion
+    }
+
+    /// Share of detected pixels that are vegetation (1 when nothing was
+    /// detected).
+    pub fn precision(&self) -> f32 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+
+    /// Share of vegetation pixels that were detected (1 when there is no
+    /// vegetation).
+    pub fn recall(&self) -> f32 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+}
+
+fn ratio(part: usize, whole: usize) -> f32 {
+    if whole == 0 {
+        1.0
+    } else {
+        part as f32 / whole as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `width` x `height` field: a leaf of `leaf` colour over the left
+    /// third, soil elsewhere, with its mask.
+    fn field(width: usize, height: usize, leaf: [u8; 3], soil: [u8; 3]) -> (Image, Image) {
+        let is_leaf = |i: usize| i % width < width / 3;
+        let data = (0..width * height)
+            .flat_map(|i| if is_leaf(i) { leaf } else { soil })
+            .collect();
+        let mask = (0..width * height)
+            .map(|i| if is_leaf(i) { 255 } else { 0 })
+            .collect();
+        (
+            Image {
+                width,
+                height,
+                channels: 3,
+                data,
+            },
+            Image {
+                width,
+                height,
+                channels: 1,
+                data: mask,
+            },
+        )
+    }
+
+    #[test]
+    fn reads_ppm_and_pgm() {
+        let mut ppm = b"P6\n# a comment\n2 1\n255\n".to_vec();
+        ppm.extend_from_slice(&[1, 2, 3, 10, 20, 30]);
+        let image = Image::read_pnm(&ppm[..]).unwrap();
+        assert_eq!((image.width, image.height, image.channels), (2, 1, 3));
+        assert_eq!(image.data, [1, 2, 3, 10, 20, 30]);
+
+        let pgm = b"P5 2 2 255 \x00\xff\x80\x7f";
+        let mask = Image::read_pnm(&pgm[..]).unwrap();
+        assert_eq!((mask.channels, mask.data.len()), (1, 4));
+
+        assert!(Image::read_pnm(&b"P3\n1 1\n255\n0 0 0\n"[..]).is_err());
+        assert!(Image::read_pnm(&b"P6\n1 1\n65535\n"[..]).is_err());
+        assert!(Image::read_pnm(&b"P6\n2 2\n255\n\x00"[..]).is_err());
+        // Oversized or overflowing headers fail before allocating.
+        assert!(Image::read_pnm(&b"P6\n100000 100000\n255\n"[..]).is_err());
+        let overflow = format!("P6\n{} 2\n255\n", usize::MAX);
+        assert!(Image::read_pnm(overflow.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn split_holds_out_evenly_spread_bands() {
+        let images = [field(4, 160, [40, 200, 40], [120, 90, 70])];
+        let (train, held_out) = split(&images, 0.2);
+        assert_eq!((train.len(), held_out.len()), (4 * 128, 4 * 32));
+        // Bands 0 and 5 (rows 0..16 and 80..96) are held out.
+        assert_eq!(held_out.rows.len(), 32);
+        let (all, none) = split(&images, 0.0);
+        assert_eq!((all.len(), none.len()), (4 * 160, 0));
+    }
+
+    #[test]
+    fn fit_learns_a_pale_crop_the_defaults_miss() {
+        // Washed-out leaves (ExG 14, below the default threshold of 20)
+        // on grey soil that just passes the green-dominance gate.
+        let images = [
+            field(30, 64, [150, 160, 156], [140, 142, 141]),
+            field(30, 64, [100, 110, 106], [90, 91, 90]),
+        ];
+        let (train, held_out) = split(&images, 0.25);
+        let defaults = PlantVision::default();
+        let before = Confusion::evaluate(&defaults, &held_out);
+        assert_eq!(before.true_positives, 0);
+
+        let trained = fit(&defaults, &train).unwrap();
+        assert_eq!(trained.fitted, train.len());
+        let sum = trained.exg.abs() + trained.green_ratio.abs() + trained.chroma.abs();
+        assert!((sum - 1.0).abs() < 1e-5, "{trained:?}");
+        let after = Confusion::evaluate(&trained.apply(&defaults), &held_out);
+        assert_eq!((after.precision(), after.recall()), (1.0, 1.0), "{after:?}");
+    }
+
+    #[test]
+    fn fit_weighs_repeated_colours_by_count() {
+        let image = field(30, 16, [150, 160, 156], [140, 142, 141]);
+        let (once, _) = split(std::slice::from_ref(&image), 0.0);
+        let (twice, _) = split(&[image.clone(), image], 0.0);
+        let (a, b) = (
+            fit(&PlantVision::default(), &once).unwrap(),
+            fit(&PlantVision::default(), &twice).unwrap(),
+        );
+        assert_eq!(b.fitted, 2 * a.fitted);
+        assert_eq!(a.exg_threshold, b.exg_threshold);
+        assert!((a.exg - b.exg).abs() < 1e-6, "{a:?} {b:?}");
+    }
+
+    #[test]
+    fn fit_needs_both_classes() {
+        let images = [field(30, 16, [40, 200, 40], [120, 90, 70])];
+        let (train, _) = split(&images, 0.0);
+        // Red-brown soil fails the green-dominance gate: only leaves remain.
+        assert!(fit(&PlantVision::default(), &train).is_err());
+    }
+
+    #[test]
+    fn confusion_ratios() {
+        let confusion = Confusion {
+            true_positives: 3,
+            false_positives: 1,
+            false_negatives: 2,
+            true_negatives: 10,
+        };
+        assert_eq!(confusion.precision(), 0.75);
+        assert_eq!(confusion.recall(), 0.6);
+        assert_eq!(Confusion::default().precision(), 1.0);
+    }
+}
@@ -1,5 +1,16 @@
 //! Adaptive vegetation detector combining multiple color cues.
 
+use crate::config::{IndexSelection, VisionConfig};
+use crate::indices::{IndexKind, VegetationIndex};
+use crate::white_balance::WhiteBalance;
+use serde::Deserialize;
+use std::simd::prelude::*;
+use std::simd::{f32x16, u8x16, Simd};
+use std::sync::Arc;
+
+/// Pixels scored per SIMD block (48 interleaved RGB bytes).
+const LANES: usize = 16;
+
 /// High-level vegetation detector tuned for spotting green plants.
 ///
 /// The detector fuses a classic Excess Green measurement with
@@ -15,6 +26,91 @@ pub struct PlantVision {
     /// Minimum chroma to reject grey/brown backgrounds.
     pub chroma_floor: f32,
     weights: Weights,
+    lighting: Lighting,
+    index: Option<Arc<dyn VegetationIndex>>,
+    /// NDVI threshold; `Some` switches the input to RGBN pixels.
+    ndvi_threshold: Option<f32>,
+    balance: Option<Arc<WhiteBalance>>,
+    lut: Option<Arc<RgbLut>>,
+}
+
+/// Layout of the pixels fed to the detector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFormat {
+    /// Interleaved 8-bit R, G, B.
+    #[default]
+    Rgb24,
+    /// Interleaved 8-bit R, G, B and near-infrared, from a NIR-modified
+    /// camera.
+    Rgbn,
+}
+
+impl PixelFormat {
+    /// Bytes per pixel.
+    pub fn channels(self) -> usize {
+        match self {
+            Self::Rgb24 => 3,
+            Self::Rgbn => 4,
+        }
+    }
+}
+
+impl std::fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Rgb24 => "RGB24",
+            Self::Rgbn => "RGBN",
+        })
+    }
+}
+
+/// Normalised difference vegetation index `(NIR - R) / (NIR + R)`, in
+/// `-1..=1` (`0` when both are black). Leaves reflect strongly in the
+/// near infrared and absorb red, so they score far above soil whatever
+/// their colour.
+#[inline]
+pub fn ndvi(red: u8, nir: u8) -> f32 {
+    let (red, nir) = (f32::from(red), f32::from(nir));
+    if red + nir == 0.0 {
+        0.0
+    } else {
+        (nir - red) / (nir + red)
+    }
+}
+
+/// Luminance gates for shadows and glare, on the luma
+/// `(r + 2g + b) / 4` of each pixel. All zero (the default) disables them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Lighting {
+    /// Pixels darker than this are rejected: in deep shadow sensor noise
+    /// outweighs colour.
+    pub luma_floor: u8,
+    /// Pixels at least this bright are specular glare (`0` disables):
+    /// their colour is clipped, so instead of being scored they take the
+    /// decision of their neighbours — a glare run flanked by vegetation
+    /// on both sides is vegetation.
+    pub luma_ceiling: u8,
+    /// Below this luma the chroma floor shrinks in proportion to
+    /// brightness, since shade scales a leaf's chroma down with it.
+    pub shadow_luma: u8,
+}
+
+impl Lighting {
+    /// Whether every gate is disabled.
+    pub fn is_off(&self) -> bool {
+        *self == Self::default()
+    }
+
+    #[inline]
+    fn luma(r: u8, g: u8, b: u8) -> u8 {
+        ((u16::from(r) + 2 * u16::from(g) + u16::from(b)) >> 2) as u8
+    }
+
+    #[inline]
+    fn is_glare(&self, luma: u8) -> bool {
+        self.luma_ceiling > 0 && luma >= self.luma_ceiling
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -37,6 +133,11 @@ impl Default for PlantVision {
                 chroma: 0.15,
                 bias: 0.0,
             },
+            lighting: Lighting::default(),
+            index: None,
+            ndvi_threshold: None,
+            balance: None,
+            lut: None,
         }
     }
 }
@@ -60,30 +161,346 @@ impl PlantVision {
                 chroma,
                 bias,
             },
+            lighting: Lighting::default(),
+            index: None,
+            ndvi_threshold: None,
+            balance: None,
+            lut: None,
         }
     }
 
-    /// Compute a vegetation mask for an interleaved RGB image.
-    pub fn detect(&self, rgb: &[u8]) -> Vec<bool> {
+    /// Create a detector from the `[vision]` config section, with the
+    /// selected `index` (NDVI on RGBN pixels for `"ndvi"`), the `[vision.lighting]` gates, white balance when
+    /// `[vision.white_balance] gains` are not all 1, and the lookup table
+    /// when `lut_bits` is non-zero.
+    pub fn from_config(cfg: &VisionConfig) -> Self {
+        let mut vision = Self::new(
+            cfg.exg_threshold,
+            cfg.green_ratio_floor,
+            cfg.chroma_floor,
+            (
+                cfg.weights.exg,
+                cfg.weights.green_ratio,
+                cfg.weights.chroma,
+                cfg.weights.bias,
+            ),
+        )
+        .with_lighting(cfg.lighting.lighting());
+        if let Some(index) = cfg.vegetation_index() {
+            vision = vision.with_index(index);
+        }
+        if cfg.index == IndexSelection::Single(IndexKind::Ndvi) {
+            vision = vision.with_ndvi(cfg.index_thresholds.ndvi);
+        }
+        if cfg.white_balance.gains != [1.0; 3] {
+            vision = vision.with_white_balance(WhiteBalance::new(cfg.white_balance.gains));
+        }
+        if cfg.lut_bits > 0 {
+            vision.with_lookup_table(cfg.lut_bits)
+        } else {
+            vision
+        }
+    }
+
+    /// Precompute the decision for every quantized RGB value so
+    /// [`Self::detect`] does one table lookup per pixel.
+    ///
+    /// `bits` (1–8) is kept per channel: 5 gives a 32x32x32 table (4 KiB)
+    /// that scores each cell at its lowest-valued corner, 8 gives the
+    /// exact full 2^24-entry table (2 MiB). The table is a snapshot of the
+    /// current thresholds and weights — rebuild it after changing them.
+    ///
+    /// # Panics
+    /// Panics if `bits` is not in `1..=8`, or in NDVI mode.
+    pub fn with_lookup_table(mut self, bits: u8) -> Self {
         assert!(
-            rgb.len().is_multiple_of(3),
-            "RGB slice must be multiple of 3",
+            self.ndvi_threshold.is_none(),
+            "NDVI mode has no RGB lookup table"
         );
-        let mut mask = Vec::with_capacity(rgb.len() / 3);
-        for &[r, g, b] in rgb.as_chunks::<3>().0 {
-            mask.push(self.score_pixel(r, g, b) > 0.0);
+        // Score the grid directly, not through a previous table.
+        self.lut = None;
+        self.lut = Some(Arc::new(RgbLut::build(&self, bits)));
+        self
+    }
+
+    /// Correct each pixel with `balance` before scoring. A lookup table
+    /// is rebuilt so it includes the correction.
+    pub fn with_white_balance(mut self, balance: WhiteBalance) -> Self {
+        self.balance = Some(Arc::new(balance));
+        match self.lookup_bits() {
+            Some(bits) => self.with_lookup_table(bits),
+            None => self,
+        }
+    }
+
+    /// Replace the fused score's `(exg, green_ratio, chroma, bias)`
+    /// weights. A lookup table is rebuilt for the new weights.
+    pub fn with_weights(mut self, weights: (f32, f32, f32, f32)) -> Self {
+        let (exg, green_ratio, chroma, bias) = weights;
+        self.weights = Weights {
+            exg,
+            green_ratio,
+            chroma,
+            bias,
+        };
+        match self.lookup_bits() {
+            Some(bits) => self.with_lookup_table(bits),
+            None => self,
         }
+    }
+
+    /// Gate pixels on luminance with `lighting`. A lookup table is
+    /// rebuilt so it includes the gates.
+    pub fn with_lighting(mut self, lighting: Lighting) -> Self {
+        self.lighting = lighting;
+        match self.lookup_bits() {
+            Some(bits) => self.with_lookup_table(bits),
+            None => self,
+        }
+    }
+
+    /// Score pixels with `index` in place of the fused ExG, green-ratio
+    /// and chroma cues (and their green-dominance gate). Luminance gates,
+    /// white balance and the lookup table still apply; a lookup table is
+    /// rebuilt for the new index.
+    pub fn with_index(mut self, index: Box<dyn VegetationIndex>) -> Self {
+        self.index = Some(Arc::from(index));
+        match self.lookup_bits() {
+            Some(bits) => self.with_lookup_table(bits),
+            None => self,
+        }
+    }
+
+    /// The index replacing the fused cues, if any.
+    pub fn index(&self) -> Option<&dyn VegetationIndex> {
+        self.index.as_deref()
+    }
+
+    /// Take RGBN pixels and detect vegetation where
+    /// [`ndvi`] exceeds `threshold`, in place of every colour cue and
+    /// index. The luminance gates still apply to the RGB part; white
+    /// balance does not, so red and NIR stay comparable.
+    ///
+    /// # Panics
+    /// Panics if a lookup table is active.
+    pub fn with_ndvi(mut self, threshold: f32) -> Self {
+        assert!(self.lut.is_none(), "NDVI mode has no RGB lookup table");
+        self.ndvi_threshold = Some(threshold);
+        self
+    }
+
+    /// The NDVI threshold, in NDVI mode.
+    pub fn ndvi_threshold(&self) -> Option<f32> {
+        self.ndvi_threshold
+    }
+
+    /// Layout of the pixels [`Self::detect`] expects.
+    pub fn pixel_format(&self) -> PixelFormat {
+        if self.ndvi_threshold.is_some() {
+            PixelFormat::Rgbn
+        } else {
+            PixelFormat::Rgb24
+        }
+    }
+
+    /// The luminance gates.
+    pub fn lighting(&self) -> Lighting {
+        self.lighting
+    }
+
+    /// The white balance applied before scoring, if any.
+    pub fn white_balance(&self) -> Option<&WhiteBalance> {
+        self.balance.as_deref()
+    }
+
+    /// Bits per channel of the active lookup table, if any.
+    pub fn lookup_bits(&self) -> Option<u8> {
+        self.lut.as_ref().map(|lut| lut.bits)
+    }
+
+    /// Compute a vegetation mask for an interleaved RGB image (RGBN in
+    /// NDVI mode).
+    pub fn detect(&self, rgb: &[u8]) -> Vec<bool> {
+        let channels = self.pixel_format().channels();
+        assert!(
+            rgb.len().is_multiple_of(channels),
+            "Pixel slice must be a multiple of {channels} bytes",
+        );
+        let mut mask = vec![false; rgb.len() / channels];
+        self.detect_into(rgb, &mut mask);
         mask
     }
 
+    /// Like [`Self::detect`], but writes into a caller-owned buffer so a
+    /// row (or frame) can be classified without allocating.
+    ///
+    /// White balance is applied on the fly while each block is loaded;
+    /// a lookup table already includes it. Glare runs are filled from
+    /// their neighbours along `rgb`, so pass whole rows.
+    pub fn detect_into(&self, rgb: &[u8], mask: &mut [bool]) {
+        let channels = self.pixel_format().channels();
+        assert_eq!(
+            rgb.len(),
+            mask.len() * channels,
+            "Pixel slice must hold exactly {channels} bytes per mask entry",
+        );
+        if let Some(threshold) = self.ndvi_threshold {
+            for (out, &[r, g, b, n]) in mask.iter_mut().zip(rgb.as_chunks::<4>().0) {
+                let luma = Lighting::luma(r, g, b);
+                *out = luma >= self.lighting.luma_floor
+                    && !self.lighting.is_glare(luma)
+                    && ndvi(r, n) > threshold;
+            }
+        } else if let Some(lut) = &self.lut {
+            for (out, &[r, g, b]) in mask.iter_mut().zip(rgb.as_chunks::<3>().0) {
+                *out = lut.get(r, g, b);
+            }
+        } else {
+            self.score_into(rgb, mask);
+        }
+        if self.lighting.luma_ceiling > 0 {
+            self.fill_glare(rgb, channels, mask);
+        }
+    }
+
+    fn score_into(&self, rgb: &[u8], mask: &mut [bool]) {
+        if self.index.is_some() {
+            // Indices are scalar; a lookup table is the fast path for them.
+            for (out, &[r, g, b]) in mask.iter_mut().zip(rgb.as_chunks::<3>().0) {
+                let [r, g, b] = match &self.balance {
+                    Some(balance) => balance.apply(r, g, b),
+                    None => [r, g, b],
+                };
+                *out = self.score_pixel(r, g, b) > 0.0;
+            }
+            return;
+        }
+        let (blocks, tail) = rgb.as_chunks::<{ 3 * LANES }>();
+        let (mask_blocks, mask_tail) = mask.as_chunks_mut::<LANES>();
+        for (out, block) in mask_blocks.iter_mut().zip(blocks) {
+            let scores = match &self.balance {
+                Some(balance) => self.score_block(&balance.apply_block(block)),
+                None => self.score_block(block),
+            };
+            *out = scores.simd_gt(Simd::splat(0.0)).to_array();
+        }
+        for (out, &[r, g, b]) in mask_tail.iter_mut().zip(tail.as_chunks::<3>().0) {
+            let [r, g, b] = match &self.balance {
+                Some(balance) => balance.apply(r, g, b),
+                None => [r, g, b],
+            };
+            *out = self.score_pixel(r, g, b) > 0.0;
+        }
+    }
+
+    /// Mark each run of glare pixels as vegetation when the pixels on
+    /// both sides of it are. Runs touching either end of `rgb` are left
+    /// rejected.
+    fn fill_glare(&self, rgb: &[u8], channels: usize, mask: &mut [bool]) {
+        let mut run_start = None;
+        for (i, px) in rgb.chunks_exact(channels).enumerate() {
+            let [r, g, b] = match &self.balance {
+                Some(balance) if self.ndvi_threshold.is_none() => {
+                    balance.apply(px[0], px[1], px[2])
+                }
+                _ => [px[0], px[1], px[2]],
+            };
+            if self.lighting.is_glare(Lighting::luma(r, g, b)) {
+                run_start.get_or_insert(i);
+            } else if let Some(start) = run_start.take() {
+                if start > 0 && mask[start - 1] && mask[i] {
+                    mask[start..i].fill(true);
+                }
+            }
+        }
+    }
+
+    /// SIMD version of [`Self::score_pixel`] for 16 interleaved pixels.
+    ///
+    /// Every operation mirrors the scalar path in the same order and in
+    /// f32, so the two produce bit-identical scores (including the `-1.0`
+    /// returned for pixels failing the green-dominance or luminance
+    /// gates).
+    #[inline]
+    fn score_block(&self, px: &[u8; 3 * LANES]) -> f32x16 {
+        let r = u8x16::from_array(std::array::from_fn(|j| px[3 * j]));
+        let g = u8x16::from_array(std::array::from_fn(|j| px[3 * j + 1]));
+        let b = u8x16::from_array(std::array::from_fn(|j| px[3 * j + 2]));
+        let mut gate = g.simd_ge(r) & g.simd_ge(b);
+        let luma: u8x16 =
+            ((r.cast::<u16>() + Simd::splat(2) * g.cast::<u16>() + b.cast::<u16>()) >> 2).cast();
+        gate &= luma.simd_ge(Simd::splat(self.lighting.luma_floor));
+        if self.lighting.luma_ceiling > 0 {
+            gate &= luma.simd_lt(Simd::splat(self.lighting.luma_ceiling));
+        }
+
+        let r_f: f32x16 = r.cast();
+        let g_f: f32x16 = g.cast();
+        let b_f: f32x16 = b.cast();
+        let sum = r_f + g_f + b_f + Simd::splat(1.0);
+        let exg = Simd::splat(2.0) * g_f - r_f - b_f;
+        let exg_term = (exg - Simd::splat(self.exg_threshold as f32)) / Simd::splat(255.0);
+        let green_ratio = g_f / sum;
+        let green_ratio_term = green_ratio - Simd::splat(self.green_ratio_floor);
+        let maxc: f32x16 = r.simd_max(g).simd_max(b).cast();
+        let minc: f32x16 = r.simd_min(g).simd_min(b).cast();
+        let chroma = (maxc - minc) / Simd::splat(255.0);
+        let chroma_floor = if self.lighting.shadow_luma > 0 {
+            let luma_f: f32x16 = luma.cast();
+            Simd::splat(self.chroma_floor)
+                * (luma_f / Simd::splat(f32::from(self.lighting.shadow_luma)))
+                    .simd_min(Simd::splat(1.0))
+        } else {
+            Simd::splat(self.chroma_floor)
+        };
+        let chroma_term = chroma - chroma_floor;
+
+        let score = Simd::splat(self.weights.exg) * exg_term
+            + Simd::splat(self.weights.green_ratio) * green_ratio_term
+            + Simd::splat(self.weights.chroma) * chroma_term
+            + Simd::splat(self.weights.bias);
+        gate.cast::<i32>().select(score, Simd::splat(-1.0))
+    }
+
     #[inline]
     fn score_pixel(&self, r: u8, g: u8, b: u8) -> f32 {
+        if let Some(index) = &self.index {
+            let luma = Lighting::luma(r, g, b);
+            if luma < self.lighting.luma_floor || self.lighting.is_glare(luma) {
+                return -1.0;
+            }
+            return index.score(r, g, b);
+        }
+        match self.cues(r, g, b) {
+            Some([exg_term, green_ratio_term, chroma_term]) => {
+                self.weights.exg * exg_term
+                    + self.weights.green_ratio * green_ratio_term
+                    + self.weights.chroma * chroma_term
+                    + self.weights.bias
+            }
+            None => -1.0,
+        }
+    }
+
+    /// The fused score's cue terms for one (white-balanced) pixel: ExG,
+    /// green ratio and chroma, each less its threshold. The score is their
+    /// weighted sum plus the bias. `None` for pixels failing the
+    /// green-dominance or luminance gates, which score `-1` whatever the
+    /// weights.
+    #[inline]
+    pub fn cues(&self, r: u8, g: u8, b: u8) -> Option<[f32; 3]> {
         // Green-dominance gate: vegetation must have green as the strongest
         // channel. Without it the chroma cue rewards saturated warm soils
         // (red-brown dirt: high R, low B) enough to tip the fused score
         // positive and spray continuously on red soil.
         if g < r || g < b {
-            return -1.0;
+            return None;
+        }
+        // Luminance gates: near-black shadow pixels are mostly noise and
+        // glare pixels have lost their colour to clipping.
+        let luma = Lighting::luma(r, g, b);
+        if luma < self.lighting.luma_floor || self.lighting.is_glare(luma) {
+            return None;
         }
         let r_f = r as f32;
         let g_f = g as f32;
@@ -96,18 +513,168 @@ impl PlantVision {
         let maxc = r.max(g).max(b) as f32;
         let minc = r.min(g).min(b) as f32;
         let chroma = (maxc - minc) / 255.0;
-        let chroma_term = chroma - self.chroma_floor;
+        let chroma_floor = if self.lighting.shadow_luma > 0 {
+            self.chroma_floor * (f32::from(luma) / f32::from(self.lighting.shadow_luma)).min(1.0)
+        } else {
+            self.chroma_floor
+        };
+        let chroma_term = chroma - chroma_floor;
+        Some([exg_term, green_ratio_term, chroma_term])
+    }
+}
+
+/// Axis-aligned pixel rectangle within a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    /// The whole of a `width` x `height` frame.
+    pub fn full(width: usize, height: usize) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// Row `y` (relative to the rectangle) of a row-major frame `width`
+    /// pixels wide with `channels` entries per pixel.
+    #[inline]
+    pub fn row<'a, T>(&self, data: &'a [T], width: usize, channels: usize, y: usize) -> &'a [T] {
+        let start = ((self.y + y) * width + self.x) * channels;
+        &data[start..start + self.width * channels]
+    }
+}
+
+/// Region of interest given as margins cropped from each edge of a
+/// reference frame (normally the `[camera]` size), e.g. the horizon at
+/// the top and the tractor hitch at the bottom. Margins scale with the
+/// frame for other frame sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Roi {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+    pub ref_width: usize,
+    pub ref_height: usize,
+}
+
+impl Roi {
+    /// The region left inside the margins of a `width` x `height` frame
+    /// (empty if the margins cover it).
+    pub fn rect(&self, width: usize, height: usize) -> Rect {
+        let scale = |margin: usize, size: usize, reference: usize| {
+            if size == reference {
+                margin
+            } else {
+                (margin as f64 * size as f64 / reference as f64).round() as usize
+            }
+        };
+        let left = scale(self.left, width, self.ref_width).min(width);
+        let right = scale(self.right, width, self.ref_width);
+        let top = scale(self.top, height, self.ref_height).min(height);
+        let bottom = scale(self.bottom, height, self.ref_height);
+        Rect {
+            x: left,
+            y: top,
+            width: width.saturating_sub(left + right),
+            height: height.saturating_sub(top + bottom),
+        }
+    }
+}
+
+/// Per-pixel vegetation classifier used by the streaming lane reducer.
+///
+/// Implementors fill `mask` with one decision per interleaved pixel of
+/// `rgb` (typically a single frame row), writing every entry.
+pub trait Scorer {
+    fn detect_into(&self, rgb: &[u8], mask: &mut [bool]);
+
+    /// Bytes per pixel of the input (3 for RGB).
+    fn channels(&self) -> usize {
+        3
+    }
+}
+
+impl Scorer for PlantVision {
+    fn detect_into(&self, rgb: &[u8], mask: &mut [bool]) {
+        PlantVision::detect_into(self, rgb, mask);
+    }
+
+    fn channels(&self) -> usize {
+        self.pixel_format().channels()
+    }
+}
+
+/// Bit-packed vegetation decisions indexed by quantized `(r, g, b)`.
+struct RgbLut {
+    bits: u8,
+    words: Vec<u64>,
+}
+
+impl RgbLut {
+    fn build(vision: &PlantVision, bits: u8) -> Self {
+        assert!(
+            (1..=8).contains(&bits),
+            "lookup table bits must be in 1..=8"
+        );
+        let shift = 8 - bits;
+        let side = 1usize << bits;
+        let mut words = vec![0u64; (side * side * side).div_ceil(64)];
+        // Score one (r, g) row of cells at a time so the build runs
+        // through the SIMD kernel rather than pixel by pixel. Glare
+        // filling looks at neighbouring pixels, so it stays out of the
+        // table.
+        let mut row = Vec::with_capacity(side * 3);
+        let mut hits = vec![false; side];
+        for qr in 0..side {
+            for qg in 0..side {
+                row.clear();
+                for qb in 0..side {
+                    row.extend_from_slice(&[
+                        (qr << shift) as u8,
+                        (qg << shift) as u8,
+                        (qb << shift) as u8,
+                    ]);
+                }
+                let base = (qr * side + qg) * side;
+                vision.score_into(&row, &mut hits);
+                for (qb, &hit) in hits.iter().enumerate() {
+                    if hit {
+                        let idx = base + qb;
+                        words[idx / 64] |= 1 << (idx % 64);
+                    }
+                }
+            }
+        }
+        Self { bits, words }
+    }
+
+    #[inline]
+    fn get(&self, r: u8, g: u8, b: u8) -> bool {
+        let shift = 8 - self.bits;
+        let idx = ((((r >> shift) as usize) << self.bits | (g >> shift) as usize) << self.bits)
+            | (b >> shift) as usize;
+        self.words[idx / 64] & (1 << (idx % 64)) != 0
+    }
+}
 
-        self.weights.exg * exg_term
-            + self.weights.green_ratio * green_ratio_term
-            + self.weights.chroma * chroma_term
-            + self.weights.bias
+impl std::fmt::Debug for RgbLut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RgbLut").field("bits", &self.bits).finish()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::PlantVision;
+    use super::{Lighting, PlantVision};
 
     #[test]
     fn bright_green_is_detected() {
@@ -142,4 +709,226 @@ mod tests {
         let mask = aggressive.detect(&[70, 150, 60]);
         assert!(mask[0]);
     }
+
+    #[test]
+    fn simd_kernel_matches_scalar_score() {
+        // Sweep a coarse RGB grid (plus the 255 edge) so every gate and
+        // cue is exercised on both sides of its threshold. The pixel count
+        // is not a multiple of 16, so the scalar tail runs too.
+        let steps: Vec<u8> = (0..=255).step_by(7).chain([255]).collect();
+        let mut rgb = Vec::new();
+        for &r in &steps {
+            for &g in &steps {
+                for &b in &steps {
+                    rgb.extend_from_slice(&[r, g, b]);
+                }
+            }
+        }
+        assert_ne!((rgb.len() / 3) % 16, 0);
+        for detector in [
+            PlantVision::default(),
+            PlantVision::new(-40, 0.2, 0.0, (0.7, 0.1, 0.4, -0.05)),
+            PlantVision::new(90, 0.5, 0.25, (0.3, 0.6, 0.1, 0.02)),
+            PlantVision::default().with_lighting(Lighting {
+                luma_floor: 12,
+                luma_ceiling: 240,
+                shadow_luma: 80,
+            }),
+        ] {
+            let (blocks, _) = rgb.as_chunks::<48>();
+            for (i, block) in blocks.iter().enumerate() {
+                let scores = detector.score_block(block).to_array();
+                for (j, score) in scores.into_iter().enumerate() {
+                    let (r, g, b) = (block[3 * j], block[3 * j + 1], block[3 * j + 2]);
+                    assert_eq!(
+                        score.to_bits(),
+                        detector.score_pixel(r, g, b).to_bits(),
+                        "pixel {} ({r}, {g}, {b}) with {detector:?}",
+                        16 * i + j,
+                    );
+                }
+            }
+            // The scoring pass, before glare filling looks at neighbours.
+            let mut mask = vec![false; rgb.len() / 3];
+            detector.score_into(&rgb, &mut mask);
+            for (p, &m) in mask.iter().enumerate() {
+                let (r, g, b) = (rgb[3 * p], rgb[3 * p + 1], rgb[3 * p + 2]);
+                assert_eq!(m, detector.score_pixel(r, g, b) > 0.0, "pixel {p}");
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_table_matches_scorer_at_grid_points() {
+        for bits in [4u8, 5, 6] {
+            let shift = 8 - bits;
+            let direct = PlantVision::new(-10, 0.3, 0.05, (0.6, 0.3, 0.2, -0.01));
+            let lut = direct.clone().with_lookup_table(bits);
+            assert_eq!(lut.lookup_bits(), Some(bits));
+            let side = 1u16 << bits;
+            let mut rgb = Vec::new();
+            for qr in 0..side {
+                for qg in 0..side {
+                    for qb in 0..side {
+                        rgb.extend_from_slice(&[
+                            (qr << shift) as u8,
+                            (qg << shift) as u8,
+                            (qb << shift) as u8,
+                        ]);
+                    }
+                }
+            }
+            assert_eq!(lut.detect(&rgb), direct.detect(&rgb), "{bits}-bit table");
+        }
+    }
+
+    #[test]
+    fn lookup_table_cells_share_their_grid_decision() {
+        // Every pixel in a 5-bit cell takes the decision of the cell's
+        // lowest corner.
+        let lut = PlantVision::default().with_lookup_table(5);
+        let corner = lut.detect(&[32, 192, 64])[0];
+        assert!(corner);
+        assert_eq!(lut.detect(&[39, 199, 71]), vec![corner]);
+    }
+
+    #[test]
+    fn white_balance_corrects_colour_cast_before_scoring() {
+        use crate::white_balance::WhiteBalance;
+
+        // A leaf under a strong blue cast: blue outweighs green, so the
+        // green-dominance gate rejects it.
+        let leaf = [40u8, 150, 190];
+        let plain = PlantVision::default();
+        assert!(!plain.detect(&leaf)[0]);
+        let balanced =
+            PlantVision::default().with_white_balance(WhiteBalance::new([1.0, 1.0, 0.3]));
+        assert!(balanced.detect(&leaf)[0]);
+        // The SIMD blocks and the scalar tail agree.
+        let row = leaf.repeat(21);
+        assert!(balanced.detect(&row).iter().all(|&m| m));
+        // A lookup table bakes the correction in, whichever order they
+        // are added in.
+        let lut_first = PlantVision::default()
+            .with_lookup_table(8)
+            .with_white_balance(WhiteBalance::new([1.0, 1.0, 0.3]));
+        assert!(lut_first.detect(&leaf)[0]);
+        assert!(balanced.with_lookup_table(8).detect(&leaf)[0]);
+    }
+
+    #[test]
+    fn shadow_gates_drop_noise_and_keep_shaded_leaves() {
+        // Near-black soil under the tractor's shadow, with a green tint
+        // from sensor noise, and a dull leaf in the same shade.
+        let noise = [6u8, 14, 4];
+        let leaf = [36u8, 44, 34];
+        let plain = PlantVision::default();
+        assert!(plain.detect(&noise)[0]);
+        assert!(!plain.detect(&leaf)[0]);
+
+        let gated = PlantVision::default().with_lighting(Lighting {
+            luma_floor: 12,
+            luma_ceiling: 0,
+            shadow_luma: 80,
+        });
+        let frame = [noise, leaf].repeat(17).concat();
+        let mask = gated.detect(&frame);
+        assert!(mask.chunks(2).all(|px| px == [false, true]), "{mask:?}");
+        assert_eq!(gated.clone().with_lookup_table(8).detect(&frame), mask);
+        // Bright pixels keep the full chroma floor.
+        assert!(!gated.detect(&[120, 90, 70])[0]);
+    }
+
+    #[test]
+    fn glare_inside_a_leaf_takes_the_leaf_decision() {
+        let soil = [120u8, 90, 70];
+        let leaf = [60u8, 200, 60];
+        let glare = [[255u8, 255, 255], [252, 250, 255]];
+        // soil | leaf, glare, leaf | soil, glare, soil | glare, leaf
+        let row = [
+            &[soil, soil, leaf][..],
+            &glare,
+            &[leaf, leaf, soil],
+            &glare,
+            &[soil],
+            &glare,
+            &[leaf],
+        ]
+        .concat()
+        .concat();
+        let plain = PlantVision::default();
+        let detected = |mask: Vec<bool>| -> Vec<usize> {
+            mask.iter()
+                .enumerate()
+                .filter(|(_, &m)| m)
+                .map(|(i, _)| i)
+                .collect()
+        };
+        assert_eq!(detected(plain.detect(&row)), [2, 5, 6, 13]);
+
+        let gated = plain.with_lighting(Lighting {
+            luma_floor: 0,
+            luma_ceiling: 240,
+            shadow_luma: 0,
+        });
+        // Only the run between two leaf pixels is filled.
+        assert_eq!(detected(gated.detect(&row)), [2, 3, 4, 5, 6, 13]);
+        assert_eq!(
+            gated.clone().with_lookup_table(8).detect(&row),
+            gated.detect(&row)
+        );
+    }
+
+    #[test]
+    fn index_replaces_fused_cues() {
+        use crate::indices::Exgr;
+
+        let dull_leaf = [36u8, 44, 34];
+        assert!(!PlantVision::default().detect(&dull_leaf)[0]);
+        let exgr = PlantVision::default().with_index(Box::new(Exgr { threshold: 0.0 }));
+        assert!(exgr.index().is_some());
+        assert!(exgr.detect(&dull_leaf)[0]);
+        assert!(!exgr.detect(&[120, 90, 70])[0]);
+        // Luminance gates still apply.
+        let gated = exgr.clone().with_lighting(Lighting {
+            luma_floor: 50,
+            luma_ceiling: 0,
+            shadow_luma: 0,
+        });
+        assert!(!gated.detect(&dull_leaf)[0]);
+        // A lookup table is built from the index.
+        let frame: Vec<u8> = (0..=255u8).flat_map(|v| [v, v / 2 + 64, 255 - v]).collect();
+        assert_eq!(
+            exgr.clone().with_lookup_table(8).detect(&frame),
+            exgr.detect(&frame)
+        );
+    }
+
+    #[test]
+    fn ndvi_mode_reads_rgbn_pixels() {
+        use super::{ndvi, PixelFormat};
+
+        assert_eq!(ndvi(50, 150), 0.5);
+        assert_eq!(ndvi(0, 0), 0.0);
+        assert_eq!(ndvi(200, 0), -1.0);
+
+        let vision = PlantVision::default().with_ndvi(0.2);
+        assert_eq!(vision.pixel_format(), PixelFormat::Rgbn);
+        let pixels = [
+            [140u8, 80, 60, 230], // red-brown leaf, bright in NIR
+            [40, 200, 40, 40],    // green plastic, dark in NIR
+            [120, 90, 70, 110],   // soil
+            [4, 6, 3, 20],        // shadow noise
+        ];
+        assert_eq!(vision.detect(&pixels.concat()), [true, false, false, true]);
+        let floored = vision.with_lighting(Lighting {
+            luma_floor: 12,
+            luma_ceiling: 0,
+            shadow_luma: 0,
+        });
+        assert_eq!(
+            floored.detect(&pixels.concat()),
+            [true, false, false, false]
+        );
+    }
 }
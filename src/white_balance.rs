@@ -0,0 +1,172 @@
+//! Software white balance applied before vegetation scoring.
+//!
+//! Cheap USB cameras drift in colour temperature, which shifts every
+//! colour cue [`crate::vision::PlantVision`] relies on. [`WhiteBalance`]
+//! scales each channel by a fixed gain through a 256-entry table per
+//! channel, so it costs three byte lookups per pixel inside the scoring
+//! pass. Gains come from config, or from a [`Calibration`] over a few
+//! frames against a grey-world or known-soil reference.
+
+/// Largest gain accepted for a single channel.
+pub const MAX_GAIN: f32 = 8.0;
+
+/// Per-channel gains with their precomputed lookup tables.
+#[derive(Clone)]
+pub struct WhiteBalance {
+    gains: [f32; 3],
+    tables: [[u8; 256]; 3],
+}
+
+impl WhiteBalance {
+    /// Scale R, G and B by `gains`, saturating at 255.
+    ///
+    /// # Panics
+    /// Panics unless every gain is finite and in `(0, MAX_GAIN]`.
+    pub fn new(gains: [f32; 3]) -> Self {
+        assert!(
+            gains
+                .iter()
+                .all(|g| g.is_finite() && *g > 0.0 && *g <= MAX_GAIN),
+            "White balance gains must be in (0, {MAX_GAIN}]"
+        );
+        let tables =
+            gains.map(|gain| std::array::from_fn(|v| (v as f32 * gain).round().min(255.0) as u8));
+        Self { gains, tables }
+    }
+
+    /// The R, G, B gains.
+    pub fn gains(&self) -> [f32; 3] {
+        self.gains
+    }
+
+    /// Balance one pixel.
+    #[inline]
+    pub fn apply(&self, r: u8, g: u8, b: u8) -> [u8; 3] {
+        [
+            self.tables[0][r as usize],
+            self.tables[1][g as usize],
+            self.tables[2][b as usize],
+        ]
+    }
+
+    /// Balance a block of interleaved RGB pixels.
+    #[inline]
+    pub fn apply_block<const N: usize>(&self, px: &[u8; N]) -> [u8; N] {
+        std::array::from_fn(|i| self.tables[i % 3][px[i] as usize])
+    }
+}
+
+impl std::fmt::Debug for WhiteBalance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WhiteBalance")
+            .field("gains", &self.gains)
+            .finish()
+    }
+}
+
+/// What a calibration frame should average to once balanced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reference {
+    /// The scene averages to grey: equalise the channel means.
+    GreyWorld,
+    /// The scene is mostly soil of this known RGB colour.
+    Soil([u8; 3]),
+}
+
+/// Accumulates channel means over calibration frames.
+#[derive(Debug, Clone, Default)]
+pub struct Calibration {
+    sums: [u64; 3],
+    pixels: u64,
+}
+
+impl Calibration {
+    /// Calibration with no frames yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add every pixel of an interleaved RGB frame.
+    pub fn add_frame(&mut self, rgb: &[u8]) {
+        for &[r, g, b] in rgb.as_chunks::<3>().0 {
+            self.sums[0] += u64::from(r);
+            self.sums[1] += u64::from(g);
+            self.sums[2] += u64::from(b);
+        }
+        self.pixels += (rgb.len() / 3) as u64;
+    }
+
+    /// Mean R, G, B of the frames seen so far.
+    pub fn means(&self) -> [f32; 3] {
+        let pixels = self.pixels.max(1) as f64;
+        self.sums.map(|sum| (sum as f64 / pixels) as f32)
+    }
+
+    /// Gains that move the observed means onto `reference`, clamped to
+    /// `[1 / MAX_GAIN, MAX_GAIN]`. A channel that was never lit keeps a
+    /// gain of 1.
+    pub fn gains(&self, reference: Reference) -> [f32; 3] {
+        let means = self.means();
+        let target = match reference {
+            Reference::GreyWorld => [means.iter().sum::<f32>() / 3.0; 3],
+            Reference::Soil(rgb) => rgb.map(f32::from),
+        };
+        std::array::from_fn(|c| {
+            if means[c] < 1.0 || target[c] < 1.0 {
+                1.0
+            } else {
+                (target[c] / means[c]).clamp(1.0 / MAX_GAIN, MAX_GAIN)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gains_scale_and_saturate() {
+        let wb = WhiteBalance::new([1.5, 1.0, 0.5]);
+        assert_eq!(wb.apply(100, 100, 100), [150, 100, 50]);
+        assert_eq!(wb.apply(200, 255, 255), [255, 255, 128]);
+        let block = wb.apply_block(&[100u8, 100, 100, 200, 255, 255]);
+        assert_eq!(block, [150, 100, 50, 255, 255, 128]);
+    }
+
+    #[test]
+    fn grey_world_equalises_a_warm_cast() {
+        // Soil and plants under a warm (red-heavy, blue-poor) light.
+        let frame: Vec<u8> = [[150, 100, 50], [90, 160, 40]].repeat(50).concat();
+        let mut calibration = Calibration::new();
+        calibration.add_frame(&frame);
+        let wb = WhiteBalance::new(calibration.gains(Reference::GreyWorld));
+        let mut balanced = Calibration::new();
+        let corrected: Vec<u8> = frame
+            .as_chunks::<3>()
+            .0
+            .iter()
+            .flat_map(|&[r, g, b]| wb.apply(r, g, b))
+            .collect();
+        balanced.add_frame(&corrected);
+        let [r, g, b] = balanced.means();
+        assert!((r - g).abs() < 1.0 && (g - b).abs() < 1.0, "{r} {g} {b}");
+    }
+
+    #[test]
+    fn soil_reference_maps_soil_onto_its_known_colour() {
+        let frame = [[130u8, 90, 50]; 10].concat();
+        let mut calibration = Calibration::new();
+        calibration.add_frame(&frame);
+        let wb = WhiteBalance::new(calibration.gains(Reference::Soil([120, 90, 70])));
+        assert_eq!(wb.apply(130, 90, 50), [120, 90, 70]);
+    }
+
+    #[test]
+    fn dark_channel_keeps_unit_gain() {
+        let mut calibration = Calibration::new();
+        calibration.add_frame(&[0, 80, 40, 0, 100, 60]);
+        assert_eq!(calibration.gains(Reference::GreyWorld)[0], 1.0);
+        assert_eq!(Calibration::new().gains(Reference::GreyWorld), [1.0; 3]);
+    }
+}
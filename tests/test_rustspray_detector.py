@@ -67,6 +67,23 @@ class TestProtocolHandshake:
         with pytest.raises(RuntimeError, match="protocol mismatch"):
             FutureDetector(BINARY, CONFIG, num_lanes=4, mock_gpio=True)
 
+    def test_rgbn_config_reports_its_own_protocol(self, tmp_path):
+        config = tmp_path / "rgbn.toml"
+        config.write_text('[camera]\npixel_format = "rgbn"\n[vision]\nindex = "ndvi"\n')
+        out = subprocess.run(
+            [BINARY, "--output-version", "--config", str(config)],
+            capture_output=True,
+            timeout=10,
+            check=True,
+        ).stdout
+        assert json.loads(out)["ipc_protocol"] == RustSprayDetector.RGBN_PROTOCOL_VERSION
+        det = RustSprayDetector(BINARY, str(config), num_lanes=4, mock_gpio=True)
+        try:
+            with pytest.raises(ValueError, match="HxWx4"):
+                det.detect(synthetic_frame(set()))
+        finally:
+            det.close()
+
     def test_missing_binary_raises(self):
         with pytest.raises(RuntimeError, match="not found"):
             RustSprayDetector("/nonexistent/rustspray", CONFIG, mock_gpio=True)
@@ -98,6 +115,46 @@ class TestDetection:
         partial[:, 0:4] = GREEN  # 4 of lane 0's 16 columns = 25%
         assert detector.detect(partial)[2][0] is True
 
+    def test_lane_coverage_reports_ratios(self, detector):
+        frame = synthetic_frame({2})
+        frame[:, 0:4] = GREEN  # 4 of lane 0's 16 columns = 25%
+        detector.detect(frame)
+        assert detector.lane_coverage == pytest.approx([0.25, 0.0, 1.0, 0.0])
+
+    def test_blob_boxes_replace_lane_strips(self, tmp_path):
+        config = tmp_path / "blobs.toml"
+        config.write_text("[vision.blobs]\nenabled = true\nmin_area = 10\n")
+        det = RustSprayDetector(BINARY, str(config), num_lanes=4, mock_gpio=True)
+        try:
+            frame = synthetic_frame(set())
+            frame[4:12, 18:30] = GREEN  # one 12x8 weed in lane 1
+            frame[1, 50] = GREEN  # a speck, dropped by min_area
+            boxes, _, lane_states = det.detect(frame)
+            assert boxes == [(18, 4, 12, 8)]
+            assert det.blobs[0]["area"] == 96
+            assert det.blobs[0]["cx"] == pytest.approx(24.0)
+            assert lane_states == [False, True, False, False]
+        finally:
+            det.close()
+
+    def test_rgbn_frames_use_ndvi(self, tmp_path):
+        config = tmp_path / "rgbn.toml"
+        config.write_text('[camera]\npixel_format = "rgbn"\n[vision]\nindex = "ndvi"\n')
+        det = RustSprayDetector(BINARY, str(config), num_lanes=4, mock_gpio=True)
+        try:
+            frame = np.zeros((HEIGHT, WIDTH, 4), dtype=np.uint8)
+            frame[:, :] = (120, 90, 70, 110)  # soil
+            frame[:, 16:32] = (140, 80, 60, 230)  # red-leaved crop, bright NIR
+            boxes, annotated, lane_states = det.detect(frame)
+            assert lane_states == [False, True, False, False]
+            assert annotated.shape == (HEIGHT, WIDTH, 3)
+        finally:
+            det.close()
+
+    def test_reports_exg_threshold(self, detector):
+        detector.detect(synthetic_frame({0}))
+        assert detector.exg_threshold == 20  # fixed [vision] exg_threshold
+
     def test_frame_counter_is_monotonic(self, detector):
         frame = synthetic_frame(set())
         first = detector._send_frame(frame.tobytes(), WIDTH, HEIGHT)